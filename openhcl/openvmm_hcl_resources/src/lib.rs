@@ -39,6 +39,9 @@
     // SCSI
     scsidisk::resolver::SimpleScsiResolver,
 
+    // Consoles
+    framebuffer::ConsoleResolver,
+
     // Vmbus devices
     hyperv_ic::resolver::ShutdownIcResolver,
     storvsp::resolver::StorvspResolver,
@@ -75,6 +75,7 @@
 #[cfg(feature = "profiler")]
 use profiler_worker::ProfilerWorkerParameters;
 use std::time::Duration;
+use vm_resource::IntoResource;
 use vmsocket::VmAddress;
 use vmsocket::VmListener;
 use vnc_worker_defs::VncParameters;
@@ -331,7 +332,13 @@ async fn launch_workers(
         let listener = VmListener::bind(VmAddress::vsock_any(opt.vnc_port))
             .context("failed to bind socket")?;
 
-        let input_send = remote_console_cfg.input.sender();
+        let console = framebuffer::ConsoleHandle {
+            framebuffer,
+            input: remote_console_cfg.input.sender(),
+            pointer: None,
+            resize: None,
+        }
+        .into_resource();
 
         let vnc_host = launch_mesh_host(mesh, "vnc", Some(tracing.tracer()))
             .await
@@ -343,8 +350,22 @@ async fn launch_workers(
                     vnc_worker_defs::VNC_WORKER_VMSOCKET,
                     VncParameters {
                         listener,
-                        framebuffer,
-                        input_send,
+                        console,
+                        name: vnc_worker_defs::DEFAULT_NAME.into(),
+                        name_updates: mesh::Receiver::new(),
+                        file_transfer_dir: None,
+                        input_audit: false,
+                        max_frame_rate: vnc_worker_defs::DEFAULT_MAX_FRAME_RATE,
+                        input_rate_limit: None,
+                        reverse_connection: None,
+                        websocket: false,
+                        websocket_origins: Vec::new(),
+                        serial: None,
+                        clipboard: None,
+                        keyboard_layout: input_core::keymap::KeyboardLayout::EnUs,
+                        permissive: false,
+                        handshake_timeout: vnc_worker_defs::DEFAULT_HANDSHAKE_TIMEOUT,
+                        registry: None,
                     },
                 )
                 .await?,
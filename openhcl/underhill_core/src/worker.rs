@@ -141,6 +141,7 @@
 use vm_resource::kind::DiskHandleKind;
 use vm_resource::kind::KeyboardInputHandleKind;
 use vm_resource::kind::MouseInputHandleKind;
+use vm_resource::kind::TabletInputHandleKind;
 use vm_topology::memory::MemoryLayout;
 use vm_topology::memory::MemoryRangeWithNode;
 use vm_topology::processor::ProcessorTopology;
@@ -2106,6 +2107,9 @@ async fn new_underhill_vm(
     resolver.add_async_resolver::<MouseInputHandleKind, _, MultiplexedInputHandle, _>(
         input_distributor.client().clone(),
     );
+    resolver.add_async_resolver::<TabletInputHandleKind, _, MultiplexedInputHandle, _>(
+        input_distributor.client().clone(),
+    );
 
     let input_distributor = state_units
         .add("input")
@@ -2821,12 +2825,15 @@ async fn new_underhill_vm(
         vmbus_device_handles.push(
             uidevices_resources::SynthVideoHandle {
                 framebuffer: video_core::SharedFramebufferHandle.into_resource(),
+                resize_requests: mesh::Receiver::new(),
+                pointer: None,
             }
             .into_resource(),
         );
         vmbus_device_handles.push(
             uidevices_resources::SynthKeyboardHandle {
                 source: MultiplexedInputHandle { elevation: 1 }.into_resource(),
+                lock_keys: None,
             }
             .into_resource(),
         );
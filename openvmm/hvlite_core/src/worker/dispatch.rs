@@ -76,6 +76,8 @@
 use scsi_core::ResolveScsiDeviceHandleParams;
 use scsidisk::SimpleScsiDisk;
 use scsidisk::atapi_scsi::AtapiScsiDisk;
+use sdhci::SdhciController;
+use sdhci_resources::SdhciDiskConfig;
 use serial_16550_resources::ComPort;
 use state_unit::SavedStateUnit;
 use state_unit::SpawnedUnit;
@@ -102,6 +104,7 @@
 use vm_resource::kind::DiskHandleKind;
 use vm_resource::kind::KeyboardInputHandleKind;
 use vm_resource::kind::MouseInputHandleKind;
+use vm_resource::kind::TabletInputHandleKind;
 use vm_resource::kind::VirtioDeviceHandle;
 use vm_resource::kind::VmbusDeviceHandleKind;
 use vm_topology::memory::MemoryLayout;
@@ -160,6 +163,7 @@ fn from_config(config: Config) -> Self {
             load_mode: config.load_mode,
             floppy_disks: config.floppy_disks,
             ide_disks: config.ide_disks,
+            sdhci_disks: config.sdhci_disks,
             vpci_devices: config.vpci_devices,
             hypervisor: config.hypervisor,
             memory: config.memory,
@@ -201,6 +205,7 @@ pub struct Manifest {
     load_mode: LoadMode,
     floppy_disks: Vec<FloppyDiskConfig>,
     ide_disks: Vec<IdeDeviceConfig>,
+    sdhci_disks: Vec<SdhciDiskConfig>,
     vpci_devices: Vec<VpciDeviceConfig>,
     memory: MemoryConfig,
     processor_topology: ProcessorTopologyConfig,
@@ -569,6 +574,60 @@ fn choose_hypervisor() -> anyhow::Result<Hypervisor> {
     anyhow::bail!("no hypervisor available");
 }
 
+/// Checks that the chipset includes the firmware device needed by the load
+/// mode, and no other, along with the devices that the firmware and the video
+/// devices depend on, so that a mismatched configuration fails at VM creation
+/// instead of leaving the guest with no firmware to run.
+fn validate_firmware_config(
+    load_mode: &LoadMode,
+    chipset: &BaseChipsetManifest,
+    has_vga_firmware: bool,
+    has_framebuffer: bool,
+) -> anyhow::Result<()> {
+    let (firmware, needs_pcat, needs_uefi) = match load_mode {
+        LoadMode::Pcat { .. } => ("PCAT BIOS", true, false),
+        LoadMode::Uefi { .. } => ("UEFI", false, true),
+        LoadMode::Linux { .. } => ("Linux direct boot", false, false),
+        LoadMode::Igvm { .. } => ("IGVM", false, false),
+        LoadMode::None => ("no firmware", false, false),
+    };
+    for (name, needed, present) in [
+        ("PCAT BIOS", needs_pcat, chipset.with_hyperv_firmware_pcat),
+        ("UEFI", needs_uefi, chipset.with_hyperv_firmware_uefi),
+    ] {
+        if needed && !present {
+            anyhow::bail!("{firmware} requires the {name} firmware device in the chipset");
+        }
+        if present && !needed {
+            anyhow::bail!("the {name} firmware device cannot be used with {firmware}");
+        }
+    }
+    // The PCAT BIOS programs the PIIX4 chipset and keeps its settings in CMOS.
+    if needs_pcat {
+        for (name, present) in [
+            ("PIIX4 PCI bus", chipset.with_piix4_pci_bus),
+            ("PIIX4 PCI-ISA bridge", chipset.with_piix4_pci_isa_bridge),
+            ("PIIX4 CMOS RTC", chipset.with_piix4_cmos_rtc),
+        ] {
+            if !present {
+                anyhow::bail!("{firmware} requires the {name} device in the chipset");
+            }
+        }
+    }
+    if chipset.with_hyperv_vga {
+        if !chipset.with_piix4_pci_bus {
+            anyhow::bail!("the VGA device requires the PIIX4 PCI bus in the chipset");
+        }
+        if !has_vga_firmware {
+            anyhow::bail!("the VGA device requires a VGA BIOS ROM");
+        }
+    }
+    if chipset.with_hyperv_framebuffer && !has_framebuffer {
+        anyhow::bail!("the framebuffer device requires a framebuffer");
+    }
+    Ok(())
+}
+
 fn convert_vtl2_config(
     vtl2_cfg: Option<&Vtl2Config>,
     load_mode: &LoadMode,
@@ -723,6 +782,13 @@ async fn new_with_hypervisor<P, H>(
     {
         tracing::info!(mem_size = cfg.memory.mem_size, "guest RAM config");
 
+        validate_firmware_config(
+            &cfg.load_mode,
+            &cfg.chipset,
+            cfg.vga_firmware.is_some(),
+            cfg.framebuffer.is_some(),
+        )?;
+
         let vmtime_keeper = VmTimeKeeper::new(&driver_source.simple(), VmTime::from_100ns(0));
         let vmtime_source = vmtime_keeper
             .builder()
@@ -1103,6 +1169,7 @@ async fn load(
             LoadMode::Pcat {
                 firmware,
                 boot_order,
+                num_lock,
             } => {
                 tracing::debug!(?firmware, "Loading BIOS firmware.");
                 let rom_builder = RomBuilder::new("bios".into(), Box::new(mapper.clone()));
@@ -1153,7 +1220,7 @@ async fn load(
                                     attached: true,
                                 })
                             },
-                            num_lock_enabled: false,
+                            num_lock_enabled: *num_lock,
                             // TODO: these are all very bogus values, and need to be swapped out with something better
                             smbios: firmware_pcat::config::SmbiosConstants {
                                 bios_guid: Guid {
@@ -1222,6 +1289,9 @@ async fn load(
         resolver.add_async_resolver::<MouseInputHandleKind, _, MultiplexedInputHandle, _>(
             input_distributor.client().clone(),
         );
+        resolver.add_async_resolver::<TabletInputHandleKind, _, MultiplexedInputHandle, _>(
+            input_distributor.client().clone(),
+        );
 
         let input_distributor = state_units
             .add("input")
@@ -2074,6 +2144,43 @@ async fn add_virtio_vpci(
             }
         }
 
+        // Construct SD host controllers, one per card.
+        for (index, disk_cfg) in cfg.sdhci_disks.into_iter().enumerate() {
+            let SdhciDiskConfig {
+                disk_type,
+                read_only,
+            } = disk_cfg;
+
+            let disk = open_simple_disk(&resolver, disk_type, read_only)
+                .await
+                .context("failed to open sd card disk")?;
+
+            let pci_inta_line = pci_inta_line.context("missing PCI INT#A line")?;
+
+            let device_number = pci_device_number;
+            pci_device_number += 1;
+            pci_legacy_interrupts.push(((device_number, None), pci_inta_line));
+
+            let bus = if cfg.chipset.with_piix4_pci_bus {
+                pci_bus_id_piix4.clone()
+            } else {
+                pci_bus_id_generic.clone()
+            };
+
+            chipset_builder
+                .arc_mutex_device(format!("sdhci{index}"))
+                .with_pci_addr(0, device_number, 0)
+                .on_pci_bus(bus)
+                .try_add(|services| {
+                    SdhciController::new(
+                        disk,
+                        read_only,
+                        services.new_line(IRQ_LINE_SET, "interrupt", pci_inta_line),
+                        &mut services.register_mmio(),
+                    )
+                })?;
+        }
+
         let mut virt_serial_io = None;
         {
             if with_virtio_serial_mmio {
@@ -2835,6 +2942,7 @@ async fn serialize(
             load_mode: self.inner.load_mode,
             floppy_disks: vec![], // TODO
             ide_disks: vec![],    // TODO
+            sdhci_disks: vec![],  // TODO
             vpci_devices: vec![], // TODO
             memory: self.inner.memory_cfg,
             processor_topology: self.inner.processor_topology.to_config(),
@@ -2965,3 +3073,146 @@ fn add_devices_to_dsdt(
     dsdt.add_vmbus(cfg.with_generic_pci_bus || cfg.with_i440bx_host_pci_bridge);
     dsdt.add_rtc();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hvlite_defs::config::DEFAULT_PCAT_BOOT_ORDER;
+    use hvlite_pcat_locator::RomFileLocation;
+
+    fn chipset(pcat: bool, uefi: bool) -> BaseChipsetManifest {
+        BaseChipsetManifest {
+            with_hyperv_firmware_pcat: pcat,
+            with_hyperv_firmware_uefi: uefi,
+            with_piix4_pci_bus: pcat,
+            with_piix4_pci_isa_bridge: pcat,
+            with_piix4_cmos_rtc: pcat,
+            ..BaseChipsetManifest::empty()
+        }
+    }
+
+    fn uefi() -> LoadMode {
+        LoadMode::Uefi {
+            firmware: tempfile::tempfile().unwrap(),
+            enable_debugging: false,
+            enable_memory_protections: false,
+            disable_frontpage: false,
+            enable_tpm: false,
+            enable_battery: false,
+            enable_serial: false,
+            enable_vpci_boot: false,
+            uefi_console_mode: None,
+            default_boot_always_attempt: false,
+        }
+    }
+
+    fn pcat() -> LoadMode {
+        LoadMode::Pcat {
+            firmware: RomFileLocation {
+                file: tempfile::tempfile().unwrap(),
+                start: 0,
+                len: 0,
+            },
+            boot_order: DEFAULT_PCAT_BOOT_ORDER,
+            num_lock: false,
+        }
+    }
+
+    #[test]
+    fn firmware_config_matches_load_mode() {
+        validate_firmware_config(&pcat(), &chipset(true, false), false, false).unwrap();
+        validate_firmware_config(&uefi(), &chipset(false, true), false, false).unwrap();
+        validate_firmware_config(&LoadMode::None, &chipset(false, false), false, false).unwrap();
+    }
+
+    #[test]
+    fn firmware_config_rejects_mismatches() {
+        for (load_mode, pcat, uefi, error) in [
+            (
+                pcat(),
+                false,
+                false,
+                "PCAT BIOS requires the PCAT BIOS firmware device in the chipset",
+            ),
+            (
+                pcat(),
+                true,
+                true,
+                "the UEFI firmware device cannot be used with PCAT BIOS",
+            ),
+            (
+                uefi(),
+                false,
+                false,
+                "UEFI requires the UEFI firmware device in the chipset",
+            ),
+            (
+                uefi(),
+                true,
+                true,
+                "the PCAT BIOS firmware device cannot be used with UEFI",
+            ),
+            (
+                LoadMode::None,
+                false,
+                true,
+                "the UEFI firmware device cannot be used with no firmware",
+            ),
+        ] {
+            let err = validate_firmware_config(&load_mode, &chipset(pcat, uefi), false, false)
+                .unwrap_err();
+            assert_eq!(err.to_string(), error);
+        }
+    }
+
+    #[test]
+    fn firmware_config_rejects_missing_dependencies() {
+        let err = validate_firmware_config(
+            &pcat(),
+            &BaseChipsetManifest {
+                with_piix4_cmos_rtc: false,
+                ..chipset(true, false)
+            },
+            false,
+            false,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "PCAT BIOS requires the PIIX4 CMOS RTC device in the chipset"
+        );
+
+        let vga = BaseChipsetManifest {
+            with_hyperv_vga: true,
+            ..chipset(true, false)
+        };
+        validate_firmware_config(&pcat(), &vga, true, false).unwrap();
+        let err = validate_firmware_config(&pcat(), &vga, false, false).unwrap_err();
+        assert_eq!(err.to_string(), "the VGA device requires a VGA BIOS ROM");
+        let err = validate_firmware_config(
+            &uefi(),
+            &BaseChipsetManifest {
+                with_hyperv_vga: true,
+                ..chipset(false, true)
+            },
+            true,
+            false,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the VGA device requires the PIIX4 PCI bus in the chipset"
+        );
+
+        let framebuffer = BaseChipsetManifest {
+            with_hyperv_framebuffer: true,
+            ..chipset(false, true)
+        };
+        validate_firmware_config(&uefi(), &framebuffer, false, true).unwrap();
+        let err = validate_firmware_config(&uefi(), &framebuffer, false, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the framebuffer device requires a framebuffer"
+        );
+    }
+}
@@ -25,6 +25,7 @@ pub struct Config {
     pub load_mode: LoadMode,
     pub floppy_disks: Vec<floppy_resources::FloppyDiskConfig>,
     pub ide_disks: Vec<ide_resources::IdeDeviceConfig>,
+    pub sdhci_disks: Vec<sdhci_resources::SdhciDiskConfig>,
     pub vpci_devices: Vec<VpciDeviceConfig>,
     pub memory: MemoryConfig,
     pub processor_topology: ProcessorTopologyConfig,
@@ -119,6 +120,8 @@ pub enum LoadMode {
     Pcat {
         firmware: RomFileLocation,
         boot_order: [PcatBootDevice; 4],
+        /// Whether the BIOS turns NumLock on at boot.
+        num_lock: bool,
     },
     Igvm {
         file: File,
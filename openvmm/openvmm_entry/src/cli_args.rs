@@ -27,11 +27,15 @@
 use hvlite_defs::config::PcatBootDevice;
 use hvlite_defs::config::Vtl2BaseAddressType;
 use hvlite_defs::config::X2ApicConfig;
+use input_core::keymap::KeyboardLayout;
+use input_core::remap::KeyRemap;
 use std::ffi::OsString;
 use std::net::SocketAddr;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use std::str::FromStr;
 use thiserror::Error;
+use vnc_worker_defs::VncProxy;
 
 /// OpenVMM virtual machine monitor.
 ///
@@ -208,9 +212,112 @@ pub struct Options {
     #[clap(long)]
     pub vnc: bool,
 
-    /// VNC port number
-    #[clap(long, value_name = "PORT", default_value = "5900")]
-    pub vnc_port: u16,
+    /// VNC port number, or a range of ports as START-END to use the first
+    /// free one (for launching many VMs without assigning each a port)
+    #[clap(long, value_name = "PORT", default_value = "5900", value_parser = parse_vnc_port)]
+    pub vnc_port: RangeInclusive<u16>,
+
+    /// listen for vnc connections on a Unix domain socket at PATH instead of
+    /// a TCP port, for proxying console traffic without opening a port
+    #[clap(long, value_name = "PATH")]
+    pub vnc_path: Option<PathBuf>,
+
+    /// accept WebSocket connections on the vnc port or socket, so that
+    /// browser clients such as noVNC can connect without a websockify proxy
+    #[clap(long)]
+    pub vnc_websocket: bool,
+
+    /// allow WebSocket connections from web pages with ORIGIN, such as
+    /// `http://localhost:6080` for a local noVNC (can be passed multiple
+    /// times). Connections from other web pages are refused
+    #[clap(long, value_name = "ORIGIN", requires("vnc_websocket"))]
+    pub vnc_websocket_origin: Vec<String>,
+
+    /// host directory to expose to VNC clients via the UltraVNC file transfer
+    /// extension
+    #[clap(long, value_name = "PATH")]
+    pub vnc_file_transfer_dir: Option<PathBuf>,
+
+    /// log a summary of each VNC connection's input (event counts, timing,
+    /// and special key combinations such as Ctrl-Alt-Del), without the keys
+    /// typed
+    #[clap(long)]
+    pub vnc_input_audit: bool,
+
+    /// the VM name shown by VNC clients (can be changed at runtime with the
+    /// interactive `rename` command)
+    #[clap(long, value_name = "NAME", default_value = vnc_worker_defs::DEFAULT_NAME)]
+    pub vnc_name: String,
+
+    /// the keyboard layout the guest is configured with, so that characters
+    /// typed on VNC clients (including AltGr and dead key combinations) and
+    /// with the interactive `type` command reach the guest as the right keys
+    /// (en-us, de, fr, or ja)
+    #[clap(long, value_name = "LAYOUT", default_value = "en-us")]
+    pub vnc_keyboard_layout: KeyboardLayout,
+
+    /// work around VNC clients that do not follow the protocol (for example,
+    /// ones that expect screen updates without requesting them), rather than
+    /// disconnecting them
+    #[clap(long)]
+    pub vnc_permissive: bool,
+
+    /// the time in seconds (at least 1) allowed for a VNC client to send its
+    /// part of each phase of the connection handshake before it is
+    /// disconnected
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        default_value_t = vnc_worker_defs::DEFAULT_HANDSHAKE_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub vnc_handshake_timeout: u64,
+
+    /// the maximum number of VNC framebuffer updates to send per second, from
+    /// 1 to 240
+    #[clap(
+        long,
+        value_name = "FPS",
+        default_value_t = vnc_worker_defs::DEFAULT_MAX_FRAME_RATE,
+        value_parser = clap::value_parser!(u32).range(1..=240)
+    )]
+    pub vnc_max_fps: u32,
+
+    /// limit the keyboard and pointer events accepted from each VNC client to
+    /// this many per second, dropping the excess
+    #[clap(
+        long,
+        value_name = "EVENTS",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub vnc_input_rate: Option<u32>,
+
+    /// the number of VNC input events allowed in a burst before
+    /// --vnc-input-rate applies (defaults to one second's worth)
+    #[clap(
+        long,
+        value_name = "EVENTS",
+        requires("vnc_input_rate"),
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub vnc_input_burst: Option<u32>,
+
+    /// connect to a VNC viewer listening at HOST:PORT (a reverse connection),
+    /// reconnecting whenever no client is connected
+    #[clap(long, value_name = "HOST:PORT")]
+    pub vnc_connect: Option<String>,
+
+    /// the proxy to make --vnc-connect connections through, as
+    /// http://HOST:PORT (using CONNECT) or socks5://HOST:PORT
+    #[clap(long, value_name = "URL", requires("vnc_connect"), value_parser = parse_vnc_proxy)]
+    pub vnc_proxy: Option<VncProxy>,
+
+    /// keys to remap before they reach the guest, as a comma-separated list of
+    /// swap-caps-ctrl, super-to-alt, FROM=TO, or CODE=off, where the codes are
+    /// hex scancodes (can be changed at runtime with the interactive
+    /// `key-remap` command)
+    #[clap(long, value_name = "SPEC")]
+    pub key_remap: Option<KeyRemap>,
 
     /// set the APIC ID offset, for testing APIC IDs that don't match VP index
     #[cfg(guest_arch = "x86_64")]
@@ -238,19 +345,19 @@ pub struct Options {
     #[clap(long, conflicts_with("virtio_console"))]
     pub virtio_console_pci: bool,
 
-    /// COM1 binding (console | stderr | listen=\<path\> | listen=tcp:\<ip\>:\<port\> | term[=\<program\>][,name=<windowtitle>] | none)
+    /// COM1 binding (console | stderr | listen=\<path\> | listen=tcp:\<ip\>:\<port\> | term[=\<program\>][,name=<windowtitle>] | vnc[,readonly] | none)
     #[clap(long, value_name = "SERIAL")]
     pub com1: Option<SerialConfigCli>,
 
-    /// COM2 binding (console | stderr | listen=\<path\> | listen=tcp:\<ip\>:\<port\> | term[=\<program\>][,name=<windowtitle>] | none)
+    /// COM2 binding (console | stderr | listen=\<path\> | listen=tcp:\<ip\>:\<port\> | term[=\<program\>][,name=<windowtitle>] | vnc[,readonly] | none)
     #[clap(long, value_name = "SERIAL")]
     pub com2: Option<SerialConfigCli>,
 
-    /// COM3 binding (console | stderr | listen=\<path\> | listen=tcp:\<ip\>:\<port\> | term[=\<program\>][,name=<windowtitle>] | none)
+    /// COM3 binding (console | stderr | listen=\<path\> | listen=tcp:\<ip\>:\<port\> | term[=\<program\>][,name=<windowtitle>] | vnc[,readonly] | none)
     #[clap(long, value_name = "SERIAL")]
     pub com3: Option<SerialConfigCli>,
 
-    /// COM4 binding (console | stderr | listen=\<path\> | listen=tcp:\<ip\>:\<port\> | term[=\<program\>][,name=<windowtitle>] | none)
+    /// COM4 binding (console | stderr | listen=\<path\> | listen=tcp:\<ip\>:\<port\> | term[=\<program\>][,name=<windowtitle>] | vnc[,readonly] | none)
     #[clap(long, value_name = "SERIAL")]
     pub com4: Option<SerialConfigCli>,
 
@@ -258,11 +365,11 @@ pub struct Options {
     #[clap(long, value_name = "SERIAL")]
     pub virtio_serial: Option<SerialConfigCli>,
 
-    /// vmbus com1 serial binding (console | stderr | listen=\<path\> | listen=tcp:\<ip\>:\<port\> | term[=\<program\>][,name=<windowtitle>] | none)
+    /// vmbus com1 serial binding (console | stderr | listen=\<path\> | listen=tcp:\<ip\>:\<port\> | term[=\<program\>][,name=<windowtitle>] | vnc[,readonly] | none)
     #[structopt(long, value_name = "SERIAL")]
     pub vmbus_com1_serial: Option<SerialConfigCli>,
 
-    /// vmbus com2 serial binding (console | stderr | listen=\<path\> | listen=tcp:\<ip\>:\<port\> | term[=\<program\>][,name=<windowtitle>] | none)
+    /// vmbus com2 serial binding (console | stderr | listen=\<path\> | listen=tcp:\<ip\>:\<port\> | term[=\<program\>][,name=<windowtitle>] | vnc[,readonly] | none)
     #[structopt(long, value_name = "SERIAL")]
     pub vmbus_com2_serial: Option<SerialConfigCli>,
 
@@ -498,6 +605,31 @@ pub struct Options {
     #[clap(long, value_name = "FILE", requires("pcat"), conflicts_with("uefi"))]
     pub floppy: Vec<FloppyDiskCli>,
 
+    /// attach an SD card, on its own PCI SD host controller (can be passed
+    /// multiple times)
+    ///
+    #[clap(long_help = r#"
+e.g: --sdhci file:/path/to/sdcard.img,ro
+
+syntax: \<path\> | kind:<arg>[,flag,opt=arg,...]
+
+valid disk kinds:
+    `mem:<len>`                    memory backed disk
+        <len>: length of ramdisk, e.g.: `1G`
+    `memdiff:<disk>`               memory backed diff disk
+        <disk>: lower disk, e.g.: `file:base.img`
+    `file:\<path\>`                  file-backed disk
+        \<path\>: path to file
+
+flags:
+    `ro`                           write-protect the card
+
+The controller needs a PCI bus, so this is only supported with `--pcat`, or
+with Linux direct boot on x86_64 without `--hv`.
+"#)]
+    #[clap(long, value_name = "FILE")]
+    pub sdhci: Vec<SdhciDiskCli>,
+
     /// enable guest watchdog device
     #[clap(long)]
     pub guest_watchdog: bool,
@@ -526,6 +658,28 @@ pub struct Options {
     #[clap(long)]
     pub battery: bool,
 
+    /// turn NumLock on in the guest at boot and after restore
+    #[clap(long_help = r#"
+Turn NumLock on in the guest at boot and after restore.
+
+Whenever the guest sets the keyboard LEDs, the PS/2 and synthetic keyboards
+press the lock keys that differ from the requested state, until the user first
+types. With PCAT BIOS firmware, the BIOS also turns NumLock on. CapsLock is
+turned off unless --caps-lock is also passed.
+"#)]
+    #[clap(long)]
+    pub num_lock: bool,
+
+    /// turn CapsLock on in the guest at boot and after restore, in the same
+    /// way as --num-lock
+    #[clap(long)]
+    pub caps_lock: bool,
+
+    /// the action to take when the guest resets the CPU via the i8042
+    /// keyboard controller (PCAT only)
+    #[clap(long, value_name = "ACTION", default_value = "reset")]
+    pub i8042_reset: I8042ResetCli,
+
     /// set the uefi console mode
     #[clap(long)]
     pub uefi_console_mode: Option<UefiConsoleModeCli>,
@@ -591,6 +745,16 @@ pub enum VirtioBusCli {
     Vpci,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum I8042ResetCli {
+    /// Reset the VM.
+    Reset,
+    /// Power off the VM.
+    PowerOff,
+    /// Log the request and keep the VM running.
+    Notify,
+}
+
 #[derive(clap::ValueEnum, Clone, Copy)]
 pub enum SecureBootTemplateCli {
     Windows,
@@ -920,6 +1084,32 @@ fn from_str(s: &str) -> anyhow::Result<Self> {
     }
 }
 
+// <kind>[,ro]
+#[derive(Clone)]
+pub struct SdhciDiskCli {
+    pub kind: DiskCliKind,
+    pub read_only: bool,
+}
+
+impl FromStr for SdhciDiskCli {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut opts = s.split(',');
+        let kind = opts.next().unwrap().parse()?;
+
+        let mut read_only = false;
+        for opt in opts {
+            match opt {
+                "ro" => read_only = true,
+                _ => anyhow::bail!("unknown option: '{opt}'"),
+            }
+        }
+
+        Ok(SdhciDiskCli { kind, read_only })
+    }
+}
+
 #[derive(Clone)]
 pub struct DebugconSerialConfigCli {
     pub port: u16,
@@ -953,6 +1143,10 @@ pub enum SerialConfigCli {
     Stderr,
     Pipe(PathBuf),
     Tcp(SocketAddr),
+    /// Mirrored to VNC clients through text chat.
+    Vnc {
+        read_only: bool,
+    },
 }
 
 impl FromStr for SerialConfigCli {
@@ -971,6 +1165,9 @@ fn from_str(s: &str) -> Result<Self, Self::Err> {
             "none" => SerialConfigCli::None,
             "console" => SerialConfigCli::Console,
             "stderr" => SerialConfigCli::Stderr,
+            "vnc" => SerialConfigCli::Vnc {
+                read_only: keyvalues.iter().any(|(key, _)| key == "readonly"),
+            },
             "term" => match first_value {
                 Some(path) => {
                     // If user supplies a name key, use it to title the window
@@ -1179,6 +1376,29 @@ fn parse_vtl2_relocation(s: &str) -> Result<Vtl2BaseAddressType, UnknownVtl2Relo
     }
 }
 
+fn parse_vnc_port(s: &str) -> Result<RangeInclusive<u16>, &'static str> {
+    const ERR: &str = "expected PORT or START-END";
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    let start = start.parse().map_err(|_| ERR)?;
+    let end = end.parse().map_err(|_| ERR)?;
+    if start > end {
+        return Err("the port range is empty");
+    }
+    Ok(start..=end)
+}
+
+fn parse_vnc_proxy(s: &str) -> Result<VncProxy, &'static str> {
+    if let Some(address) = s.strip_prefix("http://") {
+        Ok(VncProxy::HttpConnect(
+            address.trim_end_matches('/').to_owned(),
+        ))
+    } else if let Some(address) = s.strip_prefix("socks5://") {
+        Ok(VncProxy::Socks5(address.trim_end_matches('/').to_owned()))
+    } else {
+        Err("expected http://HOST:PORT or socks5://HOST:PORT")
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum SmtConfigCli {
     Auto,
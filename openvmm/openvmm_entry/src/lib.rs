@@ -24,11 +24,13 @@
 use anyhow::Context;
 use anyhow::bail;
 use chipset_resources::battery::HostBatteryUpdate;
+use chipset_resources::i8042::I8042ResetAction;
 use clap::CommandFactory;
 use clap::FromArgMatches;
 use clap::Parser;
 use cli_args::DiskCliKind;
 use cli_args::EndpointConfigCli;
+use cli_args::I8042ResetCli;
 use cli_args::NicConfigCli;
 use cli_args::SerialConfigCli;
 use cli_args::UefiConsoleModeCli;
@@ -78,6 +80,11 @@
 use hvlite_defs::worker::VmWorkerParameters;
 use hvlite_helpers::disk::open_disk_type;
 use input_core::MultiplexedInputHandle;
+use input_core::key_sequence::KeySequence;
+use input_core::lock_keys::LockKeys;
+use input_core::rate_limit::InputRateLimit;
+use input_core::remap::KeyRemap;
+use input_core::remap::RemappedKeyboardHandle;
 use inspect::InspectMut;
 use inspect::InspectionBuilder;
 use io::Read;
@@ -100,6 +107,7 @@
 use pal_async::timer::PolledTimer;
 use scsidisk_resources::SimpleScsiDiskHandle;
 use scsidisk_resources::SimpleScsiDvdHandle;
+use sdhci_resources::SdhciDiskConfig;
 use serial_16550_resources::ComPort;
 use serial_core::resources::DisconnectedSerialBackendHandle;
 use serial_io::SerialIo;
@@ -112,6 +120,7 @@
 use std::io::IsTerminal;
 use std::io::Write;
 use std::net::TcpListener;
+use std::ops::RangeInclusive;
 use std::path::Path;
 use std::path::PathBuf;
 use std::pin::pin;
@@ -147,7 +156,12 @@
 use vmcore::non_volatile_store::resources::EphemeralNonVolatileStoreHandle;
 use vmgs_resources::VmgsFileHandle;
 use vmotherboard::ChipsetDeviceHandle;
+use vnc_worker_defs::ReverseConnection;
+use vnc_worker_defs::VncClipboard;
 use vnc_worker_defs::VncParameters;
+use vnc_worker_defs::VncRegistration;
+use vnc_worker_defs::VncRegistryRequest;
+use vnc_worker_defs::VncSerial;
 
 pub fn hvlite_main() {
     // Save the current state of the terminal so we can restore it back to
@@ -186,6 +200,10 @@ struct VmResources {
     kvp_ic: Option<mesh::Sender<hyperv_ic_resources::kvp::KvpConnectRpc>>,
     scsi_rpc: Option<mesh::Sender<ScsiControllerRequest>>,
     ged_rpc: Option<mesh::Sender<get_resources::ged::GuestEmulationRequest>>,
+    vnc_serial: Option<VncSerial>,
+    video_resize: Option<mesh::Sender<uidevices_resources::ResizeRpc>>,
+    video_pointer: Option<video_core::PointerSource>,
+    key_remap: Option<mesh::CellUpdater<KeyRemap>>,
     #[cfg(windows)]
     switch_ports: Vec<vmswitch::kernel::SwitchPort>,
 }
@@ -210,6 +228,7 @@ fn vm_config_from_command_line(
     };
 
     let console_state: RefCell<Option<ConsoleState<'_>>> = RefCell::new(None);
+    let vnc_serial: RefCell<Option<VncSerial>> = RefCell::new(None);
     let setup_serial = |name: &str, cli_cfg, device| -> anyhow::Result<_> {
         Ok(match cli_cfg {
             SerialConfigCli::Console => {
@@ -253,6 +272,41 @@ fn vm_config_from_command_line(
             SerialConfigCli::Tcp(addr) => {
                 Some(serial_io::bind_tcp_serial(&addr).context("failed to bind serial")?)
             }
+            SerialConfigCli::Vnc { read_only } => {
+                if let Some(vnc_serial) = vnc_serial.borrow().as_ref() {
+                    bail!("vnc serial already set by {}", vnc_serial.name);
+                }
+                let (config, serial) = serial_io::anonymous_serial_pair(&serial_driver)?;
+                let (mut serial_read, mut serial_write) = AsyncReadExt::split(serial);
+                let (output_send, output_recv) = mesh::channel();
+                serial_driver
+                    .spawn(format!("{name}-vnc-output"), async move {
+                        let mut buf = vec![0; 4096];
+                        while let Ok(n @ 1..) = serial_read.read(&mut buf).await {
+                            output_send.send(buf[..n].to_vec());
+                        }
+                    })
+                    .detach();
+                let input = (!read_only).then(|| {
+                    let (input_send, mut input_recv) = mesh::channel::<Vec<u8>>();
+                    serial_driver
+                        .spawn(format!("{name}-vnc-input"), async move {
+                            while let Some(data) = input_recv.next().await {
+                                if serial_write.write_all(&data).await.is_err() {
+                                    break;
+                                }
+                            }
+                        })
+                        .detach();
+                    input_send
+                });
+                *vnc_serial.borrow_mut() = Some(VncSerial {
+                    name: name.to_owned(),
+                    output: output_recv,
+                    input,
+                });
+                Some(config)
+            }
             SerialConfigCli::NewConsole(app, window_title) => {
                 let path = console_relay::random_console_path();
                 let config =
@@ -306,6 +360,7 @@ fn vm_config_from_command_line(
                 Some(io.config)
             }
             SerialConfigCli::Tcp(_addr) => anyhow::bail!("TCP virtio serial not supported"),
+            SerialConfigCli::Vnc { .. } => anyhow::bail!("VNC virtio serial not supported"),
             SerialConfigCli::NewConsole(app, window_title) => {
                 let path = console_relay::random_console_path();
 
@@ -443,6 +498,7 @@ fn vm_config_from_command_line(
         resources.console_in = Some(input);
         console_str = device;
     }
+    resources.vnc_serial = vnc_serial.into_inner();
 
     if opt.shared_memory {
         tracing::warn!("--shared-memory/-M flag has no effect and will be removed");
@@ -536,6 +592,21 @@ fn vm_config_from_command_line(
         })
         .collect::<Result<Vec<_>, _>>()?;
 
+    let sdhci_disks: Vec<_> = opt
+        .sdhci
+        .iter()
+        .map(|disk| -> anyhow::Result<_> {
+            let &cli_args::SdhciDiskCli {
+                ref kind,
+                read_only,
+            } = disk;
+            Ok(SdhciDiskConfig {
+                disk_type: disk_open(kind, read_only)?,
+                read_only,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
     let mut mana_nics = [(); 3].map(|()| None);
     let mut underhill_nics = Vec::new();
     let mut vpci_devices = Vec::new();
@@ -730,6 +801,35 @@ fn vm_config_from_command_line(
         tx.send(HostBatteryUpdate::default_present());
         chipset = chipset.with_battery(rx);
     }
+    let i8042_reset_action = match opt.i8042_reset {
+        I8042ResetCli::Reset => I8042ResetAction::Reset,
+        I8042ResetCli::PowerOff => I8042ResetAction::PowerOff,
+        I8042ResetCli::Notify => {
+            let (send, mut recv) = mesh::channel();
+            spawner
+                .spawn("i8042-reset", async move {
+                    while let Ok(()) = recv.recv().await {
+                        tracing::info!("guest requested reset via the i8042 controller");
+                    }
+                })
+                .detach();
+            I8042ResetAction::Notify(send)
+        }
+    };
+    chipset = chipset.with_i8042_reset_action(i8042_reset_action);
+    let lock_keys = (opt.num_lock || opt.caps_lock).then_some(LockKeys {
+        num_lock: opt.num_lock,
+        caps_lock: opt.caps_lock,
+    });
+    if let Some(lock_keys) = lock_keys {
+        chipset = chipset.with_lock_keys(lock_keys);
+    }
+    // Each keyboard remaps its input with its own cell of the table, so that
+    // the interactive key-remap command updates them all.
+    let key_remap = resources.key_remap.insert(mesh::CellUpdater::new(
+        opt.key_remap.clone().unwrap_or_default(),
+    ));
+    chipset = chipset.with_key_remap(key_remap.cell());
     if let Some(cfg) = &opt.debugcon {
         chipset = chipset.with_debugcon(
             debugcon_cfg.unwrap_or_else(|| DisconnectedSerialBackendHandle.into_resource()),
@@ -744,6 +844,14 @@ fn vm_config_from_command_line(
         .build()
         .context("failed to build chipset configuration")?;
 
+    // SD host controllers are PCI devices, so emit a nice error early instead
+    // of failing to find their interrupt line when the VM starts.
+    if !opt.sdhci.is_empty()
+        && !(chipset.with_generic_pci_bus || chipset.with_i440bx_host_pci_bridge)
+    {
+        anyhow::bail!("--sdhci requires a PCI bus, which this VM configuration does not have");
+    }
+
     if let Some(path) = &opt.igvm {
         let file = fs_err::File::open(path)
             .context("failed to open igvm file")?
@@ -774,6 +882,7 @@ fn vm_config_from_command_line(
                 .pcat_boot_order
                 .map(|x| x.0)
                 .unwrap_or(DEFAULT_PCAT_BOOT_ORDER),
+            num_lock: opt.num_lock,
         };
     } else if opt.uefi {
         use hvlite_defs::config::UefiConsoleMode;
@@ -1046,22 +1155,33 @@ fn vm_config_from_command_line(
     };
 
     if opt.gfx {
+        let (resize_send, resize_recv) = mesh::channel();
+        resources.video_resize = Some(resize_send);
+        let (pointer_source, pointer_sink) = video_core::pointer_pair();
+        resources.video_pointer = Some(pointer_source);
         vmbus_devices.extend([
             (
                 DeviceVtl::Vtl0,
                 SynthVideoHandle {
                     framebuffer: SharedFramebufferHandle.into_resource(),
+                    resize_requests: resize_recv,
+                    pointer: Some(pointer_sink),
                 }
                 .into_resource(),
             ),
             (
                 DeviceVtl::Vtl0,
                 SynthKeyboardHandle {
-                    source: MultiplexedInputHandle {
-                        // Save 0 for PS/2
-                        elevation: 1,
+                    source: RemappedKeyboardHandle {
+                        source: MultiplexedInputHandle {
+                            // Save 0 for PS/2
+                            elevation: 1,
+                        }
+                        .into_resource(),
+                        table: resources.key_remap.as_mut().expect("always set").cell(),
                     }
                     .into_resource(),
+                    lock_keys,
                 }
                 .into_resource(),
             ),
@@ -1291,6 +1411,7 @@ fn vm_config_from_command_line(
         floppy_disks,
         vpci_devices,
         ide_disks: Vec::new(),
+        sdhci_disks,
         memory: MemoryConfig {
             mem_size: opt.memory,
             mmio_gaps,
@@ -1370,6 +1491,20 @@ fn openvmm_terminal_app() -> Option<PathBuf> {
         .map(Into::into)
 }
 
+/// Binds to the first free port in `ports` on localhost.
+fn bind_vnc_port(ports: &RangeInclusive<u16>) -> anyhow::Result<TcpListener> {
+    for port in ports.clone() {
+        match TcpListener::bind(("127.0.0.1", port)) {
+            Ok(listener) => return Ok(listener),
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse && port != *ports.end() => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("binding to VNC port {port}"));
+            }
+        }
+    }
+    unreachable!("port ranges are not empty")
+}
+
 // Tries to remove `path` if it is confirmed to be a Unix socket.
 fn cleanup_socket(path: &Path) {
     #[cfg(windows)]
@@ -1812,6 +1947,22 @@ enum InteractiveCommand {
     #[clap(visible_alias = "V")]
     RestartVnc,
 
+    /// Rename the VM, updating the name shown by VNC clients.
+    Rename {
+        /// The new name.
+        name: String,
+    },
+
+    /// Show the address the VNC server is listening on.
+    Vnc,
+
+    /// Show the text last copied on a VNC client, or copy text to the VNC
+    /// clients.
+    Clipboard {
+        /// The text to copy.
+        text: Option<String>,
+    },
+
     /// Start an hvsocket terminal window.
     #[clap(visible_alias = "v")]
     Hvsock {
@@ -1833,6 +1984,50 @@ enum InteractiveCommand {
     #[clap(visible_alias = "i")]
     Input { data: Vec<String> },
 
+    /// Type text into the VM using the keyboard.
+    ///
+    /// The input parameters are typed separated by spaces, using the keyboard
+    /// layout given by --vnc-keyboard-layout (US English by default).
+    #[clap(visible_alias = "t")]
+    Type {
+        /// The delay between keystrokes, in milliseconds.
+        #[clap(long, default_value = "10")]
+        delay_ms: u64,
+        /// Press enter after typing the text.
+        #[clap(long, short = 'n')]
+        enter: bool,
+        data: Vec<String>,
+    },
+
+    /// Send a key combination to the VM using the keyboard.
+    Keys {
+        /// The delay between keystrokes, in milliseconds.
+        #[clap(long, default_value = "10")]
+        delay_ms: u64,
+        #[clap(subcommand)]
+        sequence: KeysCommand,
+    },
+
+    /// Ask the guest to change its display resolution.
+    ///
+    /// Completes once the guest sets its new video mode. Requires the
+    /// synthetic video device (--gfx).
+    Resize {
+        /// The width, in pixels.
+        width: u16,
+        /// The height, in pixels.
+        height: u16,
+    },
+
+    /// Replace the table of keys remapped before they reach the guest.
+    ///
+    /// Takes the same comma-separated list as --key-remap. With no list,
+    /// keys are sent unchanged.
+    KeyRemap {
+        /// The remappings.
+        remap: Option<KeyRemap>,
+    },
+
     /// Switch to input mode.
     ///
     /// Once in input mode, Ctrl-Q returns to command mode.
@@ -1906,6 +2101,20 @@ enum InteractiveCommand {
     Kvp(kvp::KvpCommand),
 }
 
+#[derive(clap::Subcommand)]
+enum KeysCommand {
+    /// Ctrl+Alt+Del.
+    CtrlAltDel,
+    /// Alt+SysRq+KEY, a Linux magic SysRq command.
+    Sysrq {
+        /// The command key, such as `s` to sync or `b` to reboot.
+        key: char,
+    },
+    /// Right Ctrl+Scroll Lock twice, to crash a Windows guest configured with
+    /// CrashOnCtrlScroll.
+    CrashDump,
+}
+
 struct CommandParser {
     app: clap::Command,
 }
@@ -1941,32 +2150,85 @@ fn new_hvsock_service_id(port: u32) -> Guid {
 
 async fn run_control(driver: &DefaultDriver, mesh: &VmmMesh, opt: Options) -> anyhow::Result<()> {
     let (mut vm_config, mut resources) = vm_config_from_command_line(driver, &opt)?;
+    let input_send = vm_config.input.sender();
+    // The layout given for VNC clients is the guest's, so text typed from the
+    // console uses it too.
+    let keyboard_layout = opt.vnc_keyboard_layout;
 
     let mut vnc_worker = None;
+    let mut vnc_rename = None;
+    let mut vnc_clipboard = None;
+    let (vnc_registry_send, vnc_registry_recv) = mesh::channel();
+    let (vnc_client_text_send, vnc_client_text_recv) = mesh::channel();
+    if resources.vnc_serial.is_some() && !(opt.gfx || opt.vnc) {
+        bail!("mirroring a serial port to vnc requires --vnc or --gfx");
+    }
     if opt.gfx || opt.vnc {
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", opt.vnc_port))
-            .with_context(|| format!("binding to VNC port {}", opt.vnc_port))?;
-
-        let input_send = vm_config.input.sender();
-        let framebuffer = resources.framebuffer_access.expect("synth video enabled");
+        let console = framebuffer::ConsoleHandle {
+            framebuffer: resources.framebuffer_access.expect("synth video enabled"),
+            input: vm_config.input.sender(),
+            pointer: resources.video_pointer,
+            resize: resources.video_resize.clone(),
+        }
+        .into_resource();
 
         let vnc_host = mesh
             .make_host("vnc", None)
             .await
             .context("spawning vnc process failed")?;
 
-        vnc_worker = Some(
+        let (rename_send, rename_recv) = mesh::channel();
+        vnc_rename = Some(rename_send);
+        let (guest_text_send, guest_text_recv) = mesh::channel();
+        vnc_clipboard = Some(guest_text_send);
+        let params = VncParameters {
+            listener: (),
+            console,
+            name: opt.vnc_name.clone(),
+            name_updates: rename_recv,
+            file_transfer_dir: opt.vnc_file_transfer_dir.clone(),
+            input_audit: opt.vnc_input_audit,
+            max_frame_rate: opt.vnc_max_fps,
+            input_rate_limit: opt.vnc_input_rate.map(|events_per_second| InputRateLimit {
+                events_per_second,
+                burst: opt.vnc_input_burst.unwrap_or(events_per_second),
+            }),
+            reverse_connection: opt.vnc_connect.clone().map(|viewer| ReverseConnection {
+                viewer,
+                proxy: opt.vnc_proxy.clone(),
+            }),
+            websocket: opt.vnc_websocket,
+            websocket_origins: opt.vnc_websocket_origin.clone(),
+            serial: resources.vnc_serial.take(),
+            clipboard: Some(VncClipboard {
+                client_text: vnc_client_text_send,
+                guest_text: guest_text_recv,
+            }),
+            keyboard_layout: opt.vnc_keyboard_layout,
+            permissive: opt.vnc_permissive,
+            handshake_timeout: Duration::from_secs(opt.vnc_handshake_timeout),
+            registry: Some(vnc_registry_send),
+        };
+        let worker = if let Some(path) = &opt.vnc_path {
+            cleanup_socket(path);
+            let listener = unix_socket::UnixListener::bind(path)
+                .with_context(|| format!("binding to VNC socket {}", path.display()))?;
+            vnc_host
+                .launch_worker(
+                    vnc_worker_defs::VNC_WORKER_UNIX,
+                    params.with_listener(listener),
+                )
+                .await?
+        } else {
+            let listener = bind_vnc_port(&opt.vnc_port)?;
             vnc_host
                 .launch_worker(
                     vnc_worker_defs::VNC_WORKER_TCP,
-                    VncParameters {
-                        listener,
-                        framebuffer,
-                        input_send,
-                    },
+                    params.with_listener(listener),
                 )
-                .await?,
-        )
+                .await?
+        };
+        vnc_worker = Some(worker);
     }
 
     // spin up the debug worker
@@ -2203,6 +2465,8 @@ enum Event {
         PulseSaveRestore,
         Worker(WorkerEvent),
         VncWorker(WorkerEvent),
+        VncRegistry(VncRegistryRequest),
+        VncClipboard(String),
         StateChange(Result<StateChange, RpcError>),
         ShutdownResult(Result<hyperv_ic_resources::shutdown::ShutdownResult, RpcError>),
     }
@@ -2213,6 +2477,15 @@ enum Event {
 
     let mut notify_recv = notify_recv.map(Event::Halt);
 
+    let mut vnc_registry_recv = vnc_registry_recv.map(Event::VncRegistry);
+    // Where the VNC server is listening, which may not be known until it
+    // starts if its port was allocated from a range.
+    let mut vnc_registrations = Vec::<VncRegistration>::new();
+
+    let mut vnc_client_text_recv = vnc_client_text_recv.map(Event::VncClipboard);
+    // The text last copied on a VNC client.
+    let mut vnc_client_text = None;
+
     let mut inspect_completion_engine_recv =
         inspect_completion_engine_recv.map(Event::InspectRequestFromCompletionEngine);
 
@@ -2251,6 +2524,8 @@ enum Event {
                 pulse_save_restore.into_stream(),
                 vm,
                 vnc,
+                &mut vnc_registry_recv,
+                &mut vnc_client_text_recv,
                 change,
                 shutdown.into_stream(),
             )
@@ -2355,6 +2630,20 @@ enum Event {
                 }
                 continue;
             }
+            Event::VncRegistry(req) => {
+                match req {
+                    VncRegistryRequest::Register(registration) => {
+                        vnc_registrations.retain(|r| r.address != registration.address);
+                        vnc_registrations.push(registration);
+                    }
+                    VncRegistryRequest::List(rpc) => rpc.complete(vnc_registrations.clone()),
+                }
+                continue;
+            }
+            Event::VncClipboard(text) => {
+                vnc_client_text = Some(text);
+                continue;
+            }
             Event::StateChange(r) => {
                 match r {
                     Ok(sc) => match sc {
@@ -2545,6 +2834,81 @@ fn state_change<U: 'static + Send>(
             InteractiveCommand::Nmi => {
                 let _ = vm_rpc.call(VmRpc::Nmi, 0).await;
             }
+            InteractiveCommand::Type {
+                delay_ms,
+                enter,
+                data,
+            } => {
+                let mut text = data.join(" ");
+                if enter {
+                    text.push('\n');
+                }
+                let input_send = input_send.clone();
+                let mut timer = PolledTimer::new(driver);
+                driver
+                    .spawn("type-text", async move {
+                        if let Err(err) = input_core::text::type_text(
+                            &input_send,
+                            &mut timer,
+                            keyboard_layout,
+                            &text,
+                            Duration::from_millis(delay_ms),
+                        )
+                        .await
+                        {
+                            eprintln!("error: {err}");
+                        }
+                    })
+                    .detach();
+            }
+            InteractiveCommand::Resize { width, height } => {
+                let Some(video_resize) = resources.video_resize.clone() else {
+                    eprintln!("error: no synthetic video device configured");
+                    continue;
+                };
+                driver
+                    .spawn("resize", async move {
+                        let result = CancelContext::new()
+                            .with_timeout(Duration::from_secs(30))
+                            .until_cancelled(video_resize.call_failable(|rpc| rpc, (width, height)))
+                            .await;
+                        match result {
+                            Ok(Ok((width, height))) => {
+                                println!("resolution changed to {width}x{height}")
+                            }
+                            Ok(Err(err)) => eprintln!("error: {err}"),
+                            Err(_) => eprintln!("error: timed out waiting for the guest"),
+                        }
+                    })
+                    .detach();
+            }
+            InteractiveCommand::KeyRemap { remap } => {
+                let updater = resources.key_remap.as_mut().expect("always set");
+                updater.set(remap.unwrap_or_default()).await;
+            }
+            InteractiveCommand::Keys { delay_ms, sequence } => {
+                let sequence = match sequence {
+                    KeysCommand::CtrlAltDel => KeySequence::CtrlAltDel,
+                    KeysCommand::Sysrq { key } => KeySequence::SysRq(key),
+                    KeysCommand::CrashDump => KeySequence::CrashOnCtrlScroll,
+                };
+                let input_send = input_send.clone();
+                let mut timer = PolledTimer::new(driver);
+                driver
+                    .spawn("send-keys", async move {
+                        if let Err(err) = input_core::key_sequence::send_key_sequence(
+                            &input_send,
+                            &mut timer,
+                            sequence,
+                            Duration::from_millis(delay_ms),
+                        )
+                        .await
+                        {
+                            eprintln!("error: {err}");
+                        }
+                    })
+                    .detach();
+            }
             InteractiveCommand::ClearHalt => {
                 vm_rpc.call(VmRpc::ClearHalt, ()).await.ok();
             }
@@ -2691,6 +3055,31 @@ fn state_change<U: 'static + Send>(
                     eprintln!("ERROR: no VNC server running");
                 }
             }
+            InteractiveCommand::Rename { name } => {
+                if let Some(rename) = &vnc_rename {
+                    rename.send(name);
+                } else {
+                    eprintln!("ERROR: no VNC server running");
+                }
+            }
+            InteractiveCommand::Vnc => {
+                if vnc_registrations.is_empty() {
+                    eprintln!("ERROR: no VNC server running");
+                }
+                for VncRegistration { name, address } in &vnc_registrations {
+                    println!("{address} ({name})");
+                }
+            }
+            InteractiveCommand::Clipboard { text } => {
+                if let Some(clipboard) = &vnc_clipboard {
+                    match text {
+                        Some(text) => clipboard.send(text),
+                        None => println!("{}", vnc_client_text.as_deref().unwrap_or("")),
+                    }
+                } else {
+                    eprintln!("ERROR: no VNC server running");
+                }
+            }
             InteractiveCommand::Hvsock { term, port } => {
                 let vm_rpc = &vm_rpc;
                 let action = async || {
@@ -27,6 +27,8 @@
 use hvlite_defs::worker::VmWorkerParameters;
 use hvlite_helpers::disk::open_disk_type;
 use hvlite_ttrpc_vmservice as vmservice;
+use input_core::MultiplexedInputHandle;
+use input_core::key_sequence::KeySequence;
 use inspect::Inspect;
 use inspect::InspectionBuilder;
 use inspect_proto::InspectResponse2;
@@ -46,6 +48,7 @@
 use pal_async::DefaultDriver;
 use pal_async::DefaultPool;
 use pal_async::task::Spawn;
+use pal_async::timer::PolledTimer;
 use parking_lot::Mutex;
 use scsidisk_resources::SimpleScsiDiskHandle;
 use std::fs::File;
@@ -55,6 +58,7 @@
 use storvsp_resources::ScsiControllerHandle;
 use storvsp_resources::ScsiControllerRequest;
 use storvsp_resources::ScsiDeviceAndPath;
+use uidevices_resources::SynthKeyboardHandle;
 use unix_socket::UnixListener;
 use virtio_resources::VirtioPciDeviceHandle;
 use vm_manifest_builder::VmManifestBuilder;
@@ -259,6 +263,8 @@ fn start_rpc<F, R>(
 struct Vm {
     worker_rpc: mesh::Sender<VmRpc>,
     scsi_rpc: Option<mesh::Sender<ScsiControllerRequest>>,
+    /// Input for the synthetic keyboard, if the VM has one.
+    input_send: Option<mesh::Sender<input_core::InputData>>,
     notify_recv: Mutex<Option<mesh::Receiver<HaltReason>>>,
 }
 
@@ -334,6 +340,10 @@ async fn handle(&mut self, ctx: mesh::CancelContext, request: vmservice::Vm) ->
                         let r = self.modify_resource(&vm, request);
                         self.start_rpc(response, r);
                     }
+                    vmservice::Vm::SendKeySequence(request, response) => {
+                        let r = self.send_key_sequence(&vm, request);
+                        self.start_rpc(response, r);
+                    }
 
                     r @ vmservice::Vm::CapabilitiesVm(_, _)
                     | r @ vmservice::Vm::PropertiesVm(_, _) => {
@@ -452,11 +462,13 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
         .build()
         .context("failed to build vm configuration")?;
 
+        let (input_send, input_recv) = mesh::channel();
         let mut config = Config {
             // TODO: devices, other stuff
             load_mode,
             ide_disks: vec![],
             floppy_disks: vec![],
+            sdhci_disks: vec![],
             vpci_devices: vec![],
             memory: MemoryConfig {
                 mem_size: req_config
@@ -486,7 +498,7 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
             },
             #[cfg(windows)]
             kernel_vmnics: vec![],
-            input: mesh::Receiver::new(),
+            input: input_recv,
             framebuffer: None,
             vga_firmware: None,
             vtl2_gfx: false,
@@ -510,6 +522,7 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
         };
 
         let mut scsi_rpc = None;
+        let mut keyboard_input = None;
         if let Some(devices_config) = req_config.devices_config {
             if !devices_config.scsi_disks.is_empty() {
                 let mut devices = Vec::new();
@@ -531,6 +544,18 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
                 scsi_rpc = Some(send);
             }
 
+            if devices_config.synthetic_keyboard {
+                config.vmbus_devices.push((
+                    DeviceVtl::Vtl0,
+                    SynthKeyboardHandle {
+                        source: MultiplexedInputHandle { elevation: 1 }.into_resource(),
+                        lock_keys: None,
+                    }
+                    .into_resource(),
+                ));
+                keyboard_input = Some(input_send);
+            }
+
             for nic in devices_config.nic_config {
                 config.vmbus_devices.push(parse_nic_config(nic)?);
             }
@@ -590,6 +615,7 @@ async fn create_vm(&mut self, request: vmservice::CreateVmRequest) -> anyhow::Re
         self.worker_handle = Some(worker);
         self.vm = Some(Arc::new(Vm {
             scsi_rpc,
+            input_send: keyboard_input,
             notify_recv: Mutex::new(Some(notify_recv)),
             worker_rpc: send,
         }));
@@ -614,6 +640,42 @@ fn resume_vm(&mut self, vm: &Vm) -> impl Future<Output = anyhow::Result<()>> + u
         async move { recv.await.map(drop).context("resume failed") }
     }
 
+    fn send_key_sequence(
+        &mut self,
+        vm: &Vm,
+        request: vmservice::SendKeySequenceRequest,
+    ) -> anyhow::Result<impl Future<Output = anyhow::Result<()>> + use<>> {
+        use vmservice::send_key_sequence_request::Sequence;
+
+        let sequence = if request.sequence == Sequence::CtrlAltDel as i32 {
+            KeySequence::CtrlAltDel
+        } else if request.sequence == Sequence::Sysrq as i32 {
+            let mut chars = request.sysrq_key.chars();
+            let (Some(key), None) = (chars.next(), chars.next()) else {
+                bail!("sysrq_key must be a single character");
+            };
+            KeySequence::SysRq(key)
+        } else if request.sequence == Sequence::CrashOnCtrlScroll as i32 {
+            KeySequence::CrashOnCtrlScroll
+        } else {
+            bail!("unsupported key sequence {}", request.sequence);
+        };
+        let delay = Duration::from_millis(match request.delay_ms {
+            0 => 10,
+            ms => ms.into(),
+        });
+        let input_send = vm
+            .input_send
+            .clone()
+            .context("the VM was not created with a synthetic keyboard")?;
+        let mut timer = PolledTimer::new(&self.driver);
+        Ok(async move {
+            input_core::key_sequence::send_key_sequence(&input_send, &mut timer, sequence, delay)
+                .await?;
+            Ok(())
+        })
+    }
+
     fn wait_vm(
         &mut self,
         mut ctx: mesh::CancelContext,
@@ -73,6 +73,12 @@
     virtio_net::resolver::VirtioNetResolver,
     virtio_pmem::resolver::VirtioPmemResolver,
 
+    // Consoles
+    framebuffer::ConsoleResolver,
+
+    // Input
+    input_core::remap::RemappedKeyboardResolver,
+
     // Vmbus devices
     guest_crash_device::resolver::GuestCrashDeviceResolver,
     guest_emulation_device::resolver::GuestEmulationDeviceResolver,
@@ -91,6 +97,7 @@
 mesh_worker::register_workers! {
     hvlite_core::VmWorker,
     vnc_worker::VncWorker<std::net::TcpListener>,
+    vnc_worker::VncWorker<unix_socket::UnixListener>,
 
     #[cfg(feature = "gdb")]
     debug_worker::DebuggerWorker<std::net::TcpListener>,
@@ -327,6 +327,7 @@ pub fn new(
             // Devices
             floppy_disks,
             ide_disks,
+            sdhci_disks: Vec::new(),
             vpci_devices,
             vmbus_devices,
 
@@ -557,6 +558,7 @@ fn load_firmware(&self) -> anyhow::Result<LoadMode> {
                 LoadMode::Pcat {
                     firmware,
                     boot_order: DEFAULT_PCAT_BOOT_ORDER,
+                    num_lock: false,
                 }
             }
             (
@@ -880,6 +882,8 @@ fn config_video(
                 DeviceVtl::Vtl0,
                 SynthVideoHandle {
                     framebuffer: SharedFramebufferHandle.into_resource(),
+                    resize_requests: mesh::Receiver::new(),
+                    pointer: None,
                 }
                 .into_resource(),
             )),
@@ -904,6 +904,47 @@ fn default_field(item: &mut InplaceOption<'_, T>) -> Result<()> {
     }
 }
 
+/// A field encoder for paths, which are encoded as strings.
+///
+/// Paths that are not valid UTF-8 are encoded lossily, since the native
+/// encoding of such paths differs between platforms.
+#[cfg(feature = "std")]
+pub struct PathField;
+
+#[cfg(feature = "std")]
+builtin_field_type!(std::path::PathBuf, PathField, "string");
+
+#[cfg(feature = "std")]
+impl<R> FieldEncode<std::path::PathBuf, R> for PathField {
+    fn write_field(item: std::path::PathBuf, writer: FieldWriter<'_, '_, R>) {
+        writer.bytes(item.to_string_lossy().as_bytes())
+    }
+
+    fn compute_field_size(item: &mut std::path::PathBuf, sizer: FieldSizer<'_>) {
+        sizer.bytes(item.to_string_lossy().len())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R> FieldDecode<'_, std::path::PathBuf, R> for PathField {
+    fn read_field(
+        item: &mut InplaceOption<'_, std::path::PathBuf>,
+        reader: FieldReader<'_, '_, R>,
+    ) -> Result<()> {
+        item.set(
+            core::str::from_utf8(reader.bytes()?)
+                .map_err(DecodeError::InvalidUtf8)?
+                .into(),
+        );
+        Ok(())
+    }
+
+    fn default_field(item: &mut InplaceOption<'_, std::path::PathBuf>) -> Result<()> {
+        item.set(Default::default());
+        Ok(())
+    }
+}
+
 /// An encoder for `Cow<'a, str>` or `Cow<'a, [u8]>` that creates
 /// [`Cow::Borrowed`] on read.
 pub struct BorrowedCowField;
@@ -1734,6 +1775,11 @@ impl DefaultEncoding for Cow<'_, str> {
     type Encoding = StringField;
 }
 
+#[cfg(feature = "std")]
+impl DefaultEncoding for std::path::PathBuf {
+    type Encoding = PathField;
+}
+
 impl<T: DefaultEncoding> DefaultEncoding for Option<T> {
     type Encoding = OptionField<T::Encoding>;
 }
@@ -1521,6 +1521,17 @@ fn test_duration() {
         );
     }
 
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_path() {
+        assert_field_roundtrips(
+            std::path::PathBuf::from("dir/file"),
+            expect!([r#"
+                1: string "dir/file"
+                raw: 0a086469722f66696c65"#]),
+        );
+    }
+
     #[test]
     fn test_failure_recovery() {
         let m = encode(("foo", 2, 3));
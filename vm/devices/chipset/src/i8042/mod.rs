@@ -19,8 +19,10 @@
 use chipset_device::poll_device::PollDevice;
 use input_core::InputSource;
 use input_core::KeyboardData;
+use input_core::lock_keys::LockKeys;
 use inspect::Inspect;
 use inspect::InspectMut;
+use inspect_counters::Counter;
 use open_enum::open_enum;
 use spec::CommandFlag;
 use spec::ControllerCommand;
@@ -30,6 +32,7 @@
 use std::task::Waker;
 use vmcore::device_state::ChangeDeviceState;
 use vmcore::line_interrupt::LineInterrupt;
+use vmcore::vmtime::VmTimeAccess;
 
 /// An Intel 8042 keyboard/mouse controller.
 #[derive(InspectMut)]
@@ -37,6 +40,9 @@ pub struct I8042Device {
     // Runtime glue
     #[inspect(skip)]
     trigger_reset: Box<dyn Fn() + Send + Sync>,
+    /// Whether `trigger_reset` resets the device, so that it only needs to be
+    /// called once until then.
+    latch_reset: bool,
     keyboard_interrupt: LineInterrupt,
     mouse_interrupt: LineInterrupt,
 
@@ -47,20 +53,90 @@ pub struct I8042Device {
     // Runtime book-keeping
     #[inspect(skip)]
     waker: Option<Waker>,
+    #[inspect(mut)]
+    faults: FaultInjection,
+    stats: I8042Stats,
+    /// The levels last set on the interrupt lines, so that the lines are only
+    /// updated when they change.
+    #[inspect(skip)]
+    keyboard_interrupt_level: bool,
+    #[inspect(skip)]
+    mouse_interrupt_level: bool,
 
     // Volatile state
     state: I8042State,
 }
 
+/// Faults to inject into the keyboard and mouse output, to exercise guest
+/// driver error handling. Each count is set through inspect and decremented as
+/// the fault is injected.
+#[derive(InspectMut, Default)]
+struct FaultInjection {
+    /// The number of device acknowledgements (0xFA) to drop.
+    #[inspect(mut)]
+    drop_acks: u32,
+    /// The number of device output bytes to replace with 0xFF, as sent by a
+    /// device after a parity error or other failure.
+    #[inspect(mut)]
+    error_responses: u32,
+    /// The number of times to discard the keyboard's pending output and report
+    /// a buffer overrun instead.
+    #[inspect(mut)]
+    keyboard_overruns: u32,
+}
+
+impl FaultInjection {
+    /// Applies any pending faults to a byte from the keyboard or mouse,
+    /// returning `None` if the byte is dropped.
+    fn apply(&mut self, byte: u8) -> Option<u8> {
+        if byte == DEVICE_ACKNOWLEDGE && self.drop_acks > 0 {
+            self.drop_acks -= 1;
+            tracing::info!("injected fault: dropped acknowledgement");
+            return None;
+        }
+        if self.error_responses > 0 {
+            self.error_responses -= 1;
+            tracing::info!(byte, "injected fault: error response");
+            return Some(DEVICE_ERROR);
+        }
+        Some(byte)
+    }
+}
+
+/// Each output byte needs its own interrupt, since the guest reads one byte at
+/// a time, so these show how efficiently input is being delivered.
+#[derive(Inspect, Default)]
+struct I8042Stats {
+    /// Keyboard interrupts raised.
+    keyboard_interrupts: Counter,
+    /// Bytes of keyboard output loaded into the output buffer.
+    keyboard_bytes: Counter,
+    /// Mouse interrupts raised.
+    mouse_interrupts: Counter,
+    /// Bytes of mouse output loaded into the output buffer.
+    mouse_bytes: Counter,
+}
+
+/// The response sent by the keyboard and mouse to acknowledge a command.
+const DEVICE_ACKNOWLEDGE: u8 = 0xfa;
+/// The response sent by the keyboard and mouse on an error.
+const DEVICE_ERROR: u8 = 0xff;
+
 #[derive(Inspect, Clone)]
+#[inspect(extra = "I8042State::inspect_extra")]
 struct I8042State {
     command_flag: CommandFlag,
     #[inspect(flatten)]
     data_port_target: DataPortTarget,
+    #[inspect(hex)]
     output_buffer: u8,
     output_buffer_state: OutputBufferState,
     a20_gate: bool,
+    #[inspect(bytes)]
     memory: [u8; 32],
+    /// Set once the guest has requested a reset, so that repeated requests
+    /// before the reset takes effect only trigger it once.
+    reset_requested: bool,
 }
 
 #[derive(Inspect, Copy, Clone, PartialEq, Eq)]
@@ -92,8 +168,42 @@ fn new() -> Self {
             output_buffer: 0,
             output_buffer_state: OutputBufferState::Empty,
             a20_gate: true,
+            reset_requested: false,
         }
     }
+
+    /// Whether the keyboard interrupt (IRQ1) is asserted.
+    fn keyboard_interrupt_pending(&self) -> bool {
+        self.command_flag.allow_keyboard_interrupts()
+            && self.output_buffer_state == OutputBufferState::Keyboard
+    }
+
+    /// Whether the mouse interrupt (IRQ12) is asserted.
+    fn mouse_interrupt_pending(&self) -> bool {
+        self.command_flag.allow_mouse_interrupts()
+            && self.output_buffer_state == OutputBufferState::Mouse
+    }
+
+    /// Reports the command byte and interrupt state decoded, so that input
+    /// problems can be diagnosed without consulting the 8042 documentation.
+    fn inspect_extra(&self, resp: &mut inspect::Response<'_>) {
+        let port_state = |disabled| if disabled { "disabled" } else { "enabled" };
+        resp.hex("command_byte", u8::from(self.command_flag))
+            .field(
+                "keyboard_port",
+                port_state(self.command_flag.disable_keyboard()),
+            )
+            .field("mouse_port", port_state(self.command_flag.disable_mouse()))
+            .field(
+                "scan_code_translation",
+                self.command_flag.enable_scan_code(),
+            )
+            .field(
+                "keyboard_interrupt_pending",
+                self.keyboard_interrupt_pending(),
+            )
+            .field("mouse_interrupt_pending", self.mouse_interrupt_pending());
+    }
 }
 
 open_enum! {
@@ -106,23 +216,36 @@ enum ControllerPort: u16 {
 impl I8042Device {
     /// Returns a new controller with an attached PS/2 keyboard.
     ///
-    /// Calls `reset` to reset the VM on guest request.
+    /// Calls `reset` on guest request to reset the VM. If `latch_reset` is
+    /// set, `reset` is expected to reset the device, and is called at most
+    /// once until it does; otherwise it is called on every request. If
+    /// `lock_keys` is set, the keyboard brings the guest's lock keys to it at
+    /// boot and after restore. `mouse_vmtime` is used to time the mouse's self
+    /// test.
     pub async fn new(
         reset: Box<dyn Fn() + Send + Sync>,
+        latch_reset: bool,
         keyboard_interrupt: LineInterrupt,
         mouse_interrupt: LineInterrupt,
         mut keyboard_input: Box<dyn InputSource<KeyboardData>>,
+        lock_keys: Option<LockKeys>,
+        mouse_vmtime: VmTimeAccess,
     ) -> Self {
         // Activate the input immediately.
         keyboard_input.set_active(true).await;
         I8042Device {
             trigger_reset: reset,
+            latch_reset,
             keyboard_interrupt,
             mouse_interrupt,
             state: I8042State::new(),
-            keyboard: Ps2Keyboard::new(keyboard_input),
-            mouse: Ps2Mouse::new(),
+            keyboard: Ps2Keyboard::new(keyboard_input, lock_keys),
+            mouse: Ps2Mouse::new(mouse_vmtime),
             waker: None,
+            faults: FaultInjection::default(),
+            stats: I8042Stats::default(),
+            keyboard_interrupt_level: false,
+            mouse_interrupt_level: false,
         }
     }
 }
@@ -135,11 +258,16 @@ async fn stop(&mut self) {}
     async fn reset(&mut self) {
         let Self {
             trigger_reset: _,
+            latch_reset: _,
             keyboard_interrupt: _,
             mouse_interrupt: _,
             keyboard,
             mouse,
             waker: _,
+            faults: _,
+            stats: _,
+            keyboard_interrupt_level: _,
+            mouse_interrupt_level: _,
             state,
         } = self;
 
@@ -164,6 +292,7 @@ fn supports_poll_device(&mut self) -> Option<&mut dyn PollDevice> {
 impl PollDevice for I8042Device {
     fn poll_device(&mut self, cx: &mut Context<'_>) {
         self.keyboard.poll(cx);
+        self.mouse.poll(cx);
         self.load_device_output();
         self.waker = Some(cx.waker().clone());
     }
@@ -260,14 +389,29 @@ fn get_static_regions(&mut self) -> &[(&str, std::ops::RangeInclusive<u16>)] {
 
 impl I8042Device {
     fn sync_interrupts(&mut self) {
-        self.keyboard_interrupt.set_level(
-            self.state.command_flag.allow_keyboard_interrupts()
-                && self.state.output_buffer_state == OutputBufferState::Keyboard,
-        );
-        self.mouse_interrupt.set_level(
-            self.state.command_flag.allow_mouse_interrupts()
-                && self.state.output_buffer_state == OutputBufferState::Mouse,
-        );
+        let keyboard = self.state.keyboard_interrupt_pending();
+        if keyboard != self.keyboard_interrupt_level {
+            self.keyboard_interrupt_level = keyboard;
+            self.keyboard_interrupt.set_level(keyboard);
+            if keyboard {
+                self.stats.keyboard_interrupts.increment();
+            }
+        }
+        let mouse = self.state.mouse_interrupt_pending();
+        if mouse != self.mouse_interrupt_level {
+            self.mouse_interrupt_level = mouse;
+            self.mouse_interrupt.set_level(mouse);
+            if mouse {
+                self.stats.mouse_interrupts.increment();
+            }
+        }
+    }
+
+    fn request_reset(&mut self) {
+        if !self.state.reset_requested {
+            self.state.reset_requested = self.latch_reset;
+            (self.trigger_reset)();
+        }
     }
 
     fn write_output_byte(&mut self, state: OutputBufferState, data: u8) {
@@ -360,7 +504,7 @@ fn handle_command(
                 }
                 if !output_port.reset() {
                     tracing::info!("initiated reset via WRITE_OUTPUT_PORT command");
-                    (self.trigger_reset)();
+                    self.request_reset();
                 }
             }
             ControllerCommand::WRITE_OUTPUT_BUFFER => {
@@ -385,7 +529,7 @@ fn handle_command(
                     // If we get this command, the program wants to restart the
                     // machine if bit 0 of the command is clear.
                     tracing::info!("initiated reset via PULSE_OUTPUT_FX command");
-                    (self.trigger_reset)();
+                    self.request_reset();
                 } else {
                     // This command (along with commands 0xF0 through 0xFE) strobes the
                     // four output bits on the keyboard controller. Except for 0xFE, all
@@ -429,19 +573,35 @@ fn load_device_output(&mut self) -> bool {
             return true;
         }
 
-        if !self.state.command_flag.disable_mouse() {
-            if let Some(byte) = self.mouse.output() {
-                self.write_output_byte(OutputBufferState::Mouse, byte);
+        loop {
+            let mouse_byte = if self.state.command_flag.disable_mouse() {
+                None
+            } else {
+                self.mouse.output()
+            };
+            let (state, byte) = if let Some(byte) = mouse_byte {
+                (OutputBufferState::Mouse, byte)
+            } else {
+                if self.faults.keyboard_overruns > 0 && self.keyboard.overrun() {
+                    self.faults.keyboard_overruns -= 1;
+                    tracing::info!("injected fault: keyboard overrun");
+                }
+                let Some(byte) = self.keyboard.output() else {
+                    return false;
+                };
+                (OutputBufferState::Keyboard, byte)
+            };
+
+            if let Some(byte) = self.faults.apply(byte) {
+                match state {
+                    OutputBufferState::Keyboard => self.stats.keyboard_bytes.increment(),
+                    OutputBufferState::Mouse => self.stats.mouse_bytes.increment(),
+                    OutputBufferState::Empty | OutputBufferState::Controller => unreachable!(),
+                }
+                self.write_output_byte(state, byte);
                 return true;
             }
         }
-
-        if let Some(byte) = self.keyboard.output() {
-            self.write_output_byte(OutputBufferState::Keyboard, byte);
-            return true;
-        }
-
-        false
     }
 
     /// Loads the output buffer with the next device output byte, waking the
@@ -494,6 +654,8 @@ pub struct SavedControllerState {
             pub a20_gate: bool,
             #[mesh(6)]
             pub memory: [u8; 32],
+            #[mesh(7)]
+            pub reset_requested: bool,
         }
 
         #[derive(Protobuf)]
@@ -532,6 +694,7 @@ fn save(&mut self) -> Result<Self::SavedState, SaveError> {
                 output_buffer_state,
                 a20_gate,
                 memory,
+                reset_requested,
             } = self.state;
 
             let saved_state = state::SavedState {
@@ -553,6 +716,7 @@ fn save(&mut self) -> Result<Self::SavedState, SaveError> {
                     },
                     a20_gate,
                     memory,
+                    reset_requested,
                 },
                 keyboard: self.keyboard.save()?,
                 mouse: self.mouse.save()?,
@@ -576,6 +740,7 @@ fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
                     output_buffer_state,
                     a20_gate,
                     memory,
+                    reset_requested,
                 } = controller;
 
                 self.state = I8042State {
@@ -596,6 +761,7 @@ fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
                     },
                     a20_gate,
                     memory,
+                    reset_requested,
                 };
             }
 
@@ -608,3 +774,341 @@ fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expect_test::ExpectFile;
+    use expect_test::expect_file;
+    use futures::FutureExt;
+    use pal_async::DefaultDriver;
+    use pal_async::DefaultPool;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::task::Poll;
+    use std::time::Duration;
+    use test_with_tracing::test;
+    use vmcore::save_restore::SaveRestore;
+    use vmcore::vmtime;
+    use vmcore::vmtime::VmTime;
+    use vmcore::vmtime::VmTimeKeeper;
+    use vmcore::vmtime::VmTimeSource;
+
+    /// A keyboard that never sends input.
+    struct NoInput;
+
+    impl futures::Stream for NoInput {
+        type Item = KeyboardData;
+
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<KeyboardData>> {
+            Poll::Pending
+        }
+    }
+
+    impl InputSource<KeyboardData> for NoInput {
+        fn set_active(
+            &mut self,
+            _active: bool,
+        ) -> Pin<Box<dyn '_ + std::future::Future<Output = ()> + Send>> {
+            Box::pin(async {})
+        }
+    }
+
+    /// A device whose VM time only advances when the test advances it.
+    struct TestDevice {
+        device: I8042Device,
+        keeper: VmTimeKeeper,
+        now: VmTime,
+        _vmtime: VmTimeSource,
+    }
+
+    impl TestDevice {
+        async fn new(driver: &DefaultDriver) -> Self {
+            Self::with_reset(driver, Box::new(|| {}), true, None).await
+        }
+
+        async fn with_reset(
+            driver: &DefaultDriver,
+            reset: Box<dyn Fn() + Send + Sync>,
+            latch_reset: bool,
+            lock_keys: Option<LockKeys>,
+        ) -> Self {
+            let now = VmTime::from_100ns(0);
+            let keeper = VmTimeKeeper::new(driver, now);
+            let vmtime = keeper.builder().build(driver).await.unwrap();
+            let device = I8042Device::new(
+                reset,
+                latch_reset,
+                LineInterrupt::detached(),
+                LineInterrupt::detached(),
+                Box::new(NoInput),
+                lock_keys,
+                vmtime.access("mouse"),
+            )
+            .await;
+            Self {
+                device,
+                keeper,
+                now,
+                _vmtime: vmtime,
+            }
+        }
+
+        /// Advances VM time by `duration` and polls the device, so that
+        /// anything it is waiting for by then completes.
+        async fn advance(&mut self, duration: Duration) {
+            self.now = self.now.wrapping_add(duration);
+            self.keeper
+                .restore(vmtime::SavedState::from_vmtime(self.now))
+                .await;
+            self.device
+                .poll_device(&mut Context::from_waker(Waker::noop()));
+        }
+    }
+
+    fn with_device(f: impl FnOnce(&mut I8042Device)) {
+        DefaultPool::run_with(async |driver| {
+            f(&mut TestDevice::new(&driver).await.device);
+        })
+    }
+
+    /// Compares the device's inspect tree against a golden file, so that
+    /// changes to the paths diagnostics tooling relies on are deliberate.
+    ///
+    /// Run the tests with `UPDATE_EXPECT=1` to update the golden files.
+    fn check_inspect(device: &mut I8042Device, expected: ExpectFile) {
+        let node = inspect::inspect("", device).results();
+        expected.assert_eq(&format!("{node:#}\n"));
+    }
+
+    #[test]
+    fn inspect_after_reset() {
+        with_device(|device| {
+            check_inspect(device, expect_file!["snapshots/inspect_reset.txt"]);
+        });
+    }
+
+    #[test]
+    fn inspect_awaiting_command_data() {
+        with_device(|device| {
+            device
+                .io_write(
+                    ControllerPort::COMMAND.0,
+                    &[ControllerCommand::WRITE_COMMAND_BYTE.0],
+                )
+                .unwrap();
+            check_inspect(
+                device,
+                expect_file!["snapshots/inspect_awaiting_command_data.txt"],
+            );
+        });
+    }
+
+    /// Replays a guest driver's port accesses from a trace file, checking that
+    /// each read returns the value the driver expects.
+    ///
+    /// Each line is `w <port> <value>` or `r <port> <value>`, in hex, or
+    /// `t <ms>` to advance VM time by that many milliseconds. Blank lines and
+    /// `#` comments are ignored.
+    async fn replay(test: &mut TestDevice, trace: &str) {
+        for (i, line) in trace.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            match *line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                ["t", ms] => {
+                    let ms = ms.parse().unwrap();
+                    test.advance(Duration::from_millis(ms)).await;
+                }
+                [op @ ("w" | "r"), port, value] => {
+                    let port = u16::from_str_radix(port, 16).unwrap();
+                    let value = u8::from_str_radix(value, 16).unwrap();
+                    if op == "w" {
+                        test.device.io_write(port, &[value]).unwrap();
+                    } else {
+                        let mut data = [0];
+                        test.device.io_read(port, &mut data).unwrap();
+                        assert!(
+                            data[0] == value,
+                            "line {line_number}: {line}: read {:#04x}",
+                            data[0]
+                        );
+                    }
+                }
+                _ => panic!("line {line_number}: malformed: {line}"),
+            }
+        }
+    }
+
+    fn replay_file(trace: &str) {
+        DefaultPool::run_with(async |driver| {
+            replay(&mut TestDevice::new(&driver).await, trace).await;
+        })
+    }
+
+    #[test]
+    fn replay_linux_atkbd() {
+        replay_file(include_str!("traces/linux_atkbd.txt"));
+    }
+
+    #[test]
+    fn replay_linux_psmouse() {
+        replay_file(include_str!("traces/linux_psmouse.txt"));
+    }
+
+    #[test]
+    fn replay_windows_i8042prt() {
+        replay_file(include_str!("traces/windows_i8042prt.txt"));
+    }
+
+    #[test]
+    fn repeated_reset_pulses() {
+        for latch_reset in [true, false] {
+            DefaultPool::run_with(async |driver| {
+                let resets = Arc::new(AtomicUsize::new(0));
+                let mut test = TestDevice::with_reset(
+                    &driver,
+                    Box::new({
+                        let resets = resets.clone();
+                        move || {
+                            resets.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }),
+                    latch_reset,
+                    None,
+                )
+                .await;
+                let pulse = |device: &mut I8042Device| {
+                    device.io_write(ControllerPort::COMMAND.0, &[0xfe]).unwrap();
+                };
+
+                pulse(&mut test.device);
+                pulse(&mut test.device);
+                let expected = if latch_reset { 1 } else { 2 };
+                assert_eq!(resets.load(Ordering::Relaxed), expected);
+
+                // Resetting the device, as a VM reset does, allows another.
+                test.device.reset().await;
+                pulse(&mut test.device);
+                assert_eq!(resets.load(Ordering::Relaxed), expected + 1);
+            })
+        }
+    }
+
+    #[test]
+    fn mouse_reset_completes_after_self_test() {
+        DefaultPool::run_with(async |driver| {
+            let mut test = TestDevice::new(&driver).await;
+            let read = |device: &mut I8042Device| {
+                let mut status = [0];
+                device
+                    .io_read(ControllerPort::COMMAND.0, &mut status)
+                    .unwrap();
+                (status[0] & 1 != 0).then(|| {
+                    let mut data = [0];
+                    device.io_read(ControllerPort::DATA.0, &mut data).unwrap();
+                    data[0]
+                })
+            };
+
+            let device = &mut test.device;
+            device
+                .io_write(
+                    ControllerPort::COMMAND.0,
+                    &[ControllerCommand::WRITE_AUX_DEVICE.0],
+                )
+                .unwrap();
+            device.io_write(ControllerPort::DATA.0, &[0xff]).unwrap();
+            assert_eq!(read(device), Some(0xfa));
+            assert_eq!(read(device), None);
+
+            // The self test is still running just before the delay.
+            test.advance(Duration::from_millis(399)).await;
+            assert_eq!(read(&mut test.device), None);
+
+            test.advance(Duration::from_millis(2)).await;
+            assert_eq!(read(&mut test.device), Some(0xaa));
+            assert_eq!(read(&mut test.device), Some(0x00));
+            assert_eq!(read(&mut test.device), None);
+        })
+    }
+
+    #[test]
+    fn lock_keys() {
+        DefaultPool::run_with(async |driver| {
+            let lock_keys = LockKeys {
+                num_lock: true,
+                caps_lock: false,
+            };
+            let mut test =
+                TestDevice::with_reset(&driver, Box::new(|| {}), true, Some(lock_keys)).await;
+            let device = &mut test.device;
+            let read = |device: &mut I8042Device| {
+                let mut data = [0];
+                device.io_read(ControllerPort::DATA.0, &mut data).unwrap();
+                data[0]
+            };
+
+            // The guest turns on only CapsLock, so NumLock and CapsLock are
+            // pressed after the acknowledgements.
+            device.io_write(ControllerPort::DATA.0, &[0xed]).unwrap();
+            device.io_write(ControllerPort::DATA.0, &[0x04]).unwrap();
+            for byte in [0xfa, 0xfa, 0x45, 0xc5, 0x3a, 0xba] {
+                assert_eq!(read(device), byte);
+            }
+
+            // They are pressed again after restore.
+            let state = device.save().unwrap();
+            device.restore(state).unwrap();
+            device.poll_device(&mut Context::from_waker(Waker::noop()));
+            for byte in [0x45, 0xc5, 0x3a, 0xba] {
+                assert_eq!(read(device), byte);
+            }
+        })
+    }
+
+    #[test]
+    fn interrupt_per_output_byte() {
+        with_device(|device| {
+            // Identify the keyboard, which responds with three bytes.
+            device.io_write(ControllerPort::DATA.0, &[0xf2]).unwrap();
+            for _ in 0..3 {
+                let mut data = [0];
+                device.io_read(ControllerPort::DATA.0, &mut data).unwrap();
+                // Polling the status does not raise another interrupt.
+                device
+                    .io_read(ControllerPort::COMMAND.0, &mut data)
+                    .unwrap();
+            }
+            assert_eq!(device.stats.keyboard_bytes.get(), 3);
+            assert_eq!(device.stats.keyboard_interrupts.get(), 3);
+            assert_eq!(device.stats.mouse_interrupts.get(), 0);
+        });
+    }
+
+    #[test]
+    fn inject_faults() {
+        with_device(|device| {
+            for path in ["faults/drop_acks", "faults/error_responses"] {
+                inspect::update(path, "1", &mut *device)
+                    .now_or_never()
+                    .unwrap()
+                    .unwrap();
+            }
+            // Identify the keyboard, which responds with an acknowledgement
+            // followed by 0xAB, 0x41.
+            device.io_write(ControllerPort::DATA.0, &[0xf2]).unwrap();
+            let mut read = || {
+                let mut data = [0];
+                device.io_read(ControllerPort::DATA.0, &mut data).unwrap();
+                data[0]
+            };
+            assert_eq!(read(), DEVICE_ERROR);
+            assert_eq!(read(), 0x41);
+        });
+    }
+}
@@ -4,10 +4,15 @@
 //! PS/2 keyboard.
 
 use self::spec::ACKNOWLEDGE_COMMAND;
+use self::spec::LED_CAPS_LOCK;
+use self::spec::LED_NUM_LOCK;
+use self::spec::LED_SCROLL_LOCK;
 use self::spec::Ps2KeyboardCommand;
 use futures::Stream;
 use input_core::InputSource;
 use input_core::KeyboardData;
+use input_core::lock_keys::LockKeySync;
+use input_core::lock_keys::LockKeys;
 use inspect::Inspect;
 use std::collections::VecDeque;
 use std::pin::Pin;
@@ -44,9 +49,15 @@ pub enum Ps2KeyboardCommand: u8 {
     }
 
     pub const ACKNOWLEDGE_COMMAND: u8 = 0xFA;
+
+    // LED bits set by TURN_ON_OFF_LE_DS.
+    pub const LED_SCROLL_LOCK: u8 = 0x1;
+    pub const LED_NUM_LOCK: u8 = 0x2;
+    pub const LED_CAPS_LOCK: u8 = 0x4;
 }
 
 #[derive(Inspect)]
+#[inspect(extra = "KeyboardState::inspect_extra")]
 struct KeyboardState {
     previous_command: Option<Ps2KeyboardCommand>,
     #[inspect(hex)]
@@ -66,6 +77,13 @@ fn new() -> Self {
             output_buffer: VecDeque::new(),
         }
     }
+
+    fn inspect_extra(&self, resp: &mut inspect::Response<'_>) {
+        resp.field("scroll_lock", self.led_state & LED_SCROLL_LOCK != 0)
+            .field("num_lock", self.led_state & LED_NUM_LOCK != 0)
+            .field("caps_lock", self.led_state & LED_CAPS_LOCK != 0)
+            .field("pending_bytes", self.output_buffer.len());
+    }
 }
 
 #[derive(Inspect)]
@@ -74,20 +92,29 @@ pub struct Ps2Keyboard {
     keyboard_input: Box<dyn InputSource<KeyboardData>>,
     #[inspect(flatten)]
     state: KeyboardState,
+    #[inspect(skip)]
+    lock_keys: LockKeySync,
 }
 
 const KEYBOARD_BUFFER_SIZE: usize = 21;
 
 impl Ps2Keyboard {
-    pub fn new(keyboard_input: Box<dyn InputSource<KeyboardData>>) -> Self {
+    /// Returns a new keyboard, which brings the guest's lock keys to
+    /// `lock_keys` at boot and after restore, if set.
+    pub fn new(
+        keyboard_input: Box<dyn InputSource<KeyboardData>>,
+        lock_keys: Option<LockKeys>,
+    ) -> Self {
         Self {
             keyboard_input,
             state: KeyboardState::new(),
+            lock_keys: LockKeySync::new(lock_keys),
         }
     }
 
     pub fn reset(&mut self) {
         self.state = KeyboardState::new();
+        self.lock_keys.arm();
     }
 
     pub fn poll(&mut self, cx: &mut Context<'_>) {
@@ -95,12 +122,10 @@ pub fn poll(&mut self, cx: &mut Context<'_>) {
         // delay between each keystroke.
         while self.state.output_buffer.len() < KEYBOARD_BUFFER_SIZE - 2 {
             if let Poll::Ready(Some(input)) = Pin::new(&mut self.keyboard_input).poll_next(cx) {
-                if input.code > 0xff {
-                    self.state.output_buffer.push_back((input.code >> 8) as u8);
+                if input.make {
+                    self.lock_keys.disarm();
                 }
-                self.state
-                    .output_buffer
-                    .push_back((input.code as u8) | if input.make { 0 } else { 0x80 });
+                self.push_keystroke(input);
             } else {
                 break;
             }
@@ -113,6 +138,37 @@ pub fn output(&mut self) -> Option<u8> {
         Some(value)
     }
 
+    /// Discards the pending output and reports a buffer overrun, as if the
+    /// guest had not read it in time. Returns `false` if there was no pending
+    /// output to discard.
+    pub fn overrun(&mut self) -> bool {
+        if self.state.output_buffer.is_empty() {
+            return false;
+        }
+        self.state.output_buffer.clear();
+        self.state.output_buffer.push_back(0);
+        true
+    }
+
+    fn push_keystroke(&mut self, input: KeyboardData) {
+        if input.code > 0xff {
+            self.push((input.code >> 8) as u8);
+        }
+        self.push((input.code as u8) | if input.make { 0 } else { 0x80 });
+    }
+
+    /// Presses the lock keys needed to bring the guest's lock state, as shown
+    /// by its LEDs, to the configured state.
+    fn sync_lock_keys(&mut self) {
+        let current = LockKeys {
+            num_lock: self.state.led_state & LED_NUM_LOCK != 0,
+            caps_lock: self.state.led_state & LED_CAPS_LOCK != 0,
+        };
+        for keystroke in self.lock_keys.keystrokes(current) {
+            self.push_keystroke(keystroke);
+        }
+    }
+
     fn push(&mut self, value: u8) {
         if self.state.output_buffer.len() <= KEYBOARD_BUFFER_SIZE {
             self.state.output_buffer.push_back(value);
@@ -148,6 +204,7 @@ fn command(&mut self, command: Ps2KeyboardCommand, data: Option<u8>) -> Option<(
                     return None;
                 } else {
                     self.state.led_state = data;
+                    self.sync_lock_keys();
                 }
             }
             Ps2KeyboardCommand::ECHO => {
@@ -279,6 +336,8 @@ fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
                 led_state,
                 output_buffer: output_buffer.into(),
             };
+            self.lock_keys.arm();
+            self.sync_lock_keys();
 
             Ok(())
         }
@@ -1,45 +1,226 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-//! PS/2 mouse. Not currently implemented.
+//! PS/2 mouse. Movement and button reports are not currently implemented.
 
+use self::spec::ACKNOWLEDGE_COMMAND;
+use self::spec::Ps2MouseCommand;
 use inspect::Inspect;
 use std::collections::VecDeque;
+use std::task::Context;
+use std::time::Duration;
+use vmcore::vmtime::VmTime;
+use vmcore::vmtime::VmTimeAccess;
 
-/// Not yet implemented.
+/// PS/2 mouse definitions.
+mod spec {
+    use inspect::Inspect;
+    use open_enum::open_enum;
+
+    open_enum! {
+        #[derive(Inspect)]
+        #[inspect(debug)]
+        pub enum Ps2MouseCommand: u8 {
+            SET_SCALING_1_1         = 0xE6,
+            SET_SCALING_2_1         = 0xE7,
+            SET_RESOLUTION          = 0xE8,
+            STATUS_REQUEST          = 0xE9,
+            SET_STREAM_MODE         = 0xEA,
+            SET_REMOTE_MODE         = 0xF0,
+            GET_DEVICE_ID           = 0xF2,
+            SET_SAMPLE_RATE         = 0xF3,
+            ENABLE_DATA_REPORTING   = 0xF4,
+            DISABLE_DATA_REPORTING  = 0xF5,
+            SET_DEFAULTS            = 0xF6,
+            RESET                   = 0xFF,
+        }
+    }
+
+    pub const ACKNOWLEDGE_COMMAND: u8 = 0xFA;
+    pub const SELF_TEST_PASSED: u8 = 0xAA;
+    pub const DEVICE_ID: u8 = 0;
+
+    // Bits of the first STATUS_REQUEST response byte.
+    pub const STATUS_SCALING_2_1: u8 = 0x10;
+    pub const STATUS_DATA_REPORTING: u8 = 0x20;
+    pub const STATUS_REMOTE_MODE: u8 = 0x40;
+}
+
+/// How long the basic assurance test (BAT) takes after a RESET command, after
+/// which the mouse reports completion.
+const RESET_DELAY: Duration = Duration::from_millis(400);
+
+/// The settings changed by mouse commands.
+#[derive(Debug, Copy, Clone, Inspect)]
+struct MouseSettings {
+    /// The resolution, as a power of two counts per millimeter (0-3).
+    resolution: u8,
+    /// Samples per second.
+    sample_rate: u8,
+    /// Whether 2:1 scaling is enabled (rather than 1:1).
+    scaling_2_1: bool,
+    /// Whether the mouse is in remote mode (rather than stream mode).
+    remote_mode: bool,
+    /// Whether data reporting is enabled.
+    data_reporting: bool,
+}
+
+impl MouseSettings {
+    /// The settings after power on, RESET, or SET_DEFAULTS.
+    const DEFAULT: Self = Self {
+        resolution: 2,
+        sample_rate: 100,
+        scaling_2_1: false,
+        remote_mode: false,
+        data_reporting: false,
+    };
+
+    /// Returns the three-byte STATUS_REQUEST response.
+    fn status(&self) -> [u8; 3] {
+        let mut flags = 0;
+        if self.scaling_2_1 {
+            flags |= spec::STATUS_SCALING_2_1;
+        }
+        if self.data_reporting {
+            flags |= spec::STATUS_DATA_REPORTING;
+        }
+        if self.remote_mode {
+            flags |= spec::STATUS_REMOTE_MODE;
+        }
+        [flags, self.resolution, self.sample_rate]
+    }
+}
+
+/// Supports the configuration commands, but never reports movement or button
+/// presses.
 #[derive(Inspect)]
 pub struct Ps2Mouse {
+    // Runtime glue
+    vmtime: VmTimeAccess,
+
+    // Volatile state
     #[inspect(bytes)]
     output_buffer: VecDeque<u8>,
+    /// When the self test started by the last RESET completes.
+    reset_complete: Option<VmTime>,
+    /// A command waiting for its data byte.
+    previous_command: Option<Ps2MouseCommand>,
+    settings: MouseSettings,
 }
 
 impl Ps2Mouse {
-    pub fn new() -> Self {
+    pub fn new(vmtime: VmTimeAccess) -> Self {
         Self {
+            vmtime,
             output_buffer: VecDeque::new(),
+            reset_complete: None,
+            previous_command: None,
+            settings: MouseSettings::DEFAULT,
         }
     }
 
     pub fn reset(&mut self) {
-        *self = Self::new();
+        self.output_buffer.clear();
+        self.reset_complete = None;
+        self.previous_command = None;
+        self.settings = MouseSettings::DEFAULT;
+        self.vmtime.cancel_timeout();
     }
 
     pub fn output(&mut self) -> Option<u8> {
         self.output_buffer.pop_front()
     }
 
-    pub fn input(&mut self, data: u8) {
-        tracing::trace!(data, "mouse command");
+    /// Completes a pending RESET once its self test has finished.
+    pub fn poll(&mut self, cx: &mut Context<'_>) {
+        if let Some(deadline) = self.reset_complete {
+            self.vmtime.set_timeout_if_before(deadline);
+            if self.vmtime.poll_timeout(cx).is_ready() {
+                self.reset_complete = None;
+                self.output_buffer.push_back(spec::SELF_TEST_PASSED);
+                self.output_buffer.push_back(spec::DEVICE_ID);
+            }
+        }
+    }
 
-        // RESET
-        if data == 0xFF {
-            self.output_buffer.push_back(0xFA); // ACKNOWLEDGE
-            self.output_buffer.push_back(0xAA); // COMPLETE
-            self.output_buffer.push_back(0); // IDENTITY
+    pub fn input(&mut self, input: u8) {
+        let (command, data) = if let Some(command) = self.previous_command.take() {
+            (command, Some(input))
         } else {
-            tracing::debug!(?data, "unimplemented mouse command");
-            self.output_buffer.push_back(0xFA); // ACKNOWLEDGE
+            (Ps2MouseCommand(input), None)
+        };
+        if self.command(command, data).is_none() {
+            self.previous_command = Some(command);
+        }
+    }
+
+    /// Returns `None` if the command needs to wait for its data byte.
+    fn command(&mut self, command: Ps2MouseCommand, data: Option<u8>) -> Option<()> {
+        tracing::trace!(?command, data, "mouse command");
+        match command {
+            Ps2MouseCommand::SET_SCALING_1_1 => {
+                self.settings.scaling_2_1 = false;
+                self.output_buffer.push_back(ACKNOWLEDGE_COMMAND);
+            }
+            Ps2MouseCommand::SET_SCALING_2_1 => {
+                self.settings.scaling_2_1 = true;
+                self.output_buffer.push_back(ACKNOWLEDGE_COMMAND);
+            }
+            Ps2MouseCommand::SET_RESOLUTION => {
+                // Acknowledge both the command and its data byte.
+                self.output_buffer.push_back(ACKNOWLEDGE_COMMAND);
+                self.settings.resolution = data? & 3;
+            }
+            Ps2MouseCommand::STATUS_REQUEST => {
+                self.output_buffer.push_back(ACKNOWLEDGE_COMMAND);
+                self.output_buffer.extend(self.settings.status());
+            }
+            Ps2MouseCommand::SET_STREAM_MODE => {
+                self.settings.remote_mode = false;
+                self.output_buffer.push_back(ACKNOWLEDGE_COMMAND);
+            }
+            Ps2MouseCommand::SET_REMOTE_MODE => {
+                self.settings.remote_mode = true;
+                self.output_buffer.push_back(ACKNOWLEDGE_COMMAND);
+            }
+            Ps2MouseCommand::GET_DEVICE_ID => {
+                self.output_buffer.push_back(ACKNOWLEDGE_COMMAND);
+                self.output_buffer.push_back(spec::DEVICE_ID);
+            }
+            Ps2MouseCommand::SET_SAMPLE_RATE => {
+                // Acknowledge both the command and its data byte.
+                self.output_buffer.push_back(ACKNOWLEDGE_COMMAND);
+                self.settings.sample_rate = data?;
+            }
+            Ps2MouseCommand::ENABLE_DATA_REPORTING => {
+                self.settings.data_reporting = true;
+                self.output_buffer.push_back(ACKNOWLEDGE_COMMAND);
+            }
+            Ps2MouseCommand::DISABLE_DATA_REPORTING => {
+                self.settings.data_reporting = false;
+                self.output_buffer.push_back(ACKNOWLEDGE_COMMAND);
+            }
+            Ps2MouseCommand::SET_DEFAULTS => {
+                self.settings = MouseSettings::DEFAULT;
+                self.output_buffer.push_back(ACKNOWLEDGE_COMMAND);
+            }
+            Ps2MouseCommand::RESET => {
+                // Discard anything not yet read and acknowledge immediately.
+                // The completion code and device ID follow once the self test
+                // is done.
+                self.settings = MouseSettings::DEFAULT;
+                self.output_buffer.clear();
+                self.output_buffer.push_back(ACKNOWLEDGE_COMMAND);
+                let deadline = self.vmtime.now().wrapping_add(RESET_DELAY);
+                self.reset_complete = Some(deadline);
+                self.vmtime.set_timeout(deadline);
+            }
+            command => {
+                tracing::debug!(?command, "unimplemented mouse command");
+                self.output_buffer.push_back(ACKNOWLEDGE_COMMAND);
+            }
         }
+        Some(())
     }
 }
 
@@ -52,12 +233,36 @@ mod save_restore {
     mod state {
         use mesh::payload::Protobuf;
         use vmcore::save_restore::SavedStateRoot;
+        use vmcore::vmtime::VmTime;
 
         #[derive(Protobuf, SavedStateRoot)]
         #[mesh(package = "chipset.i8042.mouse")]
         pub struct SavedState {
             #[mesh(1)]
             pub output_buffer: Vec<u8>,
+            #[mesh(2)]
+            pub reset_complete: Option<VmTime>,
+            #[mesh(3)]
+            pub previous_command: Option<u8>,
+            /// `None` in state saved before settings were tracked, in which
+            /// case the defaults are used.
+            #[mesh(4)]
+            pub settings: Option<SavedSettings>,
+        }
+
+        #[derive(Protobuf)]
+        #[mesh(package = "chipset.i8042.mouse")]
+        pub struct SavedSettings {
+            #[mesh(1)]
+            pub resolution: u8,
+            #[mesh(2)]
+            pub sample_rate: u8,
+            #[mesh(3)]
+            pub scaling_2_1: bool,
+            #[mesh(4)]
+            pub remote_mode: bool,
+            #[mesh(5)]
+            pub data_reporting: bool,
         }
     }
 
@@ -65,21 +270,68 @@ impl SaveRestore for Ps2Mouse {
         type SavedState = state::SavedState;
 
         fn save(&mut self) -> Result<Self::SavedState, SaveError> {
-            let Self { output_buffer } = self;
+            let Self {
+                vmtime: _,
+                output_buffer,
+                reset_complete,
+                previous_command,
+                settings:
+                    MouseSettings {
+                        resolution,
+                        sample_rate,
+                        scaling_2_1,
+                        remote_mode,
+                        data_reporting,
+                    },
+            } = self;
 
             let saved_state = state::SavedState {
                 output_buffer: output_buffer.iter().copied().collect(),
+                reset_complete: *reset_complete,
+                previous_command: previous_command.map(|x| x.0),
+                settings: Some(state::SavedSettings {
+                    resolution: *resolution,
+                    sample_rate: *sample_rate,
+                    scaling_2_1: *scaling_2_1,
+                    remote_mode: *remote_mode,
+                    data_reporting: *data_reporting,
+                }),
             };
 
             Ok(saved_state)
         }
 
         fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
-            let state::SavedState { output_buffer } = state;
+            let state::SavedState {
+                output_buffer,
+                reset_complete,
+                previous_command,
+                settings,
+            } = state;
 
-            *self = Self {
-                output_buffer: output_buffer.into(),
-            };
+            self.output_buffer = output_buffer.into();
+            self.reset_complete = reset_complete;
+            self.previous_command = previous_command.map(Ps2MouseCommand);
+            self.settings = settings.map_or(
+                MouseSettings::DEFAULT,
+                |state::SavedSettings {
+                     resolution,
+                     sample_rate,
+                     scaling_2_1,
+                     remote_mode,
+                     data_reporting,
+                 }| MouseSettings {
+                    resolution,
+                    sample_rate,
+                    scaling_2_1,
+                    remote_mode,
+                    data_reporting,
+                },
+            );
+            match reset_complete {
+                Some(deadline) => self.vmtime.set_timeout(deadline),
+                None => self.vmtime.cancel_timeout(),
+            }
 
             Ok(())
         }
@@ -9,6 +9,7 @@
 use chipset_device_resources::ResolveChipsetDeviceHandleParams;
 use chipset_device_resources::ResolvedChipsetDevice;
 use chipset_resources::i8042::I8042DeviceHandle;
+use chipset_resources::i8042::I8042ResetAction;
 use power_resources::PowerRequest;
 use power_resources::PowerRequestHandleKind;
 use thiserror::Error;
@@ -65,14 +66,29 @@ async fn resolve(
             .await
             .map_err(ResolveI8042Error::ResolvePowerRequest)?;
 
-        let reset = Box::new(move || {
-            power_request.power_request(PowerRequest::Reset);
-        });
+        // Only a VM reset resets the device, so only then do repeated requests
+        // need to be ignored until it takes effect.
+        let latch_reset = matches!(resource.reset_action, I8042ResetAction::Reset);
+        let reset: Box<dyn Fn() + Send + Sync> = match resource.reset_action {
+            I8042ResetAction::Reset => Box::new(move || {
+                power_request.power_request(PowerRequest::Reset);
+            }),
+            I8042ResetAction::PowerOff => Box::new(move || {
+                power_request.power_request(PowerRequest::PowerOff);
+            }),
+            I8042ResetAction::Notify(send) => Box::new(move || send.send(())),
+        };
 
-        Ok(
-            I8042Device::new(reset, keyboard_interrupt, mouse_interrupt, keyboard_input.0)
-                .await
-                .into(),
+        Ok(I8042Device::new(
+            reset,
+            latch_reset,
+            keyboard_interrupt,
+            mouse_interrupt,
+            keyboard_input.0,
+            resource.lock_keys,
+            input.vmtime.access("i8042-mouse"),
         )
+        .await
+        .into())
     }
 }
@@ -60,6 +60,14 @@ struct ReadBackCommand {
 const PIT_CONTROL_REGISTER: u16 = 0x43;
 const PIT_PORT61_REGISTER: u16 = 0x61;
 
+// Bits of port 0x61 (system control port B).
+const PORT61_TIMER2_GATE: u8 = 0x01;
+/// The speaker data and parity/channel check enable bits, which read back as
+/// written.
+const PORT61_CONTROL_MASK: u8 = 0x0e;
+const PORT61_DRAM_REFRESH: u8 = 0x10;
+const PORT61_TIMER2_OUT: u8 = 0x20;
+
 #[derive(Debug, Inspect)]
 struct Timer {
     // Static configuration
@@ -321,10 +329,16 @@ fn write(&mut self, n: u8) {
             RwMode::LOW => self.state.cr = n,
             RwMode::HIGH => self.state.cr = n << 8,
             RwMode::LOW_HIGH => {
-                if let Some(low) = self.state.cr_low {
+                if let Some(low) = self.state.cr_low.take() {
                     self.state.cr = (n << 8) | (low as u16);
                 } else {
                     self.state.cr_low = Some(n as u8);
+                    if self.state.op_mode() == Mode::TerminalCount {
+                        // Writing the first byte stops the count, and the
+                        // output goes low immediately.
+                        self.state.state = CountState::Inactive;
+                        self.set_out(false);
+                    }
                     // Wait for high to be set before taking any actions.
                     return;
                 }
@@ -513,6 +527,13 @@ pub struct PitDevice {
 
     // Volatile state
     last: VmTime,
+    /// The nanoseconds past `last` at which the last tick occurred, since
+    /// `VmTime` only has 100ns resolution. Not saved; losing it on restore
+    /// costs less than a tick.
+    last_nanos: u64,
+    /// The control bits of port 0x61 other than timer 2's gate.
+    #[inspect(hex)]
+    port61: u8,
 }
 
 impl PitDevice {
@@ -526,8 +547,10 @@ pub fn new(interrupt: LineInterrupt, vmtime: VmTimeAccess) -> Self {
                 Timer::new(false, None),
             ],
             last: vmtime.now(),
+            last_nanos: 0,
             vmtime,
             dram_refresh: false,
+            port61: 0,
         }
     }
 
@@ -536,11 +559,14 @@ fn evaluate(&mut self, now: VmTime) {
         //
         // N.B. if self.last were set to now, then each call to evaluate
         // would leak a portion of a tick, causing timers to expire late.
+        //
+        // Likewise, `last` is rounded down to VmTime's resolution, so keep the
+        // remainder to avoid counting part of a tick twice.
         let delta = now.checked_sub(self.last).unwrap_or(Duration::ZERO);
-        let ticks = delta.as_nanos() as u64 / NANOS_PER_TICK;
-        self.last = self
-            .last
-            .wrapping_add(Duration::from_nanos(ticks * NANOS_PER_TICK));
+        let ticks = (delta.as_nanos() as u64).saturating_sub(self.last_nanos) / NANOS_PER_TICK;
+        let elapsed = self.last_nanos + ticks * NANOS_PER_TICK;
+        self.last = self.last.wrapping_add(Duration::from_nanos(elapsed));
+        self.last_nanos = elapsed % 100;
         self.timers[0].evaluate(ticks);
         self.timers[1].evaluate(ticks);
         self.timers[2].evaluate(ticks);
@@ -552,10 +578,10 @@ fn arm_wakeup(&mut self) {
         if let Some(next) = self.timers[0].state.next_wakeup() {
             // Delay waking up if the next wakeup is too soon to avoid spinning.
             let next = next.max(20);
-            self.vmtime.set_timeout_if_before(
-                self.last
-                    .wrapping_add(Duration::from_nanos(next * NANOS_PER_TICK)),
-            );
+            self.vmtime
+                .set_timeout_if_before(self.last.wrapping_add(Duration::from_nanos(
+                    self.last_nanos + next * NANOS_PER_TICK,
+                )));
         }
     }
 }
@@ -570,6 +596,8 @@ async fn reset(&mut self) {
             timer.reset();
         }
         self.last = self.vmtime.now();
+        self.last_nanos = 0;
+        self.port61 = 0;
     }
 }
 
@@ -613,9 +641,18 @@ fn io_read(&mut self, io_port: u16, data: &mut [u8]) -> IoResult {
                 data[0] = !0;
             }
             PIT_PORT61_REGISTER => {
-                data[0] = ((self.timers[2].state.out as u8) << 5)
-                    | ((self.dram_refresh as u8) << 4)
-                    | self.timers[2].state.gate as u8;
+                let timer2 = &self.timers[2].state;
+                let mut value = self.port61;
+                if timer2.gate {
+                    value |= PORT61_TIMER2_GATE;
+                }
+                if self.dram_refresh {
+                    value |= PORT61_DRAM_REFRESH;
+                }
+                if timer2.out {
+                    value |= PORT61_TIMER2_OUT;
+                }
+                data[0] = value;
                 // Cycle the DRAM refresh bit every read. PCAT uses this to
                 // validate that DRAM is working, but it's not practical or
                 // useful to make the timing accurate.
@@ -669,7 +706,8 @@ fn io_write(&mut self, io_port: u16, data: &[u8]) -> IoResult {
                 }
             }
             PIT_PORT61_REGISTER => {
-                self.timers[2].set_gate((b & 1) != 0);
+                self.port61 = b & PORT61_CONTROL_MASK;
+                self.timers[2].set_gate(b & PORT61_TIMER2_GATE != 0);
             }
             _ => return IoResult::Err(IoError::InvalidRegister),
         }
@@ -746,6 +784,8 @@ pub struct SavedState {
             pub timers: [SavedTimerState; 3],
             #[mesh(2)]
             pub last: VmTime,
+            #[mesh(3)]
+            pub port61: u8,
         }
     }
 
@@ -764,6 +804,8 @@ fn save(&mut self) -> Result<Self::SavedState, SaveError> {
                 timers,
                 dram_refresh: _,
                 last,
+                last_nanos: _,
+                port61,
             } = self;
 
             Ok(state::SavedState {
@@ -804,11 +846,16 @@ fn save(&mut self) -> Result<Self::SavedState, SaveError> {
                 }),
 
                 last: *last,
+                port61: *port61,
             })
         }
 
         fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
-            let state::SavedState { timers, last } = state;
+            let state::SavedState {
+                timers,
+                last,
+                port61,
+            } = state;
 
             for (timer, state) in self.timers.iter_mut().zip(timers) {
                 let state::SavedTimerState {
@@ -849,6 +896,8 @@ fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
             }
 
             self.last = last;
+            self.last_nanos = 0;
+            self.port61 = port61 & PORT61_CONTROL_MASK;
             if last.is_after(self.vmtime.now()) {
                 return Err(RestoreError::InvalidSavedState(
                     PitDeviceRestoreError::InvalidLastTick.into(),
@@ -864,10 +913,20 @@ fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
 mod tests {
     use super::ControlWord;
     use super::Mode;
+    use super::NANOS_PER_TICK;
+    use super::PORT61_DRAM_REFRESH;
+    use super::PORT61_TIMER2_OUT;
+    use super::PitDevice;
     use super::RwMode;
     use super::Timer;
     use super::to_bcd;
     use crate::pit::from_bcd;
+    use chipset_device::pio::PortIoIntercept;
+    use pal_async::DefaultPool;
+    use std::time::Duration;
+    use vmcore::line_interrupt::LineInterrupt;
+    use vmcore::vmtime::VmTime;
+    use vmcore::vmtime::VmTimeKeeper;
 
     #[test]
     fn test_bcd_comp() {
@@ -970,4 +1029,75 @@ fn test_binary() {
     fn test_bcd() {
         test_output(true);
     }
+
+    #[test]
+    fn mode0_rewrite_stops_count() {
+        let mut timer = Timer::new(true, None);
+        set_timer(&mut timer, Mode::TerminalCount, 10, false);
+        timer.evaluate(5);
+
+        // The first byte of the new count stops the old one.
+        timer.write(20);
+        assert!(timer.state.next_wakeup().is_none());
+        timer.evaluate(100);
+        assert!(!timer.state.out);
+
+        // The new count starts once it is complete.
+        timer.write(0);
+        check_invert(&mut timer, false, 21);
+        check_done(&mut timer);
+    }
+
+    /// Replicates SeaBIOS's TSC calibration, which gates timer 2 on with the
+    /// speaker off, counts down in mode 0, and polls port 0x61 until the
+    /// timer's output goes high.
+    #[test]
+    fn seabios_tsc_calibration() {
+        const CALIBRATE_COUNT: u16 = 0x800;
+        const PPCB_T2GATE: u8 = 0x01;
+        const PPCB_SPKR: u8 = 0x02;
+
+        DefaultPool::run_with(async |driver| {
+            // The time keeper is never started, so time only advances when
+            // the test evaluates the PIT at a later time.
+            let keeper = VmTimeKeeper::new(&driver, VmTime::from_100ns(0));
+            let vmtime = keeper.builder().build(&driver).await.unwrap();
+            let mut pit = PitDevice::new(LineInterrupt::detached(), vmtime.access("pit"));
+            let start = pit.last;
+
+            // Ignore the refresh bit, which toggles on every read.
+            let read61 = |pit: &mut PitDevice| {
+                let mut data = [0];
+                pit.io_read(0x61, &mut data).unwrap();
+                data[0] & !PORT61_DRAM_REFRESH
+            };
+
+            // The speaker and check enable bits read back as written.
+            pit.io_write(0x61, &[0x0e]).unwrap();
+            assert_eq!(read61(&mut pit), 0x0e);
+
+            let orig = read61(&mut pit);
+            pit.io_write(0x61, &[(orig & !PPCB_SPKR) | PPCB_T2GATE])
+                .unwrap();
+            assert_eq!(read61(&mut pit), (orig & !PPCB_SPKR) | PPCB_T2GATE);
+
+            // Timer 2, LSB then MSB, mode 0, binary.
+            pit.io_write(0x43, &[0xb0]).unwrap();
+            pit.io_write(0x42, &[CALIBRATE_COUNT as u8]).unwrap();
+            pit.io_write(0x42, &[(CALIBRATE_COUNT >> 8) as u8]).unwrap();
+
+            // The output goes high N+1 ticks after the count is written. VmTime
+            // has 100ns resolution, so round each tick's time up to it.
+            for tick in 1..=u64::from(CALIBRATE_COUNT) + 1 {
+                let nanos = (tick * NANOS_PER_TICK).div_ceil(100) * 100;
+                pit.evaluate(start.wrapping_add(Duration::from_nanos(nanos)));
+                let expired = read61(&mut pit) & PORT61_TIMER2_OUT != 0;
+                assert_eq!(expired, tick > CALIBRATE_COUNT.into(), "tick {tick}");
+            }
+
+            // Restoring the original value leaves the expired output high.
+            pit.io_write(0x61, &[orig]).unwrap();
+            assert_eq!(read61(&mut pit), orig | PORT61_TIMER2_OUT);
+        })
+    }
 }
@@ -8,6 +8,7 @@
 pub mod i8042 {
     //! Resource definitions for the i8042 PS2 keyboard/mouse controller.
 
+    use input_core::lock_keys::LockKeys;
     use mesh::MeshPayload;
     use vm_resource::Resource;
     use vm_resource::ResourceId;
@@ -19,6 +20,23 @@ pub mod i8042 {
     pub struct I8042DeviceHandle {
         /// The keyboard input.
         pub keyboard_input: Resource<KeyboardInputHandleKind>,
+        /// What to do when the guest resets the CPU via the controller.
+        pub reset_action: I8042ResetAction,
+        /// The NumLock and CapsLock state to bring the guest to at boot and
+        /// after restore, if any.
+        pub lock_keys: Option<LockKeys>,
+    }
+
+    /// The action taken when the guest resets the CPU via the controller
+    /// (e.g. by pulsing the reset line with command 0xFE).
+    #[derive(MeshPayload)]
+    pub enum I8042ResetAction {
+        /// Reset the VM.
+        Reset,
+        /// Power off the VM.
+        PowerOff,
+        /// Leave the VM running and send a notification instead.
+        Notify(mesh::Sender<()>),
     }
 
     impl ResourceId<ChipsetDeviceHandleKind> for I8042DeviceHandle {
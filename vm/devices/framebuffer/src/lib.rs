@@ -24,6 +24,7 @@
 use guestmem::GuestMemory;
 use guestmem::MappableGuestMemory;
 use guestmem::MemoryMapper;
+use input_core::InputData;
 use inspect::Inspect;
 use inspect::InspectMut;
 use memory_range::MemoryRange;
@@ -35,11 +36,20 @@
 use std::convert::Infallible;
 use std::io;
 use std::sync::Arc;
+use std::task::Poll;
+use video_core::DamageRect;
 use video_core::FramebufferControl;
 use video_core::FramebufferFormat;
+use video_core::PointerSource;
+use video_core::ResizeRpc;
 use video_core::ResolvedFramebuffer;
 use video_core::SharedFramebufferHandle;
+use vm_resource::CanResolveTo;
 use vm_resource::ResolveResource;
+use vm_resource::Resource;
+use vm_resource::ResourceId;
+use vm_resource::declare_static_resolver;
+use vm_resource::kind::ConsoleHandleKind;
 use vm_resource::kind::FramebufferHandleKind;
 use vmcore::device_state::ChangeDeviceState;
 use vmcore::save_restore::RestoreError;
@@ -83,16 +93,19 @@ pub fn framebuffer(
     );
 
     let (send, recv) = mesh::channel();
+    let (damage_send, damage_recv) = mesh::channel();
 
     let fb = Framebuffer {
         vram: vram.try_clone()?,
         len,
         format_send: send,
+        damage_send,
     };
     let access = FramebufferAccess {
         vram,
         len,
         format_recv: recv,
+        damage_recv,
         offset,
     };
     Ok((fb, access))
@@ -104,6 +117,7 @@ pub struct Framebuffer {
     vram: Mappable,
     len: usize,
     format_send: mesh::Sender<FramebufferFormat>,
+    damage_send: mesh::Sender<Vec<DamageRect>>,
 }
 
 impl Framebuffer {
@@ -124,6 +138,7 @@ pub struct FramebufferAccess {
     vram: Mappable,
     len: usize,
     format_recv: mesh::Receiver<FramebufferFormat>,
+    damage_recv: mesh::Receiver<Vec<DamageRect>>,
     offset: u64,
 }
 
@@ -136,6 +151,11 @@ pub fn view(self) -> io::Result<View> {
             mapping,
             format_recv: self.format_recv,
             format: None,
+            damage_recv: self.damage_recv,
+            damage_tracked: false,
+            damage: Vec::new(),
+            damage_overflowed: false,
+            format_changed: false,
             vram: self.vram,
             len: self.len,
             offset: self.offset,
@@ -143,12 +163,29 @@ pub fn view(self) -> io::Result<View> {
     }
 }
 
+/// The number of damaged regions a [`View`] keeps before treating the whole
+/// framebuffer as changed, so that damage reported while nobody is reading
+/// the framebuffer cannot use memory without bound.
+const MAX_DAMAGE_RECTS: usize = 256;
+
 /// A mapped view of the framebuffer.
 #[derive(Debug)]
 pub struct View {
     mapping: SparseMapping,
     format_recv: mesh::Receiver<FramebufferFormat>,
     format: Option<FramebufferFormat>,
+    damage_recv: mesh::Receiver<Vec<DamageRect>>,
+    /// Whether the video device has reported damage since the last format
+    /// change.
+    damage_tracked: bool,
+    /// The damage received since the last call to [`View::take_damage`].
+    damage: Vec<DamageRect>,
+    /// Whether more than [`MAX_DAMAGE_RECTS`] regions were damaged since the
+    /// last call to [`View::take_damage`], so that `damage` was discarded.
+    damage_overflowed: bool,
+    /// Whether the format has changed since the last call to
+    /// [`View::take_format_change`].
+    format_changed: bool,
     vram: Mappable,
     len: usize,
     offset: u64,
@@ -157,12 +194,18 @@ pub struct View {
 impl View {
     /// Reads a line within the framebuffer.
     pub fn read_line(&mut self, line: u16, data: &mut [u8]) {
+        self.read_line_at(line, 0, data)
+    }
+
+    /// Reads part of a line within the framebuffer, starting at pixel `x`.
+    pub fn read_line_at(&mut self, line: u16, x: u16, data: &mut [u8]) {
         if let Some(format) = &self.format {
             if let Some(offset) = (line as usize)
                 .checked_mul(format.bytes_per_line)
                 .and_then(|x| x.checked_add(format.offset))
+                .and_then(|offset| offset.checked_add(x as usize * 4))
             {
-                let len = std::cmp::min(data.len(), format.width * 4);
+                let len = std::cmp::min(data.len(), format.width.saturating_sub(x as usize) * 4);
                 let _ = self.mapping.read_at(offset, &mut data[..len]);
                 return;
             }
@@ -177,8 +220,16 @@ pub fn resolution(&mut self) -> (u16, u16) {
         // FUTURE-use a channel/port type that throws away all but the last
         // message to avoid possible high memory use.
         while let Ok(format) = self.format_recv.try_recv() {
+            if self.format != Some(format) {
+                // Damage reports may not resume with the new mode.
+                self.damage_tracked = false;
+                self.damage.clear();
+                self.damage_overflowed = false;
+                self.format_changed = true;
+            }
             self.format = Some(format);
         }
+        self.recv_damage();
         if let Some(format) = &self.format {
             (format.width as u16, format.height as u16)
         } else {
@@ -186,6 +237,66 @@ pub fn resolution(&mut self) -> (u16, u16) {
         }
     }
 
+    /// Returns the regions of the framebuffer that have been updated since the
+    /// last call, or `None` if the video device is not reporting updates and
+    /// the whole framebuffer must be assumed to have changed.
+    ///
+    /// Once the video device has reported an update, it is expected to report
+    /// all of them until the format next changes.
+    pub fn take_damage(&mut self) -> Option<Vec<DamageRect>> {
+        self.recv_damage();
+        let damage = std::mem::take(&mut self.damage);
+        let overflowed = std::mem::take(&mut self.damage_overflowed);
+        (self.damage_tracked && !overflowed).then_some(damage)
+    }
+
+    /// Waits for the video device to report damage, which can then be taken
+    /// with [`View::take_damage`].
+    ///
+    /// This also collects the damage reported so far, so calling it whenever
+    /// it is ready keeps the damage reports from accumulating even when the
+    /// damage is not taken.
+    pub fn poll_damage(&mut self, cx: &mut std::task::Context<'_>) -> Poll<()> {
+        let mut received = false;
+        while let Poll::Ready(Ok(rects)) = self.damage_recv.poll_recv(cx) {
+            self.add_damage(rects);
+            received = true;
+        }
+        if received {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Collects the damage reported so far.
+    fn recv_damage(&mut self) {
+        while let Ok(rects) = self.damage_recv.try_recv() {
+            self.add_damage(rects);
+        }
+    }
+
+    fn add_damage(&mut self, rects: Vec<DamageRect>) {
+        self.damage_tracked = true;
+        if !self.damage_overflowed {
+            self.damage.extend(rects);
+            if self.damage.len() > MAX_DAMAGE_RECTS {
+                self.damage.clear();
+                self.damage_overflowed = true;
+            }
+        }
+    }
+
+    /// Returns whether the format has changed since the last call, as seen by
+    /// [`View::resolution`].
+    ///
+    /// The guest can change the stride or offset of the framebuffer without
+    /// changing its resolution (for example, when switching video modes), in
+    /// which case previously read contents no longer match the guest's.
+    pub fn take_format_change(&mut self) -> bool {
+        std::mem::take(&mut self.format_changed)
+    }
+
     /// Gets the framebuffer access back.
     pub fn access(self) -> FramebufferAccess {
         // Put the current format at the head of the channel.
@@ -198,6 +309,7 @@ pub fn access(self) -> FramebufferAccess {
             vram: self.vram,
             len: self.len,
             format_recv: recv,
+            damage_recv: self.damage_recv,
             offset: self.offset,
         }
     }
@@ -487,6 +599,11 @@ async fn unmap(&mut self) {
     async fn set_format(&mut self, format: FramebufferFormat) {
         self.set_format(format);
     }
+    async fn damage(&mut self, rects: &[DamageRect]) {
+        if let Some(framebuffer) = &self.inner.lock().framebuffer {
+            framebuffer.damage_send.send(rects.to_vec());
+        }
+    }
 }
 
 impl ResolveResource<FramebufferHandleKind, SharedFramebufferHandle> for FramebufferLocalControl {
@@ -501,3 +618,78 @@ fn resolve(
         Ok(self.clone().into())
     }
 }
+
+impl CanResolveTo<ResolvedConsole> for ConsoleHandleKind {
+    type Input<'a> = ();
+}
+
+/// A handle to a guest console: the framebuffer to display and the channel to
+/// send the corresponding keyboard and mouse input to, and optionally the
+/// guest's pointer for the console to draw.
+///
+/// Bundling these lets a console consumer (such as the VNC worker) resolve
+/// everything it needs as a single resource.
+#[derive(MeshPayload)]
+pub struct ConsoleHandle {
+    /// The framebuffer memory.
+    pub framebuffer: FramebufferAccess,
+    /// A channel to send input to.
+    pub input: mesh::Sender<InputData>,
+    /// The guest's pointer, if the video device can report it.
+    pub pointer: Option<PointerSource>,
+    /// Requests to change the guest's resolution, if the video device
+    /// supports them.
+    pub resize: Option<mesh::Sender<ResizeRpc>>,
+}
+
+impl ResourceId<ConsoleHandleKind> for ConsoleHandle {
+    const ID: &'static str = "console";
+}
+
+/// A resolved console, with the framebuffer mapped.
+pub struct ResolvedConsole {
+    /// The mapped framebuffer.
+    pub view: View,
+    /// A channel to send input to.
+    pub input: mesh::Sender<InputData>,
+    /// The guest's pointer, if the video device can report it.
+    pub pointer: Option<PointerSource>,
+    /// Requests to change the guest's resolution, if the video device
+    /// supports them.
+    pub resize: Option<mesh::Sender<ResizeRpc>>,
+}
+
+impl ResolvedConsole {
+    /// Unmaps the framebuffer and converts the console back into a resource,
+    /// e.g. to hand it to a restarted worker.
+    pub fn into_resource(self) -> Resource<ConsoleHandleKind> {
+        Resource::new(ConsoleHandle {
+            framebuffer: self.view.access(),
+            input: self.input,
+            pointer: self.pointer,
+            resize: self.resize,
+        })
+    }
+}
+
+/// A resolver for [`ConsoleHandle`].
+pub struct ConsoleResolver;
+
+declare_static_resolver! {
+    ConsoleResolver,
+    (ConsoleHandleKind, ConsoleHandle),
+}
+
+impl ResolveResource<ConsoleHandleKind, ConsoleHandle> for ConsoleResolver {
+    type Output = ResolvedConsole;
+    type Error = io::Error;
+
+    fn resolve(&self, resource: ConsoleHandle, _input: ()) -> Result<Self::Output, Self::Error> {
+        Ok(ResolvedConsole {
+            view: resource.framebuffer.view()?,
+            input: resource.input,
+            pointer: resource.pointer,
+            resize: resource.resize,
+        })
+    }
+}
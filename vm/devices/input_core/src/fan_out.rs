@@ -0,0 +1,187 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Mirroring a single input stream to multiple consumers, such as a device
+//! and an input logger or latency monitor.
+//!
+//! Each consumer has its own bounded queue, so consumers apply backpressure
+//! independently: a consumer that is slow to drain its input neither holds up
+//! the others nor uses unbounded memory. Once a consumer's queue is full, its
+//! oldest messages are dropped to make room, and the consumer can see how many
+//! it missed.
+
+use crate::InputSource;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+
+struct Queue<T> {
+    messages: VecDeque<T>,
+    capacity: usize,
+    dropped: u64,
+    active: bool,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+/// A sink that mirrors each input message to multiple
+/// [`FanOutInputSource`]s.
+pub struct FanOutInputSink<T> {
+    consumers: Vec<Arc<Mutex<Queue<T>>>>,
+}
+
+impl<T> Default for FanOutInputSink<T> {
+    fn default() -> Self {
+        Self {
+            consumers: Vec::new(),
+        }
+    }
+}
+
+impl<T: Clone> FanOutInputSink<T> {
+    /// Returns a new sink with no consumers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a new consumer that can fall up to `capacity` messages behind
+    /// before its oldest messages are dropped, returning the input source it
+    /// should read from.
+    pub fn add_consumer(&mut self, capacity: usize) -> FanOutInputSource<T> {
+        assert!(capacity > 0, "a consumer must be able to queue a message");
+        let queue = Arc::new(Mutex::new(Queue {
+            messages: VecDeque::new(),
+            capacity,
+            dropped: 0,
+            active: false,
+            closed: false,
+            waker: None,
+        }));
+        self.consumers.push(queue.clone());
+        FanOutInputSource { queue }
+    }
+
+    /// Sends an input message to all active consumers.
+    pub fn send(&mut self, input: T) {
+        // Forget the consumers whose sources have been dropped.
+        self.consumers.retain(|queue| Arc::strong_count(queue) > 1);
+        for queue in &self.consumers {
+            let mut queue = queue.lock();
+            if !queue.active {
+                continue;
+            }
+            if queue.messages.len() == queue.capacity {
+                queue.messages.pop_front();
+                queue.dropped += 1;
+            }
+            queue.messages.push_back(input.clone());
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Returns true if any consumer is currently active.
+    pub fn is_active(&self) -> bool {
+        self.consumers.iter().any(|queue| queue.lock().active)
+    }
+}
+
+impl<T> Drop for FanOutInputSink<T> {
+    fn drop(&mut self) {
+        // End the consumers' streams once they have drained their queues.
+        for queue in &self.consumers {
+            let mut queue = queue.lock();
+            queue.closed = true;
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// An input source fed by a [`FanOutInputSink`].
+pub struct FanOutInputSource<T> {
+    queue: Arc<Mutex<Queue<T>>>,
+}
+
+impl<T> FanOutInputSource<T> {
+    /// Returns the number of messages dropped so far because this consumer's
+    /// queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.queue.lock().dropped
+    }
+}
+
+impl<T: Send> InputSource<T> for FanOutInputSource<T> {
+    fn set_active(
+        &mut self,
+        active: bool,
+    ) -> Pin<Box<dyn '_ + std::future::Future<Output = ()> + Send>> {
+        self.queue.lock().active = active;
+        Box::pin(async {})
+    }
+}
+
+impl<T> futures::Stream for FanOutInputSource<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut queue = self.queue.lock();
+        if let Some(message) = queue.messages.pop_front() {
+            Poll::Ready(Some(message))
+        } else if queue.closed {
+            Poll::Ready(None)
+        } else {
+            queue.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FanOutInputSink;
+    use crate::InputSource;
+    use futures::StreamExt;
+    use futures::executor::block_on;
+
+    #[test]
+    fn independent_consumers() {
+        let mut sink = FanOutInputSink::new();
+        let mut fast = sink.add_consumer(4);
+        let mut slow = sink.add_consumer(2);
+        let mut inactive = sink.add_consumer(4);
+        assert!(!sink.is_active());
+        block_on(fast.set_active(true));
+        block_on(slow.set_active(true));
+        assert!(sink.is_active());
+
+        for i in 0..4 {
+            sink.send(i);
+            assert_eq!(block_on(fast.next()), Some(i));
+        }
+        // The slow consumer only kept the latest messages that fit.
+        assert_eq!(slow.dropped(), 2);
+        drop(sink);
+        assert_eq!(block_on(slow.by_ref().collect::<Vec<_>>()), [2, 3]);
+        assert_eq!(fast.dropped(), 0);
+        assert_eq!(block_on(fast.next()), None);
+        assert_eq!(block_on(inactive.next()), None);
+    }
+
+    #[test]
+    fn dropped_consumer() {
+        let mut sink = FanOutInputSink::new();
+        let mut source = sink.add_consumer(1);
+        block_on(source.set_active(true));
+        drop(source);
+        assert!(sink.is_active());
+        sink.send(0);
+        assert!(!sink.is_active());
+    }
+}
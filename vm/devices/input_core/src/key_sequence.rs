@@ -0,0 +1,168 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Well-known key combinations, such as Ctrl+Alt+Del, that are awkward or
+//! impossible to send from a client keyboard without the host intercepting
+//! them.
+
+use crate::InputData;
+use crate::KeyboardData;
+use crate::keymap::KeyboardLayout;
+use crate::keymap::Keystroke;
+use crate::keymap::Level;
+use crate::keymap::Position;
+use mesh::MeshPayload;
+use pal_async::timer::PolledTimer;
+use std::time::Duration;
+use thiserror::Error;
+
+const SCANCODE_LEFT_CTRL: u16 = 0x1d;
+const SCANCODE_LEFT_ALT: u16 = 0x38;
+const SCANCODE_RIGHT_CTRL: u16 = 0xe01d;
+const SCANCODE_DELETE: u16 = 0xe053;
+const SCANCODE_SCROLL_LOCK: u16 = 0x46;
+/// The code sent for Print Screen while Alt is held.
+const SCANCODE_SYSRQ: u16 = 0x54;
+
+/// A key combination to send to the guest.
+#[derive(Debug, Copy, Clone, MeshPayload)]
+pub enum KeySequence {
+    /// Ctrl+Alt+Del, the secure attention sequence.
+    CtrlAltDel,
+    /// Alt+SysRq+`key`, a Linux magic SysRq command (for example, `s` to sync
+    /// or `b` to reboot). The guest must have the SysRq key enabled.
+    SysRq(char),
+    /// Right Ctrl held while pressing Scroll Lock twice, which crashes a
+    /// Windows guest configured with `CrashOnCtrlScroll`, to capture a
+    /// memory dump of a hung system.
+    CrashOnCtrlScroll,
+}
+
+/// A magic SysRq key that cannot be typed.
+#[derive(Debug, Error)]
+#[error("invalid SysRq key {0:?}")]
+pub struct InvalidSysRqKey(pub char);
+
+impl KeySequence {
+    /// Returns the keystrokes for the sequence.
+    ///
+    /// Modifiers are pressed first and released last, in reverse order, so the
+    /// keyboard is left with no keys held down.
+    pub fn keystrokes(&self) -> Result<Vec<KeyboardData>, InvalidSysRqKey> {
+        let (modifiers, keys): (&[u16], Vec<u16>) = match *self {
+            KeySequence::CtrlAltDel => (
+                &[SCANCODE_LEFT_CTRL, SCANCODE_LEFT_ALT],
+                vec![SCANCODE_DELETE],
+            ),
+            KeySequence::SysRq(key) => {
+                // Linux identifies SysRq commands by the key pressed, as
+                // labeled on a US keyboard, whatever the guest's layout.
+                let code = match KeyboardLayout::EnUs.keystroke(key) {
+                    Some(Keystroke::Key(Position {
+                        scancode,
+                        level: Level::Base,
+                    })) if key.is_ascii_alphanumeric() => scancode,
+                    _ => return Err(InvalidSysRqKey(key)),
+                };
+                (&[SCANCODE_LEFT_ALT, SCANCODE_SYSRQ], vec![code])
+            }
+            KeySequence::CrashOnCtrlScroll => (
+                &[SCANCODE_RIGHT_CTRL],
+                vec![SCANCODE_SCROLL_LOCK, SCANCODE_SCROLL_LOCK],
+            ),
+        };
+        let press = |code| KeyboardData { code, make: true };
+        let release = |code| KeyboardData { code, make: false };
+        let mut keystrokes = Vec::new();
+        keystrokes.extend(modifiers.iter().copied().map(press));
+        for code in keys {
+            keystrokes.extend([press(code), release(code)]);
+        }
+        keystrokes.extend(modifiers.iter().rev().copied().map(release));
+        Ok(keystrokes)
+    }
+}
+
+/// Sends `sequence` to `send`, waiting `delay` between each keystroke so that
+/// the guest sees the keys held together rather than as a single burst.
+///
+/// Nothing is sent if the sequence is invalid.
+pub async fn send_key_sequence(
+    send: &mesh::Sender<InputData>,
+    timer: &mut PolledTimer,
+    sequence: KeySequence,
+    delay: Duration,
+) -> Result<(), InvalidSysRqKey> {
+    for keystroke in sequence.keystrokes()? {
+        send.send(InputData::Keyboard(keystroke));
+        timer.sleep(delay).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InvalidSysRqKey;
+    use super::KeySequence;
+    use crate::KeyboardData;
+
+    fn keystrokes(sequence: KeySequence) -> Vec<(u16, bool)> {
+        sequence
+            .keystrokes()
+            .unwrap()
+            .into_iter()
+            .map(|KeyboardData { code, make }| (code, make))
+            .collect()
+    }
+
+    #[test]
+    fn ctrl_alt_del() {
+        assert_eq!(
+            keystrokes(KeySequence::CtrlAltDel),
+            [
+                (0x1d, true),
+                (0x38, true),
+                (0xe053, true),
+                (0xe053, false),
+                (0x38, false),
+                (0x1d, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn sysrq() {
+        assert_eq!(
+            keystrokes(KeySequence::SysRq('s')),
+            [
+                (0x38, true),
+                (0x54, true),
+                (0x1f, true),
+                (0x1f, false),
+                (0x54, false),
+                (0x38, false),
+            ]
+        );
+        for key in ['S', '!', ' ', 'é'] {
+            assert!(matches!(
+                KeySequence::SysRq(key).keystrokes(),
+                Err(InvalidSysRqKey(k)) if k == key
+            ));
+        }
+    }
+
+    #[test]
+    fn crash_on_ctrl_scroll() {
+        assert_eq!(
+            keystrokes(KeySequence::CrashOnCtrlScroll),
+            [
+                (0xe01d, true),
+                (0x46, true),
+                (0x46, false),
+                (0x46, true),
+                (0x46, false),
+                (0xe01d, false),
+            ]
+        );
+    }
+}
@@ -0,0 +1,477 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Keyboard layout tables, describing which keys (and modifiers) type each
+//! character on the keyboard layout the guest is configured with.
+//!
+//! The tables are compiled in rather than loaded at runtime. Each describes a
+//! layout the guest OS can be configured with, so a table is only useful
+//! alongside a guest that uses the layout, and there is no file format to
+//! keep stable. To support another layout:
+//!
+//! 1. Add a table listing, for each key that types a character, its scancode
+//!    and what it types with no modifier, with shift, and with AltGr.
+//! 2. Add a [`KeyboardLayout`] variant returning the table from
+//!    `KeyboardLayout::keys`, and give it a name in `KeyboardLayout::name`.
+
+use Sym::Char as C;
+use Sym::Dead as D;
+use Sym::Empty as N;
+use mesh::MeshPayload;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// A keyboard layout for the guest, which determines the scancodes sent to
+/// type each character.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, MeshPayload)]
+pub enum KeyboardLayout {
+    /// US English (QWERTY).
+    #[default]
+    EnUs,
+    /// German (QWERTZ).
+    De,
+    /// French (AZERTY).
+    Fr,
+    /// Japanese (JIS 106/109-key).
+    Ja,
+}
+
+impl KeyboardLayout {
+    /// Every layout.
+    pub const ALL: [KeyboardLayout; 4] = [
+        KeyboardLayout::EnUs,
+        KeyboardLayout::De,
+        KeyboardLayout::Fr,
+        KeyboardLayout::Ja,
+    ];
+
+    /// Returns the name the layout is parsed from.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyboardLayout::EnUs => "en-us",
+            KeyboardLayout::De => "de",
+            KeyboardLayout::Fr => "fr",
+            KeyboardLayout::Ja => "ja",
+        }
+    }
+
+    fn keys(&self) -> &'static [Key] {
+        match self {
+            KeyboardLayout::EnUs => EN_US,
+            KeyboardLayout::De => DE,
+            KeyboardLayout::Fr => FR,
+            KeyboardLayout::Ja => JA,
+        }
+    }
+
+    /// Returns whether the layout has characters typed with AltGr, in which
+    /// case the right Alt key acts as AltGr rather than as Alt.
+    pub fn has_altgr(&self) -> bool {
+        self.keys().iter().any(|key| key.altgr != N)
+    }
+
+    /// Returns the keys to press to type `c`, or `None` if it cannot be typed
+    /// on the layout.
+    pub fn keystroke(&self, c: char) -> Option<Keystroke> {
+        if let Some(position) = self.find(Sym::Char(c)) {
+            return Some(Keystroke::Key(position));
+        }
+        // Characters that are not on the layout may still be typed by
+        // combining a dead key with another character.
+        Dead::ALL.iter().find_map(|&dead| {
+            let base = dead.base(c)?;
+            Some(Keystroke::Composed {
+                dead: self.find(Sym::Dead(dead))?,
+                key: self.find(Sym::Char(base))?,
+            })
+        })
+    }
+
+    /// Returns the position of the dead key for `dead`, if the layout has one.
+    pub fn dead_key(&self, dead: Dead) -> Option<Position> {
+        self.find(Sym::Dead(dead))
+    }
+
+    /// Returns the position of `sym` on the layout, if it is there.
+    fn find(&self, sym: Sym) -> Option<Position> {
+        self.keys().iter().chain([&SPACE]).find_map(|key| {
+            [
+                (key.base, Level::Base),
+                (key.shift, Level::Shift),
+                (key.altgr, Level::AltGr),
+            ]
+            .into_iter()
+            .find(|&(s, _)| s == sym)
+            .map(|(_, level)| Position {
+                scancode: key.scancode,
+                level,
+            })
+        })
+    }
+}
+
+/// An error returned when parsing an unknown [`KeyboardLayout`] name.
+#[derive(Debug, Error)]
+#[error("unknown keyboard layout {0:?}, expected en-us, de, fr, or ja")]
+pub struct InvalidKeyboardLayout(pub String);
+
+impl FromStr for KeyboardLayout {
+    type Err = InvalidKeyboardLayout;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|layout| layout.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| InvalidKeyboardLayout(s.to_owned()))
+    }
+}
+
+/// The modifiers that must be held with a key.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Level {
+    /// No shift key or AltGr.
+    Base,
+    /// A shift key.
+    Shift,
+    /// AltGr, without a shift key.
+    AltGr,
+}
+
+/// A key on the layout, with the modifiers that must be held with it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Position {
+    /// The key's scancode.
+    pub scancode: u16,
+    /// The modifiers to hold.
+    pub level: Level,
+}
+
+/// The keys to press to type a keysym.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Keystroke {
+    /// A single key.
+    Key(Position),
+    /// A dead key, then the key it combines with.
+    Composed {
+        /// The dead key.
+        dead: Position,
+        /// The key typing the character the accent is added to.
+        key: Position,
+    },
+}
+
+/// What a key types at one level.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Sym {
+    /// Nothing.
+    Empty,
+    /// A character.
+    Char(char),
+    /// A dead key, which combines with the next character typed.
+    Dead(Dead),
+}
+
+/// A dead key's accent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[expect(missing_docs)] // self explanatory variants
+pub enum Dead {
+    Grave,
+    Acute,
+    Circumflex,
+    Tilde,
+    Diaeresis,
+}
+
+impl Dead {
+    /// Every accent.
+    pub const ALL: [Dead; 5] = [
+        Dead::Grave,
+        Dead::Acute,
+        Dead::Circumflex,
+        Dead::Tilde,
+        Dead::Diaeresis,
+    ];
+
+    /// Returns the character that the dead key combines with to type `c`.
+    /// The accent itself is typed by following the dead key with a space.
+    fn base(&self, c: char) -> Option<char> {
+        let (bases, composed) = match self {
+            Dead::Grave => (" aeiouAEIOU", "`àèìòùÀÈÌÒÙ"),
+            Dead::Acute => (" aeiouyAEIOUY", "´áéíóúýÁÉÍÓÚÝ"),
+            Dead::Circumflex => (" aeiouAEIOU", "^âêîôûÂÊÎÔÛ"),
+            Dead::Tilde => (" anoANO", "~ãñõÃÑÕ"),
+            Dead::Diaeresis => (" aeiouyAEIOU", "¨äëïöüÿÄËÏÖÜ"),
+        };
+        bases
+            .chars()
+            .zip(composed.chars())
+            .find_map(|(base, composed)| (composed == c).then_some(base))
+    }
+}
+
+/// A key that types characters, with what it types at each level.
+#[derive(Debug, Copy, Clone)]
+struct Key {
+    scancode: u16,
+    base: Sym,
+    shift: Sym,
+    altgr: Sym,
+}
+
+const fn key(scancode: u16, base: Sym, shift: Sym, altgr: Sym) -> Key {
+    Key {
+        scancode,
+        base,
+        shift,
+        altgr,
+    }
+}
+
+/// A key typing an ASCII letter, in upper case with shift.
+const fn letter(scancode: u16, c: char) -> Key {
+    key(scancode, C(c), C(c.to_ascii_uppercase()), N)
+}
+
+/// The space bar, which is in the same place on every layout. It is looked up
+/// separately for typing characters, but is needed to type a dead key's
+/// accent.
+const SPACE: Key = key(0x39, C(' '), N, N);
+
+const EN_US: &[Key] = &[
+    key(0x29, C('`'), C('~'), N),
+    key(0x02, C('1'), C('!'), N),
+    key(0x03, C('2'), C('@'), N),
+    key(0x04, C('3'), C('#'), N),
+    key(0x05, C('4'), C('$'), N),
+    key(0x06, C('5'), C('%'), N),
+    key(0x07, C('6'), C('^'), N),
+    key(0x08, C('7'), C('&'), N),
+    key(0x09, C('8'), C('*'), N),
+    key(0x0a, C('9'), C('('), N),
+    key(0x0b, C('0'), C(')'), N),
+    key(0x0c, C('-'), C('_'), N),
+    key(0x0d, C('='), C('+'), N),
+    letter(0x10, 'q'),
+    letter(0x11, 'w'),
+    letter(0x12, 'e'),
+    letter(0x13, 'r'),
+    letter(0x14, 't'),
+    letter(0x15, 'y'),
+    letter(0x16, 'u'),
+    letter(0x17, 'i'),
+    letter(0x18, 'o'),
+    letter(0x19, 'p'),
+    key(0x1a, C('['), C('{'), N),
+    key(0x1b, C(']'), C('}'), N),
+    key(0x2b, C('\\'), C('|'), N),
+    letter(0x1e, 'a'),
+    letter(0x1f, 's'),
+    letter(0x20, 'd'),
+    letter(0x21, 'f'),
+    letter(0x22, 'g'),
+    letter(0x23, 'h'),
+    letter(0x24, 'j'),
+    letter(0x25, 'k'),
+    letter(0x26, 'l'),
+    key(0x27, C(';'), C(':'), N),
+    key(0x28, C('\''), C('"'), N),
+    letter(0x2c, 'z'),
+    letter(0x2d, 'x'),
+    letter(0x2e, 'c'),
+    letter(0x2f, 'v'),
+    letter(0x30, 'b'),
+    letter(0x31, 'n'),
+    letter(0x32, 'm'),
+    key(0x33, C(','), C('<'), N),
+    key(0x34, C('.'), C('>'), N),
+    key(0x35, C('/'), C('?'), N),
+];
+
+const DE: &[Key] = &[
+    key(0x29, D(Dead::Circumflex), C('°'), N),
+    key(0x02, C('1'), C('!'), N),
+    key(0x03, C('2'), C('"'), C('²')),
+    key(0x04, C('3'), C('§'), C('³')),
+    key(0x05, C('4'), C('$'), N),
+    key(0x06, C('5'), C('%'), N),
+    key(0x07, C('6'), C('&'), N),
+    key(0x08, C('7'), C('/'), C('{')),
+    key(0x09, C('8'), C('('), C('[')),
+    key(0x0a, C('9'), C(')'), C(']')),
+    key(0x0b, C('0'), C('='), C('}')),
+    key(0x0c, C('ß'), C('?'), C('\\')),
+    key(0x0d, D(Dead::Acute), D(Dead::Grave), N),
+    key(0x10, C('q'), C('Q'), C('@')),
+    letter(0x11, 'w'),
+    key(0x12, C('e'), C('E'), C('€')),
+    letter(0x13, 'r'),
+    letter(0x14, 't'),
+    letter(0x15, 'z'),
+    letter(0x16, 'u'),
+    letter(0x17, 'i'),
+    letter(0x18, 'o'),
+    letter(0x19, 'p'),
+    key(0x1a, C('ü'), C('Ü'), N),
+    key(0x1b, C('+'), C('*'), C('~')),
+    letter(0x1e, 'a'),
+    letter(0x1f, 's'),
+    letter(0x20, 'd'),
+    letter(0x21, 'f'),
+    letter(0x22, 'g'),
+    letter(0x23, 'h'),
+    letter(0x24, 'j'),
+    letter(0x25, 'k'),
+    letter(0x26, 'l'),
+    key(0x27, C('ö'), C('Ö'), N),
+    key(0x28, C('ä'), C('Ä'), N),
+    key(0x2b, C('#'), C('\''), N),
+    key(0x56, C('<'), C('>'), C('|')),
+    letter(0x2c, 'y'),
+    letter(0x2d, 'x'),
+    letter(0x2e, 'c'),
+    letter(0x2f, 'v'),
+    letter(0x30, 'b'),
+    letter(0x31, 'n'),
+    key(0x32, C('m'), C('M'), C('µ')),
+    key(0x33, C(','), C(';'), N),
+    key(0x34, C('.'), C(':'), N),
+    key(0x35, C('-'), C('_'), N),
+];
+
+const FR: &[Key] = &[
+    key(0x29, C('²'), N, N),
+    // The digits are typed with shift.
+    key(0x02, C('&'), C('1'), N),
+    key(0x03, C('é'), C('2'), D(Dead::Tilde)),
+    key(0x04, C('"'), C('3'), C('#')),
+    key(0x05, C('\''), C('4'), C('{')),
+    key(0x06, C('('), C('5'), C('[')),
+    key(0x07, C('-'), C('6'), C('|')),
+    key(0x08, C('è'), C('7'), D(Dead::Grave)),
+    key(0x09, C('_'), C('8'), C('\\')),
+    key(0x0a, C('ç'), C('9'), C('^')),
+    key(0x0b, C('à'), C('0'), C('@')),
+    key(0x0c, C(')'), C('°'), C(']')),
+    key(0x0d, C('='), C('+'), C('}')),
+    letter(0x10, 'a'),
+    letter(0x11, 'z'),
+    key(0x12, C('e'), C('E'), C('€')),
+    letter(0x13, 'r'),
+    letter(0x14, 't'),
+    letter(0x15, 'y'),
+    letter(0x16, 'u'),
+    letter(0x17, 'i'),
+    letter(0x18, 'o'),
+    letter(0x19, 'p'),
+    key(0x1a, D(Dead::Circumflex), D(Dead::Diaeresis), N),
+    key(0x1b, C('$'), C('£'), C('¤')),
+    letter(0x1e, 'q'),
+    letter(0x1f, 's'),
+    letter(0x20, 'd'),
+    letter(0x21, 'f'),
+    letter(0x22, 'g'),
+    letter(0x23, 'h'),
+    letter(0x24, 'j'),
+    letter(0x25, 'k'),
+    letter(0x26, 'l'),
+    letter(0x27, 'm'),
+    key(0x28, C('ù'), C('%'), N),
+    key(0x2b, C('*'), C('µ'), N),
+    key(0x56, C('<'), C('>'), N),
+    letter(0x2c, 'w'),
+    letter(0x2d, 'x'),
+    letter(0x2e, 'c'),
+    letter(0x2f, 'v'),
+    letter(0x30, 'b'),
+    letter(0x31, 'n'),
+    key(0x32, C(','), C('?'), N),
+    key(0x33, C(';'), C('.'), N),
+    key(0x34, C(':'), C('/'), N),
+    key(0x35, C('!'), C('§'), N),
+];
+
+const JA: &[Key] = &[
+    key(0x02, C('1'), C('!'), N),
+    key(0x03, C('2'), C('"'), N),
+    key(0x04, C('3'), C('#'), N),
+    key(0x05, C('4'), C('$'), N),
+    key(0x06, C('5'), C('%'), N),
+    key(0x07, C('6'), C('&'), N),
+    key(0x08, C('7'), C('\''), N),
+    key(0x09, C('8'), C('('), N),
+    key(0x0a, C('9'), C(')'), N),
+    key(0x0b, C('0'), N, N),
+    key(0x0c, C('-'), C('='), N),
+    key(0x0d, C('^'), C('~'), N),
+    // The yen key, which types a backslash (shown as a yen sign).
+    key(0x7d, C('\\'), C('|'), N),
+    letter(0x10, 'q'),
+    letter(0x11, 'w'),
+    letter(0x12, 'e'),
+    letter(0x13, 'r'),
+    letter(0x14, 't'),
+    letter(0x15, 'y'),
+    letter(0x16, 'u'),
+    letter(0x17, 'i'),
+    letter(0x18, 'o'),
+    letter(0x19, 'p'),
+    key(0x1a, C('@'), C('`'), N),
+    key(0x1b, C('['), C('{'), N),
+    letter(0x1e, 'a'),
+    letter(0x1f, 's'),
+    letter(0x20, 'd'),
+    letter(0x21, 'f'),
+    letter(0x22, 'g'),
+    letter(0x23, 'h'),
+    letter(0x24, 'j'),
+    letter(0x25, 'k'),
+    letter(0x26, 'l'),
+    key(0x27, C(';'), C('+'), N),
+    key(0x28, C(':'), C('*'), N),
+    key(0x2b, C(']'), C('}'), N),
+    letter(0x2c, 'z'),
+    letter(0x2d, 'x'),
+    letter(0x2e, 'c'),
+    letter(0x2f, 'v'),
+    letter(0x30, 'b'),
+    letter(0x31, 'n'),
+    letter(0x32, 'm'),
+    key(0x33, C(','), C('<'), N),
+    key(0x34, C('.'), C('>'), N),
+    key(0x35, C('/'), C('?'), N),
+    // The ro key, next to the right shift key.
+    key(0x73, C('\\'), C('_'), N),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::KeyboardLayout;
+    use super::SPACE;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn unique_scancodes() {
+        for layout in KeyboardLayout::ALL {
+            let mut scancodes = BTreeSet::new();
+            for key in layout.keys().iter().chain([&SPACE]) {
+                assert!(
+                    scancodes.insert(key.scancode),
+                    "{layout:?} lists scancode {:#x} more than once",
+                    key.scancode
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn from_str() {
+        for layout in KeyboardLayout::ALL {
+            assert_eq!(layout.name().parse::<KeyboardLayout>().unwrap(), layout);
+        }
+        assert_eq!(
+            "EN-US".parse::<KeyboardLayout>().unwrap(),
+            KeyboardLayout::EnUs
+        );
+        "xx".parse::<KeyboardLayout>().unwrap_err();
+    }
+}
@@ -5,7 +5,14 @@
 
 #![forbid(unsafe_code)]
 
+pub mod fan_out;
+pub mod key_sequence;
+pub mod keymap;
+pub mod lock_keys;
 pub mod mesh_input;
+pub mod rate_limit;
+pub mod remap;
+pub mod text;
 
 use mesh::MeshPayload;
 use std::pin::Pin;
@@ -13,25 +20,57 @@
 use vm_resource::ResourceId;
 use vm_resource::kind::KeyboardInputHandleKind;
 use vm_resource::kind::MouseInputHandleKind;
+use vm_resource::kind::TabletInputHandleKind;
 
-/// Keyboard or mouse input data.
+/// Keyboard, mouse, or tablet input data.
 #[derive(Debug, Copy, Clone, MeshPayload)]
 pub enum InputData {
     /// A keystoke.
     Keyboard(KeyboardData),
-    /// A mouse move or click.
+    /// A relative mouse move or click.
     Mouse(MouseData),
+    /// An absolute pointer move or click.
+    Tablet(TabletData),
 }
 
-/// A mouse input event.
+/// A relative mouse input event.
 #[derive(Debug, Copy, Clone, MeshPayload)]
 pub struct MouseData {
+    /// A bitmask of the buttons that are pressed.
+    pub button_mask: u8,
+    /// The relative X movement, positive to the right.
+    pub dx: i16,
+    /// The relative Y movement, positive downward.
+    pub dy: i16,
+}
+
+/// An absolute pointer (tablet) input event.
+///
+/// Coordinates are normalized to the range `0..=TabletData::MAX_COORDINATE`
+/// regardless of the resolution of the source, so that devices can scale them
+/// to their own coordinate space without knowing the display size. The source
+/// resolution is still reported for devices that want to map a position back
+/// to a pixel.
+#[derive(Debug, Copy, Clone, MeshPayload)]
+pub struct TabletData {
     /// A bitmask of the buttons that are pressed.
     pub button_mask: u8,
     /// The absolute X location.
     pub x: u16,
     /// The absolute Y location.
     pub y: u16,
+    /// The width in pixels of the display the location was taken on, or zero
+    /// if unknown.
+    pub width: u16,
+    /// The height in pixels of the display the location was taken on, or zero
+    /// if unknown.
+    pub height: u16,
+}
+
+impl TabletData {
+    /// The maximum value of the `x` and `y` coordinates, corresponding to the
+    /// right and bottom edges of the display.
+    pub const MAX_COORDINATE: u16 = 0x7fff;
 }
 
 /// A keyboard input event.
@@ -68,6 +107,12 @@ fn from(value: T) -> Self {
     }
 }
 
+impl<T: 'static + InputSource<TabletData>> From<T> for ResolvedInputSource<TabletData> {
+    fn from(value: T) -> Self {
+        Self(Box::new(value))
+    }
+}
+
 impl CanResolveTo<ResolvedInputSource<KeyboardData>> for KeyboardInputHandleKind {
     type Input<'a> = &'a str;
 }
@@ -76,6 +121,10 @@ impl CanResolveTo<ResolvedInputSource<MouseData>> for MouseInputHandleKind {
     type Input<'a> = &'a str;
 }
 
+impl CanResolveTo<ResolvedInputSource<TabletData>> for TabletInputHandleKind {
+    type Input<'a> = &'a str;
+}
+
 /// An input handle for input multiplexed over an input channel serving multiple
 /// devices.
 #[derive(MeshPayload)]
@@ -91,6 +140,14 @@ impl ResourceId<KeyboardInputHandleKind> for MultiplexedInputHandle {
     const ID: &'static str = "keyboard";
 }
 
+/// Note that this ID carried absolute positions before [`TabletData`] was
+/// split out. Absolute pointing devices must now use the `"tablet"` ID under
+/// [`TabletInputHandleKind`]; a `"mouse"` handle now only delivers relative
+/// [`MouseData`].
 impl ResourceId<MouseInputHandleKind> for MultiplexedInputHandle {
     const ID: &'static str = "mouse";
 }
+
+impl ResourceId<TabletInputHandleKind> for MultiplexedInputHandle {
+    const ID: &'static str = "tablet";
+}
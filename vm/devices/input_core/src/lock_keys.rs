@@ -0,0 +1,142 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Bringing the guest's NumLock and CapsLock to a configured state.
+//!
+//! The lock state belongs to the guest OS, which cannot be told it directly.
+//! Instead, whenever the guest reports its lock state by setting the keyboard
+//! LEDs, the keyboard presses the lock keys that differ from the configured
+//! state. This starts again at boot and after restore, and stops once the user
+//! types, so that the user can change the state afterwards.
+
+use crate::KeyboardData;
+use mesh::MeshPayload;
+
+const SCANCODE_NUM_LOCK: u16 = 0x45;
+const SCANCODE_CAPS_LOCK: u16 = 0x3a;
+
+/// The number of times the guest's lock state is corrected before giving up,
+/// in case the guest does not change its LEDs when the lock keys are pressed.
+const MAX_CORRECTIONS: u8 = 4;
+
+/// The state of the lock keys.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, MeshPayload)]
+pub struct LockKeys {
+    /// NumLock is on.
+    pub num_lock: bool,
+    /// CapsLock is on.
+    pub caps_lock: bool,
+}
+
+/// Tracks bringing the guest's lock keys to a configured state.
+#[derive(Debug)]
+pub struct LockKeySync {
+    target: Option<LockKeys>,
+    corrections_left: u8,
+}
+
+impl LockKeySync {
+    /// Returns a new tracker for the lock state `target`, if any, which is
+    /// applied from the guest's first report.
+    pub fn new(target: Option<LockKeys>) -> Self {
+        Self {
+            target,
+            corrections_left: MAX_CORRECTIONS,
+        }
+    }
+
+    /// Starts applying the configured lock state again, after the guest boots
+    /// or is restored.
+    pub fn arm(&mut self) {
+        self.corrections_left = MAX_CORRECTIONS;
+    }
+
+    /// Stops applying the configured lock state, once the user has typed.
+    pub fn disarm(&mut self) {
+        self.corrections_left = 0;
+    }
+
+    /// Returns the keystrokes that change the guest's lock state from
+    /// `current`, as reported by its LEDs, to the configured state.
+    pub fn keystrokes(&mut self, current: LockKeys) -> Vec<KeyboardData> {
+        let Some(target) = self.target else {
+            return Vec::new();
+        };
+        if self.corrections_left == 0 || current == target {
+            return Vec::new();
+        }
+        self.corrections_left -= 1;
+        let mut keystrokes = Vec::new();
+        for (code, toggle) in [
+            (SCANCODE_NUM_LOCK, current.num_lock != target.num_lock),
+            (SCANCODE_CAPS_LOCK, current.caps_lock != target.caps_lock),
+        ] {
+            if toggle {
+                keystrokes.extend([
+                    KeyboardData { code, make: true },
+                    KeyboardData { code, make: false },
+                ]);
+            }
+        }
+        keystrokes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LockKeySync;
+    use super::LockKeys;
+    use crate::KeyboardData;
+
+    const OFF: LockKeys = LockKeys {
+        num_lock: false,
+        caps_lock: false,
+    };
+    const NUM: LockKeys = LockKeys {
+        num_lock: true,
+        caps_lock: false,
+    };
+    const CAPS: LockKeys = LockKeys {
+        num_lock: false,
+        caps_lock: true,
+    };
+
+    fn codes(keystrokes: Vec<KeyboardData>) -> Vec<(u16, bool)> {
+        keystrokes.into_iter().map(|k| (k.code, k.make)).collect()
+    }
+
+    #[test]
+    fn toggles_differing_keys() {
+        let mut sync = LockKeySync::new(Some(NUM));
+        assert_eq!(codes(sync.keystrokes(NUM)), []);
+        assert_eq!(codes(sync.keystrokes(OFF)), [(0x45, true), (0x45, false)]);
+        assert_eq!(
+            codes(sync.keystrokes(CAPS)),
+            [(0x45, true), (0x45, false), (0x3a, true), (0x3a, false)]
+        );
+    }
+
+    #[test]
+    fn unconfigured() {
+        let mut sync = LockKeySync::new(None);
+        assert_eq!(codes(sync.keystrokes(CAPS)), []);
+    }
+
+    #[test]
+    fn disarm_and_rearm() {
+        let mut sync = LockKeySync::new(Some(NUM));
+        sync.disarm();
+        assert_eq!(codes(sync.keystrokes(OFF)), []);
+        sync.arm();
+        assert_eq!(codes(sync.keystrokes(OFF)), [(0x45, true), (0x45, false)]);
+    }
+
+    #[test]
+    fn gives_up() {
+        let mut sync = LockKeySync::new(Some(NUM));
+        for _ in 0..4 {
+            assert!(!sync.keystrokes(OFF).is_empty());
+        }
+        assert_eq!(codes(sync.keystrokes(OFF)), []);
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Rate limiting for input from untrusted sources.
+
+use mesh::MeshPayload;
+use std::time::Instant;
+
+/// The rate limit to apply to an input source.
+#[derive(Debug, Copy, Clone, MeshPayload)]
+pub struct InputRateLimit {
+    /// The sustained number of events allowed per second.
+    pub events_per_second: u32,
+    /// The number of events that can be delivered back-to-back before the
+    /// sustained rate applies.
+    pub burst: u32,
+}
+
+/// A token bucket limiting the rate of input events.
+///
+/// Callers are responsible for deciding which events are subject to the
+/// limit. Events that only release state (a key release, for example) should
+/// generally bypass it, so that dropping input never leaves keys or buttons
+/// held down in the guest.
+#[derive(Debug)]
+pub struct InputRateLimiter {
+    limit: InputRateLimit,
+    tokens: f64,
+    last_refill: Instant,
+    dropped: u64,
+}
+
+impl InputRateLimiter {
+    /// Returns a new rate limiter, starting with a full burst available.
+    pub fn new(limit: InputRateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst.max(1).into(),
+            last_refill: Instant::now(),
+            dropped: 0,
+        }
+    }
+
+    /// Returns whether an event may be delivered now, consuming its share of
+    /// the limit if so.
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    /// Returns whether an event may be delivered at time `now`.
+    pub fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        self.tokens = elapsed
+            .as_secs_f64()
+            .mul_add(f64::from(self.limit.events_per_second), self.tokens)
+            .min(self.limit.burst.max(1).into());
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            self.dropped += 1;
+            false
+        }
+    }
+
+    /// The number of events rejected so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InputRateLimit;
+    use super::InputRateLimiter;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    fn acquired(limiter: &mut InputRateLimiter, now: Instant, attempts: usize) -> usize {
+        (0..attempts)
+            .filter(|_| limiter.try_acquire_at(now))
+            .count()
+    }
+
+    #[test]
+    fn burst() {
+        let mut limiter = InputRateLimiter::new(InputRateLimit {
+            events_per_second: 10,
+            burst: 5,
+        });
+        let start = limiter.last_refill;
+        assert_eq!(acquired(&mut limiter, start, 8), 5);
+        assert_eq!(limiter.dropped(), 3);
+
+        // Tokens do not accumulate beyond the burst, however long the source
+        // is idle.
+        let later = start + Duration::from_secs(60);
+        assert_eq!(acquired(&mut limiter, later, 8), 5);
+        assert_eq!(limiter.dropped(), 6);
+    }
+
+    #[test]
+    fn refill() {
+        let mut limiter = InputRateLimiter::new(InputRateLimit {
+            events_per_second: 10,
+            burst: 10,
+        });
+        let start = limiter.last_refill;
+        assert_eq!(acquired(&mut limiter, start, 10), 10);
+        assert!(!limiter.try_acquire_at(start));
+
+        // One event is allowed per 100ms, with partial tokens carried over.
+        assert!(!limiter.try_acquire_at(start + Duration::from_millis(50)));
+        assert!(limiter.try_acquire_at(start + Duration::from_millis(100)));
+        assert!(!limiter.try_acquire_at(start + Duration::from_millis(150)));
+        assert_eq!(
+            acquired(&mut limiter, start + Duration::from_millis(450), 10),
+            3
+        );
+
+        // Time going backwards does not add tokens.
+        assert!(!limiter.try_acquire_at(start));
+    }
+
+    #[test]
+    fn zero_burst() {
+        // A burst of zero still allows one event at a time.
+        let mut limiter = InputRateLimiter::new(InputRateLimit {
+            events_per_second: 1,
+            burst: 0,
+        });
+        let start = limiter.last_refill;
+        assert_eq!(acquired(&mut limiter, start, 3), 1);
+        assert_eq!(acquired(&mut limiter, start + Duration::from_secs(5), 3), 1);
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Host-side key remapping, for guests where the keyboard layout cannot be
+//! changed (such as locked-down images).
+//!
+//! A keyboard's input is remapped by wrapping its input resource in a
+//! [`RemappedKeyboardHandle`].
+
+use crate::InputSource;
+use crate::KeyboardData;
+use crate::ResolvedInputSource;
+use async_trait::async_trait;
+use futures::Stream;
+use futures::StreamExt;
+use mesh::MeshPayload;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::Context;
+use std::task::Poll;
+use std::task::ready;
+use thiserror::Error;
+use vm_resource::AsyncResolveResource;
+use vm_resource::ResolveError;
+use vm_resource::Resource;
+use vm_resource::ResourceId;
+use vm_resource::ResourceResolver;
+use vm_resource::declare_static_async_resolver;
+use vm_resource::kind::KeyboardInputHandleKind;
+
+const SCANCODE_CAPS_LOCK: u16 = 0x3a;
+const SCANCODE_LEFT_CTRL: u16 = 0x1d;
+const SCANCODE_LEFT_ALT: u16 = 0x38;
+const SCANCODE_RIGHT_ALT: u16 = 0xe038;
+const SCANCODE_LEFT_SUPER: u16 = 0xe05b;
+const SCANCODE_RIGHT_SUPER: u16 = 0xe05c;
+
+/// A table of scancodes to replace before input reaches the guest.
+///
+/// Scancodes are set 1 codes, with the `0xe0` prefix in the high byte for
+/// extended keys, as in [`KeyboardData`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, MeshPayload)]
+pub struct KeyRemap {
+    /// Sorted by `from`.
+    mappings: Vec<KeyMapping>,
+}
+
+/// A single entry in a [`KeyRemap`] table.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, MeshPayload)]
+pub struct KeyMapping {
+    /// The scancode from the client.
+    pub from: u16,
+    /// The scancode sent to the guest, or `None` if the key is disabled.
+    pub to: Option<u16>,
+}
+
+impl KeyRemap {
+    /// Returns an empty table, which sends every key unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether the table is empty.
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    /// Returns the mappings, sorted by the scancode from the client.
+    pub fn mappings(&self) -> &[KeyMapping] {
+        &self.mappings
+    }
+
+    /// Sends `to` for the key `from`, replacing any previous mapping for it.
+    pub fn map(&mut self, from: u16, to: u16) {
+        self.insert(from, Some(to));
+    }
+
+    /// Exchanges the keys `a` and `b`.
+    pub fn swap(&mut self, a: u16, b: u16) {
+        self.map(a, b);
+        self.map(b, a);
+    }
+
+    /// Stops the key `code` from being sent to the guest.
+    pub fn disable(&mut self, code: u16) {
+        self.insert(code, None);
+    }
+
+    fn insert(&mut self, from: u16, to: Option<u16>) {
+        match self.mappings.binary_search_by_key(&from, |m| m.from) {
+            Ok(i) => self.mappings[i].to = to,
+            Err(i) => self.mappings.insert(i, KeyMapping { from, to }),
+        }
+    }
+
+    /// Returns the scancode to send to the guest for `code`, or `None` if the
+    /// key is disabled.
+    pub fn lookup(&self, code: u16) -> Option<u16> {
+        match self.mappings.binary_search_by_key(&code, |m| m.from) {
+            Ok(i) => self.mappings[i].to,
+            Err(_) => Some(code),
+        }
+    }
+}
+
+/// An invalid entry in a key remapping specification.
+#[derive(Debug, Error)]
+#[error(
+    "invalid key remapping {0:?}, expected swap-caps-ctrl, super-to-alt, CODE=CODE, or CODE=off"
+)]
+pub struct InvalidKeyRemap(pub String);
+
+impl FromStr for KeyRemap {
+    type Err = InvalidKeyRemap;
+
+    /// Parses a comma-separated list of remappings:
+    ///
+    /// * `swap-caps-ctrl` exchanges Caps Lock and the left Ctrl key.
+    /// * `super-to-alt` sends Alt for the Super (Windows) keys.
+    /// * `CODE=CODE` sends the second hex scancode for the first.
+    /// * `CODE=off` disables the key with the hex scancode.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut remap = Self::new();
+        for entry in s.split(',').filter(|entry| !entry.is_empty()) {
+            let invalid = || InvalidKeyRemap(entry.to_owned());
+            match entry {
+                "swap-caps-ctrl" => remap.swap(SCANCODE_CAPS_LOCK, SCANCODE_LEFT_CTRL),
+                "super-to-alt" => {
+                    remap.map(SCANCODE_LEFT_SUPER, SCANCODE_LEFT_ALT);
+                    remap.map(SCANCODE_RIGHT_SUPER, SCANCODE_RIGHT_ALT);
+                }
+                _ => {
+                    let (from, to) = entry.split_once('=').ok_or_else(invalid)?;
+                    let from = parse_scancode(from).ok_or_else(invalid)?;
+                    if to == "off" {
+                        remap.disable(from);
+                    } else {
+                        remap.map(from, parse_scancode(to).ok_or_else(invalid)?);
+                    }
+                }
+            }
+        }
+        Ok(remap)
+    }
+}
+
+fn parse_scancode(s: &str) -> Option<u16> {
+    u16::from_str_radix(s.strip_prefix("0x").unwrap_or(s), 16).ok()
+}
+
+/// Applies a [`KeyRemap`] table, which can be changed at any time, to
+/// keyboard input.
+pub struct KeyRemapper {
+    table: mesh::Cell<KeyRemap>,
+    /// The scancode sent for each key that is held down, so that its release
+    /// matches its press even if the table changes in between.
+    held: BTreeMap<u16, Option<u16>>,
+}
+
+impl KeyRemapper {
+    /// Returns a remapper that uses the current value of `table`.
+    pub fn new(table: mesh::Cell<KeyRemap>) -> Self {
+        Self {
+            table,
+            held: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the keystroke to send to the guest for `input`, or `None` if
+    /// the key is disabled.
+    pub fn remap(&mut self, input: KeyboardData) -> Option<KeyboardData> {
+        let code = if input.make {
+            let code = self.table.with(|table| table.lookup(input.code));
+            self.held.insert(input.code, code);
+            code
+        } else {
+            match self.held.remove(&input.code) {
+                Some(code) => code,
+                None => self.table.with(|table| table.lookup(input.code)),
+            }
+        };
+        Some(KeyboardData {
+            code: code?,
+            make: input.make,
+        })
+    }
+}
+
+/// A handle to keyboard input from `source`, remapped with `table`.
+#[derive(MeshPayload)]
+pub struct RemappedKeyboardHandle {
+    /// The keyboard input to remap.
+    pub source: Resource<KeyboardInputHandleKind>,
+    /// The remapping table, which can be changed while the VM is running.
+    pub table: mesh::Cell<KeyRemap>,
+}
+
+impl ResourceId<KeyboardInputHandleKind> for RemappedKeyboardHandle {
+    const ID: &'static str = "remapped_keyboard";
+}
+
+/// An input source that remaps the keys from another.
+struct RemappedInputSource {
+    source: Box<dyn InputSource<KeyboardData>>,
+    remapper: KeyRemapper,
+}
+
+impl Stream for RemappedInputSource {
+    type Item = KeyboardData;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<KeyboardData>> {
+        let this = self.get_mut();
+        loop {
+            let Some(input) = ready!(this.source.poll_next_unpin(cx)) else {
+                break Poll::Ready(None);
+            };
+            if let Some(input) = this.remapper.remap(input) {
+                break Poll::Ready(Some(input));
+            }
+        }
+    }
+}
+
+impl InputSource<KeyboardData> for RemappedInputSource {
+    fn set_active(
+        &mut self,
+        active: bool,
+    ) -> Pin<Box<dyn '_ + std::future::Future<Output = ()> + Send>> {
+        self.source.set_active(active)
+    }
+}
+
+/// Resolver for [`RemappedKeyboardHandle`].
+pub struct RemappedKeyboardResolver;
+
+declare_static_async_resolver!(
+    RemappedKeyboardResolver,
+    (KeyboardInputHandleKind, RemappedKeyboardHandle)
+);
+
+#[async_trait]
+impl AsyncResolveResource<KeyboardInputHandleKind, RemappedKeyboardHandle>
+    for RemappedKeyboardResolver
+{
+    type Output = ResolvedInputSource<KeyboardData>;
+    type Error = ResolveError;
+
+    async fn resolve(
+        &self,
+        resolver: &ResourceResolver,
+        resource: RemappedKeyboardHandle,
+        input: &str,
+    ) -> Result<Self::Output, Self::Error> {
+        let source = resolver.resolve(resource.source, input).await?;
+        Ok(RemappedInputSource {
+            source: source.0,
+            remapper: KeyRemapper::new(resource.table),
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyMapping;
+    use super::KeyRemap;
+    use super::KeyRemapper;
+    use crate::KeyboardData;
+    use futures::executor::block_on;
+
+    #[test]
+    fn from_str() {
+        let remap: KeyRemap = "swap-caps-ctrl,super-to-alt,0x1e=0x30,2c=off"
+            .parse()
+            .unwrap();
+        let mapping = |from, to| KeyMapping { from, to };
+        assert_eq!(
+            remap.mappings(),
+            [
+                mapping(0x1d, Some(0x3a)),
+                mapping(0x1e, Some(0x30)),
+                mapping(0x2c, None),
+                mapping(0x3a, Some(0x1d)),
+                mapping(0xe05b, Some(0x38)),
+                mapping(0xe05c, Some(0xe038)),
+            ]
+        );
+        assert_eq!(remap.lookup(0x1e), Some(0x30));
+        assert_eq!(remap.lookup(0x2c), None);
+        assert_eq!(remap.lookup(0x10), Some(0x10));
+
+        // A later entry replaces an earlier one for the same key.
+        let remap: KeyRemap = "1e=30,1e=off".parse().unwrap();
+        assert_eq!(remap.lookup(0x1e), None);
+
+        assert!("".parse::<KeyRemap>().unwrap().is_empty());
+        for invalid in ["swap", "1e", "1e=", "zz=30", "1e=on", "10000=1"] {
+            let err = invalid.parse::<KeyRemap>().unwrap_err();
+            assert_eq!(err.0, invalid);
+        }
+    }
+
+    #[test]
+    fn release_matches_press() {
+        let key = |code, make| KeyboardData { code, make };
+        let remap = |remapper: &mut KeyRemapper, code, make| {
+            remapper
+                .remap(key(code, make))
+                .map(|KeyboardData { code, make }| (code, make))
+        };
+        let (mut updater, table) = mesh::cell("3a=1d,2c=off".parse::<KeyRemap>().unwrap());
+        let mut remapper = KeyRemapper::new(table);
+
+        assert_eq!(remap(&mut remapper, 0x3a, true), Some((0x1d, true)));
+        assert_eq!(remap(&mut remapper, 0x2c, true), None);
+
+        // Keys held while the table changes are released as they were
+        // pressed.
+        block_on(updater.set(KeyRemap::new()));
+        assert_eq!(remap(&mut remapper, 0x3a, false), Some((0x1d, false)));
+        assert_eq!(remap(&mut remapper, 0x2c, false), None);
+
+        // Later presses use the new table.
+        assert_eq!(remap(&mut remapper, 0x3a, true), Some((0x3a, true)));
+        assert_eq!(remap(&mut remapper, 0x3a, false), Some((0x3a, false)));
+
+        // A release without a press uses the current table.
+        block_on(updater.set("2c=off".parse().unwrap()));
+        assert_eq!(remap(&mut remapper, 0x2c, false), None);
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Support for typing text into the guest as a sequence of keystrokes.
+
+use crate::InputData;
+use crate::KeyboardData;
+use crate::keymap::KeyboardLayout;
+use crate::keymap::Keystroke;
+use crate::keymap::Level;
+use crate::keymap::Position;
+use pal_async::timer::PolledTimer;
+use std::time::Duration;
+use thiserror::Error;
+
+/// The scancode of the left shift key.
+const SCANCODE_LEFT_SHIFT: u16 = 0x2a;
+/// The scancode of AltGr, the right Alt key.
+const SCANCODE_ALTGR: u16 = 0xe038;
+const SCANCODE_ENTER: u16 = 0x1c;
+const SCANCODE_TAB: u16 = 0x0f;
+
+/// A character that cannot be typed on the keyboard layout.
+#[derive(Debug, Error)]
+#[error("character {0:?} cannot be typed on the guest's keyboard layout")]
+pub struct UnsupportedCharacter(pub char);
+
+/// Returns the scancode of the modifier held for `level`, if any.
+fn modifier(level: Level) -> Option<u16> {
+    match level {
+        Level::Base => None,
+        Level::Shift => Some(SCANCODE_LEFT_SHIFT),
+        Level::AltGr => Some(SCANCODE_ALTGR),
+    }
+}
+
+/// Converts `text` into the keystrokes that type it on `layout`.
+///
+/// Shift and AltGr are pressed and released as needed, and are released again
+/// at the end, so the keyboard is left with no keys held down. Characters that
+/// are not on the layout are typed with its dead keys where possible.
+pub fn text_to_keystrokes(
+    layout: KeyboardLayout,
+    text: &str,
+) -> Result<Vec<KeyboardData>, UnsupportedCharacter> {
+    let mut keystrokes = Vec::with_capacity(text.len() * 2);
+    let mut level = Level::Base;
+    let mut type_key = |position: Position| {
+        if position.level != level {
+            if let Some(code) = modifier(level) {
+                keystrokes.push(KeyboardData { code, make: false });
+            }
+            if let Some(code) = modifier(position.level) {
+                keystrokes.push(KeyboardData { code, make: true });
+            }
+            level = position.level;
+        }
+        let code = position.scancode;
+        keystrokes.push(KeyboardData { code, make: true });
+        keystrokes.push(KeyboardData { code, make: false });
+    };
+    for c in text.chars() {
+        let keystroke = match c {
+            '\n' => Keystroke::Key(Position {
+                scancode: SCANCODE_ENTER,
+                level: Level::Base,
+            }),
+            '\t' => Keystroke::Key(Position {
+                scancode: SCANCODE_TAB,
+                level: Level::Base,
+            }),
+            c => layout.keystroke(c).ok_or(UnsupportedCharacter(c))?,
+        };
+        match keystroke {
+            Keystroke::Key(position) => type_key(position),
+            Keystroke::Composed { dead, key } => {
+                type_key(dead);
+                type_key(key);
+            }
+        }
+    }
+    if let Some(code) = modifier(level) {
+        keystrokes.push(KeyboardData { code, make: false });
+    }
+    Ok(keystrokes)
+}
+
+/// Types `text` on `layout` by sending keystrokes to `send`, waiting `delay`
+/// between each one so that the guest does not drop input that arrives too
+/// quickly.
+///
+/// Nothing is sent if `text` contains a character that cannot be typed.
+pub async fn type_text(
+    send: &mesh::Sender<InputData>,
+    timer: &mut PolledTimer,
+    layout: KeyboardLayout,
+    text: &str,
+    delay: Duration,
+) -> Result<(), UnsupportedCharacter> {
+    for keystroke in text_to_keystrokes(layout, text)? {
+        send.send(InputData::Keyboard(keystroke));
+        timer.sleep(delay).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Formats keystrokes as `+code` for presses and `-code` for releases.
+    fn keys(layout: KeyboardLayout, text: &str) -> Vec<String> {
+        text_to_keystrokes(layout, text)
+            .unwrap()
+            .into_iter()
+            .map(|k| format!("{}{:x}", if k.make { '+' } else { '-' }, k.code))
+            .collect()
+    }
+
+    #[test]
+    fn shift_held_across_characters() {
+        assert_eq!(
+            keys(KeyboardLayout::EnUs, "aBC\n"),
+            [
+                "+1e", "-1e", "+2a", "+30", "-30", "+2e", "-2e", "-2a", "+1c", "-1c"
+            ]
+        );
+    }
+
+    #[test]
+    fn altgr_on_other_layouts() {
+        assert_eq!(
+            keys(KeyboardLayout::De, "A@"),
+            ["+2a", "+1e", "-1e", "-2a", "+e038", "+10", "-10", "-e038"]
+        );
+        // The same character is typed with shift on a US keyboard.
+        assert_eq!(keys(KeyboardLayout::EnUs, "@"), ["+2a", "+3", "-3", "-2a"]);
+    }
+
+    #[test]
+    fn dead_keys() {
+        // Circumflex, then e.
+        assert_eq!(keys(KeyboardLayout::Fr, "ê"), ["+1a", "-1a", "+12", "-12"]);
+    }
+
+    #[test]
+    fn unsupported_character() {
+        let err = text_to_keystrokes(KeyboardLayout::EnUs, "a€").unwrap_err();
+        assert_eq!(err.0, '€');
+        text_to_keystrokes(KeyboardLayout::De, "a€").unwrap();
+    }
+}
@@ -130,7 +130,8 @@ pub enum Subclass: u8 {
             BRIDGE_OTHER = 0x80,
 
             // Base System Peripheral (Class code: 0x08)
-            // Other values: 0x00 - 0x06
+            // Other values: 0x00 - 0x04, 0x06
+            BASE_SYSTEM_PERIPHERAL_SD_HOST_CONTROLLER = 0x05,
             BASE_SYSTEM_PERIPHERAL_OTHER = 0x80,
         }
     }
@@ -0,0 +1,521 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! The SD memory card state machine.
+//!
+//! The card is always presented as an SDHC card (CSD version 2.0), so block
+//! addressing is used and the block length is fixed at 512 bytes.
+
+use crate::spec;
+use crate::spec::CardStatus;
+use crate::spec::app_command;
+use crate::spec::command;
+use disk_backend::Disk;
+use inspect::Inspect;
+
+/// The relative card address assigned by SEND_RELATIVE_ADDR.
+const RCA: u16 = 0x4d53;
+
+/// The number of blocks in each unit of the CSD C_SIZE field (512KiB).
+pub(crate) const CAPACITY_UNIT_BLOCKS: u64 = 1024;
+
+/// The largest capacity that can be described by an SDHC/SDXC CSD.
+const MAX_CAPACITY_UNITS: u64 = 1 << 22;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Inspect)]
+pub(crate) enum CardState {
+    Idle,
+    Ready,
+    Ident,
+    Standby,
+    Transfer,
+    Data,
+    Receive,
+    Inactive,
+}
+
+impl CardState {
+    /// The CURRENT_STATE value reported in the card status.
+    fn status_value(&self) -> u8 {
+        match self {
+            CardState::Idle => 0,
+            CardState::Ready => 1,
+            CardState::Ident => 2,
+            CardState::Standby => 3,
+            CardState::Transfer => 4,
+            CardState::Data => 5,
+            CardState::Receive => 6,
+            // Inactive cards never respond, so this is never reported.
+            CardState::Inactive => 0,
+        }
+    }
+}
+
+/// The response to a command.
+pub(crate) enum Response {
+    None,
+    /// A 48-bit response (R1, R3, R6, R7).
+    Short(u32),
+    /// A 48-bit response with busy signaling on DAT0 (R1b).
+    Busy(u32),
+    /// A 136-bit response (R2), carrying a 128-bit card register.
+    Long(u128),
+}
+
+/// The data transfer requested by a command.
+pub(crate) enum DataPhase {
+    Read {
+        lba: u64,
+        multi: bool,
+    },
+    Write {
+        lba: u64,
+        multi: bool,
+    },
+    /// A card register (e.g. the SCR) is to be read.
+    Register(Vec<u8>),
+}
+
+/// The card did not respond to the command, either because it is illegal in
+/// the current state or because it is not supported.
+pub(crate) struct NoResponse;
+
+#[derive(Inspect)]
+pub(crate) struct Card {
+    disk: Disk,
+    /// The exposed capacity, in blocks.
+    capacity: u64,
+    read_only: bool,
+    state: CardState,
+    #[inspect(hex)]
+    rca: u16,
+    app_cmd: bool,
+    wide_bus: bool,
+    /// Error bits to report in the next R1 response.
+    #[inspect(debug)]
+    pending_status: CardStatus,
+}
+
+impl Card {
+    pub fn new(disk: Disk, read_only: bool) -> Self {
+        // Capacity is reported in 512KiB units, so any partial unit at the end
+        // of the disk is not accessible.
+        let units = (disk.sector_count() / CAPACITY_UNIT_BLOCKS).min(MAX_CAPACITY_UNITS);
+        Self {
+            capacity: units * CAPACITY_UNIT_BLOCKS,
+            read_only: read_only || disk.is_read_only(),
+            disk,
+            state: CardState::Idle,
+            rca: 0,
+            app_cmd: false,
+            wide_bus: false,
+            pending_status: CardStatus::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = CardState::Idle;
+        self.rca = 0;
+        self.app_cmd = false;
+        self.wide_bus = false;
+        self.pending_status = CardStatus::new();
+    }
+
+    pub fn disk(&self) -> &Disk {
+        &self.disk
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Completes a data transfer, returning the card to the transfer state.
+    pub fn end_data(&mut self) {
+        if matches!(self.state, CardState::Data | CardState::Receive) {
+            self.state = CardState::Transfer;
+        }
+    }
+
+    /// Reports an error that occurred during a data transfer in the next
+    /// status response.
+    pub fn set_out_of_range(&mut self) {
+        self.pending_status.set_out_of_range(true);
+    }
+
+    fn status(&mut self) -> u32 {
+        let status = std::mem::take(&mut self.pending_status)
+            .with_current_state(self.state.status_value())
+            .with_ready_for_data(self.state == CardState::Transfer)
+            .with_app_cmd(self.app_cmd);
+        status.into()
+    }
+
+    /// Runs a command, returning the response and any resulting data
+    /// transfer.
+    pub fn command(
+        &mut self,
+        index: u8,
+        arg: u32,
+    ) -> Result<(Response, Option<DataPhase>), NoResponse> {
+        if self.state == CardState::Inactive {
+            return Err(NoResponse);
+        }
+        if std::mem::take(&mut self.app_cmd) {
+            if let Some(r) = self.app_command(index, arg)? {
+                return Ok(r);
+            }
+            // Not an application command, so handle it as a regular command.
+        }
+        let response = match index {
+            command::GO_IDLE_STATE => {
+                self.reset();
+                Response::None
+            }
+            command::SEND_IF_COND => {
+                if self.state != CardState::Idle {
+                    return Err(NoResponse);
+                }
+                // Only the 2.7-3.6V range is accepted. Echo the voltage and
+                // check pattern.
+                if (arg >> 8) & 0xf != 1 {
+                    return Err(NoResponse);
+                }
+                Response::Short(arg & 0xfff)
+            }
+            command::ALL_SEND_CID => {
+                if self.state != CardState::Ready {
+                    return Err(NoResponse);
+                }
+                self.state = CardState::Ident;
+                Response::Long(self.cid())
+            }
+            command::SEND_RELATIVE_ADDR => {
+                if !matches!(self.state, CardState::Ident | CardState::Standby) {
+                    return Err(NoResponse);
+                }
+                self.state = CardState::Standby;
+                self.rca = RCA;
+                // R6: the RCA and a subset of the card status.
+                let status = self.status();
+                let status =
+                    (status & 0x1fff) | ((status >> 6) & 0x2000) | ((status >> 8) & 0xc000);
+                Response::Short((self.rca as u32) << 16 | status)
+            }
+            command::SELECT_CARD => {
+                if (arg >> 16) as u16 == self.rca {
+                    if self.state != CardState::Standby && self.state != CardState::Transfer {
+                        return Err(NoResponse);
+                    }
+                    self.state = CardState::Transfer;
+                    Response::Busy(self.status())
+                } else {
+                    // Deselected cards do not respond.
+                    if self.state == CardState::Transfer {
+                        self.state = CardState::Standby;
+                    }
+                    return Err(NoResponse);
+                }
+            }
+            command::SEND_CSD | command::SEND_CID => {
+                if self.state != CardState::Standby || (arg >> 16) as u16 != self.rca {
+                    return Err(NoResponse);
+                }
+                Response::Long(if index == command::SEND_CSD {
+                    self.csd()
+                } else {
+                    self.cid()
+                })
+            }
+            command::STOP_TRANSMISSION => {
+                if !matches!(self.state, CardState::Data | CardState::Receive) {
+                    return Err(NoResponse);
+                }
+                let status = self.status();
+                self.state = CardState::Transfer;
+                Response::Busy(status)
+            }
+            command::SEND_STATUS => {
+                if (arg >> 16) as u16 != self.rca {
+                    return Err(NoResponse);
+                }
+                Response::Short(self.status())
+            }
+            command::GO_INACTIVE_STATE => {
+                if (arg >> 16) as u16 == self.rca {
+                    self.state = CardState::Inactive;
+                }
+                Response::None
+            }
+            command::SET_BLOCKLEN => {
+                // The block length is fixed for SDHC cards; the argument is
+                // ignored.
+                if self.state != CardState::Transfer {
+                    return Err(NoResponse);
+                }
+                Response::Short(self.status())
+            }
+            command::READ_SINGLE_BLOCK
+            | command::READ_MULTIPLE_BLOCK
+            | command::WRITE_BLOCK
+            | command::WRITE_MULTIPLE_BLOCK => {
+                if self.state != CardState::Transfer {
+                    return Err(NoResponse);
+                }
+                let lba = arg.into();
+                let write = matches!(index, command::WRITE_BLOCK | command::WRITE_MULTIPLE_BLOCK);
+                let multi = matches!(
+                    index,
+                    command::READ_MULTIPLE_BLOCK | command::WRITE_MULTIPLE_BLOCK
+                );
+                if lba >= self.capacity {
+                    self.pending_status.set_out_of_range(true);
+                    return Ok((Response::Short(self.status()), None));
+                }
+                if write && self.read_only {
+                    self.pending_status.set_wp_violation(true);
+                    return Ok((Response::Short(self.status()), None));
+                }
+                let status = self.status();
+                let data = if write {
+                    self.state = CardState::Receive;
+                    DataPhase::Write { lba, multi }
+                } else {
+                    self.state = CardState::Data;
+                    DataPhase::Read { lba, multi }
+                };
+                return Ok((Response::Short(status), Some(data)));
+            }
+            command::APP_CMD => {
+                if (arg >> 16) as u16 != self.rca && self.state != CardState::Idle {
+                    return Err(NoResponse);
+                }
+                self.app_cmd = true;
+                Response::Short(self.status())
+            }
+            _ => {
+                tracelimit::warn_ratelimited!(index, arg, "unsupported sd command");
+                return Err(NoResponse);
+            }
+        };
+        Ok((response, None))
+    }
+
+    /// Runs an application-specific command, or returns `None` if `index` is
+    /// not one.
+    fn app_command(
+        &mut self,
+        index: u8,
+        arg: u32,
+    ) -> Result<Option<(Response, Option<DataPhase>)>, NoResponse> {
+        // The app command bit is reported in the response to the ACMD itself.
+        self.app_cmd = true;
+        let r = match index {
+            app_command::SD_SEND_OP_COND => {
+                if !matches!(self.state, CardState::Idle | CardState::Ready) {
+                    return Err(NoResponse);
+                }
+                // An argument with an empty voltage window is an inquiry, which
+                // does not start initialization.
+                let host_window = spec::Ocr::from(arg).voltage_window();
+                if host_window & spec::OCR_VOLTAGE_WINDOW != 0 {
+                    self.state = CardState::Ready;
+                }
+                let ocr = spec::Ocr::new()
+                    .with_voltage_window(spec::OCR_VOLTAGE_WINDOW)
+                    .with_ccs(true)
+                    .with_power_up_complete(self.state == CardState::Ready);
+                (Response::Short(ocr.into()), None)
+            }
+            app_command::SET_BUS_WIDTH => {
+                if self.state != CardState::Transfer {
+                    return Err(NoResponse);
+                }
+                self.wide_bus = arg & 3 == 2;
+                (Response::Short(self.status()), None)
+            }
+            app_command::SET_CLR_CARD_DETECT => {
+                if self.state != CardState::Transfer {
+                    return Err(NoResponse);
+                }
+                (Response::Short(self.status()), None)
+            }
+            app_command::SD_STATUS => {
+                if self.state != CardState::Transfer {
+                    return Err(NoResponse);
+                }
+                let status = self.status();
+                self.state = CardState::Data;
+                let mut sd_status = vec![0; 64];
+                sd_status[0] = if self.wide_bus { 0x80 } else { 0 };
+                (
+                    Response::Short(status),
+                    Some(DataPhase::Register(sd_status)),
+                )
+            }
+            app_command::SEND_SCR => {
+                if self.state != CardState::Transfer {
+                    return Err(NoResponse);
+                }
+                let status = self.status();
+                self.state = CardState::Data;
+                (
+                    Response::Short(status),
+                    Some(DataPhase::Register(SCR.to_vec())),
+                )
+            }
+            _ => {
+                self.app_cmd = false;
+                return Ok(None);
+            }
+        };
+        self.app_cmd = false;
+        Ok(Some(r))
+    }
+
+    /// Builds the card identification register.
+    fn cid(&self) -> u128 {
+        let manufacturer_id = 0u128;
+        let oem_id = u16::from_be_bytes(*b"MS") as u128;
+        let product_name = b"VSDHC"
+            .iter()
+            .fold(0u128, |acc, &c| (acc << 8) | c as u128);
+        let product_revision = 0x10u128;
+        let serial_number = 1u128;
+        manufacturer_id << 120
+            | oem_id << 104
+            | product_name << 64
+            | product_revision << 56
+            | serial_number << 24
+            | 1
+    }
+
+    /// Builds the card specific data register (version 2.0).
+    fn csd(&self) -> u128 {
+        let c_size = (self.capacity / CAPACITY_UNIT_BLOCKS - 1) as u128;
+        1u128 << 126 // CSD_STRUCTURE: version 2.0
+            | 0x0e << 112 // TAAC: 1ms
+            | 0x32 << 96 // TRAN_SPEED: 25MHz
+            | CCC << 84
+            | 9 << 80 // READ_BL_LEN: 512 bytes
+            | c_size << 48
+            | 1 << 46 // ERASE_BLK_EN
+            | 0x7f << 39 // SECTOR_SIZE
+            | 2 << 26 // R2W_FACTOR
+            | 9 << 22 // WRITE_BL_LEN: 512 bytes
+            | (self.read_only as u128) << 12 // TMP_WRITE_PROTECT
+            | 1
+    }
+}
+
+/// The supported command classes: basic, block read, block write, and
+/// application-specific.
+const CCC: u128 = 1 << 0 | 1 << 2 | 1 << 4 | 1 << 8;
+
+/// The SD configuration register: physical layer version 2.00, SDHC security,
+/// and 1- and 4-bit bus widths.
+const SCR: [u8; 8] = [0x02, 0x35, 0, 0, 0, 0, 0, 0];
+
+pub(crate) mod save_restore {
+    use super::*;
+
+    pub mod state {
+        use mesh::payload::Protobuf;
+
+        #[derive(Protobuf)]
+        #[mesh(package = "storage.sdhci")]
+        pub enum SavedCardState {
+            #[mesh(1)]
+            Idle,
+            #[mesh(2)]
+            Ready,
+            #[mesh(3)]
+            Ident,
+            #[mesh(4)]
+            Standby,
+            #[mesh(5)]
+            Transfer,
+            #[mesh(6)]
+            Data,
+            #[mesh(7)]
+            Receive,
+            #[mesh(8)]
+            Inactive,
+        }
+
+        #[derive(Protobuf)]
+        #[mesh(package = "storage.sdhci")]
+        pub struct SavedCard {
+            #[mesh(1)]
+            pub state: SavedCardState,
+            #[mesh(2)]
+            pub rca: u16,
+            #[mesh(3)]
+            pub app_cmd: bool,
+            #[mesh(4)]
+            pub wide_bus: bool,
+            #[mesh(5)]
+            pub pending_status: u32,
+        }
+    }
+
+    impl Card {
+        pub fn save(&self) -> state::SavedCard {
+            let Self {
+                disk: _,
+                capacity: _,
+                read_only: _,
+                state,
+                rca,
+                app_cmd,
+                wide_bus,
+                pending_status,
+            } = *self;
+
+            state::SavedCard {
+                state: match state {
+                    CardState::Idle => state::SavedCardState::Idle,
+                    CardState::Ready => state::SavedCardState::Ready,
+                    CardState::Ident => state::SavedCardState::Ident,
+                    CardState::Standby => state::SavedCardState::Standby,
+                    CardState::Transfer => state::SavedCardState::Transfer,
+                    CardState::Data => state::SavedCardState::Data,
+                    CardState::Receive => state::SavedCardState::Receive,
+                    CardState::Inactive => state::SavedCardState::Inactive,
+                },
+                rca,
+                app_cmd,
+                wide_bus,
+                pending_status: pending_status.into(),
+            }
+        }
+
+        pub fn restore(&mut self, state: state::SavedCard) {
+            let state::SavedCard {
+                state,
+                rca,
+                app_cmd,
+                wide_bus,
+                pending_status,
+            } = state;
+
+            self.state = match state {
+                state::SavedCardState::Idle => CardState::Idle,
+                state::SavedCardState::Ready => CardState::Ready,
+                state::SavedCardState::Ident => CardState::Ident,
+                state::SavedCardState::Standby => CardState::Standby,
+                state::SavedCardState::Transfer => CardState::Transfer,
+                state::SavedCardState::Data => CardState::Data,
+                state::SavedCardState::Receive => CardState::Receive,
+                state::SavedCardState::Inactive => CardState::Inactive,
+            };
+            self.rca = rca;
+            self.app_cmd = app_cmd;
+            self.wide_bus = wide_bus;
+            self.pending_status = pending_status.into();
+        }
+    }
+}
@@ -0,0 +1,1703 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Emulator for a PCI SD host controller (SDHCI), with a single slot holding
+//! an SD memory card backed by a [`Disk`].
+//!
+//! This is intended for guests (such as embedded OS images) that expect to
+//! boot from or mount an SD card, and is found by guest drivers through its
+//! PCI class code (SD host controller).
+//!
+//! Some notable limitations of the current implementation:
+//!
+//! - data is transferred through the buffer data port only (no SDMA or ADMA)
+//! - the card is always an SDHC card, so the disk's sector size must be 512
+//!   bytes, and any partial 512KiB unit at the end of the disk is not exposed
+//! - no support for hot-add/remove of the card
+//!
+//! As with the floppy controller, this implements a pragmatic subset of the
+//! specification: enough for common guest drivers to identify the card and
+//! read and write blocks.
+
+#![forbid(unsafe_code)]
+
+mod card;
+mod spec;
+
+use self::card::Card;
+use self::card::DataPhase;
+use self::card::Response;
+use chipset_device::ChipsetDevice;
+use chipset_device::io::IoError;
+use chipset_device::io::IoResult;
+use chipset_device::mmio::MmioIntercept;
+use chipset_device::mmio::RegisterMmioIntercept;
+use chipset_device::pci::PciConfigSpace;
+use chipset_device::poll_device::PollDevice;
+use disk_backend::Disk;
+use guestmem::AlignedHeapMemory;
+use guestmem::GuestMemory;
+use guestmem::ranges::PagedRange;
+use inspect::Inspect;
+use inspect::InspectMut;
+use pci_core::PciInterruptPin;
+use pci_core::cfg_space_emu::BarMemoryKind;
+use pci_core::cfg_space_emu::ConfigSpaceType0Emulator;
+use pci_core::cfg_space_emu::DeviceBars;
+use pci_core::cfg_space_emu::IntxInterrupt;
+use pci_core::spec::hwid::ClassCode;
+use pci_core::spec::hwid::HardwareIds;
+use pci_core::spec::hwid::ProgrammingInterface;
+use pci_core::spec::hwid::Subclass;
+use scsi_buffers::RequestBuffers;
+use spec::Register;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+use thiserror::Error;
+use vmcore::device_state::ChangeDeviceState;
+use vmcore::line_interrupt::LineInterrupt;
+
+/// The PCI configuration space offset of the slot information register.
+const PCI_SLOT_INFO: u16 = 0x40;
+
+/// The size of the bounce buffer used for disk IO.
+const BUFFER_BYTES: usize = 64 * 1024;
+
+/// The number of blocks that fit in the bounce buffer.
+const BUFFER_BLOCKS: u64 = (BUFFER_BYTES / spec::BLOCK_SIZE) as u64;
+
+const CAPABILITIES: spec::Capabilities = spec::Capabilities::new()
+    .with_timeout_clock_frequency(1)
+    .with_timeout_clock_unit_mhz(true)
+    .with_base_clock_frequency(50)
+    .with_high_speed(true)
+    .with_voltage_3_3(true);
+
+/// The normal interrupt status bit that summarizes the error interrupt status.
+const ERROR_INTERRUPT: spec::NormalInterrupt =
+    spec::NormalInterrupt::new().with_error_interrupt(true);
+
+/// The maximum current for 3.3V, in 4mA units.
+const MAX_CURRENT_3_3V: u32 = 200 / 4;
+
+/// The [`spec::PowerControl::bus_voltage`] value for 3.3V.
+const BUS_VOLTAGE_3_3V: u8 = 0b111;
+
+/// Errors returned by [`SdhciController::new`].
+#[derive(Debug, Error)]
+pub enum NewSdhciControllerError {
+    /// The disk's sector size is not supported.
+    #[error("unsupported sector size {0}, must be 512")]
+    UnsupportedSectorSize(u32),
+    /// The disk is too small to hold a single unit of SDHC capacity.
+    #[error("disk is too small ({0} bytes), must be at least 512KiB")]
+    TooSmall(u64),
+}
+
+/// An SD host controller with a single card slot.
+#[derive(InspectMut)]
+pub struct SdhciController {
+    cfg_space: ConfigSpaceType0Emulator,
+    #[inspect(skip)]
+    interrupt: Arc<IntxInterrupt>,
+
+    regs: Registers,
+    card: Card,
+    transfer: Option<Transfer>,
+
+    #[inspect(skip)]
+    buffer: Buffer,
+    #[inspect(with = "Option::is_some")]
+    io: Option<Io>,
+    #[inspect(skip)]
+    waker: Option<Waker>,
+}
+
+#[derive(Inspect, Default)]
+struct Registers {
+    #[inspect(hex)]
+    sdma_address: u32,
+    #[inspect(debug)]
+    block_size: spec::BlockSize,
+    block_count: u16,
+    #[inspect(hex)]
+    argument: u32,
+    #[inspect(debug)]
+    transfer_mode: spec::TransferMode,
+    #[inspect(debug)]
+    command: spec::Command,
+    #[inspect(with = "|x| inspect::iter_by_index(x).map_value(inspect::AsHex)")]
+    response: [u32; 4],
+    #[inspect(hex)]
+    host_control_1: u8,
+    #[inspect(debug)]
+    power_control: spec::PowerControl,
+    #[inspect(hex)]
+    block_gap_control: u8,
+    #[inspect(hex)]
+    wakeup_control: u8,
+    #[inspect(debug)]
+    clock_control: spec::ClockControl,
+    #[inspect(hex)]
+    timeout_control: u8,
+    #[inspect(debug)]
+    normal_status: spec::NormalInterrupt,
+    #[inspect(debug)]
+    error_status: spec::ErrorInterrupt,
+    #[inspect(debug)]
+    normal_status_enable: spec::NormalInterrupt,
+    #[inspect(debug)]
+    error_status_enable: spec::ErrorInterrupt,
+    #[inspect(debug)]
+    normal_signal_enable: spec::NormalInterrupt,
+    #[inspect(debug)]
+    error_signal_enable: spec::ErrorInterrupt,
+    #[inspect(hex)]
+    host_control_2: u16,
+}
+
+/// An in-progress data transfer.
+#[derive(Inspect)]
+struct Transfer {
+    write: bool,
+    /// Whether this is a multi-block transfer, which the card expects to be
+    /// ended with STOP_TRANSMISSION.
+    multi: bool,
+    block_len: usize,
+    /// The blocks left to transfer, or `None` if the transfer continues until
+    /// STOP_TRANSMISSION.
+    blocks_remaining: Option<u32>,
+    auto_cmd12: bool,
+    /// The next LBA to read from or write to the disk.
+    next_lba: u64,
+    /// The number of valid bytes in the buffer (reads) or the number of bytes
+    /// expected in the buffer (writes).
+    len: usize,
+    /// The guest's current offset within the buffer.
+    pos: usize,
+    /// Whether the guest can access the buffer data port.
+    buffer_ready: bool,
+}
+
+struct Buffer {
+    memory: GuestMemory,
+}
+
+impl Buffer {
+    fn new() -> Self {
+        Self {
+            memory: GuestMemory::new(
+                "sdhci_buffer",
+                Arc::new(AlignedHeapMemory::new(BUFFER_BYTES)),
+            ),
+        }
+    }
+
+    fn buffers(&self, len: usize, is_write: bool) -> RequestBuffers<'_> {
+        // The buffer is 16 4KB pages long.
+        static BUFFER_RANGE: Option<PagedRange<'_>> = PagedRange::new(
+            0,
+            BUFFER_BYTES,
+            &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+        );
+
+        RequestBuffers::new(
+            &self.memory,
+            BUFFER_RANGE.unwrap().subrange(0, len),
+            is_write,
+        )
+    }
+}
+
+struct Io(Pin<Box<dyn Send + Future<Output = Result<(), disk_backend::DiskError>>>>);
+
+impl SdhciController {
+    /// Creates a new SD host controller with a card backed by `disk`.
+    pub fn new(
+        disk: Disk,
+        read_only: bool,
+        interrupt: LineInterrupt,
+        register_mmio: &mut dyn RegisterMmioIntercept,
+    ) -> Result<Self, NewSdhciControllerError> {
+        if disk.sector_size() as usize != spec::BLOCK_SIZE {
+            return Err(NewSdhciControllerError::UnsupportedSectorSize(
+                disk.sector_size(),
+            ));
+        }
+        if disk.sector_count() < card::CAPACITY_UNIT_BLOCKS {
+            return Err(NewSdhciControllerError::TooSmall(
+                disk.sector_count() * spec::BLOCK_SIZE as u64,
+            ));
+        }
+
+        let bars = DeviceBars::new().bar0(
+            spec::REGISTER_SET_LEN,
+            BarMemoryKind::Intercept(register_mmio.new_io_region("bar0", spec::REGISTER_SET_LEN)),
+        );
+
+        let mut cfg_space = ConfigSpaceType0Emulator::new(
+            HardwareIds {
+                vendor_id: 0x1414,
+                device_id: 0x00ad,
+                revision_id: 0,
+                prog_if: ProgrammingInterface::NONE,
+                sub_class: Subclass::BASE_SYSTEM_PERIPHERAL_SD_HOST_CONTROLLER,
+                base_class: ClassCode::BASE_SYSTEM_PERIPHERAL,
+                type0_sub_vendor_id: 0,
+                type0_sub_system_id: 0,
+            },
+            Vec::new(),
+            bars,
+        );
+        let interrupt = cfg_space.set_interrupt_pin(PciInterruptPin::IntA, interrupt);
+
+        Ok(Self {
+            cfg_space,
+            interrupt,
+            regs: Registers::default(),
+            card: Card::new(disk, read_only),
+            transfer: None,
+            buffer: Buffer::new(),
+            io: None,
+            waker: None,
+        })
+    }
+
+    /// Sets the asynchronous IO to be polled in `poll_device`.
+    fn set_io<F, Fut>(&mut self, f: F)
+    where
+        F: FnOnce(Disk, GuestMemory) -> Fut,
+        Fut: 'static + Future<Output = Result<(), disk_backend::DiskError>> + Send,
+    {
+        let fut = (f)(self.card.disk().clone(), self.buffer.memory.clone());
+        assert!(self.io.is_none());
+        self.io = Some(Io(Box::pin(fut)));
+        // Ensure poll_device gets called again.
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn reset_all(&mut self) {
+        self.regs = Registers::default();
+        self.card.reset();
+        self.transfer = None;
+        self.update_interrupt();
+    }
+
+    fn reset_dat(&mut self) {
+        // Any in-flight IO is left to complete, but its result is discarded.
+        self.transfer = None;
+        self.regs.normal_status = self
+            .regs
+            .normal_status
+            .with_transfer_complete(false)
+            .with_buffer_read_ready(false)
+            .with_buffer_write_ready(false)
+            .with_block_gap_event(false)
+            .with_dma_interrupt(false);
+        self.card.end_data();
+    }
+
+    fn present_state(&self) -> spec::PresentState {
+        let transfer = self.transfer.as_ref();
+        let dat_busy = transfer.is_some() || self.io.is_some();
+        spec::PresentState::new()
+            .with_command_inhibit_dat(dat_busy)
+            .with_dat_line_active(dat_busy)
+            .with_write_transfer_active(transfer.is_some_and(|t| t.write))
+            .with_read_transfer_active(transfer.is_some_and(|t| !t.write))
+            .with_buffer_write_enable(transfer.is_some_and(|t| t.write && t.buffer_ready))
+            .with_buffer_read_enable(transfer.is_some_and(|t| !t.write && t.buffer_ready))
+            .with_card_inserted(true)
+            .with_card_state_stable(true)
+            .with_card_detect_pin_level(true)
+            .with_write_protect_pin_level(!self.card.is_read_only())
+            .with_dat_line_signal_level(0xf)
+            .with_cmd_line_signal_level(true)
+    }
+
+    fn raise(&mut self, status: spec::NormalInterrupt) {
+        let status = u16::from(status) & u16::from(self.regs.normal_status_enable);
+        self.regs.normal_status = (u16::from(self.regs.normal_status) | status).into();
+    }
+
+    fn raise_error(&mut self, error: spec::ErrorInterrupt) {
+        let error = u16::from(error) & u16::from(self.regs.error_status_enable);
+        self.regs.error_status = (u16::from(self.regs.error_status) | error).into();
+    }
+
+    fn update_interrupt(&mut self) {
+        let error = u16::from(self.regs.error_status) != 0;
+        self.regs.normal_status.set_error_interrupt(error);
+        self.interrupt.set_level(self.interrupt_pending());
+    }
+
+    fn issue_command(&mut self) {
+        let command = self.regs.command;
+        if command.data_present() && (self.transfer.is_some() || self.io.is_some()) {
+            tracelimit::warn_ratelimited!(
+                index = command.index(),
+                "data command issued while data lines are busy"
+            );
+            return;
+        }
+
+        let (response, data) = match self.card.command(command.index(), self.regs.argument) {
+            Ok(r) => r,
+            Err(card::NoResponse) => {
+                // Commands without a response complete without waiting for
+                // one.
+                if command.response_type() != spec::RESPONSE_TYPE_NONE {
+                    self.raise_error(spec::ErrorInterrupt::new().with_command_timeout(true));
+                } else {
+                    self.raise(spec::NormalInterrupt::new().with_command_complete(true));
+                }
+                return;
+            }
+        };
+
+        // The host decides how much of the response to latch, so its
+        // response type must match the length of the card's response.
+        let response_type = command.response_type();
+        match (response, response_type) {
+            (_, spec::RESPONSE_TYPE_NONE) => {}
+            (
+                Response::Short(r) | Response::Busy(r),
+                spec::RESPONSE_TYPE_48 | spec::RESPONSE_TYPE_48_BUSY,
+            ) => {
+                self.regs.response[0] = r;
+            }
+            (Response::Long(r), spec::RESPONSE_TYPE_136) => {
+                // The CRC byte is not stored.
+                let r = r >> 8;
+                for (i, v) in self.regs.response.iter_mut().enumerate() {
+                    *v = (r >> (32 * i)) as u32;
+                }
+            }
+            (Response::None, _) => {
+                self.raise_error(spec::ErrorInterrupt::new().with_command_timeout(true));
+                return;
+            }
+            (_, response_type) => {
+                tracelimit::warn_ratelimited!(
+                    index = command.index(),
+                    response_type,
+                    "response type does not match the command"
+                );
+                if data.is_some() {
+                    self.card.end_data();
+                }
+                self.raise_error(spec::ErrorInterrupt::new().with_command_end_bit(true));
+                return;
+            }
+        }
+        // Busy signaling is only waited for when the host asks for it.
+        let busy = response_type == spec::RESPONSE_TYPE_48_BUSY;
+        self.raise(spec::NormalInterrupt::new().with_command_complete(true));
+
+        if command.index() == spec::command::STOP_TRANSMISSION {
+            // Abandon any open-ended transfer. Buffered read data is dropped.
+            self.transfer = None;
+        }
+
+        if !command.data_present() {
+            // The host did not set up a data transfer, so the card's data
+            // phase will never happen.
+            if data.is_some() {
+                self.card.end_data();
+            }
+            if busy {
+                // There is no busy period to wait for.
+                self.raise(spec::NormalInterrupt::new().with_transfer_complete(true));
+            }
+            return;
+        }
+
+        let Some(data) = data else {
+            self.raise_error(spec::ErrorInterrupt::new().with_data_timeout(true));
+            return;
+        };
+
+        self.start_transfer(data);
+    }
+
+    fn start_transfer(&mut self, data: DataPhase) {
+        let mode = self.regs.transfer_mode;
+        if mode.dma_enable() {
+            tracelimit::warn_ratelimited!("dma transfers are not supported");
+            self.card.end_data();
+            self.raise_error(spec::ErrorInterrupt::new().with_data_timeout(true));
+            return;
+        }
+
+        let block_len = self.regs.block_size.block_size() as usize;
+        let (write, lba, multi) = match data {
+            DataPhase::Read { lba, multi } => (false, lba, multi),
+            DataPhase::Write { lba, multi } => (true, lba, multi),
+            DataPhase::Register(data) => {
+                let len = data.len();
+                if block_len != len {
+                    tracelimit::warn_ratelimited!(block_len, len, "unexpected register block size");
+                }
+                self.buffer.memory.write_at(0, &data).unwrap();
+                self.transfer = Some(Transfer {
+                    write: false,
+                    multi: false,
+                    block_len: len,
+                    blocks_remaining: Some(1),
+                    auto_cmd12: false,
+                    next_lba: 0,
+                    len,
+                    pos: 0,
+                    buffer_ready: true,
+                });
+                self.raise(spec::NormalInterrupt::new().with_buffer_read_ready(true));
+                return;
+            }
+        };
+
+        if block_len != spec::BLOCK_SIZE {
+            tracelimit::warn_ratelimited!(block_len, "unsupported block size");
+            self.card.end_data();
+            self.raise_error(spec::ErrorInterrupt::new().with_data_end_bit(true));
+            return;
+        }
+
+        let blocks_remaining = if !multi {
+            Some(1)
+        } else if mode.block_count_enable() {
+            Some(self.regs.block_count.into())
+        } else {
+            None
+        };
+
+        let mut transfer = Transfer {
+            write,
+            multi,
+            block_len,
+            blocks_remaining,
+            auto_cmd12: multi && mode.auto_cmd_enable() == spec::AUTO_CMD12,
+            next_lba: lba,
+            len: 0,
+            pos: 0,
+            buffer_ready: false,
+        };
+
+        if blocks_remaining == Some(0) {
+            self.transfer = Some(transfer);
+            self.complete_transfer();
+        } else if write {
+            transfer.len = block_len;
+            transfer.buffer_ready = true;
+            self.transfer = Some(transfer);
+            self.raise(spec::NormalInterrupt::new().with_buffer_write_ready(true));
+        } else {
+            self.transfer = Some(transfer);
+            self.start_read();
+        }
+    }
+
+    /// Starts reading the next chunk of blocks into the buffer.
+    fn start_read(&mut self) {
+        let capacity = self.card.capacity();
+        let transfer = self.transfer.as_mut().unwrap();
+        let lba = transfer.next_lba;
+        let count = BUFFER_BLOCKS
+            .min(transfer.blocks_remaining.map_or(u64::MAX, Into::into))
+            .min(capacity - lba);
+        if count == 0 {
+            self.fail_out_of_range();
+            return;
+        }
+        let len = count as usize * spec::BLOCK_SIZE;
+        transfer.len = len;
+        transfer.pos = 0;
+        transfer.next_lba += count;
+        self.set_io(async move |disk, memory| {
+            let buffer = Buffer { memory };
+            disk.read_vectored(&buffer.buffers(len, true), lba).await
+        });
+    }
+
+    /// Starts writing the buffered block to the disk.
+    fn start_write(&mut self) {
+        let transfer = self.transfer.as_mut().unwrap();
+        transfer.buffer_ready = false;
+        let lba = transfer.next_lba;
+        if lba >= self.card.capacity() {
+            self.fail_out_of_range();
+            return;
+        }
+        let len = transfer.len;
+        transfer.next_lba += 1;
+        self.set_io(async move |disk, memory| {
+            let buffer = Buffer { memory };
+            disk.write_vectored(&buffer.buffers(len, false), lba, false)
+                .await
+        });
+    }
+
+    fn fail_out_of_range(&mut self) {
+        self.transfer = None;
+        self.card.set_out_of_range();
+        self.card.end_data();
+        self.raise_error(spec::ErrorInterrupt::new().with_data_timeout(true));
+    }
+
+    fn handle_io_completion(&mut self, result: Result<(), disk_backend::DiskError>) {
+        let Some(transfer) = &mut self.transfer else {
+            // The transfer was stopped or reset while the IO was in flight.
+            return;
+        };
+
+        if let Err(err) = result {
+            tracelimit::error_ratelimited!(
+                error = &err as &dyn std::error::Error,
+                write = transfer.write,
+                "sd card io failed"
+            );
+            self.transfer = None;
+            self.card.end_data();
+            self.raise_error(spec::ErrorInterrupt::new().with_data_crc(true));
+            self.update_interrupt();
+            return;
+        }
+
+        if transfer.write {
+            self.block_done();
+        } else {
+            transfer.buffer_ready = true;
+            self.raise(spec::NormalInterrupt::new().with_buffer_read_ready(true));
+        }
+        self.update_interrupt();
+    }
+
+    /// Accounts for a block that has been fully transferred, continuing or
+    /// completing the transfer.
+    fn block_done(&mut self) {
+        let transfer = self.transfer.as_mut().unwrap();
+        if let Some(remaining) = &mut transfer.blocks_remaining {
+            *remaining -= 1;
+            if *remaining == 0 {
+                self.complete_transfer();
+                return;
+            }
+        }
+        if transfer.write {
+            transfer.pos = 0;
+            transfer.buffer_ready = true;
+            self.raise(spec::NormalInterrupt::new().with_buffer_write_ready(true));
+        } else if transfer.pos < transfer.len {
+            self.raise(spec::NormalInterrupt::new().with_buffer_read_ready(true));
+        } else {
+            transfer.buffer_ready = false;
+            self.start_read();
+        }
+    }
+
+    fn complete_transfer(&mut self) {
+        let transfer = self.transfer.take().unwrap();
+        if transfer.auto_cmd12 {
+            match self.card.command(spec::command::STOP_TRANSMISSION, 0) {
+                Ok((Response::Busy(r), _)) => self.regs.response[3] = r,
+                _ => {
+                    self.raise_error(spec::ErrorInterrupt::new().with_auto_cmd(true));
+                }
+            }
+        } else if !transfer.multi {
+            self.card.end_data();
+        }
+        self.raise(spec::NormalInterrupt::new().with_transfer_complete(true));
+    }
+
+    fn read_data(&mut self, data: &mut [u8]) {
+        data.fill(0);
+        let Some(transfer) = self
+            .transfer
+            .as_mut()
+            .filter(|t| !t.write && t.buffer_ready)
+        else {
+            tracelimit::warn_ratelimited!("buffer data port read with no data ready");
+            return;
+        };
+        let block_end = (transfer.pos / transfer.block_len + 1) * transfer.block_len;
+        let n = data.len().min(block_end.min(transfer.len) - transfer.pos);
+        self.buffer
+            .memory
+            .read_at(transfer.pos as u64, &mut data[..n])
+            .unwrap();
+        transfer.pos += n;
+        if transfer.pos == block_end.min(transfer.len) {
+            transfer.buffer_ready = transfer.pos < transfer.len;
+            self.block_done();
+        }
+    }
+
+    fn write_data(&mut self, data: &[u8]) {
+        let Some(transfer) = self.transfer.as_mut().filter(|t| t.write && t.buffer_ready) else {
+            tracelimit::warn_ratelimited!("buffer data port write with no space available");
+            return;
+        };
+        let n = data.len().min(transfer.len - transfer.pos);
+        self.buffer
+            .memory
+            .write_at(transfer.pos as u64, &data[..n])
+            .unwrap();
+        transfer.pos += n;
+        if transfer.pos == transfer.len {
+            self.start_write();
+        }
+    }
+
+    fn read_u32(&self, offset: u16) -> u32 {
+        let regs = &self.regs;
+        match Register(offset) {
+            Register::SDMA_ADDRESS => regs.sdma_address,
+            Register::BLOCK_SIZE => {
+                u16::from(regs.block_size) as u32 | (regs.block_count as u32) << 16
+            }
+            Register::ARGUMENT => regs.argument,
+            Register::TRANSFER_MODE => {
+                u16::from(regs.transfer_mode) as u32 | (u16::from(regs.command) as u32) << 16
+            }
+            Register::RESPONSE0 => regs.response[0],
+            Register::RESPONSE1 => regs.response[1],
+            Register::RESPONSE2 => regs.response[2],
+            Register::RESPONSE3 => regs.response[3],
+            Register::PRESENT_STATE => self.present_state().into(),
+            Register::HOST_CONTROL_1 => u32::from_le_bytes([
+                regs.host_control_1,
+                regs.power_control.into(),
+                regs.block_gap_control,
+                regs.wakeup_control,
+            ]),
+            Register::CLOCK_CONTROL => {
+                u16::from(regs.clock_control) as u32 | (regs.timeout_control as u32) << 16
+            }
+            Register::NORMAL_INTERRUPT_STATUS => {
+                u16::from(regs.normal_status) as u32 | (u16::from(regs.error_status) as u32) << 16
+            }
+            Register::NORMAL_INTERRUPT_STATUS_ENABLE => {
+                u16::from(regs.normal_status_enable) as u32
+                    | (u16::from(regs.error_status_enable) as u32) << 16
+            }
+            Register::NORMAL_INTERRUPT_SIGNAL_ENABLE => {
+                u16::from(regs.normal_signal_enable) as u32
+                    | (u16::from(regs.error_signal_enable) as u32) << 16
+            }
+            Register::AUTO_CMD_ERROR_STATUS => (regs.host_control_2 as u32) << 16,
+            Register::CAPABILITIES => u64::from(CAPABILITIES) as u32,
+            Register::CAPABILITIES_HIGH => (u64::from(CAPABILITIES) >> 32) as u32,
+            Register::MAX_CURRENT_CAPABILITIES => MAX_CURRENT_3_3V,
+            Register::SLOT_INTERRUPT_STATUS => {
+                let pending = self.interrupt_pending() as u32;
+                pending | (spec::HOST_CONTROLLER_VERSION_3_00 as u32) << 16
+            }
+            _ => 0,
+        }
+    }
+
+    fn interrupt_pending(&self) -> bool {
+        u16::from(self.regs.normal_status) & u16::from(self.regs.normal_signal_enable) != 0
+            || u16::from(self.regs.error_status) & u16::from(self.regs.error_signal_enable) != 0
+    }
+
+    /// Writes the bytes of `value` selected by `mask` to the dword register at
+    /// `offset`.
+    fn write_u32(&mut self, offset: u16, value: u32, mask: u32) {
+        let merge = |old: u32| (old & !mask) | (value & mask);
+        let regs = &mut self.regs;
+        match Register(offset) {
+            Register::SDMA_ADDRESS => regs.sdma_address = merge(regs.sdma_address),
+            Register::BLOCK_SIZE => {
+                let v = merge(self.read_u32(offset));
+                self.regs.block_size = (v as u16).into();
+                self.regs.block_count = (v >> 16) as u16;
+            }
+            Register::ARGUMENT => regs.argument = merge(regs.argument),
+            Register::TRANSFER_MODE => {
+                let v = merge(self.read_u32(offset));
+                self.regs.transfer_mode = (v as u16).into();
+                self.regs.command = ((v >> 16) as u16).into();
+                // Writing the upper byte of the command register issues the
+                // command.
+                if mask & 0xff00_0000 != 0 {
+                    self.issue_command();
+                }
+            }
+            Register::HOST_CONTROL_1 => {
+                let [
+                    host_control_1,
+                    power_control,
+                    block_gap_control,
+                    wakeup_control,
+                ] = merge(self.read_u32(offset)).to_le_bytes();
+                let mut power_control = spec::PowerControl::from(power_control);
+                if power_control.bus_voltage() != BUS_VOLTAGE_3_3V {
+                    power_control.set_bus_power(false);
+                }
+                self.regs.host_control_1 = host_control_1;
+                self.regs.power_control = power_control;
+                self.regs.block_gap_control = block_gap_control;
+                self.regs.wakeup_control = wakeup_control;
+            }
+            Register::CLOCK_CONTROL => {
+                let v = merge(self.read_u32(offset));
+                let mut clock_control = spec::ClockControl::from(v as u16);
+                // The clock stabilizes immediately.
+                clock_control.set_internal_clock_stable(clock_control.internal_clock_enable());
+                self.regs.clock_control = clock_control;
+                self.regs.timeout_control = (v >> 16) as u8;
+                let reset = spec::SoftwareReset::from(((value & mask) >> 24) as u8);
+                if reset.reset_all() {
+                    self.reset_all();
+                } else if reset.reset_dat() {
+                    self.reset_dat();
+                }
+            }
+            Register::NORMAL_INTERRUPT_STATUS => {
+                // Write 1 to clear.
+                let clear = value & mask;
+                regs.normal_status = (u16::from(regs.normal_status) & !(clear as u16)).into();
+                regs.error_status = (u16::from(regs.error_status) & !((clear >> 16) as u16)).into();
+            }
+            Register::NORMAL_INTERRUPT_STATUS_ENABLE => {
+                let v = merge(self.read_u32(offset));
+                // The error interrupt bit cannot be enabled separately.
+                let v = v & !(u16::from(ERROR_INTERRUPT) as u32);
+                self.regs.normal_status_enable = (v as u16).into();
+                self.regs.error_status_enable = ((v >> 16) as u16).into();
+                // Disabling a status bit clears it.
+                self.regs.normal_status = (u16::from(self.regs.normal_status) & v as u16).into();
+                self.regs.error_status =
+                    (u16::from(self.regs.error_status) & (v >> 16) as u16).into();
+            }
+            Register::NORMAL_INTERRUPT_SIGNAL_ENABLE => {
+                let v = merge(self.read_u32(offset)) & !(u16::from(ERROR_INTERRUPT) as u32);
+                self.regs.normal_signal_enable = (v as u16).into();
+                self.regs.error_signal_enable = ((v >> 16) as u16).into();
+            }
+            Register::AUTO_CMD_ERROR_STATUS => {
+                self.regs.host_control_2 = (merge(self.read_u32(offset)) >> 16) as u16;
+            }
+            _ => {
+                tracelimit::warn_ratelimited!(offset, value, mask, "unhandled register write");
+            }
+        }
+    }
+
+    fn read_bar0(&mut self, offset: u16, data: &mut [u8]) -> IoResult {
+        if Register(offset & !3) == Register::BUFFER_DATA_PORT {
+            self.read_data(data);
+            self.update_interrupt();
+            return IoResult::Ok;
+        }
+        for (i, b) in data.iter_mut().enumerate() {
+            let offset = offset.wrapping_add(i as u16);
+            *b = (self.read_u32(offset & !3) >> ((offset & 3) * 8)) as u8;
+        }
+        IoResult::Ok
+    }
+
+    fn write_bar0(&mut self, offset: u16, data: &[u8]) -> IoResult {
+        if Register(offset & !3) == Register::BUFFER_DATA_PORT {
+            self.write_data(data);
+            self.update_interrupt();
+            return IoResult::Ok;
+        }
+        let end = offset as usize + data.len();
+        let mut dword = offset as usize & !3;
+        while dword < end {
+            let mut value = 0;
+            let mut mask = 0;
+            for byte in dword.max(offset as usize)..(dword + 4).min(end) {
+                let shift = (byte - dword) * 8;
+                value |= (data[byte - offset as usize] as u32) << shift;
+                mask |= 0xff << shift;
+            }
+            self.write_u32(dword as u16, value, mask);
+            dword += 4;
+        }
+        self.update_interrupt();
+        IoResult::Ok
+    }
+}
+
+impl ChangeDeviceState for SdhciController {
+    fn start(&mut self) {}
+
+    async fn stop(&mut self) {
+        // Complete any in-flight IO so that its result is part of the saved
+        // state.
+        if let Some(io) = self.io.take() {
+            let result = io.0.await;
+            self.handle_io_completion(result);
+        }
+    }
+
+    async fn reset(&mut self) {
+        if let Some(io) = self.io.take() {
+            // Let any in-flight write land before the guest can observe the
+            // reset.
+            let _ = io.0.await;
+        }
+        self.cfg_space.reset();
+        self.reset_all();
+    }
+}
+
+impl ChipsetDevice for SdhciController {
+    fn supports_mmio(&mut self) -> Option<&mut dyn MmioIntercept> {
+        Some(self)
+    }
+
+    fn supports_pci(&mut self) -> Option<&mut dyn PciConfigSpace> {
+        Some(self)
+    }
+
+    fn supports_poll_device(&mut self) -> Option<&mut dyn PollDevice> {
+        Some(self)
+    }
+}
+
+impl PollDevice for SdhciController {
+    fn poll_device(&mut self, cx: &mut Context<'_>) {
+        if let Some(io) = self.io.as_mut() {
+            if let Poll::Ready(result) = io.0.as_mut().poll(cx) {
+                self.io = None;
+                self.handle_io_completion(result);
+            }
+        }
+        self.waker = Some(cx.waker().clone());
+    }
+}
+
+impl MmioIntercept for SdhciController {
+    fn mmio_read(&mut self, addr: u64, data: &mut [u8]) -> IoResult {
+        match self.cfg_space.find_bar(addr) {
+            Some((0, offset)) => self.read_bar0(offset, data),
+            _ => IoResult::Err(IoError::InvalidRegister),
+        }
+    }
+
+    fn mmio_write(&mut self, addr: u64, data: &[u8]) -> IoResult {
+        match self.cfg_space.find_bar(addr) {
+            Some((0, offset)) => self.write_bar0(offset, data),
+            _ => IoResult::Err(IoError::InvalidRegister),
+        }
+    }
+}
+
+impl PciConfigSpace for SdhciController {
+    fn pci_cfg_read(&mut self, offset: u16, value: &mut u32) -> IoResult {
+        if offset == PCI_SLOT_INFO {
+            // One slot, whose registers are at BAR 0.
+            *value = 0;
+            return IoResult::Ok;
+        }
+        self.cfg_space.read_u32(offset, value)
+    }
+
+    fn pci_cfg_write(&mut self, offset: u16, value: u32) -> IoResult {
+        if offset == PCI_SLOT_INFO {
+            return IoResult::Ok;
+        }
+        self.cfg_space.write_u32(offset, value)
+    }
+}
+
+mod save_restore {
+    use super::*;
+    use vmcore::save_restore::RestoreError;
+    use vmcore::save_restore::SaveError;
+    use vmcore::save_restore::SaveRestore;
+
+    mod state {
+        use crate::card::save_restore::state::SavedCard;
+        use mesh::payload::Protobuf;
+        use pci_core::cfg_space_emu::ConfigSpaceType0Emulator;
+        use vmcore::save_restore::SaveRestore;
+        use vmcore::save_restore::SavedStateRoot;
+
+        #[derive(Protobuf)]
+        #[mesh(package = "storage.sdhci")]
+        pub struct SavedRegisters {
+            #[mesh(1)]
+            pub sdma_address: u32,
+            #[mesh(2)]
+            pub block_size: u16,
+            #[mesh(3)]
+            pub block_count: u16,
+            #[mesh(4)]
+            pub argument: u32,
+            #[mesh(5)]
+            pub transfer_mode: u16,
+            #[mesh(6)]
+            pub command: u16,
+            #[mesh(7)]
+            pub response: [u32; 4],
+            #[mesh(8)]
+            pub host_control_1: u8,
+            #[mesh(9)]
+            pub power_control: u8,
+            #[mesh(10)]
+            pub block_gap_control: u8,
+            #[mesh(11)]
+            pub wakeup_control: u8,
+            #[mesh(12)]
+            pub clock_control: u16,
+            #[mesh(13)]
+            pub timeout_control: u8,
+            #[mesh(14)]
+            pub normal_status: u16,
+            #[mesh(15)]
+            pub error_status: u16,
+            #[mesh(16)]
+            pub normal_status_enable: u16,
+            #[mesh(17)]
+            pub error_status_enable: u16,
+            #[mesh(18)]
+            pub normal_signal_enable: u16,
+            #[mesh(19)]
+            pub error_signal_enable: u16,
+            #[mesh(20)]
+            pub host_control_2: u16,
+        }
+
+        #[derive(Protobuf)]
+        #[mesh(package = "storage.sdhci")]
+        pub struct SavedTransfer {
+            #[mesh(1)]
+            pub write: bool,
+            #[mesh(2)]
+            pub multi: bool,
+            #[mesh(3)]
+            pub block_len: u32,
+            #[mesh(4)]
+            pub blocks_remaining: Option<u32>,
+            #[mesh(5)]
+            pub auto_cmd12: bool,
+            #[mesh(6)]
+            pub next_lba: u64,
+            #[mesh(7)]
+            pub pos: u32,
+            #[mesh(8)]
+            pub buffer_ready: bool,
+            /// The buffered data, whose length is the transfer's buffer
+            /// length.
+            #[mesh(9)]
+            pub data: Vec<u8>,
+        }
+
+        #[derive(Protobuf, SavedStateRoot)]
+        #[mesh(package = "storage.sdhci")]
+        pub struct SavedState {
+            #[mesh(1)]
+            pub cfg_space: <ConfigSpaceType0Emulator as SaveRestore>::SavedState,
+            #[mesh(2)]
+            pub regs: SavedRegisters,
+            #[mesh(3)]
+            pub card: SavedCard,
+            #[mesh(4)]
+            pub transfer: Option<SavedTransfer>,
+        }
+    }
+
+    #[derive(Debug, Error)]
+    enum SdhciRestoreError {
+        #[error("invalid transfer length {len} at position {pos}")]
+        InvalidTransfer { len: usize, pos: usize },
+        #[error("invalid block length {0}")]
+        InvalidBlockLength(u32),
+    }
+
+    impl SaveRestore for SdhciController {
+        type SavedState = state::SavedState;
+
+        fn save(&mut self) -> Result<Self::SavedState, SaveError> {
+            // In-flight IO is completed as part of stop.
+            assert!(self.io.is_none());
+
+            let Registers {
+                sdma_address,
+                block_size,
+                block_count,
+                argument,
+                transfer_mode,
+                command,
+                response,
+                host_control_1,
+                power_control,
+                block_gap_control,
+                wakeup_control,
+                clock_control,
+                timeout_control,
+                normal_status,
+                error_status,
+                normal_status_enable,
+                error_status_enable,
+                normal_signal_enable,
+                error_signal_enable,
+                host_control_2,
+            } = self.regs;
+
+            let regs = state::SavedRegisters {
+                sdma_address,
+                block_size: block_size.into(),
+                block_count,
+                argument,
+                transfer_mode: transfer_mode.into(),
+                command: command.into(),
+                response,
+                host_control_1,
+                power_control: power_control.into(),
+                block_gap_control,
+                wakeup_control,
+                clock_control: clock_control.into(),
+                timeout_control,
+                normal_status: normal_status.into(),
+                error_status: error_status.into(),
+                normal_status_enable: normal_status_enable.into(),
+                error_status_enable: error_status_enable.into(),
+                normal_signal_enable: normal_signal_enable.into(),
+                error_signal_enable: error_signal_enable.into(),
+                host_control_2,
+            };
+
+            let transfer = self.transfer.as_ref().map(|transfer| {
+                let &Transfer {
+                    write,
+                    multi,
+                    block_len,
+                    blocks_remaining,
+                    auto_cmd12,
+                    next_lba,
+                    len,
+                    pos,
+                    buffer_ready,
+                } = transfer;
+
+                let mut data = vec![0; len];
+                self.buffer.memory.read_at(0, &mut data).unwrap();
+                state::SavedTransfer {
+                    write,
+                    multi,
+                    block_len: block_len as u32,
+                    blocks_remaining,
+                    auto_cmd12,
+                    next_lba,
+                    pos: pos as u32,
+                    buffer_ready,
+                    data,
+                }
+            });
+
+            Ok(state::SavedState {
+                cfg_space: self.cfg_space.save()?,
+                regs,
+                card: self.card.save(),
+                transfer,
+            })
+        }
+
+        fn restore(&mut self, state: Self::SavedState) -> Result<(), RestoreError> {
+            let state::SavedState {
+                cfg_space,
+                regs:
+                    state::SavedRegisters {
+                        sdma_address,
+                        block_size,
+                        block_count,
+                        argument,
+                        transfer_mode,
+                        command,
+                        response,
+                        host_control_1,
+                        power_control,
+                        block_gap_control,
+                        wakeup_control,
+                        clock_control,
+                        timeout_control,
+                        normal_status,
+                        error_status,
+                        normal_status_enable,
+                        error_status_enable,
+                        normal_signal_enable,
+                        error_signal_enable,
+                        host_control_2,
+                    },
+                card,
+                transfer,
+            } = state;
+
+            let transfer = transfer
+                .map(|transfer| {
+                    let state::SavedTransfer {
+                        write,
+                        multi,
+                        block_len,
+                        blocks_remaining,
+                        auto_cmd12,
+                        next_lba,
+                        pos,
+                        buffer_ready,
+                        data,
+                    } = transfer;
+
+                    let (len, pos) = (data.len(), pos as usize);
+                    if len > BUFFER_BYTES || pos > len {
+                        return Err(SdhciRestoreError::InvalidTransfer { len, pos });
+                    }
+                    if block_len == 0 || block_len as usize > spec::BLOCK_SIZE {
+                        return Err(SdhciRestoreError::InvalidBlockLength(block_len));
+                    }
+                    self.buffer.memory.write_at(0, &data).unwrap();
+                    Ok(Transfer {
+                        write,
+                        multi,
+                        block_len: block_len as usize,
+                        blocks_remaining,
+                        auto_cmd12,
+                        next_lba,
+                        len,
+                        pos,
+                        buffer_ready,
+                    })
+                })
+                .transpose()
+                .map_err(|err| RestoreError::InvalidSavedState(err.into()))?;
+
+            self.cfg_space.restore(cfg_space)?;
+            self.regs = Registers {
+                sdma_address,
+                block_size: block_size.into(),
+                block_count,
+                argument,
+                transfer_mode: transfer_mode.into(),
+                command: command.into(),
+                response,
+                host_control_1,
+                power_control: power_control.into(),
+                block_gap_control,
+                wakeup_control,
+                clock_control: clock_control.into(),
+                timeout_control,
+                normal_status: normal_status.into(),
+                error_status: error_status.into(),
+                normal_status_enable: normal_status_enable.into(),
+                error_status_enable: error_status_enable.into(),
+                normal_signal_enable: normal_signal_enable.into(),
+                error_signal_enable: error_signal_enable.into(),
+                host_control_2,
+            };
+            self.card.restore(card);
+            self.transfer = transfer;
+            self.update_interrupt();
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chipset_device::mmio::ExternallyManagedMmioIntercepts;
+    use pal_async::async_test;
+    use std::future::poll_fn;
+    use vmcore::save_restore::SaveRestore;
+
+    /// The RAM disk size, in blocks: two capacity units plus a partial one.
+    const DISK_BLOCKS: u64 = 2 * card::CAPACITY_UNIT_BLOCKS + 7;
+
+    const RCA: u32 = 0x4d53;
+
+    fn new_controller(disk: Disk) -> SdhciController {
+        SdhciController::new(
+            disk,
+            false,
+            LineInterrupt::detached(),
+            &mut ExternallyManagedMmioIntercepts,
+        )
+        .unwrap()
+    }
+
+    fn ram_disk() -> Disk {
+        disklayer_ram::ram_disk(DISK_BLOCKS * spec::BLOCK_SIZE as u64, false).unwrap()
+    }
+
+    fn read32(sdhci: &mut SdhciController, reg: Register) -> u32 {
+        let mut data = [0; 4];
+        sdhci.read_bar0(reg.0, &mut data).unwrap();
+        u32::from_le_bytes(data)
+    }
+
+    fn read16(sdhci: &mut SdhciController, reg: Register) -> u16 {
+        let mut data = [0; 2];
+        sdhci.read_bar0(reg.0, &mut data).unwrap();
+        u16::from_le_bytes(data)
+    }
+
+    fn write32(sdhci: &mut SdhciController, reg: Register, value: u32) {
+        sdhci.write_bar0(reg.0, &value.to_le_bytes()).unwrap();
+    }
+
+    fn write16(sdhci: &mut SdhciController, reg: Register, value: u16) {
+        sdhci.write_bar0(reg.0, &value.to_le_bytes()).unwrap();
+    }
+
+    fn normal_status(sdhci: &mut SdhciController) -> spec::NormalInterrupt {
+        read16(sdhci, Register::NORMAL_INTERRUPT_STATUS).into()
+    }
+
+    fn error_status(sdhci: &mut SdhciController) -> spec::ErrorInterrupt {
+        read16(sdhci, Register::ERROR_INTERRUPT_STATUS).into()
+    }
+
+    /// Acknowledges all pending status bits.
+    fn clear_status(sdhci: &mut SdhciController) {
+        write32(sdhci, Register::NORMAL_INTERRUPT_STATUS, !0);
+    }
+
+    /// Issues a command without data, returning the first response register.
+    fn command(sdhci: &mut SdhciController, index: u8, arg: u32, response_type: u8) -> u32 {
+        clear_status(sdhci);
+        write32(sdhci, Register::ARGUMENT, arg);
+        write16(
+            sdhci,
+            Register::COMMAND,
+            spec::Command::new()
+                .with_index(index)
+                .with_response_type(response_type)
+                .into(),
+        );
+        assert!(
+            normal_status(sdhci).command_complete(),
+            "command {index} failed: {:?}",
+            error_status(sdhci)
+        );
+        read32(sdhci, Register::RESPONSE0)
+    }
+
+    /// Issues a data command for `count` blocks.
+    fn data_command(sdhci: &mut SdhciController, index: u8, lba: u32, count: u16, read: bool) {
+        clear_status(sdhci);
+        write16(
+            sdhci,
+            Register::BLOCK_SIZE,
+            spec::BlockSize::new()
+                .with_block_size(spec::BLOCK_SIZE as u16)
+                .into(),
+        );
+        write16(sdhci, Register::BLOCK_COUNT, count);
+        write32(sdhci, Register::ARGUMENT, lba);
+        write16(
+            sdhci,
+            Register::TRANSFER_MODE,
+            spec::TransferMode::new()
+                .with_data_transfer_direction_read(read)
+                .with_multi_block(count > 1)
+                .with_block_count_enable(count > 1)
+                .with_auto_cmd_enable(if count > 1 { spec::AUTO_CMD12 } else { 0 })
+                .into(),
+        );
+        write16(
+            sdhci,
+            Register::COMMAND,
+            spec::Command::new()
+                .with_index(index)
+                .with_response_type(spec::RESPONSE_TYPE_48)
+                .with_data_present(true)
+                .into(),
+        );
+        assert!(normal_status(sdhci).command_complete());
+    }
+
+    /// Polls the controller until `f` returns true for its status.
+    async fn wait_for(
+        sdhci: &mut SdhciController,
+        mut f: impl FnMut(spec::NormalInterrupt) -> bool,
+    ) {
+        poll_fn(|cx| {
+            sdhci.poll_device(cx);
+            if f(normal_status(sdhci)) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    async fn read_blocks(sdhci: &mut SdhciController, lba: u32, count: u16) -> Vec<u8> {
+        let index = if count > 1 {
+            spec::command::READ_MULTIPLE_BLOCK
+        } else {
+            spec::command::READ_SINGLE_BLOCK
+        };
+        data_command(sdhci, index, lba, count, true);
+        let mut data = Vec::new();
+        for _ in 0..count {
+            wait_for(sdhci, |s| s.buffer_read_ready()).await;
+            write16(
+                sdhci,
+                Register::NORMAL_INTERRUPT_STATUS,
+                spec::NormalInterrupt::new()
+                    .with_buffer_read_ready(true)
+                    .into(),
+            );
+            for _ in 0..spec::BLOCK_SIZE / 4 {
+                data.extend(read32(sdhci, Register::BUFFER_DATA_PORT).to_le_bytes());
+            }
+        }
+        wait_for(sdhci, |s| s.transfer_complete()).await;
+        data
+    }
+
+    async fn write_blocks(sdhci: &mut SdhciController, lba: u32, data: &[u8]) {
+        let count = (data.len() / spec::BLOCK_SIZE) as u16;
+        let index = if count > 1 {
+            spec::command::WRITE_MULTIPLE_BLOCK
+        } else {
+            spec::command::WRITE_BLOCK
+        };
+        data_command(sdhci, index, lba, count, false);
+        for block in data.chunks(spec::BLOCK_SIZE) {
+            wait_for(sdhci, |s| s.buffer_write_ready()).await;
+            write16(
+                sdhci,
+                Register::NORMAL_INTERRUPT_STATUS,
+                spec::NormalInterrupt::new()
+                    .with_buffer_write_ready(true)
+                    .into(),
+            );
+            for dword in block.chunks(4) {
+                write32(
+                    sdhci,
+                    Register::BUFFER_DATA_PORT,
+                    u32::from_le_bytes(dword.try_into().unwrap()),
+                );
+            }
+        }
+        wait_for(sdhci, |s| s.transfer_complete()).await;
+    }
+
+    /// Powers up the controller and brings the card to the transfer state.
+    fn init_card(sdhci: &mut SdhciController) {
+        write32(sdhci, Register::NORMAL_INTERRUPT_STATUS_ENABLE, !0);
+        write16(
+            sdhci,
+            Register::CLOCK_CONTROL,
+            spec::ClockControl::new()
+                .with_internal_clock_enable(true)
+                .with_sd_clock_enable(true)
+                .into(),
+        );
+        command(
+            sdhci,
+            spec::command::GO_IDLE_STATE,
+            0,
+            spec::RESPONSE_TYPE_NONE,
+        );
+        assert_eq!(
+            command(
+                sdhci,
+                spec::command::SEND_IF_COND,
+                0x1aa,
+                spec::RESPONSE_TYPE_48
+            ),
+            0x1aa
+        );
+        command(sdhci, spec::command::APP_CMD, 0, spec::RESPONSE_TYPE_48);
+        let ocr = spec::Ocr::from(command(
+            sdhci,
+            spec::app_command::SD_SEND_OP_COND,
+            0x40ff8000,
+            spec::RESPONSE_TYPE_48,
+        ));
+        assert!(ocr.power_up_complete() && ocr.ccs());
+        command(
+            sdhci,
+            spec::command::ALL_SEND_CID,
+            0,
+            spec::RESPONSE_TYPE_136,
+        );
+        let rca = command(
+            sdhci,
+            spec::command::SEND_RELATIVE_ADDR,
+            0,
+            spec::RESPONSE_TYPE_48,
+        ) >> 16;
+        assert_eq!(rca, RCA);
+        command(
+            sdhci,
+            spec::command::SELECT_CARD,
+            RCA << 16,
+            spec::RESPONSE_TYPE_48_BUSY,
+        );
+        clear_status(sdhci);
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+    }
+
+    #[test]
+    fn registers() {
+        let mut sdhci = new_controller(ram_disk());
+
+        assert_eq!(
+            read32(&mut sdhci, Register::CAPABILITIES),
+            u64::from(CAPABILITIES) as u32
+        );
+        assert_eq!(
+            read16(&mut sdhci, Register::HOST_CONTROLLER_VERSION),
+            spec::HOST_CONTROLLER_VERSION_3_00
+        );
+        let present = spec::PresentState::from(read32(&mut sdhci, Register::PRESENT_STATE));
+        assert!(present.card_inserted() && present.write_protect_pin_level());
+        assert!(!present.command_inhibit_dat());
+
+        // The internal clock stabilizes as soon as it is enabled.
+        write16(
+            &mut sdhci,
+            Register::CLOCK_CONTROL,
+            spec::ClockControl::new()
+                .with_internal_clock_enable(true)
+                .into(),
+        );
+        let clock = spec::ClockControl::from(read16(&mut sdhci, Register::CLOCK_CONTROL));
+        assert!(clock.internal_clock_stable());
+
+        // Power is only applied at 3.3V.
+        sdhci
+            .write_bar0(Register::POWER_CONTROL.0, &[0b1011])
+            .unwrap();
+        let power = spec::PowerControl::from(read32(&mut sdhci, Register::HOST_CONTROL_1) as u8);
+        assert!(!power.bus_power());
+        sdhci
+            .write_bar0(Register::POWER_CONTROL.0, &[0b1111])
+            .unwrap();
+        let power =
+            spec::PowerControl::from((read32(&mut sdhci, Register::HOST_CONTROL_1) >> 8) as u8);
+        assert!(power.bus_power());
+
+        // Status bits are only latched while enabled, and are write-1-to-clear.
+        write16(
+            &mut sdhci,
+            Register::COMMAND,
+            spec::Command::new()
+                .with_index(spec::command::GO_IDLE_STATE)
+                .into(),
+        );
+        assert!(!normal_status(&mut sdhci).command_complete());
+        write32(
+            &mut sdhci,
+            Register::NORMAL_INTERRUPT_STATUS_ENABLE,
+            u16::from(spec::NormalInterrupt::new().with_command_complete(true)).into(),
+        );
+        write16(
+            &mut sdhci,
+            Register::COMMAND,
+            spec::Command::new()
+                .with_index(spec::command::GO_IDLE_STATE)
+                .into(),
+        );
+        assert!(normal_status(&mut sdhci).command_complete());
+        assert!(!sdhci.interrupt_pending());
+        write32(
+            &mut sdhci,
+            Register::NORMAL_INTERRUPT_SIGNAL_ENABLE,
+            u16::from(spec::NormalInterrupt::new().with_command_complete(true)).into(),
+        );
+        assert!(sdhci.interrupt_pending());
+        assert_eq!(read32(&mut sdhci, Register::SLOT_INTERRUPT_STATUS) & 1, 1);
+        clear_status(&mut sdhci);
+        assert_eq!(u16::from(normal_status(&mut sdhci)), 0);
+        assert!(!sdhci.interrupt_pending());
+
+        // Reset all clears the registers.
+        sdhci
+            .write_bar0(
+                Register::SOFTWARE_RESET.0,
+                &[spec::SoftwareReset::new().with_reset_all(true).into()],
+            )
+            .unwrap();
+        assert_eq!(
+            read32(&mut sdhci, Register::NORMAL_INTERRUPT_SIGNAL_ENABLE),
+            0
+        );
+        assert_eq!(read16(&mut sdhci, Register::CLOCK_CONTROL), 0);
+    }
+
+    #[test]
+    fn commands() {
+        let mut sdhci = new_controller(ram_disk());
+        init_card(&mut sdhci);
+
+        // The CSD can only be read in the standby state.
+        command(
+            &mut sdhci,
+            spec::command::SELECT_CARD,
+            0,
+            spec::RESPONSE_TYPE_NONE,
+        );
+        command(
+            &mut sdhci,
+            spec::command::SEND_CSD,
+            RCA << 16,
+            spec::RESPONSE_TYPE_136,
+        );
+        // C_SIZE is in bits 48..70 of the CSD, which is stored without its CRC
+        // byte.
+        let c_size = (read32(&mut sdhci, Register::RESPONSE1) >> 8) & 0x3f_ffff;
+        assert_eq!(
+            u64::from(c_size) + 1,
+            DISK_BLOCKS / card::CAPACITY_UNIT_BLOCKS
+        );
+
+        // A response type that does not match the card's response fails.
+        clear_status(&mut sdhci);
+        write32(&mut sdhci, Register::ARGUMENT, RCA << 16);
+        write16(
+            &mut sdhci,
+            Register::COMMAND,
+            spec::Command::new()
+                .with_index(spec::command::SEND_CID)
+                .with_response_type(spec::RESPONSE_TYPE_48)
+                .into(),
+        );
+        assert!(!normal_status(&mut sdhci).command_complete());
+        assert!(error_status(&mut sdhci).command_end_bit());
+        assert!(normal_status(&mut sdhci).error_interrupt());
+
+        // A command the card does not answer times out.
+        clear_status(&mut sdhci);
+        write32(&mut sdhci, Register::ARGUMENT, 0);
+        write16(
+            &mut sdhci,
+            Register::COMMAND,
+            spec::Command::new()
+                .with_index(spec::command::SEND_IF_COND)
+                .with_response_type(spec::RESPONSE_TYPE_48)
+                .into(),
+        );
+        assert!(error_status(&mut sdhci).command_timeout());
+        clear_status(&mut sdhci);
+        assert!(!normal_status(&mut sdhci).error_interrupt());
+
+        // Back in the transfer state, the card status reports it.
+        command(
+            &mut sdhci,
+            spec::command::SELECT_CARD,
+            RCA << 16,
+            spec::RESPONSE_TYPE_48_BUSY,
+        );
+        let status = spec::CardStatus::from(command(
+            &mut sdhci,
+            spec::command::SEND_STATUS,
+            RCA << 16,
+            spec::RESPONSE_TYPE_48,
+        ));
+        assert_eq!(status.current_state(), 4);
+        assert!(status.ready_for_data());
+    }
+
+    #[async_test]
+    async fn pio_transfers() {
+        let disk = ram_disk();
+        let mut sdhci = new_controller(disk.clone());
+        init_card(&mut sdhci);
+
+        let data = pattern(spec::BLOCK_SIZE, 1);
+        write_blocks(&mut sdhci, 3, &data).await;
+        assert_eq!(read_blocks(&mut sdhci, 3, 1).await, data);
+
+        // Multi-block transfers end with Auto CMD12.
+        let data = pattern(spec::BLOCK_SIZE * 3, 2);
+        write_blocks(&mut sdhci, 10, &data).await;
+        assert_eq!(read_blocks(&mut sdhci, 9, 5).await[512..2048], data);
+        let status = spec::CardStatus::from(read32(&mut sdhci, Register::RESPONSE3));
+        // The Auto CMD12 response reports the card's state when the read
+        // was stopped.
+        assert_eq!(status.current_state(), 5);
+
+        // The data reached the disk.
+        let mut sdhci = new_controller(disk);
+        init_card(&mut sdhci);
+        assert_eq!(read_blocks(&mut sdhci, 11, 1).await, data[512..1024]);
+
+        // Reads past the exposed capacity fail.
+        let capacity = (DISK_BLOCKS / card::CAPACITY_UNIT_BLOCKS) * card::CAPACITY_UNIT_BLOCKS;
+        data_command(
+            &mut sdhci,
+            spec::command::READ_MULTIPLE_BLOCK,
+            capacity as u32 - 1,
+            2,
+            true,
+        );
+        wait_for(&mut sdhci, |s| s.buffer_read_ready()).await;
+        for _ in 0..spec::BLOCK_SIZE / 4 {
+            read32(&mut sdhci, Register::BUFFER_DATA_PORT);
+        }
+        wait_for(&mut sdhci, |s| s.error_interrupt()).await;
+        assert!(error_status(&mut sdhci).data_timeout());
+        let status = spec::CardStatus::from(command(
+            &mut sdhci,
+            spec::command::SEND_STATUS,
+            RCA << 16,
+            spec::RESPONSE_TYPE_48,
+        ));
+        assert!(status.out_of_range());
+    }
+
+    #[async_test]
+    async fn save_restore_mid_transfer() {
+        let disk = ram_disk();
+        let mut sdhci = new_controller(disk.clone());
+        init_card(&mut sdhci);
+
+        let data = pattern(spec::BLOCK_SIZE * 2, 3);
+        write_blocks(&mut sdhci, 20, &data).await;
+
+        // Read half of the first block, then move to a new controller.
+        data_command(&mut sdhci, spec::command::READ_MULTIPLE_BLOCK, 20, 2, true);
+        wait_for(&mut sdhci, |s| s.buffer_read_ready()).await;
+        clear_status(&mut sdhci);
+        let mut read = Vec::new();
+        for _ in 0..spec::BLOCK_SIZE / 8 {
+            read.extend(read32(&mut sdhci, Register::BUFFER_DATA_PORT).to_le_bytes());
+        }
+        sdhci.stop().await;
+        let state = sdhci.save().unwrap();
+
+        let mut sdhci = new_controller(disk);
+        sdhci.restore(state).unwrap();
+        assert!(
+            spec::PresentState::from(read32(&mut sdhci, Register::PRESENT_STATE))
+                .buffer_read_enable()
+        );
+        for _ in 0..spec::BLOCK_SIZE / 8 {
+            read.extend(read32(&mut sdhci, Register::BUFFER_DATA_PORT).to_le_bytes());
+        }
+        wait_for(&mut sdhci, |s| s.buffer_read_ready()).await;
+        for _ in 0..spec::BLOCK_SIZE / 4 {
+            read.extend(read32(&mut sdhci, Register::BUFFER_DATA_PORT).to_le_bytes());
+        }
+        wait_for(&mut sdhci, |s| s.transfer_complete()).await;
+        assert_eq!(read, data);
+
+        // The card is back in the transfer state.
+        let status = spec::CardStatus::from(command(
+            &mut sdhci,
+            spec::command::SEND_STATUS,
+            RCA << 16,
+            spec::RESPONSE_TYPE_48,
+        ));
+        assert_eq!(status.current_state(), 4);
+    }
+}
@@ -0,0 +1,308 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Definitions from the SD Host Controller Simplified Specification (version
+//! 3.00) and the SD Physical Layer Simplified Specification.
+
+use bitfield_struct::bitfield;
+use open_enum::open_enum;
+
+/// The size of the host controller register set.
+pub const REGISTER_SET_LEN: u64 = 0x100;
+
+open_enum! {
+    /// Host controller register offsets.
+    pub enum Register: u16 {
+        SDMA_ADDRESS = 0x00,
+        BLOCK_SIZE = 0x04,
+        BLOCK_COUNT = 0x06,
+        ARGUMENT = 0x08,
+        TRANSFER_MODE = 0x0c,
+        COMMAND = 0x0e,
+        RESPONSE0 = 0x10,
+        RESPONSE1 = 0x14,
+        RESPONSE2 = 0x18,
+        RESPONSE3 = 0x1c,
+        BUFFER_DATA_PORT = 0x20,
+        PRESENT_STATE = 0x24,
+        HOST_CONTROL_1 = 0x28,
+        POWER_CONTROL = 0x29,
+        BLOCK_GAP_CONTROL = 0x2a,
+        WAKEUP_CONTROL = 0x2b,
+        CLOCK_CONTROL = 0x2c,
+        TIMEOUT_CONTROL = 0x2e,
+        SOFTWARE_RESET = 0x2f,
+        NORMAL_INTERRUPT_STATUS = 0x30,
+        ERROR_INTERRUPT_STATUS = 0x32,
+        NORMAL_INTERRUPT_STATUS_ENABLE = 0x34,
+        ERROR_INTERRUPT_STATUS_ENABLE = 0x36,
+        NORMAL_INTERRUPT_SIGNAL_ENABLE = 0x38,
+        ERROR_INTERRUPT_SIGNAL_ENABLE = 0x3a,
+        AUTO_CMD_ERROR_STATUS = 0x3c,
+        HOST_CONTROL_2 = 0x3e,
+        CAPABILITIES = 0x40,
+        CAPABILITIES_HIGH = 0x44,
+        MAX_CURRENT_CAPABILITIES = 0x48,
+        FORCE_EVENT_AUTO_CMD_ERROR = 0x50,
+        FORCE_EVENT_ERROR_INTERRUPT = 0x52,
+        ADMA_ERROR_STATUS = 0x54,
+        ADMA_ADDRESS = 0x58,
+        ADMA_ADDRESS_HIGH = 0x5c,
+        SLOT_INTERRUPT_STATUS = 0xfc,
+        HOST_CONTROLLER_VERSION = 0xfe,
+    }
+}
+
+/// Specification version 3.00.
+pub const HOST_CONTROLLER_VERSION_3_00: u16 = 0x0002;
+
+#[bitfield(u16)]
+pub struct BlockSize {
+    #[bits(12)]
+    pub block_size: u16,
+    #[bits(3)]
+    pub sdma_buffer_boundary: u8,
+    _reserved: bool,
+}
+
+#[bitfield(u16)]
+pub struct TransferMode {
+    pub dma_enable: bool,
+    pub block_count_enable: bool,
+    #[bits(2)]
+    pub auto_cmd_enable: u8,
+    /// Set for reads (card to host).
+    pub data_transfer_direction_read: bool,
+    pub multi_block: bool,
+    #[bits(10)]
+    _reserved: u16,
+}
+
+/// [`TransferMode::auto_cmd_enable`] value for Auto CMD12.
+pub const AUTO_CMD12: u8 = 0b01;
+
+#[bitfield(u16)]
+pub struct Command {
+    #[bits(2)]
+    pub response_type: u8,
+    _reserved: bool,
+    pub crc_check_enable: bool,
+    pub index_check_enable: bool,
+    pub data_present: bool,
+    #[bits(2)]
+    pub command_type: u8,
+    #[bits(6)]
+    pub index: u8,
+    #[bits(2)]
+    _reserved2: u8,
+}
+
+pub const RESPONSE_TYPE_NONE: u8 = 0b00;
+pub const RESPONSE_TYPE_136: u8 = 0b01;
+pub const RESPONSE_TYPE_48: u8 = 0b10;
+pub const RESPONSE_TYPE_48_BUSY: u8 = 0b11;
+
+#[bitfield(u32)]
+pub struct PresentState {
+    pub command_inhibit_cmd: bool,
+    pub command_inhibit_dat: bool,
+    pub dat_line_active: bool,
+    #[bits(5)]
+    _reserved: u8,
+    pub write_transfer_active: bool,
+    pub read_transfer_active: bool,
+    pub buffer_write_enable: bool,
+    pub buffer_read_enable: bool,
+    #[bits(4)]
+    _reserved2: u8,
+    pub card_inserted: bool,
+    pub card_state_stable: bool,
+    pub card_detect_pin_level: bool,
+    /// Set if the card is writable.
+    pub write_protect_pin_level: bool,
+    #[bits(4)]
+    pub dat_line_signal_level: u8,
+    pub cmd_line_signal_level: bool,
+    #[bits(7)]
+    _reserved3: u8,
+}
+
+#[bitfield(u8)]
+pub struct PowerControl {
+    pub bus_power: bool,
+    #[bits(3)]
+    pub bus_voltage: u8,
+    #[bits(4)]
+    _reserved: u8,
+}
+
+#[bitfield(u16)]
+pub struct ClockControl {
+    pub internal_clock_enable: bool,
+    pub internal_clock_stable: bool,
+    pub sd_clock_enable: bool,
+    _reserved: bool,
+    _reserved2: bool,
+    pub clock_generator_select: bool,
+    #[bits(2)]
+    pub upper_frequency_select: u8,
+    pub frequency_select: u8,
+}
+
+#[bitfield(u8)]
+pub struct SoftwareReset {
+    pub reset_all: bool,
+    pub reset_cmd: bool,
+    pub reset_dat: bool,
+    #[bits(5)]
+    _reserved: u8,
+}
+
+#[bitfield(u16)]
+pub struct NormalInterrupt {
+    pub command_complete: bool,
+    pub transfer_complete: bool,
+    pub block_gap_event: bool,
+    pub dma_interrupt: bool,
+    pub buffer_write_ready: bool,
+    pub buffer_read_ready: bool,
+    pub card_insertion: bool,
+    pub card_removal: bool,
+    pub card_interrupt: bool,
+    #[bits(6)]
+    _reserved: u8,
+    /// Set in the status register if any error interrupt status bit is set.
+    pub error_interrupt: bool,
+}
+
+#[bitfield(u16)]
+pub struct ErrorInterrupt {
+    pub command_timeout: bool,
+    pub command_crc: bool,
+    pub command_end_bit: bool,
+    pub command_index: bool,
+    pub data_timeout: bool,
+    pub data_crc: bool,
+    pub data_end_bit: bool,
+    pub current_limit: bool,
+    pub auto_cmd: bool,
+    pub adma: bool,
+    #[bits(6)]
+    _reserved: u8,
+}
+
+#[bitfield(u64)]
+pub struct Capabilities {
+    #[bits(6)]
+    pub timeout_clock_frequency: u8,
+    _reserved: bool,
+    /// Set if the timeout clock frequency is in MHz rather than kHz.
+    pub timeout_clock_unit_mhz: bool,
+    /// In MHz.
+    pub base_clock_frequency: u8,
+    #[bits(2)]
+    pub max_block_length: u8,
+    pub embedded_8bit: bool,
+    pub adma2: bool,
+    _reserved2: bool,
+    pub high_speed: bool,
+    pub sdma: bool,
+    pub suspend_resume: bool,
+    pub voltage_3_3: bool,
+    pub voltage_3_0: bool,
+    pub voltage_1_8: bool,
+    _reserved3: bool,
+    pub system_bus_64bit: bool,
+    pub async_interrupt: bool,
+    #[bits(2)]
+    pub slot_type: u8,
+    #[bits(32)]
+    _high: u32,
+}
+
+/// SD memory card commands.
+pub mod command {
+    pub const GO_IDLE_STATE: u8 = 0;
+    pub const ALL_SEND_CID: u8 = 2;
+    pub const SEND_RELATIVE_ADDR: u8 = 3;
+    pub const SELECT_CARD: u8 = 7;
+    pub const SEND_IF_COND: u8 = 8;
+    pub const SEND_CSD: u8 = 9;
+    pub const SEND_CID: u8 = 10;
+    pub const STOP_TRANSMISSION: u8 = 12;
+    pub const SEND_STATUS: u8 = 13;
+    pub const GO_INACTIVE_STATE: u8 = 15;
+    pub const SET_BLOCKLEN: u8 = 16;
+    pub const READ_SINGLE_BLOCK: u8 = 17;
+    pub const READ_MULTIPLE_BLOCK: u8 = 18;
+    pub const WRITE_BLOCK: u8 = 24;
+    pub const WRITE_MULTIPLE_BLOCK: u8 = 25;
+    pub const APP_CMD: u8 = 55;
+}
+
+/// SD memory card application-specific commands (preceded by APP_CMD).
+pub mod app_command {
+    pub const SET_BUS_WIDTH: u8 = 6;
+    pub const SD_STATUS: u8 = 13;
+    pub const SD_SEND_OP_COND: u8 = 41;
+    pub const SET_CLR_CARD_DETECT: u8 = 42;
+    pub const SEND_SCR: u8 = 51;
+}
+
+/// The card status returned in R1 responses.
+#[bitfield(u32)]
+pub struct CardStatus {
+    #[bits(3)]
+    _reserved: u8,
+    pub ake_seq_error: bool,
+    _reserved2: bool,
+    pub app_cmd: bool,
+    #[bits(2)]
+    _reserved3: u8,
+    pub ready_for_data: bool,
+    #[bits(4)]
+    pub current_state: u8,
+    pub erase_reset: bool,
+    pub card_ecc_disabled: bool,
+    pub wp_erase_skip: bool,
+    pub csd_overwrite: bool,
+    #[bits(2)]
+    _reserved4: u8,
+    pub error: bool,
+    pub cc_error: bool,
+    pub card_ecc_failed: bool,
+    pub illegal_command: bool,
+    pub com_crc_error: bool,
+    pub lock_unlock_failed: bool,
+    pub card_is_locked: bool,
+    pub wp_violation: bool,
+    pub erase_param: bool,
+    pub erase_seq_error: bool,
+    pub block_len_error: bool,
+    pub address_error: bool,
+    pub out_of_range: bool,
+}
+
+/// The operating conditions register, returned by SD_SEND_OP_COND.
+#[bitfield(u32)]
+pub struct Ocr {
+    #[bits(15)]
+    _reserved: u16,
+    /// The supported voltage window, 2.7-3.6V.
+    #[bits(9)]
+    pub voltage_window: u16,
+    pub switching_to_1_8v_accepted: bool,
+    #[bits(4)]
+    _reserved2: u8,
+    pub uhs2_card_status: bool,
+    /// Card capacity status: set for SDHC/SDXC cards.
+    pub ccs: bool,
+    /// Cleared while the card is still powering up.
+    pub power_up_complete: bool,
+}
+
+/// All voltages from 2.7V to 3.6V.
+pub const OCR_VOLTAGE_WINDOW: u16 = 0x1ff;
+
+/// The block size of SDHC/SDXC cards.
+pub const BLOCK_SIZE: usize = 512;
@@ -0,0 +1,20 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Client definitions for describing SD host controller configuration.
+
+#![forbid(unsafe_code)]
+
+use mesh::MeshPayload;
+use vm_resource::Resource;
+use vm_resource::kind::DiskHandleKind;
+
+/// The configuration for an SD card, each of which is attached to its own SD
+/// host controller.
+#[derive(Debug, MeshPayload)]
+pub struct SdhciDiskConfig {
+    /// The backing disk media.
+    pub disk_type: Resource<DiskHandleKind>,
+    /// Whether the card is write-protected.
+    pub read_only: bool,
+}
@@ -9,6 +9,8 @@
 use futures::StreamExt;
 use input_core::InputSource;
 use input_core::KeyboardData;
+use input_core::lock_keys::LockKeySync;
+use input_core::lock_keys::LockKeys;
 use mesh::payload::Protobuf;
 use std::io::IoSlice;
 use std::pin::pin;
@@ -32,10 +34,18 @@
 use zerocopy::IntoBytes;
 use zerocopy::KnownLayout;
 
+/// An event for the keyboard to handle while the channel is active.
+enum Event {
+    /// Input from the user.
+    Input(KeyboardData),
+    /// The guest set its LEDs to show this lock state.
+    Leds(LockKeys),
+}
+
 #[derive(Debug)]
 enum Request {
     ProtocolRequest(u32),
-    SetLedIndicators,
+    SetLedIndicators(u16),
 }
 
 #[derive(Debug, Error)]
@@ -66,17 +76,43 @@ async fn recv_packet(reader: &mut impl AsyncRecv) -> Result<Request, Error> {
             Request::ProtocolRequest(message.version)
         }
         protocol::MESSAGE_SET_LED_INDICATORS => {
-            // We don't have any actual LEDs to set, so check the message for validity but ignore its contents.
-            let _message = protocol::MessageLedIndicatorsState::read_from_prefix(buf)
+            let message = protocol::MessageLedIndicatorsState::read_from_prefix(buf)
                 .map_err(|_| Error::BadPacket)?
                 .0; // TODO: zerocopy: map_err (https://github.com/microsoft/openvmm/issues/759)
-            Request::SetLedIndicators
+            Request::SetLedIndicators(message.led_flags)
         }
         typ => return Err(Error::UnknownMessageType(typ)),
     };
     Ok(request)
 }
 
+/// Sends a keystroke to the guest.
+async fn send_keystroke(writer: &mut impl AsyncSend, input: KeyboardData) -> Result<(), Error> {
+    let mut flags = 0;
+    match input.code >> 8 {
+        0xe0 => {
+            flags |= protocol::KEYSTROKE_IS_E0;
+        }
+        0xe1 => {
+            flags |= protocol::KEYSTROKE_IS_E1;
+        }
+        _ => (),
+    }
+    if !input.make {
+        flags |= protocol::KEYSTROKE_IS_BREAK;
+    }
+    send_packet(
+        writer,
+        protocol::MESSAGE_EVENT,
+        &protocol::MessageKeystroke {
+            make_code: input.code & 0x7f,
+            padding: 0,
+            flags,
+        },
+    )
+    .await
+}
+
 async fn send_packet<T: IntoBytes + Immutable + KnownLayout>(
     writer: &mut impl AsyncSend,
     typ: u32,
@@ -92,15 +128,29 @@ async fn send_packet<T: IntoBytes + Immutable + KnownLayout>(
     Ok(())
 }
 
+/// Returns the lock state shown by the guest's LEDs.
+fn lock_keys_from_leds(led_flags: u16) -> LockKeys {
+    LockKeys {
+        num_lock: led_flags & protocol::LED_NUM_LOCK != 0,
+        caps_lock: led_flags & protocol::LED_CAPS_LOCK != 0,
+    }
+}
+
 /// A vmbus synthetic keyboard.
 pub struct Keyboard {
     source: Box<dyn InputSource<KeyboardData>>,
+    lock_keys: LockKeySync,
 }
 
 impl Keyboard {
-    /// Creates a new keyboard.
-    pub fn new(source: Box<dyn InputSource<KeyboardData>>) -> Self {
-        Self { source }
+    /// Creates a new keyboard, which brings the guest's lock keys to
+    /// `lock_keys` whenever the guest opens the channel and after restore, if
+    /// set.
+    pub fn new(source: Box<dyn InputSource<KeyboardData>>, lock_keys: Option<LockKeys>) -> Self {
+        Self {
+            source,
+            lock_keys: LockKeySync::new(lock_keys),
+        }
     }
 
     /// Extracts the keyboard input source.
@@ -129,6 +179,7 @@ fn open(
         _guest_memory: guestmem::GuestMemory,
     ) -> Result<Self::Runner, ChannelOpenError> {
         let pipe = MessagePipe::new_raw(channel)?;
+        self.lock_keys.arm();
         Ok(KeyboardChannel::new(pipe, ChannelState::default()))
     }
 
@@ -175,7 +226,10 @@ fn supports_save_restore(
 
 impl SaveRestoreSimpleVmbusDevice for Keyboard {
     fn save_open(&mut self, runner: &Self::Runner) -> Self::SavedState {
-        SavedState(runner.state.clone())
+        SavedState {
+            channel: runner.state.clone(),
+            led_flags: runner.led_flags,
+        }
     }
 
     fn restore_open(
@@ -183,20 +237,41 @@ fn restore_open(
         state: Self::SavedState,
         channel: RawAsyncChannel<GpadlRingMem>,
     ) -> Result<Self::Runner, ChannelOpenError> {
+        let SavedState {
+            channel: state,
+            led_flags,
+        } = state;
         let pipe = MessagePipe::new_raw(channel)?;
-        Ok(KeyboardChannel::new(pipe, state.0))
+        let mut runner = KeyboardChannel::new(pipe, state);
+        runner.led_flags = led_flags;
+        self.lock_keys.arm();
+        if let Some(led_flags) = led_flags {
+            runner.lock_keystrokes = self.lock_keys.keystrokes(lock_keys_from_leds(led_flags));
+        }
+        Ok(runner)
     }
 }
 
 /// Keyboard saved state.
 #[derive(Protobuf, SavedStateRoot)]
 #[mesh(package = "ui.synthkbd")]
-pub struct SavedState(#[mesh(1)] ChannelState);
+pub struct SavedState {
+    #[mesh(1)]
+    channel: ChannelState,
+    /// The LEDs last set by the guest.
+    #[mesh(2)]
+    led_flags: Option<u16>,
+}
 
 /// The keyboard task.
 pub struct KeyboardChannel<T: RingMem = GpadlRingMem> {
     channel: MessagePipe<T>,
     state: ChannelState,
+    /// The LEDs last set by the guest, if it has set them.
+    led_flags: Option<u16>,
+    /// Keystrokes to send before any input, to bring the guest's lock keys to
+    /// the configured state.
+    lock_keystrokes: Vec<KeyboardData>,
 }
 
 #[derive(Debug, Clone, Protobuf)]
@@ -224,11 +299,18 @@ fn default() -> Self {
 
 impl<T: RingMem + Unpin> KeyboardChannel<T> {
     fn new(channel: MessagePipe<T>, state: ChannelState) -> Self {
-        Self { channel, state }
+        Self {
+            channel,
+            state,
+            led_flags: None,
+            lock_keystrokes: Vec::new(),
+        }
     }
 
     async fn process(&mut self, keyboard: &mut Keyboard) -> Result<(), Error> {
         let (mut recv, mut send) = MessagePipe::split(&mut self.channel);
+        let led_flags = &mut self.led_flags;
+        let lock_keystrokes = &mut self.lock_keystrokes;
         loop {
             match self.state {
                 ChannelState::ReadVersion => {
@@ -258,31 +340,32 @@ async fn process(&mut self, keyboard: &mut Keyboard) -> Result<(), Error> {
                 }
                 ChannelState::Active { version: _ } => loop {
                     keyboard.source.set_active(true).await;
+                    let Keyboard { source, lock_keys } = &mut *keyboard;
+                    // The guest's LED changes are handled along with the
+                    // user's input, since both can send keystrokes.
+                    let (leds_send, leds_recv) = mesh::channel();
                     let send_fut = pin!(async {
-                        while let Some(input) = keyboard.source.next().await {
-                            let mut flags = 0;
-                            match input.code >> 8 {
-                                0xe0 => {
-                                    flags |= protocol::KEYSTROKE_IS_E0;
+                        for keystroke in lock_keystrokes.drain(..) {
+                            send_keystroke(&mut send, keystroke).await?;
+                        }
+                        let mut events = futures::stream::select(
+                            source.map(Event::Input),
+                            leds_recv.map(Event::Leds),
+                        );
+                        while let Some(event) = events.next().await {
+                            match event {
+                                Event::Input(input) => {
+                                    if input.make {
+                                        lock_keys.disarm();
+                                    }
+                                    send_keystroke(&mut send, input).await?;
                                 }
-                                0xe1 => {
-                                    flags |= protocol::KEYSTROKE_IS_E1;
+                                Event::Leds(current) => {
+                                    for keystroke in lock_keys.keystrokes(current) {
+                                        send_keystroke(&mut send, keystroke).await?;
+                                    }
                                 }
-                                _ => (),
-                            }
-                            if !input.make {
-                                flags |= protocol::KEYSTROKE_IS_BREAK;
                             }
-                            send_packet(
-                                &mut send,
-                                protocol::MESSAGE_EVENT,
-                                &protocol::MessageKeystroke {
-                                    make_code: input.code & 0x7f,
-                                    padding: 0,
-                                    flags,
-                                },
-                            )
-                            .await?;
                         }
                         Ok(())
                     });
@@ -290,7 +373,10 @@ async fn process(&mut self, keyboard: &mut Keyboard) -> Result<(), Error> {
                     let recv_fut = pin!(async {
                         loop {
                             match recv_packet(&mut recv).await? {
-                                Request::SetLedIndicators => (),
+                                Request::SetLedIndicators(flags) => {
+                                    *led_flags = Some(flags);
+                                    leds_send.send(lock_keys_from_leds(flags));
+                                }
                                 _ => return Err(Error::UnexpectedPacketOrder),
                             }
                         }
@@ -366,7 +452,7 @@ fn start_worker<T: RingMem + 'static + Unpin + Send + Sync>(
     async fn test_channel_working(driver: DefaultDriver) {
         let (host, mut guest) = connected_raw_message_pipes(16384);
         let (source, mut sink) = input_pair();
-        let worker = start_worker(&driver, Keyboard::new(Box::new(source)), host);
+        let worker = start_worker(&driver, Keyboard::new(Box::new(source), None), host);
 
         send_packet(
             &mut guest,
@@ -413,11 +499,93 @@ async fn test_channel_working(driver: DefaultDriver) {
         worker.await.unwrap()
     }
 
+    #[async_test]
+    async fn test_lock_keys(driver: DefaultDriver) {
+        let (host, mut guest) = connected_raw_message_pipes(16384);
+        let (source, mut sink) = input_pair();
+        let lock_keys = LockKeys {
+            num_lock: true,
+            caps_lock: false,
+        };
+        let worker = start_worker(
+            &driver,
+            Keyboard::new(Box::new(source), Some(lock_keys)),
+            host,
+        );
+
+        send_packet(
+            &mut guest,
+            protocol::MESSAGE_PROTOCOL_REQUEST,
+            &protocol::MessageProtocolRequest {
+                version: protocol::VERSION_WIN8,
+            },
+        )
+        .await
+        .unwrap();
+        match recv_packet(&mut guest).await.unwrap() {
+            Packet::ProtocolResponse(protocol::MessageProtocolResponse { accepted: 1 }) => (),
+            p => panic!("unexpected {:?}", p),
+        }
+
+        async fn set_leds(guest: &mut (impl AsyncSend + Unpin), led_flags: u16) {
+            send_packet(
+                guest,
+                protocol::MESSAGE_SET_LED_INDICATORS,
+                &protocol::MessageLedIndicatorsState {
+                    led_flags,
+                    padding: 0,
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        async fn expect_keys(
+            guest: &mut (dyn AsyncRecv + Unpin + Send + Sync),
+            keys: &[(u16, bool)],
+        ) {
+            for &key in keys {
+                match recv_packet(guest).await.unwrap() {
+                    Packet::Event(protocol::MessageKeystroke {
+                        make_code, flags, ..
+                    }) => {
+                        assert_eq!((make_code, flags & protocol::KEYSTROKE_IS_BREAK == 0), key);
+                    }
+                    p => panic!("unexpected {:?}", p),
+                }
+            }
+        }
+
+        // The guest turns on CapsLock, so NumLock and CapsLock are pressed.
+        set_leds(&mut guest, protocol::LED_CAPS_LOCK).await;
+        expect_keys(
+            &mut guest,
+            &[(0x45, true), (0x45, false), (0x3a, true), (0x3a, false)],
+        )
+        .await;
+
+        // Once the user types, the lock keys are theirs.
+        sink.send(KeyboardData {
+            code: 0x1e,
+            make: true,
+        });
+        expect_keys(&mut guest, &[(0x1e, true)]).await;
+        set_leds(&mut guest, 0).await;
+        sink.send(KeyboardData {
+            code: 0x1e,
+            make: false,
+        });
+        expect_keys(&mut guest, &[(0x1e, false)]).await;
+
+        drop(guest);
+        worker.await.unwrap()
+    }
+
     #[async_test]
     async fn test_channel_negotiation_failed(driver: DefaultDriver) {
         let (host, mut guest) = connected_raw_message_pipes(16384);
         let (source, _sink) = input_pair();
-        let worker = start_worker(&driver, Keyboard::new(Box::new(source)), host);
+        let worker = start_worker(&driver, Keyboard::new(Box::new(source), None), host);
 
         send_packet(
             &mut guest,
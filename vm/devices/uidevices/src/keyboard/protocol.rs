@@ -44,6 +44,11 @@ pub struct MessageLedIndicatorsState {
     pub padding: u16,
 }
 
+// LED bits in MessageLedIndicatorsState.
+pub const LED_SCROLL_LOCK: u16 = 1 << 0;
+pub const LED_NUM_LOCK: u16 = 1 << 1;
+pub const LED_CAPS_LOCK: u16 = 1 << 2;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
 pub struct MessageProtocolResponse {
@@ -7,7 +7,7 @@
 use async_trait::async_trait;
 use futures::StreamExt;
 use input_core::InputSource;
-use input_core::MouseData;
+use input_core::TabletData;
 use mesh::payload::Protobuf;
 use std::io::IoSlice;
 use std::pin::pin;
@@ -132,17 +132,17 @@ async fn send_packet<T: IntoBytes + Immutable + KnownLayout>(
 
 /// Vmbus synthetic mouse device.
 pub struct Mouse {
-    source: Box<dyn InputSource<MouseData>>,
+    source: Box<dyn InputSource<TabletData>>,
 }
 
 impl Mouse {
     /// Creates a new mouse device.
-    pub fn new(source: Box<dyn InputSource<MouseData>>) -> Self {
+    pub fn new(source: Box<dyn InputSource<TabletData>>) -> Self {
         Self { source }
     }
 
     /// Extracts the mouse input receiver.
-    pub fn into_source(self) -> Box<dyn InputSource<MouseData>> {
+    pub fn into_source(self) -> Box<dyn InputSource<TabletData>> {
         self.source
     }
 }
@@ -355,9 +355,9 @@ async fn process(&mut self, mouse: &mut Mouse) -> Result<(), Error> {
     }
 }
 
-// Transforms MouseData from the vnc server to an HID input report (mouse packet) by scaling coordinates and marking button flags
+// Transforms TabletData from the vnc server to an HID input report (mouse packet) by scaling coordinates and marking button flags
 async fn post_mouse_packet(
-    mouse_data: MouseData,
+    mouse_data: TabletData,
     channel: &mut (impl AsyncSend + Unpin),
 ) -> Result<(), Error> {
     let mut scrolled = protocol::ScrollType::NoChange;
@@ -58,7 +58,8 @@ async fn resolve(
             .map_err(VideoError::Framebuffer)?;
         let device = SimpleDeviceWrapper::new(
             input.driver_source.simple(),
-            Video::new(framebuffer.0).map_err(VideoError::Video)?,
+            Video::new(framebuffer.0, resource.resize_requests, resource.pointer)
+                .map_err(VideoError::Video)?,
         );
         Ok(device.into())
     }
@@ -87,8 +88,10 @@ async fn resolve(
             .resolve(resource.source, "synthkbd")
             .await
             .map_err(InputError::InputSource)?;
-        let device =
-            SimpleDeviceWrapper::new(input.driver_source.simple(), Keyboard::new(source.0));
+        let device = SimpleDeviceWrapper::new(
+            input.driver_source.simple(),
+            Keyboard::new(source.0, resource.lock_keys),
+        );
         Ok(device.into())
     }
 }
@@ -6,14 +6,23 @@
 mod protocol;
 
 use async_trait::async_trait;
+use futures::FutureExt;
+use futures::StreamExt;
+use futures::future::OptionFuture;
 use guestmem::AccessError;
 use guid::Guid;
 use mesh::payload::Protobuf;
+use mesh::rpc::Rpc;
 use std::io::IoSlice;
 use task_control::StopTask;
 use thiserror::Error;
+use video_core::DamageRect;
 use video_core::FramebufferControl;
 use video_core::FramebufferFormat;
+use video_core::PointerShape;
+use video_core::PointerSink;
+use video_core::PointerUpdate;
+use video_core::ResizeRpc;
 use vmbus_async::async_dgram::AsyncRecv;
 use vmbus_async::async_dgram::AsyncRecvExt;
 use vmbus_async::async_dgram::AsyncSend;
@@ -62,10 +71,8 @@ enum Request {
     },
     PointerPosition {
         is_visible: bool,
-        x: i32,
-        y: i32,
     },
-    PointerShape,
+    PointerShape(PointerShapeMessage),
     Dirt(Vec<protocol::Rectangle>),
     BiosInfo,
     SupportedResolutions {
@@ -74,6 +81,18 @@ enum Request {
     Capability,
 }
 
+/// A pointer shape message, which may be one part of a split shape.
+#[derive(Debug)]
+struct PointerShapeMessage {
+    partial_index: u8,
+    color: bool,
+    width: u32,
+    height: u32,
+    hotspot_x: u32,
+    hotspot_y: u32,
+    data: Vec<u8>,
+}
+
 fn parse_packet(buf: &[u8]) -> Result<Request, Error> {
     let (header, buf) =
         Ref::<_, protocol::MessageHeader>::from_prefix(buf).map_err(|_| Error::InvalidPacket)?; // TODO: zerocopy: map_err (https://github.com/microsoft/openvmm/issues/759)
@@ -111,15 +130,24 @@ fn parse_packet(buf: &[u8]) -> Result<Request, Error> {
             let message = protocol::PointerPositionMessage::ref_from_prefix(buf)
                 .map_err(|_| Error::InvalidPacket)?
                 .0; // TODO: zerocopy: map_err (https://github.com/microsoft/openvmm/issues/759)
+            // The position is only needed for relative mouse mode, which is
+            // not supported.
             Request::PointerPosition {
                 is_visible: message.is_visible != 0,
-                x: message.image_x.into(),
-                y: message.image_y.into(),
             }
         }
         protocol::MESSAGE_POINTER_SHAPE => {
-            //let message = protocol::PointerShapeMessage::from_bytes(buf).map_err(|_| Error::InvalidPacket)?; // TODO: zerocopy: map_err (https://github.com/microsoft/openvmm/issues/759)
-            Request::PointerShape
+            let (message, data) = Ref::<_, protocol::PointerShapeMessage>::from_prefix(buf)
+                .map_err(|_| Error::InvalidPacket)?; // TODO: zerocopy: map_err (https://github.com/microsoft/openvmm/issues/759)
+            Request::PointerShape(PointerShapeMessage {
+                partial_index: message.partial_index,
+                color: message.cursor_flags != 0,
+                width: message.width_pixels.into(),
+                height: message.height_pixels.into(),
+                hotspot_x: message.hotspot_x.into(),
+                hotspot_y: message.hotspot_y.into(),
+                data: data.into(),
+            })
         }
         protocol::MESSAGE_DIRT => {
             let (message, buf) = Ref::<_, protocol::DirtMessage>::from_prefix(buf)
@@ -149,22 +177,108 @@ fn parse_packet(buf: &[u8]) -> Result<Request, Error> {
     Ok(request)
 }
 
+/// Converts pointer shape data to `0xAARRGGBB` pixels, or returns `None` if
+/// the data is the wrong size.
+///
+/// Color pointers are 32-bit ARGB. Monochrome pointers are an AND mask followed
+/// by an XOR mask, each with one bit per pixel and rows padded to a byte.
+fn pointer_pixels(color: bool, width: usize, height: usize, data: &[u8]) -> Option<Vec<u32>> {
+    if color {
+        if data.len() != width * height * protocol::CURSOR_ARGB_PIXEL_SIZE {
+            return None;
+        }
+        return Some(
+            data.chunks_exact(4)
+                .map(|p| u32::from_le_bytes(p.try_into().unwrap()))
+                .collect(),
+        );
+    }
+    let stride = width.div_ceil(8);
+    if data.len() != stride * height * 2 {
+        return None;
+    }
+    let (and_mask, xor_mask) = data.split_at(stride * height);
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let bit = |mask: &[u8]| mask[y * stride + x / 8] & (0x80 >> (x % 8)) != 0;
+            // A screen-inverting pixel (both bits set) cannot be drawn by the
+            // console, so it is drawn black instead.
+            pixels.push(match (bit(and_mask), bit(xor_mask)) {
+                (false, true) => 0xffffffff,
+                (true, false) => 0,
+                (false, false) | (true, true) => 0xff000000,
+            });
+        }
+    }
+    Some(pixels)
+}
+
 /// Vmbus synthetic video device.
 pub struct Video {
     control: Box<dyn FramebufferControl>,
+    resize: ResizeState,
+    pointer: Option<PointerState>,
+}
+
+/// Host requests to change the guest's display resolution.
+struct ResizeState {
+    requests: mesh::Receiver<ResizeRpc>,
+    /// The most recently requested resolution, offered to the guest as its
+    /// default.
+    preferred: Option<(u16, u16)>,
+    /// The request waiting for the guest to set its video mode.
+    pending: Option<Rpc<(), Result<(u16, u16), mesh::error::RemoteError>>>,
+}
+
+/// A console that can draw the guest's pointer.
+struct PointerState {
+    sink: PointerSink,
+    /// Whether the console currently wants the pointer. The guest only reports
+    /// its pointer, rather than drawing it into the framebuffer, while it does.
+    enabled: bool,
+    /// The visibility last sent to the console.
+    visible: Option<bool>,
+    /// The shape data received so far, for a shape split across messages.
+    partial: Vec<u8>,
 }
 
 impl Video {
-    /// Creates a new video device.
-    pub fn new(control: Box<dyn FramebufferControl>) -> anyhow::Result<Self> {
-        Ok(Self { control })
+    /// Creates a new video device, which changes the guest's resolution on
+    /// requests from `resize_requests` and, if `pointer` is set, sends the
+    /// guest's pointer to a console to draw.
+    pub fn new(
+        control: Box<dyn FramebufferControl>,
+        resize_requests: mesh::Receiver<ResizeRpc>,
+        pointer: Option<PointerSink>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            control,
+            resize: ResizeState {
+                requests: resize_requests,
+                preferred: None,
+                pending: None,
+            },
+            pointer: pointer.map(|sink| PointerState {
+                sink,
+                enabled: false,
+                visible: None,
+                partial: Vec::new(),
+            }),
+        })
     }
 }
 
 /// The video device saved state.
 #[derive(Protobuf, SavedStateRoot)]
 #[mesh(package = "ui.synthvid")]
-pub struct SavedState(ChannelState);
+pub struct SavedState {
+    #[mesh(1)]
+    channel: ChannelState,
+    /// The resolution most recently requested by the host.
+    #[mesh(2)]
+    preferred_resolution: Option<(u16, u16)>,
+}
 
 /// The video task.
 pub struct VideoChannel {
@@ -246,6 +360,8 @@ enum ActiveState {
     },
     #[mesh(6)]
     SendCapability,
+    #[mesh(7)]
+    SendFeatureChange,
 }
 
 struct PacketBuffer {
@@ -315,6 +431,7 @@ fn inspect(&mut self, req: inspect::Request<'_>, task: Option<&mut VideoChannel>
                             "send_supported_resolutions"
                         }
                         ActiveState::SendCapability => "send_capability",
+                        ActiveState::SendFeatureChange => "send_feature_change",
                     },
                 ),
             };
@@ -325,6 +442,17 @@ fn inspect(&mut self, req: inspect::Request<'_>, task: Option<&mut VideoChannel>
                 )
                 .field_mut("channel", &mut this.channel);
         }
+        resp.field(
+            "preferred_resolution",
+            self.resize
+                .preferred
+                .map(|(width, height)| format!("{width}x{height}")),
+        )
+        .field("resize_pending", self.resize.pending.is_some())
+        .field(
+            "pointer_enabled",
+            self.pointer.as_ref().map(|pointer| pointer.enabled),
+        );
     }
 
     fn open(
@@ -342,7 +470,10 @@ async fn run(
         channel: &mut VideoChannel,
     ) -> Result<(), task_control::Cancelled> {
         stop.until_stopped(async {
-            match channel.process(&mut self.control).await {
+            match channel
+                .process(&mut self.control, &mut self.resize, &mut self.pointer)
+                .await
+            {
                 Ok(()) => {}
                 Err(err) => tracing::error!(error = &err as &dyn std::error::Error, "video error"),
             }
@@ -361,7 +492,10 @@ fn supports_save_restore(
 
 impl SaveRestoreSimpleVmbusDevice for Video {
     fn save_open(&mut self, runner: &Self::Runner) -> Self::SavedState {
-        SavedState(runner.state.clone())
+        SavedState {
+            channel: runner.state.clone(),
+            preferred_resolution: self.resize.preferred,
+        }
     }
 
     fn restore_open(
@@ -369,8 +503,13 @@ fn restore_open(
         state: Self::SavedState,
         channel: RawAsyncChannel<GpadlRingMem>,
     ) -> Result<Self::Runner, ChannelOpenError> {
+        let SavedState {
+            channel: state,
+            preferred_resolution,
+        } = state;
         let pipe = MessagePipe::new(channel)?;
-        Ok(VideoChannel::new(pipe, state.0))
+        self.resize.preferred = preferred_resolution;
+        Ok(VideoChannel::new(pipe, state))
     }
 }
 
@@ -406,6 +545,8 @@ async fn send_packet<T: IntoBytes + ?Sized + Immutable + KnownLayout>(
     async fn process(
         &mut self,
         framebuffer: &mut Box<dyn FramebufferControl>,
+        resize: &mut ResizeState,
+        pointer: &mut Option<PointerState>,
     ) -> Result<(), Error> {
         let mut channel = &mut self.channel;
         loop {
@@ -442,9 +583,16 @@ async fn process(
                     .await?;
                     if is_accepted != 0 {
                         tracelimit::info_ratelimited!(?version, "video negotiation succeeded");
+                        // Ask the guest for its pointer if the console is
+                        // already waiting for it.
+                        let substate = if pointer.as_ref().is_some_and(|p| p.enabled) {
+                            ActiveState::SendFeatureChange
+                        } else {
+                            ActiveState::ReadRequest
+                        };
                         self.state = ChannelState::Active {
                             version: *version,
-                            substate: ActiveState::ReadRequest,
+                            substate,
                         };
                     } else {
                         tracelimit::warn_ratelimited!(?version, "video negotiation failed");
@@ -457,7 +605,49 @@ async fn process(
                 } => {
                     match *substate {
                         ActiveState::ReadRequest => {
-                            let packet = self.packet_buf.recv_packet(&mut channel).await?;
+                            enum Event {
+                                Packet(Request),
+                                Resize(ResizeRpc),
+                                EnablePointer(bool),
+                            }
+
+                            let mut enable_pointer: OptionFuture<_> = pointer
+                                .as_mut()
+                                .map(|p| p.sink.enable.select_next_some())
+                                .into();
+                            let event = futures::select! { // merge semantics
+                                r = self.packet_buf.recv_packet(&mut channel).fuse() => {
+                                    Event::Packet(r?)
+                                }
+                                rpc = resize.requests.select_next_some() => Event::Resize(rpc),
+                                enable = enable_pointer => Event::EnablePointer(enable.unwrap()),
+                            };
+                            let packet = match event {
+                                Event::Packet(packet) => packet,
+                                Event::EnablePointer(enable) => {
+                                    let pointer = pointer.as_mut().unwrap();
+                                    if pointer.enabled != enable {
+                                        tracing::debug!(enable, "pointer updates changed");
+                                        pointer.enabled = enable;
+                                        pointer.visible = None;
+                                        *substate = ActiveState::SendFeatureChange;
+                                    }
+                                    continue;
+                                }
+                                Event::Resize(rpc) => {
+                                    let (resolution, rpc) = rpc.split();
+                                    tracing::info!(
+                                        ?resolution,
+                                        "requesting guest resolution change"
+                                    );
+                                    if let Some(old) = resize.pending.replace(rpc) {
+                                        old.fail(anyhow::anyhow!("superseded by a new request"));
+                                    }
+                                    resize.preferred = Some(resolution);
+                                    *substate = ActiveState::SendFeatureChange;
+                                    continue;
+                                }
+                            };
                             match packet {
                                 Request::VramLocation {
                                     user_context,
@@ -487,15 +677,62 @@ async fn process(
                                                 as usize,
                                         })
                                         .await;
+                                    if let Some(rpc) = resize.pending.take() {
+                                        let clamp = |n: u32| n.try_into().unwrap_or(u16::MAX);
+                                        let resolution = (
+                                            clamp(u32::from(situation.width_pixels)),
+                                            clamp(u32::from(situation.height_pixels)),
+                                        );
+                                        // The guest is free to keep its mode
+                                        // or pick another one.
+                                        if Some(resolution) == resize.preferred {
+                                            rpc.complete(Ok(resolution));
+                                        } else {
+                                            rpc.fail(anyhow::anyhow!(
+                                                "guest set its resolution to {}x{} instead",
+                                                resolution.0,
+                                                resolution.1
+                                            ));
+                                        }
+                                    }
                                     *substate =
                                         ActiveState::SendSituationUpdateAck { user_context };
                                 }
-                                Request::PointerPosition { is_visible, x, y } => {
-                                    let _ = (is_visible, x, y);
+                                Request::PointerPosition { is_visible } => {
+                                    if let Some(pointer) = pointer {
+                                        if pointer.enabled && pointer.visible != Some(is_visible) {
+                                            pointer.visible = Some(is_visible);
+                                            pointer
+                                                .sink
+                                                .updates
+                                                .send(PointerUpdate::Visible(is_visible));
+                                        }
+                                    }
+                                }
+                                Request::PointerShape(message) => {
+                                    if let Some(pointer) = pointer {
+                                        pointer.shape(message);
+                                    }
                                 }
-                                Request::PointerShape => {}
-                                Request::Dirt(_rects) => {
-                                    // TODO: Support dirt requests
+                                Request::Dirt(rects) => {
+                                    let rects = rects
+                                        .iter()
+                                        .filter_map(|rect| {
+                                            let left = i32::from(rect.left).max(0) as u32;
+                                            let top = i32::from(rect.top).max(0) as u32;
+                                            let right = i32::from(rect.right).max(0) as u32;
+                                            let bottom = i32::from(rect.bottom).max(0) as u32;
+                                            (right > left && bottom > top).then_some(DamageRect {
+                                                x: left,
+                                                y: top,
+                                                width: right - left,
+                                                height: bottom - top,
+                                            })
+                                        })
+                                        .collect::<Vec<_>>();
+                                    if !rects.is_empty() {
+                                        framebuffer.damage(&rects).await;
+                                    }
                                 }
                                 Request::BiosInfo => {
                                     *substate = ActiveState::SendBiosInfo;
@@ -560,17 +797,30 @@ async fn process(
                             } else {
                                 const RESOLUTIONS: &[(u16, u16)] = &[(1024, 768), (1280, 1024)];
 
+                                // Offer the resolution requested by the host
+                                // first, as the default.
+                                let resolutions = resize
+                                    .preferred
+                                    .into_iter()
+                                    .chain(
+                                        RESOLUTIONS
+                                            .iter()
+                                            .copied()
+                                            .filter(|&r| Some(r) != resize.preferred),
+                                    )
+                                    .collect::<Vec<_>>();
+
                                 let mut packet = Vec::new();
                                 packet.extend_from_slice(
                                     protocol::SupportedResolutionsResponseMessage {
                                         edid_block: protocol::EDID_BLOCK,
-                                        resolution_count: RESOLUTIONS.len().try_into().unwrap(),
+                                        resolution_count: resolutions.len().try_into().unwrap(),
                                         default_resolution_index: 0,
                                         is_standard: 0,
                                     }
                                     .as_bytes(),
                                 );
-                                for r in RESOLUTIONS {
+                                for r in &resolutions {
                                     packet.extend_from_slice(
                                         protocol::ScreenInfo {
                                             width: r.0.into(),
@@ -600,9 +850,133 @@ async fn process(
                             .await?;
                             *substate = ActiveState::ReadRequest;
                         }
+                        ActiveState::SendFeatureChange => {
+                            // Ask the guest to report its video situation, so
+                            // that it picks up the new default resolution, and
+                            // its pointer if the console is drawing it. The
+                            // guest reports the current pointer whenever these
+                            // are enabled.
+                            let pointer_needed = pointer.as_ref().is_some_and(|p| p.enabled) as u8;
+                            Self::send_packet(
+                                &mut channel,
+                                protocol::MESSAGE_FEATURE_CHANGE,
+                                &protocol::FeatureChangeMessage {
+                                    is_dirt_needed: 1,
+                                    is_pointer_position_updates_needed: pointer_needed,
+                                    is_pointer_shape_updates_needed: pointer_needed,
+                                    is_video_situation_updates_needed: 1,
+                                },
+                            )
+                            .await?;
+                            *substate = ActiveState::ReadRequest;
+                        }
                     }
                 }
             }
         }
     }
 }
+
+impl PointerState {
+    /// Handles a pointer shape message, sending the shape to the console once
+    /// it is complete.
+    fn shape(&mut self, message: PointerShapeMessage) {
+        let PointerShapeMessage {
+            partial_index,
+            color,
+            width,
+            height,
+            hotspot_x,
+            hotspot_y,
+            data,
+        } = message;
+        if partial_index == 0 {
+            self.partial.clear();
+        }
+        if self.partial.len() + data.len() > protocol::CURSOR_MAX_SIZE {
+            tracelimit::warn_ratelimited!("pointer shape too large");
+            self.partial.clear();
+            return;
+        }
+        self.partial.extend_from_slice(&data);
+        if partial_index != protocol::CURSOR_COMPLETE {
+            return;
+        }
+        let data = std::mem::take(&mut self.partial);
+        if !self.enabled {
+            return;
+        }
+        let (width, height) = (width as usize, height as usize);
+        if width > protocol::CURSOR_MAX_X
+            || height > protocol::CURSOR_MAX_Y
+            || hotspot_x as usize >= width.max(1)
+            || hotspot_y as usize >= height.max(1)
+        {
+            tracelimit::warn_ratelimited!(
+                width,
+                height,
+                hotspot_x,
+                hotspot_y,
+                "invalid pointer shape"
+            );
+            return;
+        }
+        let Some(pixels) = pointer_pixels(color, width, height, &data) else {
+            tracelimit::warn_ratelimited!(width, height, color, "invalid pointer shape data size");
+            return;
+        };
+        self.sink.updates.send(PointerUpdate::Shape(PointerShape {
+            width: width as u16,
+            height: height as u16,
+            hotspot_x: hotspot_x as u16,
+            hotspot_y: hotspot_y as u16,
+            pixels,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn pointer_pixels() {
+        const BLACK: u32 = 0xff000000;
+        const WHITE: u32 = 0xffffffff;
+        let cases: [(bool, usize, usize, &[u8], Option<Vec<u32>>); 6] = [
+            // Color pixels are little-endian ARGB.
+            (
+                true,
+                2,
+                1,
+                &[0x33, 0x22, 0x11, 0xff, 0x66, 0x55, 0x44, 0x40],
+                Some(vec![0xff112233, 0x40445566]),
+            ),
+            (true, 2, 1, &[0; 7], None),
+            // White, transparent, then black for both the plain and the
+            // screen-inverting pixel.
+            (
+                false,
+                2,
+                2,
+                &[0x40, 0x40, 0x80, 0x40],
+                Some(vec![WHITE, 0, BLACK, BLACK]),
+            ),
+            // Rows are padded to a byte.
+            (
+                false,
+                9,
+                1,
+                &[0x00, 0x00, 0x00, 0x80],
+                Some([BLACK; 8].into_iter().chain([WHITE]).collect()),
+            ),
+            (false, 9, 1, &[0; 2], None),
+            (false, 0, 0, &[], Some(Vec::new())),
+        ];
+        for (color, width, height, data, expected) in cases {
+            assert_eq!(
+                super::pointer_pixels(color, width, height, data),
+                expected,
+                "color {color}, {width}x{height}"
+            );
+        }
+    }
+}
@@ -5,19 +5,26 @@
 
 #![forbid(unsafe_code)]
 
+use input_core::lock_keys::LockKeys;
 use mesh::MeshPayload;
+use video_core::PointerSink;
 use vm_resource::Resource;
 use vm_resource::ResourceId;
 use vm_resource::kind::FramebufferHandleKind;
 use vm_resource::kind::KeyboardInputHandleKind;
-use vm_resource::kind::MouseInputHandleKind;
+use vm_resource::kind::TabletInputHandleKind;
 use vm_resource::kind::VmbusDeviceHandleKind;
 
+pub use video_core::ResizeRpc;
+
 /// Handle for a synthetic keyboard device.
 #[derive(MeshPayload)]
 pub struct SynthKeyboardHandle {
     /// The source of keyboard input.
     pub source: Resource<KeyboardInputHandleKind>,
+    /// The NumLock and CapsLock state to bring the guest to when it opens the
+    /// keyboard and after restore, if any.
+    pub lock_keys: Option<LockKeys>,
 }
 
 impl ResourceId<VmbusDeviceHandleKind> for SynthKeyboardHandle {
@@ -27,8 +34,8 @@ impl ResourceId<VmbusDeviceHandleKind> for SynthKeyboardHandle {
 /// Handle for a synthetic mouse device.
 #[derive(MeshPayload)]
 pub struct SynthMouseHandle {
-    /// The source of mouse moves and clicks.
-    pub source: Resource<MouseInputHandleKind>,
+    /// The source of absolute pointer moves and clicks.
+    pub source: Resource<TabletInputHandleKind>,
 }
 
 impl ResourceId<VmbusDeviceHandleKind> for SynthMouseHandle {
@@ -40,6 +47,11 @@ impl ResourceId<VmbusDeviceHandleKind> for SynthMouseHandle {
 pub struct SynthVideoHandle {
     /// The framebuffer memory to map into the guest for rendering.
     pub framebuffer: Resource<FramebufferHandleKind>,
+    /// Requests to change the guest's display resolution.
+    pub resize_requests: mesh::Receiver<ResizeRpc>,
+    /// A console to send the guest's pointer to, so that the console can draw
+    /// it rather than the guest.
+    pub pointer: Option<PointerSink>,
 }
 
 impl ResourceId<VmbusDeviceHandleKind> for SynthVideoHandle {
@@ -8,6 +8,7 @@
 use inspect::Inspect;
 use mesh::MeshPayload;
 use mesh::payload::Protobuf;
+use mesh::rpc::FailableRpc;
 use vm_resource::CanResolveTo;
 use vm_resource::ResourceId;
 use vm_resource::kind::FramebufferHandleKind;
@@ -51,6 +52,24 @@ pub struct FramebufferFormat {
     pub offset: usize,
 }
 
+/// A region of the framebuffer, in pixels.
+#[derive(Debug, Copy, Clone, Protobuf, PartialEq, Eq, Inspect)]
+#[mesh(package = "framebuffer")]
+pub struct DamageRect {
+    /// The left edge.
+    #[mesh(1)]
+    pub x: u32,
+    /// The top edge.
+    #[mesh(2)]
+    pub y: u32,
+    /// Width in pixels.
+    #[mesh(3)]
+    pub width: u32,
+    /// Height in pixels.
+    #[mesh(4)]
+    pub height: u32,
+}
+
 /// Functions necessary to control the framebuffer from a video device.
 ///
 /// This trait needs to be async so that an implementation of these functions can be async.
@@ -65,4 +84,78 @@ pub trait FramebufferControl: Send {
     async fn unmap(&mut self);
     /// Updates the framebuffer format.
     async fn set_format(&mut self, format: FramebufferFormat);
+    /// Reports regions of the framebuffer that the guest has updated, so that
+    /// consumers don't have to look for changes themselves.
+    ///
+    /// Not all video devices know this, so consumers must not rely on it.
+    async fn damage(&mut self, rects: &[DamageRect]) {
+        let _ = rects;
+    }
+}
+
+/// A request to change the guest's display resolution to `(width, height)`.
+///
+/// Completes once the guest next sets its video mode, with the requested
+/// resolution, or with an error if the guest set a different one.
+pub type ResizeRpc = FailableRpc<(u16, u16), (u16, u16)>;
+
+/// The guest's pointer (mouse cursor) image.
+#[derive(Debug, Clone, MeshPayload)]
+pub struct PointerShape {
+    /// Width in pixels.
+    pub width: u16,
+    /// Height in pixels.
+    pub height: u16,
+    /// The pixel within the image that is at the pointer's position.
+    pub hotspot_x: u16,
+    /// The pixel within the image that is at the pointer's position.
+    pub hotspot_y: u16,
+    /// The pixels, in rows from the top, as `0xAARRGGBB` with straight
+    /// (not premultiplied) alpha.
+    pub pixels: Vec<u32>,
+}
+
+/// A change to the guest's pointer.
+#[derive(Debug, Clone, MeshPayload)]
+pub enum PointerUpdate {
+    /// The pointer has a new image.
+    Shape(PointerShape),
+    /// The pointer was shown or hidden.
+    Visible(bool),
+}
+
+/// The video device's end of a channel for a console to draw the guest's
+/// pointer itself, rather than the guest drawing it into the framebuffer.
+#[derive(MeshPayload)]
+pub struct PointerSink {
+    /// Pointer updates for the console.
+    pub updates: mesh::Sender<PointerUpdate>,
+    /// Whether the console is currently drawing the pointer. While it is not,
+    /// the guest is asked to draw the pointer itself.
+    pub enable: mesh::Receiver<bool>,
+}
+
+/// The console's end of a pointer channel.
+#[derive(MeshPayload)]
+pub struct PointerSource {
+    /// Pointer updates from the video device, sent while enabled.
+    pub updates: mesh::Receiver<PointerUpdate>,
+    /// Enables or disables pointer updates.
+    pub enable: mesh::Sender<bool>,
+}
+
+/// Returns a new pointer channel. Pointer updates start out disabled.
+pub fn pointer_pair() -> (PointerSource, PointerSink) {
+    let (update_send, update_recv) = mesh::channel();
+    let (enable_send, enable_recv) = mesh::channel();
+    (
+        PointerSource {
+            updates: update_recv,
+            enable: enable_send,
+        },
+        PointerSink {
+            updates: update_send,
+            enable: enable_recv,
+        },
+    )
 }
@@ -29,13 +29,23 @@ impl ResourceKind for KeyboardInputHandleKind {
     const NAME: &'static str = "keyboard_input_handle";
 }
 
-/// A resource kind for mouse input source handles.
+/// A resource kind for relative mouse input source handles.
+///
+/// This kind used to carry absolute positions. Absolute pointing devices now
+/// use [`TabletInputHandleKind`] instead.
 pub enum MouseInputHandleKind {}
 
 impl ResourceKind for MouseInputHandleKind {
     const NAME: &'static str = "mouse_input_handle";
 }
 
+/// A resource kind for absolute pointer (tablet) input source handles.
+pub enum TabletInputHandleKind {}
+
+impl ResourceKind for TabletInputHandleKind {
+    const NAME: &'static str = "tablet_input_handle";
+}
+
 /// Resource kind for network endpoints.
 pub enum NetEndpointHandleKind {}
 
@@ -88,6 +98,14 @@ impl ResourceKind for FramebufferHandleKind {
     const NAME: &'static str = "framebuffer";
 }
 
+/// A resource kind for guest consoles: a framebuffer to display along with a
+/// channel for the keyboard and mouse input that goes with it.
+pub enum ConsoleHandleKind {}
+
+impl ResourceKind for ConsoleHandleKind {
+    const NAME: &'static str = "console";
+}
+
 /// A resource kind for virtio device handles.
 pub enum VirtioDeviceHandle {}
 
@@ -1,8 +1,8 @@
 // Copyright (c) Microsoft Corporation.
 // Licensed under the MIT License.
 
-//! Contains a state unit for distributing keyboard and mouse input to the
-//! appropriate devices.
+//! Contains a state unit for distributing keyboard, mouse, and tablet input to
+//! the appropriate devices.
 
 use async_trait::async_trait;
 use futures::StreamExt;
@@ -12,6 +12,7 @@
 use input_core::MouseData;
 use input_core::MultiplexedInputHandle;
 use input_core::ResolvedInputSource;
+use input_core::TabletData;
 use input_core::mesh_input::MeshInputSink;
 use input_core::mesh_input::MeshInputSource;
 use input_core::mesh_input::input_pair;
@@ -26,11 +27,12 @@
 use vm_resource::ResourceResolver;
 use vm_resource::kind::KeyboardInputHandleKind;
 use vm_resource::kind::MouseInputHandleKind;
+use vm_resource::kind::TabletInputHandleKind;
 use vmcore::save_restore::RestoreError;
 use vmcore::save_restore::SaveError;
 use vmcore::save_restore::SavedStateBlob;
 
-/// Distributes keyboard and mouse input to the appropriate devices.
+/// Distributes keyboard, mouse, and tablet input to the appropriate devices.
 pub struct InputDistributor {
     recv: mesh::Receiver<InputData>,
     client_recv: mesh::Receiver<DistributorRequest>,
@@ -46,6 +48,7 @@ pub struct InputDistributorClient {
 enum DistributorRequest {
     AddKeyboard(Rpc<Sink<KeyboardData>, Result<(), AddSinkError>>),
     AddMouse(Rpc<Sink<MouseData>, Result<(), AddSinkError>>),
+    AddTablet(Rpc<Sink<TabletData>, Result<(), AddSinkError>>),
 }
 
 impl InputDistributor {
@@ -57,6 +60,7 @@ pub fn new(input: mesh::Receiver<InputData>) -> Self {
                 running: false,
                 keyboard: Forwarder::new(),
                 mouse: Forwarder::new(),
+                tablet: Forwarder::new(),
             },
             recv: input,
             client: InputDistributorClient { send: client_send },
@@ -102,6 +106,9 @@ enum Event {
                     DistributorRequest::AddMouse(rpc) => {
                         rpc.handle_sync(|sink| self.inner.mouse.add_sink(sink))
                     }
+                    DistributorRequest::AddTablet(rpc) => {
+                        rpc.handle_sync(|sink| self.inner.tablet.add_sink(sink))
+                    }
                 },
                 Event::Done => break,
                 Event::Input(data) => {
@@ -121,12 +128,21 @@ enum Event {
                         InputData::Mouse(input) => {
                             tracing::trace!(
                                 button_mask = input.button_mask,
-                                x = input.x,
-                                y = input.y,
+                                dx = input.dx,
+                                dy = input.dy,
                                 "forwarding mouse input"
                             );
                             self.inner.mouse.forward(input)
                         }
+                        InputData::Tablet(input) => {
+                            tracing::trace!(
+                                button_mask = input.button_mask,
+                                x = input.x,
+                                y = input.y,
+                                "forwarding tablet input"
+                            );
+                            self.inner.tablet.forward(input)
+                        }
                     }
                 }
             }
@@ -185,6 +201,31 @@ pub async fn add_mouse(
 
         Ok(source)
     }
+
+    /// Adds an absolute pointing device with the given name.
+    ///
+    /// The device with the highest elevation that is active will receive input.
+    pub async fn add_tablet(
+        &self,
+        name: impl Into<String>,
+        elevation: usize,
+    ) -> Result<MeshInputSource<TabletData>, AddSinkError> {
+        let (source, sink) = input_pair();
+        // Treat a missing distributor as success.
+        self.send
+            .call(
+                DistributorRequest::AddTablet,
+                Sink {
+                    name: name.into(),
+                    elevation,
+                    sink,
+                },
+            )
+            .await
+            .unwrap_or(Ok(()))?;
+
+        Ok(source)
+    }
 }
 
 #[derive(InspectMut)]
@@ -192,6 +233,7 @@ struct Inner {
     running: bool,
     keyboard: Forwarder<KeyboardData>,
     mouse: Forwarder<MouseData>,
+    tablet: Forwarder<TabletData>,
 }
 
 impl StateUnit for Inner {
@@ -318,3 +360,20 @@ async fn resolve(
         Ok(self.add_mouse(input, resource.elevation).await?.into())
     }
 }
+
+#[async_trait]
+impl AsyncResolveResource<TabletInputHandleKind, MultiplexedInputHandle>
+    for InputDistributorClient
+{
+    type Output = ResolvedInputSource<TabletData>;
+    type Error = AddSinkError;
+
+    async fn resolve(
+        &self,
+        _resolver: &ResourceResolver,
+        resource: MultiplexedInputHandle,
+        input: &str,
+    ) -> Result<Self::Output, Self::Error> {
+        Ok(self.add_tablet(input, resource.elevation).await?.into())
+    }
+}
@@ -18,7 +18,11 @@
 use chipset_resources::battery::BatteryDeviceHandleX64;
 use chipset_resources::battery::HostBatteryUpdate;
 use chipset_resources::i8042::I8042DeviceHandle;
+use chipset_resources::i8042::I8042ResetAction;
 use input_core::MultiplexedInputHandle;
+use input_core::lock_keys::LockKeys;
+use input_core::remap::KeyRemap;
+use input_core::remap::RemappedKeyboardHandle;
 use missing_dev_resources::MissingDevHandle;
 use serial_16550_resources::Serial16550DeviceHandle;
 use serial_core::resources::DisconnectedSerialBackendHandle;
@@ -45,6 +49,9 @@ pub struct VmManifestBuilder {
     guest_watchdog: bool,
     psp: bool,
     debugcon: Option<(Resource<SerialBackendHandle>, u16)>,
+    i8042_reset_action: Option<I8042ResetAction>,
+    lock_keys: Option<LockKeys>,
+    key_remap: Option<mesh::Cell<KeyRemap>>,
 }
 
 /// The VM's base chipset type, which determines the set of core devices (such
@@ -117,6 +124,9 @@ pub fn new(ty: BaseChipsetType, arch: MachineArch) -> Self {
             guest_watchdog: false,
             psp: false,
             debugcon: None,
+            i8042_reset_action: None,
+            lock_keys: None,
+            key_remap: None,
         }
     }
 
@@ -197,6 +207,27 @@ pub fn with_guest_watchdog(mut self) -> Self {
         self
     }
 
+    /// Sets the action taken when the guest resets the CPU via the i8042
+    /// keyboard controller. Defaults to resetting the VM.
+    pub fn with_i8042_reset_action(mut self, action: I8042ResetAction) -> Self {
+        self.i8042_reset_action = Some(action);
+        self
+    }
+
+    /// Sets the NumLock and CapsLock state that the i8042 keyboard brings the
+    /// guest to at boot and after restore.
+    pub fn with_lock_keys(mut self, lock_keys: LockKeys) -> Self {
+        self.lock_keys = Some(lock_keys);
+        self
+    }
+
+    /// Sets the table of keys that the i8042 keyboard remaps before they reach
+    /// the guest.
+    pub fn with_key_remap(mut self, table: mesh::Cell<KeyRemap>) -> Self {
+        self.key_remap = Some(table);
+        self
+    }
+
     /// Enable the AMD64 PSP device.
     pub fn with_psp(mut self) -> Self {
         self.psp = true;
@@ -223,7 +254,7 @@ pub fn build(self) -> Result<VmChipsetResult, Error> {
                 if self.arch != MachineArch::X86_64 {
                     return Err(Error(ErrorInner::UnsupportedArch));
                 }
-                result.attach_i8042();
+                result.attach_i8042(self.i8042_reset_action, self.lock_keys, self.key_remap);
                 // This chipset always has a serial port even if not requested.
                 result.attach_serial_16550(
                     self.serial_wait_for_rts,
@@ -361,11 +392,26 @@ pub fn build(self) -> Result<VmChipsetResult, Error> {
 }
 
 impl VmChipsetResult {
-    fn attach_i8042(&mut self) -> &mut Self {
+    fn attach_i8042(
+        &mut self,
+        reset_action: Option<I8042ResetAction>,
+        lock_keys: Option<LockKeys>,
+        key_remap: Option<mesh::Cell<KeyRemap>>,
+    ) -> &mut Self {
+        let mut keyboard_input = MultiplexedInputHandle { elevation: 0 }.into_resource();
+        if let Some(table) = key_remap {
+            keyboard_input = RemappedKeyboardHandle {
+                source: keyboard_input,
+                table,
+            }
+            .into_resource();
+        }
         self.chipset_devices.push(ChipsetDeviceHandle {
             name: "i8042".to_owned(),
             resource: I8042DeviceHandle {
-                keyboard_input: MultiplexedInputHandle { elevation: 0 }.into_resource(),
+                keyboard_input,
+                reset_action: reset_action.unwrap_or(I8042ResetAction::Reset),
+                lock_keys,
             }
             .into_resource(),
         });
@@ -3,46 +3,108 @@
 
 //! A worker for running a VNC server.
 
+mod reverse;
+mod websocket;
+
 use anyhow::Context;
-use anyhow::anyhow;
+use framebuffer::ResolvedConsole;
 use futures::FutureExt;
+use futures::StreamExt;
+use futures::future::OptionFuture;
 use input_core::InputData;
 use input_core::KeyboardData;
-use input_core::MouseData;
+use input_core::TabletData;
+use input_core::keymap::KeyboardLayout;
+use input_core::rate_limit::InputRateLimit;
 use mesh::message::MeshField;
+use mesh::rpc::Rpc;
 use mesh_worker::Worker;
 use mesh_worker::WorkerId;
 use mesh_worker::WorkerRpc;
 use pal_async::local::LocalDriver;
+use pal_async::local::block_on;
 use pal_async::local::block_with_io;
 use pal_async::socket::Listener;
 use pal_async::socket::PolledSocket;
 use pal_async::timer::PolledTimer;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
 use std::future::Future;
 use std::net::TcpListener;
+use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
 use std::time::Duration;
+use std::time::Instant;
 use tracing_helpers::AnyhowValueExt;
+use unix_socket::UnixListener;
+use video_core::PointerSource;
+use video_core::PointerUpdate;
+use video_core::ResizeRpc;
+use vm_resource::ResourceResolver;
+use vnc_worker_defs::ReverseConnection;
+use vnc_worker_defs::VncClipboard;
 use vnc_worker_defs::VncParameters;
+use vnc_worker_defs::VncRegistration;
+use vnc_worker_defs::VncRegistryRequest;
+use vnc_worker_defs::VncSerial;
 
 /// A worker for running a VNC server.
 pub struct VncWorker<T: Listener> {
     listener: T,
-    state: State<T>,
+    name: String,
+    name_updates: mesh::Receiver<String>,
+    file_transfer_dir: Option<PathBuf>,
+    input_audit: bool,
+    max_frame_rate: u32,
+    input_rate_limit: Option<InputRateLimit>,
+    reverse_connection: Option<ReverseConnection>,
+    websocket: bool,
+    websocket_origins: Vec<String>,
+    serial: Option<VncSerial>,
+    clipboard: Option<VncClipboard>,
+    pointer: Option<PointerSource>,
+    resize: Option<mesh::Sender<ResizeRpc>>,
+    keyboard_layout: KeyboardLayout,
+    permissive: bool,
+    handshake_timeout: Duration,
+    registry: Option<mesh::Sender<VncRegistryRequest>>,
+    view: framebuffer::View,
+    input: mesh::Sender<InputData>,
+}
+
+/// A connected client.
+struct Client {
+    remote_addr: String,
+    /// The key for the client's remembered preferences, if they are
+    /// remembered.
+    identity: Option<String>,
+    task: Pin<Box<dyn Future<Output = Disconnected>>>,
+    abort: mesh::OneshotSender<()>,
+    rename: mesh::Sender<String>,
+    /// The connection's serial output, if a serial port is mirrored.
+    serial: Option<mesh::Sender<Vec<u8>>>,
+    /// The connection's guest clipboard text, if the clipboard is shared.
+    clipboard: Option<mesh::Sender<String>>,
+    /// The connection's cursor updates, if the guest's pointer is drawn by
+    /// the client.
+    cursor: Option<mesh::Sender<Option<vnc::Cursor>>>,
+    /// Whether the client supports drawing the guest's pointer.
+    cursor_enabled: bool,
+    /// Whether the client has completed the handshake.
+    joined: bool,
 }
 
-/// The current server state.
-enum State<T: Listener> {
-    Listening {
-        view: ViewWrapper,
-        input: VncInput,
-    },
-    Connected {
-        remote_addr: T::Address,
-        task: Pin<Box<dyn Future<Output = (ViewWrapper, VncInput)>>>,
-        abort: mesh::OneshotSender<()>,
-    },
-    Invalid,
+/// A request from a connection task.
+enum ClientEvent {
+    /// The client completed the handshake, and whether it agreed to share the
+    /// desktop. If it did not, the other clients must be disconnected.
+    Joined(u64, bool),
+    /// The client started or stopped supporting drawing the guest's pointer.
+    CursorEnabled(u64, bool),
 }
 
 impl Worker for VncWorker<TcpListener> {
@@ -63,6 +125,24 @@ fn run(self, rpc_recv: mesh::Receiver<WorkerRpc<Self::State>>) -> anyhow::Result
     }
 }
 
+impl Worker for VncWorker<UnixListener> {
+    type Parameters = VncParameters<UnixListener>;
+    type State = VncParameters<UnixListener>;
+    const ID: WorkerId<Self::Parameters> = vnc_worker_defs::VNC_WORKER_UNIX;
+
+    fn new(params: Self::Parameters) -> anyhow::Result<Self> {
+        Self::new_inner(params)
+    }
+
+    fn restart(state: Self::State) -> anyhow::Result<Self> {
+        Self::new(state)
+    }
+
+    fn run(self, rpc_recv: mesh::Receiver<WorkerRpc<Self::State>>) -> anyhow::Result<()> {
+        self.run_inner(rpc_recv)
+    }
+}
+
 #[cfg(any(windows, target_os = "linux"))]
 impl Worker for VncWorker<vmsocket::VmListener> {
     type Parameters = VncParameters<vmsocket::VmListener>;
@@ -84,19 +164,30 @@ fn run(self, rpc_recv: mesh::Receiver<WorkerRpc<Self::State>>) -> anyhow::Result
 
 impl<T: 'static + Listener + MeshField + Send> VncWorker<T> {
     fn new_inner(params: VncParameters<T>) -> anyhow::Result<Self> {
+        let console: ResolvedConsole =
+            block_on(ResourceResolver::new().resolve(params.console, ()))
+                .context("failed to resolve console")?;
         Ok(Self {
             listener: params.listener,
-            state: State::Listening {
-                view: ViewWrapper(
-                    params
-                        .framebuffer
-                        .view()
-                        .context("failed to map framebuffer")?,
-                ),
-                input: VncInput {
-                    send: params.input_send,
-                },
-            },
+            name: params.name,
+            name_updates: params.name_updates,
+            file_transfer_dir: params.file_transfer_dir,
+            input_audit: params.input_audit,
+            max_frame_rate: params.max_frame_rate,
+            input_rate_limit: params.input_rate_limit,
+            reverse_connection: params.reverse_connection,
+            websocket: params.websocket,
+            websocket_origins: params.websocket_origins,
+            serial: params.serial,
+            clipboard: params.clipboard,
+            pointer: console.pointer,
+            resize: console.resize,
+            keyboard_layout: params.keyboard_layout,
+            permissive: params.permissive,
+            handshake_timeout: params.handshake_timeout,
+            registry: params.registry,
+            view: console.view,
+            input: console.input,
         })
     }
 
@@ -111,16 +202,135 @@ fn run_inner(
             );
 
             let listener = PolledSocket::new(&driver, self.listener)?;
+            let encoder = vnc::EncoderPool::new(ENCODER_THREADS, ENCODER_QUEUE_DEPTH)
+                .context("failed to start VNC encoder threads")?;
+            let (serial, mut serial_output) = match self.serial {
+                Some(VncSerial {
+                    name,
+                    output,
+                    input,
+                }) => (
+                    Some(SerialMirror {
+                        name,
+                        input,
+                        history: VecDeque::new(),
+                    }),
+                    Some(output),
+                ),
+                None => (None, None),
+            };
+            let (clipboard, mut guest_text) = match self.clipboard {
+                Some(VncClipboard {
+                    client_text,
+                    guest_text,
+                }) => (
+                    Some(SharedClipboard {
+                        client_text,
+                        guest_text: None,
+                    }),
+                    Some(guest_text),
+                ),
+                None => (None, None),
+            };
+            let (pointer, mut pointer_updates) = match self.pointer {
+                Some(PointerSource { updates, enable }) => (
+                    Some(PointerMirror {
+                        enable,
+                        enabled: false,
+                        shape: None,
+                        visible: true,
+                    }),
+                    Some(updates),
+                ),
+                None => (None, None),
+            };
+            let (client_event_send, client_event_recv) = mesh::channel();
             let mut server = Server {
                 listener,
-                state: self.state,
+                encoder,
+                name: self.name,
+                file_transfer_dir: self.file_transfer_dir,
+                input_audit: self.input_audit,
+                max_frame_rate: self.max_frame_rate,
+                input_rate_limit: self.input_rate_limit,
+                reverse_connection: self.reverse_connection,
+                reverse_retry: Duration::ZERO,
+                websocket: self.websocket,
+                websocket_origins: self.websocket_origins,
+                preferences: PreferenceCache::default(),
+                errors: BTreeMap::new(),
+                keyboard_layout: self.keyboard_layout,
+                permissive: self.permissive,
+                quirks: vnc::Quirks::default(),
+                handshake_timeout: self.handshake_timeout,
+                registry: self.registry,
+                serial,
+                clipboard,
+                pointer,
+                resize: self.resize,
+                view: Arc::new(Mutex::new(SharedView {
+                    view: self.view,
+                    clients: BTreeMap::new(),
+                    requested_resize: None,
+                })),
+                input: self.input,
+                clients: BTreeMap::new(),
+                next_client_id: 0,
+                client_event_send,
+                client_event_recv,
             };
 
+            server.register();
+
+            let mut name_updates = self.name_updates;
+            enum Event<T> {
+                Rpc(T),
+                Rename(String),
+                SerialOutput(Vec<u8>),
+                GuestClipboard(String),
+                Pointer(PointerUpdate),
+            }
+
             let rpc = loop {
-                let r = futures::select! { // merge semantics
-                    r = rpc_recv.recv().fuse() => r,
+                let mut serial_data: OptionFuture<_> = serial_output
+                    .as_mut()
+                    .map(|output| output.select_next_some())
+                    .into();
+                let mut guest_clipboard: OptionFuture<_> = guest_text
+                    .as_mut()
+                    .map(|text| text.select_next_some())
+                    .into();
+                let mut pointer: OptionFuture<_> = pointer_updates
+                    .as_mut()
+                    .map(|updates| updates.select_next_some())
+                    .into();
+                let event = futures::select! { // merge semantics
+                    r = rpc_recv.recv().fuse() => Event::Rpc(r),
+                    name = name_updates.select_next_some() => Event::Rename(name),
+                    data = serial_data => Event::SerialOutput(data.unwrap()),
+                    text = guest_clipboard => Event::GuestClipboard(text.unwrap()),
+                    update = pointer => Event::Pointer(update.unwrap()),
                     r = server.process(&driver).fuse() => break r.map(|_| None)?,
                 };
+                let r = match event {
+                    Event::Rpc(r) => r,
+                    Event::Rename(name) => {
+                        server.rename(name);
+                        continue;
+                    }
+                    Event::SerialOutput(data) => {
+                        server.serial_output(data);
+                        continue;
+                    }
+                    Event::GuestClipboard(text) => {
+                        server.guest_clipboard(text);
+                        continue;
+                    }
+                    Event::Pointer(update) => {
+                        server.pointer_update(update);
+                        continue;
+                    }
+                };
                 match r {
                     Ok(message) => match message {
                         WorkerRpc::Stop => break None,
@@ -131,18 +341,60 @@ fn run_inner(
                 }
             };
             if let Some(rpc) = rpc {
-                let (view, input) = match server.state {
-                    State::Listening { view, input } => (view, input),
-                    State::Connected { task, abort, .. } => {
-                        drop(abort);
-                        task.await
-                    }
-                    State::Invalid => unreachable!(),
+                for client in std::mem::take(&mut server.clients).into_values() {
+                    drop(client.abort);
+                    client.task.await;
+                }
+                // The connection tasks held the only other references.
+                let view = Arc::into_inner(server.view)
+                    .expect("no clients are connected")
+                    .into_inner()
+                    .view;
+                let pointer = server
+                    .pointer
+                    .zip(pointer_updates)
+                    .map(|(pointer, updates)| PointerSource {
+                        updates,
+                        enable: pointer.enable,
+                    });
+                let console = ResolvedConsole {
+                    view,
+                    input: server.input,
+                    pointer,
+                    resize: server.resize,
                 };
+                let clipboard = server
+                    .clipboard
+                    .zip(guest_text)
+                    .map(|(clipboard, guest_text)| VncClipboard {
+                        client_text: clipboard.client_text,
+                        guest_text,
+                    });
                 let state = VncParameters {
                     listener: server.listener.into_inner(),
-                    framebuffer: view.0.access(),
-                    input_send: input.send,
+                    console: console.into_resource(),
+                    name: server.name,
+                    name_updates,
+                    file_transfer_dir: server.file_transfer_dir,
+                    input_audit: server.input_audit,
+                    max_frame_rate: server.max_frame_rate,
+                    input_rate_limit: server.input_rate_limit,
+                    reverse_connection: server.reverse_connection,
+                    websocket: server.websocket,
+                    websocket_origins: server.websocket_origins,
+                    serial: server
+                        .serial
+                        .zip(serial_output)
+                        .map(|(serial, output)| VncSerial {
+                            name: serial.name,
+                            output,
+                            input: serial.input,
+                        }),
+                    clipboard,
+                    keyboard_layout: server.keyboard_layout,
+                    permissive: server.permissive,
+                    handshake_timeout: server.handshake_timeout,
+                    registry: server.registry,
                 };
                 rpc.complete(Ok(state));
             }
@@ -151,102 +403,789 @@ fn run_inner(
     }
 }
 
+/// Connects to the viewer for a reverse connection, retrying until it
+/// succeeds. Never completes if there is no reverse connection configured.
+async fn connect_reverse(
+    driver: &LocalDriver,
+    config: Option<&ReverseConnection>,
+    retry: &mut Duration,
+) -> (PolledSocket<socket2::Socket>, String) {
+    let Some(config) = config else {
+        return std::future::pending().await;
+    };
+    let mut timer = PolledTimer::new(driver);
+    loop {
+        timer.sleep(*retry).await;
+        match reverse::connect(driver, config).await {
+            Ok(socket) => {
+                // Don't reconnect in a tight loop if the viewer accepts and
+                // then immediately closes connections.
+                *retry = REVERSE_RETRY_MIN;
+                return (socket, config.viewer.clone());
+            }
+            Err(err) => {
+                tracing::warn!(
+                    viewer = %config.viewer,
+                    error = err.as_error(),
+                    "failed to connect to VNC viewer"
+                );
+                *retry = (*retry * 2).clamp(REVERSE_RETRY_MIN, REVERSE_RETRY_MAX);
+            }
+        }
+    }
+}
+
+/// What a connection task returns when the client disconnects.
+struct Disconnected {
+    preferences: Option<vnc::ClientPreferences>,
+    /// The kind of error that ended the connection, if any.
+    error: Option<vnc::ErrorKind>,
+    quirks: vnc::Quirks,
+}
+
+/// The number of clients whose preferences are remembered.
+const MAX_REMEMBERED_CLIENTS: usize = 16;
+
+/// The preferences of recently disconnected clients, keyed by client identity
+/// and ordered from least to most recently used.
+#[derive(Default)]
+struct PreferenceCache(VecDeque<(String, vnc::ClientPreferences)>);
+
+impl PreferenceCache {
+    /// Removes and returns the preferences for `identity`. They are added back
+    /// when the client disconnects.
+    fn take(&mut self, identity: &str) -> Option<vnc::ClientPreferences> {
+        let index = self.0.iter().position(|(id, _)| id == identity)?;
+        self.0.remove(index).map(|(_, preferences)| preferences)
+    }
+
+    fn insert(&mut self, identity: String, preferences: vnc::ClientPreferences) {
+        self.0.retain(|(id, _)| *id != identity);
+        if self.0.len() == MAX_REMEMBERED_CLIENTS {
+            self.0.pop_front();
+        }
+        self.0.push_back((identity, preferences));
+    }
+}
+
+/// The amount of a mirrored serial port's output retained for new clients.
+const SERIAL_HISTORY_SIZE: usize = 16 * 1024;
+
+/// A serial port mirrored to VNC clients.
+struct SerialMirror {
+    name: String,
+    input: Option<mesh::Sender<Vec<u8>>>,
+    /// The port's recent output, sent to each new client.
+    history: VecDeque<u8>,
+}
+
+/// A clipboard shared between VNC clients and the guest.
+struct SharedClipboard {
+    client_text: mesh::Sender<String>,
+    /// The guest's latest clipboard text, sent to each new client.
+    guest_text: Option<String>,
+}
+
+/// The guest's pointer, drawn by VNC clients that support it.
+struct PointerMirror {
+    /// Asks the video device to send the pointer rather than have the guest
+    /// draw it.
+    enable: mesh::Sender<bool>,
+    /// Whether the video device has been asked to send the pointer.
+    enabled: bool,
+    /// The pointer's latest image.
+    shape: Option<vnc::Cursor>,
+    visible: bool,
+}
+
+/// The maximum number of clients that have completed the handshake at once.
+/// Further clients that agree to share the desktop are refused.
+const MAX_CLIENTS: usize = 8;
+
+/// The maximum number of connections still in the handshake at once. Further
+/// connections are refused until one of these completes or times out.
+const MAX_HANDSHAKES: usize = 8;
+
+/// How long a client can stop accepting data before it is disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of threads used to encode framebuffer updates.
+const ENCODER_THREADS: usize = 2;
+
+/// The number of encode jobs that can be queued before a connection waits.
+const ENCODER_QUEUE_DEPTH: usize = 2;
+
 struct Server<T: Listener> {
     listener: PolledSocket<T>,
-    state: State<T>,
+    encoder: vnc::EncoderPool,
+    name: String,
+    file_transfer_dir: Option<PathBuf>,
+    input_audit: bool,
+    max_frame_rate: u32,
+    input_rate_limit: Option<InputRateLimit>,
+    reverse_connection: Option<ReverseConnection>,
+    /// The delay before the next reverse connection attempt.
+    reverse_retry: Duration,
+    /// Whether clients accepted on `listener` connect over WebSocket.
+    websocket: bool,
+    /// The origins of the web pages allowed to connect over WebSocket.
+    websocket_origins: Vec<String>,
+    preferences: PreferenceCache,
+    /// The number of connections that ended with each kind of error.
+    errors: BTreeMap<vnc::ErrorKind, u64>,
+    keyboard_layout: KeyboardLayout,
+    permissive: bool,
+    /// The workarounds applied for all clients so far.
+    quirks: vnc::Quirks,
+    handshake_timeout: Duration,
+    /// Where to register the server's name and address.
+    registry: Option<mesh::Sender<VncRegistryRequest>>,
+    serial: Option<SerialMirror>,
+    clipboard: Option<SharedClipboard>,
+    pointer: Option<PointerMirror>,
+    /// Requests to change the guest's resolution, for clients that ask to
+    /// resize the desktop.
+    resize: Option<mesh::Sender<ResizeRpc>>,
+    view: Arc<Mutex<SharedView>>,
+    input: mesh::Sender<InputData>,
+    /// The connected clients, by ID, in the order they connected.
+    clients: BTreeMap<u64, Client>,
+    next_client_id: u64,
+    client_event_send: mesh::Sender<ClientEvent>,
+    client_event_recv: mesh::Receiver<ClientEvent>,
 }
 
+/// The delay before reconnecting to a viewer after a reverse connection ends
+/// or fails. The delay doubles with each consecutive failure, up to
+/// [`REVERSE_RETRY_MAX`].
+const REVERSE_RETRY_MIN: Duration = Duration::from_secs(1);
+
+/// The maximum delay between reverse connection attempts.
+const REVERSE_RETRY_MAX: Duration = Duration::from_secs(60);
+
 impl<T: Listener> Server<T> {
-    /// Runs the state machine forward, either advancing the current connection
-    /// task or waiting for a new connection.
+    /// Renames the desktop, updating the connected clients.
+    fn rename(&mut self, name: String) {
+        for client in self.clients.values() {
+            client.rename.send(name.clone());
+        }
+        self.name = name;
+        self.register();
+    }
+
+    /// Registers the server's name and address with the registry, if any.
+    fn register(&self) {
+        let Some(registry) = &self.registry else {
+            return;
+        };
+        match self.listener.get().local_addr() {
+            Ok(address) => registry.send(VncRegistryRequest::Register(VncRegistration {
+                name: self.name.clone(),
+                address: format!("{address:?}"),
+            })),
+            Err(err) => {
+                tracing::warn!(
+                    error = &err as &dyn std::error::Error,
+                    "failed to get VNC listener address, not registering"
+                );
+            }
+        }
+    }
+
+    /// Records output from the mirrored serial port and passes it on to the
+    /// connected clients.
+    fn serial_output(&mut self, data: Vec<u8>) {
+        let Some(serial) = &mut self.serial else {
+            return;
+        };
+        serial.history.extend(&data);
+        let excess = serial.history.len().saturating_sub(SERIAL_HISTORY_SIZE);
+        serial.history.drain(..excess);
+        for send in self
+            .clients
+            .values()
+            .filter_map(|client| client.serial.as_ref())
+        {
+            send.send(data.clone());
+        }
+    }
+
+    /// Records the guest's clipboard text and passes it on to the connected
+    /// clients.
+    fn guest_clipboard(&mut self, text: String) {
+        let Some(clipboard) = &mut self.clipboard else {
+            return;
+        };
+        for send in self
+            .clients
+            .values()
+            .filter_map(|client| client.clipboard.as_ref())
+        {
+            send.send(text.clone());
+        }
+        clipboard.guest_text = Some(text);
+    }
+
+    /// Records a change to the guest's pointer and passes the resulting cursor
+    /// on to the connected clients.
+    fn pointer_update(&mut self, update: PointerUpdate) {
+        let Some(pointer) = &mut self.pointer else {
+            return;
+        };
+        match update {
+            PointerUpdate::Shape(shape) => {
+                pointer.shape = Some(vnc::Cursor {
+                    width: shape.width,
+                    height: shape.height,
+                    hotspot_x: shape.hotspot_x,
+                    hotspot_y: shape.hotspot_y,
+                    pixels: shape.pixels,
+                });
+            }
+            PointerUpdate::Visible(visible) => pointer.visible = visible,
+        }
+        if let Some(shape) = &pointer.shape {
+            let cursor = pointer.visible.then(|| shape.clone());
+            for send in self
+                .clients
+                .values()
+                .filter_map(|client| client.cursor.as_ref())
+            {
+                send.send(cursor.clone());
+            }
+        }
+    }
+
+    /// Has the guest send its pointer rather than draw it only while every
+    /// connected client can draw it, so that no client is left without one.
+    fn update_pointer_enable(&mut self) {
+        let Some(pointer) = &mut self.pointer else {
+            return;
+        };
+        let enabled =
+            !self.clients.is_empty() && self.clients.values().all(|client| client.cursor_enabled);
+        if enabled != pointer.enabled {
+            pointer.enabled = enabled;
+            pointer.enable.send(enabled);
+            if !enabled {
+                // Hide the clients' copies of the pointer while the guest
+                // draws it. The guest sends it again once it is next enabled.
+                if pointer.shape.take().is_some() {
+                    for send in self
+                        .clients
+                        .values()
+                        .filter_map(|client| client.cursor.as_ref())
+                    {
+                        send.send(None);
+                    }
+                }
+                pointer.visible = true;
+            }
+        }
+    }
+
+    /// Runs the state machine forward, advancing the connection tasks and
+    /// accepting new connections.
+    ///
+    /// A client that agrees to share the desktop is added alongside the
+    /// connected ones, up to [`MAX_CLIENTS`]. Any other client replaces them,
+    /// so that a client that has stopped responding (or disappeared without
+    /// closing its connection) cannot keep everyone else out. Only clients
+    /// that complete the handshake count, so connecting without completing it
+    /// cannot disconnect anyone.
     ///
     /// This function's future can be dropped safely at any time without losing
     /// any data or connections.
     async fn process(&mut self, driver: &LocalDriver) -> anyhow::Result<()> {
-        loop {
-            match &mut self.state {
-                State::Listening { .. } => {
-                    // Accept the connection if one is really ready.
-                    let (socket, remote_addr) = self.listener.accept().await?;
-                    let socket = PolledSocket::new(driver, socket.into())?;
-
-                    tracing::info!(address = ?remote_addr, "VNC client connected");
-
-                    let (view, input) = if let State::Listening { view, input } =
-                        std::mem::replace(&mut self.state, State::Invalid)
-                    {
-                        (view, input)
-                    } else {
-                        unreachable!()
-                    };
+        enum Event {
+            /// A new client, and whether it connected over WebSocket.
+            Connected(PolledSocket<socket2::Socket>, String, bool),
+            Disconnected(u64, Disconnected),
+            Client(ClientEvent),
+            /// The video device reported damage to the framebuffer.
+            Damage,
+        }
 
-                    let mut vncserver = vnc::Server::new("HvLite VM".into(), socket, view, input);
-                    let mut timer = PolledTimer::new(driver);
-
-                    let (abort_send, abort_recv) = mesh::oneshot();
-                    let connection = Box::pin(async move {
-                        let updater = vncserver.updater();
-                        let update_task = async {
-                            // For now, just mark the framebuffer as updated
-                            // every 30ms (about 30 frames per second).
-                            loop {
-                                timer.sleep(Duration::from_millis(30)).await;
-                                updater.update();
-                            }
-                        };
-                        let r = futures::select! { // race semantics
-                            r = vncserver.run().fuse() => r.context("VNC error"),
-                            _ = abort_recv.fuse() => Err(anyhow!("VNC connection aborted")),
-                            _ = update_task.fuse() => unreachable!(),
-                        };
-                        match r {
-                            Ok(_) => {
-                                tracing::info!("VNC client disconnected");
-                            }
-                            Err(err) => {
-                                tracing::error!(error = err.as_error(), "VNC client error");
-                            }
+        loop {
+            // Connect to the viewer, if configured to, while no client is
+            // connected.
+            let event = {
+                let mut reverse: OptionFuture<_> = self
+                    .clients
+                    .is_empty()
+                    .then(|| {
+                        Box::pin(
+                            connect_reverse(
+                                driver,
+                                self.reverse_connection.as_ref(),
+                                &mut self.reverse_retry,
+                            )
+                            .fuse(),
+                        )
+                    })
+                    .into();
+                // Collect damage as it is reported, so that it does not pile
+                // up while no client is taking it.
+                let view = &self.view;
+                let damage = std::future::poll_fn(|cx| view.lock().view.poll_damage(cx));
+                let clients = &mut self.clients;
+                let disconnected = std::future::poll_fn(|cx| {
+                    for (&id, client) in clients.iter_mut() {
+                        if let Poll::Ready(disconnected) = client.task.as_mut().poll(cx) {
+                            return Poll::Ready((id, disconnected));
                         }
-                        vncserver.done()
-                    });
-                    self.state = State::Connected {
-                        remote_addr,
-                        task: connection,
-                        abort: abort_send,
+                    }
+                    Poll::Pending
+                });
+                futures::select! { // merge semantics
+                    r = self.listener.accept().fuse() => {
+                        let (socket, remote_addr) = r?;
+                        Event::Connected(
+                            PolledSocket::new(driver, socket.into())?,
+                            format!("{remote_addr:?}"),
+                            self.websocket,
+                        )
+                    }
+                    r = reverse => {
+                        let (socket, remote_addr) = r.unwrap();
+                        Event::Connected(socket, remote_addr, false)
+                    }
+                    (id, disconnected) = disconnected.fuse() => Event::Disconnected(id, disconnected),
+                    event = self.client_event_recv.select_next_some() => Event::Client(event),
+                    () = damage.fuse() => Event::Damage,
+                }
+            };
+            match event {
+                Event::Connected(socket, remote_addr, websocket) => {
+                    tracing::info!(address = %remote_addr, "VNC client connected");
+                    let handshakes = self.clients.values().filter(|c| !c.joined).count();
+                    if handshakes == MAX_HANDSHAKES {
+                        tracing::warn!(
+                            address = %remote_addr,
+                            "refusing VNC client: too many connections in progress"
+                        );
+                        continue;
+                    }
+                    self.connect(driver, socket, remote_addr, websocket);
+                }
+                Event::Disconnected(id, disconnected) => {
+                    let client = self.clients.remove(&id).unwrap();
+                    self.disconnected(client.identity, disconnected);
+                }
+                Event::Client(ClientEvent::Joined(id, shared)) => {
+                    let joined = self.clients.values().filter(|c| c.joined).count();
+                    let Some(client) = self.clients.get_mut(&id) else {
+                        continue;
                     };
+                    if shared {
+                        if joined == MAX_CLIENTS {
+                            self.disconnect(id, "too many clients").await;
+                            continue;
+                        }
+                        client.joined = true;
+                    } else {
+                        client.joined = true;
+                        let others = self
+                            .clients
+                            .keys()
+                            .copied()
+                            .filter(|&other| other != id)
+                            .collect::<Vec<_>>();
+                        for other in others {
+                            self.disconnect(other, "another client wants the desktop to itself")
+                                .await;
+                        }
+                    }
                 }
-                State::Connected { task, .. } => {
-                    let (view, input) = task.await;
-                    self.state = State::Listening { view, input };
+                Event::Damage => self.view.lock().collect_damage(),
+                Event::Client(ClientEvent::CursorEnabled(id, enabled)) => {
+                    if let Some(client) = self.clients.get_mut(&id) {
+                        client.cursor_enabled = enabled;
+                        self.update_pointer_enable();
+                    }
                 }
-                State::Invalid => unreachable!(),
             }
         }
     }
+
+    /// Disconnects a client, logging `reason`.
+    async fn disconnect(&mut self, id: u64, reason: &str) {
+        let Some(Client {
+            remote_addr,
+            identity,
+            task,
+            abort,
+            ..
+        }) = self.clients.remove(&id)
+        else {
+            return;
+        };
+        tracing::info!(address = %remote_addr, reason, "disconnecting VNC client");
+        // The connection task finishes as soon as it is polled after the
+        // abort, so this does not actually wait.
+        drop(abort);
+        let disconnected = task.await;
+        self.disconnected(identity, disconnected);
+    }
+
+    /// Remembers the preferences of the client with `identity` that just
+    /// disconnected, and counts the error that ended the connection.
+    fn disconnected(&mut self, identity: Option<String>, disconnected: Disconnected) {
+        let Disconnected {
+            preferences,
+            error,
+            quirks,
+        } = disconnected;
+        if let Some(kind) = error {
+            *self.errors.entry(kind).or_default() += 1;
+        }
+        self.quirks.add(&quirks);
+        if let (Some(identity), Some(preferences)) = (identity, preferences) {
+            self.preferences.insert(identity, preferences);
+        }
+        self.update_pointer_enable();
+    }
+
+    /// Starts the connection task for a newly accepted client, relaying its
+    /// connection from WebSocket framing if `websocket` is set.
+    fn connect(
+        &mut self,
+        driver: &LocalDriver,
+        socket: PolledSocket<socket2::Socket>,
+        remote_addr: String,
+        websocket: bool,
+    ) {
+        let id = self.next_client_id;
+        self.next_client_id += 1;
+        let mut input = VncInput::new(self.input.clone());
+        if self.input_audit {
+            input.audit = Some(InputAudit::new(remote_addr.clone()));
+        }
+        // Identify the client by IP address, so that its preferences survive
+        // reconnecting from a different port. Clients of Unix socket and vsock
+        // listeners have no address to tell them apart, so their preferences
+        // are not remembered.
+        let identity = socket
+            .get()
+            .peer_addr()
+            .ok()
+            .and_then(|addr| addr.as_socket())
+            .map(|addr| addr.ip().to_string());
+        let (socket, relay) = if websocket {
+            match websocket::socket_pair(driver) {
+                Ok((server_end, relay_end)) => (server_end, Some((socket, relay_end))),
+                Err(err) => {
+                    tracing::error!(
+                        address = %remote_addr,
+                        error = &err as &dyn std::error::Error,
+                        "failed to create WebSocket relay"
+                    );
+                    return;
+                }
+            }
+        } else {
+            (socket, None)
+        };
+        let view = ClientView::new(self.view.clone(), id);
+        let mut vncserver = vnc::Server::new(self.name.clone(), socket, view, input);
+        vncserver.set_encoder_pool(self.encoder.clone());
+        vncserver.set_keyboard_layout(self.keyboard_layout);
+        vncserver.set_permissive(self.permissive);
+        vncserver.set_handshake_timeout(PolledTimer::new(driver), self.handshake_timeout);
+        vncserver.set_write_timeout(PolledTimer::new(driver), WRITE_TIMEOUT);
+        if let Some(limit) = self.input_rate_limit {
+            vncserver.set_input_rate_limit(limit);
+        }
+        if let Some(preferences) = identity
+            .as_deref()
+            .and_then(|identity| self.preferences.take(identity))
+        {
+            vncserver.set_preferences(preferences);
+        }
+        let client_events = self.client_event_send.clone();
+        vncserver.set_sharing(Box::new(move |shared| {
+            client_events.send(ClientEvent::Joined(id, shared));
+        }));
+        let (rename_send, rename_recv) = mesh::channel();
+        vncserver.set_name_updates(rename_recv.boxed());
+        if let Some(dir) = &self.file_transfer_dir {
+            vncserver.set_file_transfer_root(dir.clone());
+        }
+        let serial_send = self.serial.as_ref().map(|serial| {
+            let (send, recv) = mesh::channel();
+            if !serial.history.is_empty() {
+                send.send(serial.history.iter().copied().collect());
+            }
+            let input = serial.input.clone().map(|input| {
+                Box::new(move |data| input.send(data)) as Box<dyn FnMut(Vec<u8>) + Send>
+            });
+            vncserver.set_serial(recv.boxed(), input);
+            send
+        });
+        let clipboard_send = self.clipboard.as_ref().map(|clipboard| {
+            let (send, recv) = mesh::channel();
+            if let Some(text) = &clipboard.guest_text {
+                send.send(text.clone());
+            }
+            let client_text = clipboard.client_text.clone();
+            vncserver.set_clipboard(recv.boxed(), Box::new(move |text| client_text.send(text)));
+            send
+        });
+        let cursor_send = self.pointer.is_some().then(|| {
+            let (send, recv) = mesh::channel();
+            let client_events = self.client_event_send.clone();
+            vncserver.set_cursor(
+                recv.boxed(),
+                Box::new(move |enabled| {
+                    client_events.send(ClientEvent::CursorEnabled(id, enabled))
+                }),
+            );
+            send
+        });
+        if let Some(resize) = self.resize.clone() {
+            // The guest may pick a different resolution, which the client
+            // sees when the framebuffer changes, so there is no need to wait
+            // for the result.
+            let view = self.view.clone();
+            vncserver.set_desktop_resize(Box::new(move |width, height| {
+                view.lock().requested_resize = Some((id, (width, height)));
+                resize.send(Rpc::detached((width, height)))
+            }));
+        }
+        let mut timer = PolledTimer::new(driver);
+        let frame_interval = Duration::from_secs(1) / self.max_frame_rate.max(1);
+        let relay_timer = PolledTimer::new(driver);
+        let websocket_origins = self.websocket_origins.clone();
+        let handshake_timeout = self.handshake_timeout;
+
+        let (abort_send, abort_recv) = mesh::oneshot();
+        let address = remote_addr.clone();
+        let connection = Box::pin(async move {
+            let updater = vncserver.updater();
+            let update_task = async {
+                // Pace updates to the configured frame rate. The server only
+                // sends the regions changed since the previous update, so
+                // changes made between ticks are coalesced.
+                loop {
+                    timer.sleep(frame_interval).await;
+                    updater.update();
+                }
+            };
+            let relay = async {
+                match relay {
+                    Some((client, server)) => {
+                        websocket::relay(
+                            client,
+                            server,
+                            &websocket_origins,
+                            relay_timer,
+                            handshake_timeout,
+                        )
+                        .await
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            let r = futures::select! { // race semantics
+                r = vncserver.run().fuse() => r.map_err(Some),
+                r = relay.fuse() => {
+                    // The server only sees the client go away once the relay
+                    // ends, so treat this as the client disconnecting.
+                    if let Err(err) = r {
+                        tracing::warn!(
+                            address = %address,
+                            error = err.as_error(),
+                            "VNC WebSocket error"
+                        );
+                    }
+                    Ok(())
+                }
+                _ = abort_recv.fuse() => Err(None),
+                _ = update_task.fuse() => unreachable!(),
+            };
+            let error = match r {
+                Ok(()) => {
+                    tracing::info!(address = %address, "VNC client disconnected");
+                    None
+                }
+                Err(Some(err)) => {
+                    // Include the reason the client was shown, if any, so
+                    // that reports from users can be matched to the log.
+                    tracing::error!(
+                        address = %address,
+                        kind = err.kind().as_str(),
+                        error = &err as &dyn std::error::Error,
+                        client_reason = vncserver.refusal_reason(),
+                        "VNC client error"
+                    );
+                    Some(err.kind())
+                }
+                Err(None) => {
+                    tracing::error!(address = %address, "VNC connection aborted");
+                    None
+                }
+            };
+            let preferences = vncserver.preferences().cloned();
+            let quirks = vncserver.quirks().clone();
+            let dropped_input = vncserver.dropped_input();
+            let (_, mut input) = vncserver.done();
+            // Don't leave keys or buttons stuck down in the guest if the
+            // client went away mid-press.
+            input.release_all();
+            if let Some(audit) = input.audit.take() {
+                audit.log();
+            }
+            if dropped_input != 0 {
+                tracing::warn!(
+                    address = %address,
+                    dropped = dropped_input,
+                    "VNC client input exceeded the rate limit"
+                );
+            }
+            Disconnected {
+                preferences,
+                error,
+                quirks,
+            }
+        });
+        self.clients.insert(
+            id,
+            Client {
+                remote_addr,
+                identity,
+                task: connection,
+                abort: abort_send,
+                rename: rename_send,
+                serial: serial_send,
+                clipboard: clipboard_send,
+                cursor: cursor_send,
+                cursor_enabled: false,
+                joined: false,
+            },
+        );
+        // Have the guest draw its pointer until the new client asks to draw
+        // it, which also gets the guest to send it again.
+        self.update_pointer_enable();
+    }
 }
 
 impl<T: Listener> inspect::Inspect for Server<T> {
     fn inspect(&self, req: inspect::Request<'_>) {
         let mut resp = req.respond();
-        resp.display_debug("local_addr", &self.listener.get().local_addr().unwrap());
-        let state = match &self.state {
-            State::Listening { .. } => "listening",
-            State::Connected { remote_addr, .. } => {
-                resp.display_debug("remote_addr", &remote_addr);
-                "connected"
-            }
-            State::Invalid => unreachable!(),
+        if let Ok(address) = self.listener.get().local_addr() {
+            resp.display_debug("local_addr", &address);
+        }
+        let state = if self.clients.is_empty() {
+            "listening"
+        } else {
+            "connected"
         };
-        resp.field("state", state);
+        resp.field("state", state)
+            .child("clients", |req| {
+                let mut resp = req.respond();
+                for (id, client) in &self.clients {
+                    resp.field(&id.to_string(), &client.remote_addr);
+                }
+            })
+            .field("name", &self.name)
+            .field(
+                "file_transfer_dir",
+                self.file_transfer_dir
+                    .as_ref()
+                    .map(|dir| dir.display().to_string()),
+            )
+            .field("input_audit", self.input_audit)
+            .field("max_frame_rate", self.max_frame_rate)
+            .field(
+                "input_rate_limit",
+                self.input_rate_limit.map(|l| l.events_per_second),
+            )
+            .field(
+                "reverse_connection",
+                self.reverse_connection.as_ref().map(|c| &c.viewer),
+            )
+            .field("websocket", self.websocket)
+            .field("websocket_origins", self.websocket_origins.join(","))
+            .field("remembered_clients", self.preferences.0.len())
+            .child("errors", |req| {
+                let mut resp = req.respond();
+                for (kind, count) in &self.errors {
+                    resp.field(kind.as_str(), count);
+                }
+            })
+            .field("serial", self.serial.as_ref().map(|serial| &serial.name))
+            .field("clipboard", self.clipboard.is_some())
+            .field("pointer", self.pointer.is_some())
+            .field("resize", self.resize.is_some())
+            .field("keyboard_layout", inspect::AsDebug(self.keyboard_layout))
+            .field("permissive", self.permissive)
+            .field(
+                "handshake_timeout",
+                inspect::AsDebug(self.handshake_timeout),
+            )
+            .child("quirks", |req| {
+                let vnc::Quirks {
+                    client_init_skipped,
+                    unsolicited_updates,
+                    missing_desktop_size,
+                } = &self.quirks;
+                req.respond()
+                    .field("client_init_skipped", client_init_skipped)
+                    .field("unsolicited_updates", unsolicited_updates)
+                    .field("missing_desktop_size", missing_desktop_size);
+            });
     }
 }
 
 struct VncInput {
     send: mesh::Sender<InputData>,
+    /// Keys currently held down by the client.
+    pressed_keys: BTreeSet<u16>,
+    /// The last pointer state sent by the client.
+    pointer: TabletData,
+    /// Input statistics for the current connection, if auditing is enabled.
+    audit: Option<InputAudit>,
+}
+
+impl VncInput {
+    fn new(send: mesh::Sender<InputData>) -> Self {
+        Self {
+            send,
+            pressed_keys: BTreeSet::new(),
+            pointer: TabletData {
+                button_mask: 0,
+                x: 0,
+                y: 0,
+                width: 0,
+                height: 0,
+            },
+            audit: None,
+        }
+    }
+
+    /// Releases any keys and buttons the client left pressed.
+    fn release_all(&mut self) {
+        for code in std::mem::take(&mut self.pressed_keys) {
+            self.send
+                .send(InputData::Keyboard(KeyboardData { code, make: false }));
+        }
+        if self.pointer.button_mask != 0 {
+            self.pointer.button_mask = 0;
+            self.send.send(InputData::Tablet(self.pointer));
+        }
+    }
 }
 
 impl vnc::Input for VncInput {
     fn key(&mut self, scancode: u16, is_down: bool) {
+        if is_down {
+            self.pressed_keys.insert(scancode);
+            if let Some(audit) = &mut self.audit {
+                audit.key(&self.pressed_keys, scancode);
+            }
+        } else {
+            self.pressed_keys.remove(&scancode);
+        }
         // TODO: need some kind of backpressure
         self.send.send(InputData::Keyboard(KeyboardData {
             code: scancode,
@@ -254,20 +1193,436 @@ fn key(&mut self, scancode: u16, is_down: bool) {
         }));
     }
 
-    fn mouse(&mut self, button_mask: u8, x: u16, y: u16) {
-        self.send
-            .send(InputData::Mouse(MouseData { button_mask, x, y }));
+    fn mouse(&mut self, button_mask: u8, x: u16, y: u16, width: u16, height: u16) {
+        if let Some(audit) = &mut self.audit {
+            audit.pointer();
+        }
+        self.pointer = TabletData {
+            button_mask,
+            x,
+            y,
+            width,
+            height,
+        };
+        self.send.send(InputData::Tablet(self.pointer));
     }
 }
 
-struct ViewWrapper(framebuffer::View);
+/// Per-connection input statistics, logged when the connection ends.
+///
+/// This deliberately records only counts and timing, never which keys were
+/// typed, so that the log does not capture anything the user entered.
+struct InputAudit {
+    remote_addr: String,
+    connected: Instant,
+    first_input: Option<Instant>,
+    last_input: Option<Instant>,
+    key_presses: u64,
+    pointer_events: u64,
+    ctrl_alt_del: u64,
+}
+
+impl InputAudit {
+    fn new(remote_addr: String) -> Self {
+        Self {
+            remote_addr,
+            connected: Instant::now(),
+            first_input: None,
+            last_input: None,
+            key_presses: 0,
+            pointer_events: 0,
+            ctrl_alt_del: 0,
+        }
+    }
+
+    fn input(&mut self) {
+        let now = Instant::now();
+        self.first_input.get_or_insert(now);
+        self.last_input = Some(now);
+    }
 
-impl vnc::Framebuffer for ViewWrapper {
+    /// Records a key press, given the set of keys now held down (including
+    /// `scancode`).
+    fn key(&mut self, pressed_keys: &BTreeSet<u16>, scancode: u16) {
+        const CTRL: [u16; 2] = [0x1d, 0xe01d];
+        const ALT: [u16; 2] = [0x38, 0xe038];
+        const DELETE: [u16; 2] = [0x53, 0xe053];
+
+        self.input();
+        self.key_presses += 1;
+        let held = |codes: [u16; 2]| codes.iter().any(|code| pressed_keys.contains(code));
+        if DELETE.contains(&scancode) && held(CTRL) && held(ALT) {
+            self.ctrl_alt_del += 1;
+        }
+    }
+
+    fn pointer(&mut self) {
+        self.input();
+        self.pointer_events += 1;
+    }
+
+    fn log(&self) {
+        let since_connect = |t: Option<Instant>| t.map(|t| t - self.connected);
+        tracing::info!(
+            address = %self.remote_addr,
+            duration = ?self.connected.elapsed(),
+            first_input = ?since_connect(self.first_input),
+            last_input = ?since_connect(self.last_input),
+            key_presses = self.key_presses,
+            pointer_events = self.pointer_events,
+            ctrl_alt_del = self.ctrl_alt_del,
+            "VNC client input audit"
+        );
+    }
+}
+
+/// The number of damaged regions kept for a client before they are merged
+/// into one, so that a client that is slow to take updates does not use
+/// unbounded memory.
+const MAX_PENDING_DAMAGE: usize = 256;
+
+/// The guest's framebuffer, shared by the connected clients.
+struct SharedView {
+    view: framebuffer::View,
+    /// The changes not yet seen by each client, by client ID.
+    clients: BTreeMap<u64, ClientChanges>,
+    /// The client that last asked for a resolution, and the resolution, so
+    /// that the clients can be told who changed it.
+    requested_resize: Option<(u64, (u16, u16))>,
+}
+
+#[derive(Default)]
+struct ClientChanges {
+    damage: Vec<vnc::Rect>,
+    /// Whether the whole framebuffer must be treated as changed, because the
+    /// video device did not say what changed.
+    all_damaged: bool,
+    format_changed: bool,
+}
+
+impl SharedView {
+    /// Passes the damage reported by the video device on to every client.
+    fn collect_damage(&mut self) {
+        let clamp = |n: u32| n.try_into().unwrap_or(u16::MAX);
+        let Some(damage) = self.view.take_damage() else {
+            // The whole framebuffer is treated as changed, so there is no
+            // need to remember what was damaged before.
+            for changes in self.clients.values_mut() {
+                changes.damage.clear();
+                changes.all_damaged = true;
+            }
+            return;
+        };
+        let damage = damage
+            .into_iter()
+            .map(|rect| vnc::Rect {
+                x: clamp(rect.x),
+                y: clamp(rect.y),
+                width: clamp(rect.width),
+                height: clamp(rect.height),
+            })
+            .collect::<Vec<_>>();
+        for changes in self.clients.values_mut() {
+            changes.add_damage(&damage);
+        }
+    }
+}
+
+impl ClientChanges {
+    fn add_damage(&mut self, rects: &[vnc::Rect]) {
+        self.damage.extend_from_slice(rects);
+        if self.damage.len() > MAX_PENDING_DAMAGE {
+            let bounds = self
+                .damage
+                .iter()
+                .copied()
+                .reduce(|a, b| {
+                    let x = a.x.min(b.x);
+                    let y = a.y.min(b.y);
+                    let right = (a.x as u32 + a.width as u32).max(b.x as u32 + b.width as u32);
+                    let bottom = (a.y as u32 + a.height as u32).max(b.y as u32 + b.height as u32);
+                    vnc::Rect {
+                        x,
+                        y,
+                        width: (right - x as u32).try_into().unwrap_or(u16::MAX),
+                        height: (bottom - y as u32).try_into().unwrap_or(u16::MAX),
+                    }
+                })
+                .unwrap();
+            self.damage = vec![bounds];
+        }
+    }
+}
+
+/// A client's view of the [`SharedView`].
+///
+/// The underlying view reports each change once, so changes are handed out to
+/// every client, to be taken when each is ready for its next update.
+struct ClientView {
+    shared: Arc<Mutex<SharedView>>,
+    id: u64,
+}
+
+impl ClientView {
+    fn new(shared: Arc<Mutex<SharedView>>, id: u64) -> Self {
+        // A new client is sent the whole framebuffer anyway, so it starts with
+        // no changes.
+        shared.lock().clients.insert(id, ClientChanges::default());
+        Self { shared, id }
+    }
+}
+
+impl Drop for ClientView {
+    fn drop(&mut self) {
+        self.shared.lock().clients.remove(&self.id);
+    }
+}
+
+impl vnc::Framebuffer for ClientView {
     fn read_line(&mut self, line: u16, data: &mut [u8]) {
-        self.0.read_line(line, data)
+        self.shared.lock().view.read_line(line, data)
+    }
+
+    fn read_line_at(&mut self, line: u16, x: u16, data: &mut [u8]) {
+        self.shared.lock().view.read_line_at(line, x, data)
     }
 
     fn resolution(&mut self) -> (u16, u16) {
-        self.0.resolution()
+        let mut shared = self.shared.lock();
+        let resolution = shared.view.resolution();
+        if shared.view.take_format_change() {
+            for changes in shared.clients.values_mut() {
+                changes.format_changed = true;
+            }
+        }
+        resolution
+    }
+
+    fn take_damage(&mut self) -> Option<Vec<vnc::Rect>> {
+        let mut shared = self.shared.lock();
+        shared.collect_damage();
+        let changes = shared.clients.get_mut(&self.id).unwrap();
+        let damage = std::mem::take(&mut changes.damage);
+        (!std::mem::take(&mut changes.all_damaged)).then_some(damage)
+    }
+
+    fn take_format_change(&mut self) -> bool {
+        std::mem::take(
+            &mut self
+                .shared
+                .lock()
+                .clients
+                .get_mut(&self.id)
+                .unwrap()
+                .format_changed,
+        )
+    }
+
+    fn resize_reason(&mut self) -> vnc::ResizeReason {
+        let mut shared = self.shared.lock();
+        let resolution = shared.view.resolution();
+        match shared.requested_resize {
+            Some((id, requested)) if requested == resolution => {
+                if id == self.id {
+                    vnc::ResizeReason::Client
+                } else {
+                    vnc::ResizeReason::OtherClient
+                }
+            }
+            _ => vnc::ResizeReason::Server,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientView;
+    use super::MAX_PENDING_DAMAGE;
+    use super::SharedView;
+    use framebuffer::FRAMEBUFFER_SIZE;
+    use framebuffer::FramebufferDevice;
+    use framebuffer::FramebufferLocalControl;
+    use guestmem::MappableGuestMemory;
+    use guestmem::MappedMemoryRegion;
+    use guestmem::MemoryMapper;
+    use pal_async::local::block_on;
+    use parking_lot::Mutex;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use video_core::DamageRect;
+    use video_core::FramebufferControl;
+    use video_core::FramebufferFormat;
+    use vnc::Framebuffer;
+    use vnc::ResizeReason;
+
+    /// The framebuffer is never mapped into a guest.
+    struct NoMapper;
+
+    impl MemoryMapper for NoMapper {
+        fn new_region(
+            &self,
+            _len: usize,
+            _debug_name: String,
+        ) -> std::io::Result<(Box<dyn MappableGuestMemory>, Arc<dyn MappedMemoryRegion>)> {
+            unreachable!()
+        }
+    }
+
+    /// Returns a framebuffer device in its default 1024x768 format, and the
+    /// view shared by its clients.
+    fn shared_view() -> (FramebufferDevice, Arc<Mutex<SharedView>>) {
+        let vram = sparse_mmap::alloc_shared_memory(FRAMEBUFFER_SIZE).unwrap();
+        let (fb, access) = framebuffer::framebuffer(vram, FRAMEBUFFER_SIZE, 0).unwrap();
+        let device = FramebufferDevice::new(Box::new(NoMapper), fb, None).unwrap();
+        let shared = Arc::new(Mutex::new(SharedView {
+            view: access.view().unwrap(),
+            clients: BTreeMap::new(),
+            requested_resize: None,
+        }));
+        (device, shared)
+    }
+
+    fn format(width: usize, height: usize, offset: usize) -> FramebufferFormat {
+        FramebufferFormat {
+            width,
+            height,
+            bytes_per_line: width * 4,
+            offset,
+        }
+    }
+
+    fn damage(control: &mut FramebufferLocalControl, rects: &[(u32, u32)]) {
+        let rects = rects
+            .iter()
+            .map(|&(x, y)| DamageRect {
+                x,
+                y,
+                width: 1,
+                height: 1,
+            })
+            .collect::<Vec<_>>();
+        block_on(FramebufferControl::damage(control, &rects));
+    }
+
+    fn rect(x: u16, y: u16, width: u16, height: u16) -> vnc::Rect {
+        vnc::Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn format_change() {
+        let (device, shared) = shared_view();
+        let mut a = ClientView::new(shared.clone(), 0);
+        let mut b = ClientView::new(shared.clone(), 1);
+
+        // The first format counts as a change, seen by both clients.
+        assert_eq!(a.resolution(), (1024, 768));
+        assert!(a.take_format_change());
+        assert!(!a.take_format_change());
+        assert!(b.take_format_change());
+
+        // So does moving the framebuffer without changing its resolution.
+        device.control().set_format(format(1024, 768, 4096));
+        assert_eq!(b.resolution(), (1024, 768));
+        assert!(b.take_format_change());
+        assert!(a.take_format_change());
+
+        device.control().set_format(format(800, 600, 0));
+        assert_eq!(a.resolution(), (800, 600));
+        assert_eq!(b.resolution(), (800, 600));
+        assert!(a.take_format_change());
+        assert!(b.take_format_change());
+    }
+
+    #[test]
+    fn damage_to_every_client() {
+        let (device, shared) = shared_view();
+        let mut control = device.control();
+        let mut a = ClientView::new(shared.clone(), 0);
+        let mut b = ClientView::new(shared.clone(), 1);
+        a.resolution();
+
+        damage(&mut control, &[(1, 2)]);
+        assert_eq!(a.take_damage(), Some(vec![rect(1, 2, 1, 1)]));
+        damage(&mut control, &[(3, 4)]);
+        assert_eq!(a.take_damage(), Some(vec![rect(3, 4, 1, 1)]));
+        assert_eq!(a.take_damage(), Some(vec![]));
+        assert_eq!(
+            b.take_damage(),
+            Some(vec![rect(1, 2, 1, 1), rect(3, 4, 1, 1)])
+        );
+
+        // Until the video device reports damage in a new format, everything
+        // has changed.
+        device.control().set_format(format(800, 600, 0));
+        a.resolution();
+        assert_eq!(a.take_damage(), None);
+        assert_eq!(b.take_damage(), None);
+        damage(&mut control, &[(5, 6)]);
+        // `b` found everything changed after `a` last took its damage.
+        assert_eq!(a.take_damage(), None);
+        assert_eq!(b.take_damage(), Some(vec![rect(5, 6, 1, 1)]));
+    }
+
+    #[test]
+    fn pending_damage_merged() {
+        let (device, shared) = shared_view();
+        let mut control = device.control();
+        let mut a = ClientView::new(shared.clone(), 0);
+        let mut b = ClientView::new(shared.clone(), 1);
+        a.resolution();
+
+        // While `b` takes no updates, its pending damage grows until it is
+        // merged into one region.
+        let half = (MAX_PENDING_DAMAGE / 2) as u32;
+        damage(
+            &mut control,
+            &(0..half).map(|i| (i, 10)).collect::<Vec<_>>(),
+        );
+        assert_eq!(a.take_damage().unwrap().len(), half as usize);
+        damage(
+            &mut control,
+            &(0..half).map(|i| (20, i)).collect::<Vec<_>>(),
+        );
+        assert_eq!(a.take_damage().unwrap().len(), half as usize);
+        assert_eq!(shared.lock().clients[&1].damage.len(), 2 * half as usize);
+        damage(&mut control, &[(300, 400)]);
+        assert_eq!(b.take_damage(), Some(vec![rect(0, 0, 301, 401)]));
+    }
+
+    #[test]
+    fn drop_client() {
+        let (_device, shared) = shared_view();
+        let a = ClientView::new(shared.clone(), 0);
+        let b = ClientView::new(shared.clone(), 1);
+        drop(a);
+        assert_eq!(shared.lock().clients.keys().collect::<Vec<_>>(), [&1]);
+        drop(b);
+        assert!(shared.lock().clients.is_empty());
+    }
+
+    #[test]
+    fn resize_reason() {
+        let (device, shared) = shared_view();
+        let mut a = ClientView::new(shared.clone(), 0);
+        let mut b = ClientView::new(shared.clone(), 1);
+
+        // A resolution asked for by a client is reported as such.
+        shared.lock().requested_resize = Some((0, (800, 600)));
+        device.control().set_format(format(800, 600, 0));
+        assert_eq!(a.resolution(), (800, 600));
+        assert_eq!(a.resize_reason(), ResizeReason::Client);
+        assert_eq!(b.resolution(), (800, 600));
+        assert_eq!(b.resize_reason(), ResizeReason::OtherClient);
+
+        // Anything else was changed by the guest.
+        device.control().set_format(format(640, 480, 0));
+        assert_eq!(a.resolution(), (640, 480));
+        assert_eq!(a.resize_reason(), ResizeReason::Server);
+        assert_eq!(b.resize_reason(), ResizeReason::Server);
     }
 }
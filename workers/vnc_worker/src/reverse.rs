@@ -0,0 +1,342 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Outbound connections to listening VNC viewers, optionally through an HTTP
+//! CONNECT or SOCKS5 proxy.
+
+use anyhow::Context;
+use anyhow::anyhow;
+use futures::AsyncReadExt;
+use futures::AsyncWriteExt;
+use futures::FutureExt;
+use pal_async::local::LocalDriver;
+use pal_async::socket::PolledSocket;
+use pal_async::timer::PolledTimer;
+use std::net::IpAddr;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+use vnc_worker_defs::ReverseConnection;
+use vnc_worker_defs::VncProxy;
+
+/// The time allowed to connect and complete any proxy handshake.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The limit on the size of an HTTP proxy's response headers.
+const MAX_HTTP_RESPONSE: usize = 8192;
+
+/// Connects to the viewer described by `config`.
+pub(crate) async fn connect(
+    driver: &LocalDriver,
+    config: &ReverseConnection,
+) -> anyhow::Result<PolledSocket<socket2::Socket>> {
+    let mut timer = PolledTimer::new(driver);
+    futures::select! { // race semantics
+        r = connect_inner(driver, config).fuse() => r,
+        _ = timer.sleep(CONNECT_TIMEOUT).fuse() => Err(anyhow!("timed out")),
+    }
+}
+
+async fn connect_inner(
+    driver: &LocalDriver,
+    config: &ReverseConnection,
+) -> anyhow::Result<PolledSocket<socket2::Socket>> {
+    match &config.proxy {
+        None => connect_tcp(driver, &config.viewer).await,
+        Some(VncProxy::HttpConnect(proxy)) => {
+            let mut socket = connect_tcp(driver, proxy)
+                .await
+                .context("failed to connect to HTTP proxy")?;
+            http_connect(&mut socket, &config.viewer).await?;
+            Ok(socket)
+        }
+        Some(VncProxy::Socks5(proxy)) => {
+            let mut socket = connect_tcp(driver, proxy)
+                .await
+                .context("failed to connect to SOCKS5 proxy")?;
+            socks5_connect(&mut socket, &config.viewer).await?;
+            Ok(socket)
+        }
+    }
+}
+
+/// Splits `host:port`, removing the brackets from an IPv6 host.
+///
+/// IPv6 hosts must be bracketed (`[::1]:5500`), since otherwise the port
+/// cannot be told apart from the address.
+fn split_host_port(address: &str) -> anyhow::Result<(&str, u16)> {
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        let (host, port) = rest
+            .split_once(']')
+            .with_context(|| format!("missing ']' in {address}"))?;
+        let port = port
+            .strip_prefix(':')
+            .with_context(|| format!("missing port in {address}"))?;
+        (host, port)
+    } else {
+        let (host, port) = address
+            .rsplit_once(':')
+            .with_context(|| format!("missing port in {address}"))?;
+        if host.contains(':') {
+            anyhow::bail!("IPv6 address in {address} must be enclosed in brackets");
+        }
+        (host, port)
+    };
+    if host.is_empty() {
+        anyhow::bail!("missing host in {address}");
+    }
+    let port = port
+        .parse()
+        .with_context(|| format!("invalid port in {address}"))?;
+    Ok((host, port))
+}
+
+async fn connect_tcp(
+    driver: &LocalDriver,
+    address: &str,
+) -> anyhow::Result<PolledSocket<socket2::Socket>> {
+    let (host, port) = split_host_port(address)?;
+    // Resolving a host name blocks, so do it off the worker's thread.
+    let host = host.to_owned();
+    let addrs = blocking::unblock(move || (host.as_str(), port).to_socket_addrs())
+        .await
+        .with_context(|| format!("failed to resolve {address}"))?;
+    let mut last_err = anyhow!("{address} did not resolve to any addresses");
+    for addr in addrs {
+        let socket = socket2::Socket::new(
+            socket2::Domain::for_address(addr),
+            socket2::Type::STREAM,
+            None,
+        )?;
+        let mut socket = PolledSocket::new(driver, socket)?;
+        match socket
+            .connect(&addr.into())
+            .await
+            .with_context(|| format!("failed to connect to {addr}"))
+        {
+            Ok(()) => return Ok(socket),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+/// Asks an HTTP proxy to open a tunnel to `target`.
+async fn http_connect(
+    socket: &mut PolledSocket<socket2::Socket>,
+    target: &str,
+) -> anyhow::Result<()> {
+    socket
+        .write_all(format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n").as_bytes())
+        .await?;
+
+    // Read the response a byte at a time so that none of the tunneled data
+    // is consumed.
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() == MAX_HTTP_RESPONSE {
+            anyhow::bail!("HTTP proxy response too long");
+        }
+        let mut byte = 0;
+        socket.read_exact(std::slice::from_mut(&mut byte)).await?;
+        response.push(byte);
+    }
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if !status.starts_with('2') || status.len() != 3 {
+        anyhow::bail!("HTTP proxy refused the connection: {status_line}");
+    }
+    Ok(())
+}
+
+/// Asks a SOCKS5 proxy (RFC 1928) to connect to `target`.
+async fn socks5_connect(
+    socket: &mut PolledSocket<socket2::Socket>,
+    target: &str,
+) -> anyhow::Result<()> {
+    const VERSION: u8 = 5;
+    const NO_AUTHENTICATION: u8 = 0;
+    const COMMAND_CONNECT: u8 = 1;
+    const ADDRESS_IPV4: u8 = 1;
+    const ADDRESS_DOMAIN_NAME: u8 = 3;
+    const ADDRESS_IPV6: u8 = 4;
+
+    socket.write_all(&[VERSION, 1, NO_AUTHENTICATION]).await?;
+    let mut reply = [0; 2];
+    socket.read_exact(&mut reply).await?;
+    if reply != [VERSION, NO_AUTHENTICATION] {
+        anyhow::bail!("SOCKS5 proxy requires authentication");
+    }
+
+    let (host, port) = split_host_port(target)?;
+    let mut request = vec![VERSION, COMMAND_CONNECT, 0];
+    match host.parse() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ADDRESS_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ADDRESS_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len()).context("host name too long")?;
+            request.push(ADDRESS_DOMAIN_NAME);
+            request.push(len);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    socket.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    socket.read_exact(&mut reply).await?;
+    let [version, status, _, address_type] = reply;
+    if version != VERSION {
+        anyhow::bail!("invalid SOCKS5 reply version {version}");
+    }
+    if status != 0 {
+        anyhow::bail!("SOCKS5 proxy refused the connection (error {status})");
+    }
+    // Skip the bound address and port.
+    let address_len = match address_type {
+        ADDRESS_IPV4 => 4,
+        ADDRESS_IPV6 => 16,
+        ADDRESS_DOMAIN_NAME => {
+            let mut len = 0;
+            socket.read_exact(std::slice::from_mut(&mut len)).await?;
+            len.into()
+        }
+        _ => anyhow::bail!("invalid SOCKS5 address type {address_type}"),
+    };
+    let mut bound = vec![0; address_len + 2];
+    socket.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::http_connect;
+    use super::socks5_connect;
+    use super::split_host_port;
+    use crate::websocket::socket_pair;
+    use futures::AsyncReadExt;
+    use futures::AsyncWriteExt;
+    use pal_async::local::block_with_io;
+    use pal_async::socket::PolledSocket;
+
+    #[test]
+    fn host_port() {
+        assert_eq!(
+            split_host_port("viewer.example:5500").unwrap(),
+            ("viewer.example", 5500)
+        );
+        assert_eq!(split_host_port("10.0.0.1:80").unwrap(), ("10.0.0.1", 80));
+        assert_eq!(split_host_port("[::1]:5500").unwrap(), ("::1", 5500));
+        assert_eq!(
+            split_host_port("[fe80::1%eth0]:1").unwrap(),
+            ("fe80::1%eth0", 1)
+        );
+        for bad in [
+            "viewer.example",
+            "viewer.example:",
+            "viewer.example:65536",
+            ":5500",
+            "::1:5500",
+            "fe80::1",
+            "[::1]",
+            "[::1]5500",
+            "[::1:5500",
+            "[]:5500",
+        ] {
+            assert!(split_host_port(bad).is_err(), "{bad}");
+        }
+    }
+
+    /// Runs `connect` against a fake proxy that has already queued `reply`,
+    /// returning the connection result, the bytes the proxy received, and any
+    /// reply bytes left unread.
+    fn run_proxy(
+        reply: &[u8],
+        connect: impl AsyncFnOnce(&mut PolledSocket<socket2::Socket>) -> anyhow::Result<()>,
+    ) -> (anyhow::Result<()>, Vec<u8>, Vec<u8>) {
+        block_with_io(async |driver| {
+            let (mut client, mut proxy) = socket_pair(&driver).unwrap();
+            proxy.write_all(reply).await.unwrap();
+            let result = connect(&mut client).await;
+            client.close().await.unwrap();
+            proxy.close().await.unwrap();
+            let mut request = Vec::new();
+            proxy.read_to_end(&mut request).await.unwrap();
+            let mut unread = Vec::new();
+            client.read_to_end(&mut unread).await.unwrap();
+            (result, request, unread)
+        })
+    }
+
+    #[test]
+    fn http() {
+        let (result, request, unread) = run_proxy(
+            b"HTTP/1.1 200 Connection established\r\nVia: proxy\r\n\r\nRFB",
+            async |socket| http_connect(socket, "viewer.example:5500").await,
+        );
+        result.unwrap();
+        assert_eq!(
+            request,
+            b"CONNECT viewer.example:5500 HTTP/1.1\r\nHost: viewer.example:5500\r\n\r\n"
+        );
+        // The tunneled data is left for the RFB connection.
+        assert_eq!(unread, b"RFB");
+
+        let (result, _, _) = run_proxy(
+            b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n",
+            async |socket| http_connect(socket, "viewer.example:5500").await,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn socks5() {
+        const REPLY_IPV4: &[u8] = &[5, 0, 5, 0, 0, 1, 10, 0, 0, 1, 0x15, 0x7c];
+        let cases: [(&str, &[u8]); 3] = [
+            (
+                "viewer.example:5500",
+                b"\x05\x01\x00\x05\x01\x00\x03\x0eviewer.example\x15\x7c",
+            ),
+            (
+                "10.0.0.2:5500",
+                &[5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 2, 0x15, 0x7c],
+            ),
+            (
+                "[::1]:5500",
+                &[
+                    5, 1, 0, 5, 1, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x15, 0x7c,
+                ],
+            ),
+        ];
+        for (target, expected) in cases {
+            let (result, request, unread) = run_proxy(REPLY_IPV4, async |socket| {
+                socks5_connect(socket, target).await
+            });
+            result.unwrap();
+            assert_eq!(request, expected, "{target}");
+            assert!(unread.is_empty());
+        }
+
+        // A bound domain name is skipped, too.
+        let (result, _, unread) = run_proxy(
+            b"\x05\x00\x05\x00\x00\x03\x05proxy\x15\x7cRFB",
+            async |socket| socks5_connect(socket, "10.0.0.2:5500").await,
+        );
+        result.unwrap();
+        assert_eq!(unread, b"RFB");
+
+        // Proxies requiring authentication and refusals fail.
+        for reply in [&[5, 2][..], &[5, 0, 5, 5, 0, 1, 0, 0, 0, 0, 0, 0]] {
+            let (result, _, _) = run_proxy(reply, async |socket| {
+                socks5_connect(socket, "10.0.0.2:5500").await
+            });
+            assert!(result.is_err());
+        }
+    }
+}
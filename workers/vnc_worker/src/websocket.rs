@@ -0,0 +1,475 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A WebSocket (RFC 6455) transport for the RFB stream, so that browser
+//! clients such as noVNC can connect without a websockify proxy.
+//!
+//! The client's socket is relayed to one end of a socket pair, with the other
+//! end served as a plain RFB connection.
+//!
+//! Browsers let any web page open a WebSocket to any address, including
+//! localhost, and send the page's origin in the opening handshake. Since the
+//! server does not authenticate clients, handshakes from web pages are refused
+//! unless their origin is explicitly allowed.
+
+use anyhow::Context;
+use base64::Engine;
+use futures::AsyncReadExt;
+use futures::AsyncWriteExt;
+use futures::FutureExt;
+use futures::StreamExt;
+use pal_async::local::LocalDriver;
+use pal_async::socket::PolledSocket;
+use pal_async::socket::ReadHalf;
+use pal_async::socket::WriteHalf;
+use pal_async::timer::PolledTimer;
+use sha1::Digest;
+use sha1::Sha1;
+use std::pin::pin;
+use std::time::Duration;
+
+/// The limit on the size of the client's opening handshake.
+const MAX_HTTP_REQUEST: usize = 8192;
+
+/// Appended to the client's key to compute the accept value.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The subprotocol for binary messages, offered by noVNC.
+const PROTOCOL_BINARY: &str = "binary";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+const FLAG_FIN: u8 = 0x80;
+const FLAG_MASK: u8 = 0x80;
+
+/// The largest payload of a control frame.
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+
+/// The close status for a normal closure.
+const CLOSE_NORMAL: u16 = 1000;
+
+/// The amount of the RFB stream sent to the client in each message.
+const MESSAGE_SIZE: usize = 16384;
+
+/// Returns a connected socket pair, the first end for the RFB server and the
+/// second for [`relay`].
+pub(crate) fn socket_pair(
+    driver: &LocalDriver,
+) -> std::io::Result<(PolledSocket<socket2::Socket>, PolledSocket<socket2::Socket>)> {
+    let (a, b) = unix_socket::UnixStream::pair()?;
+    Ok((
+        PolledSocket::new(driver, a.into())?,
+        PolledSocket::new(driver, b.into())?,
+    ))
+}
+
+/// Completes the WebSocket opening handshake with `client`, accepting it only
+/// from web pages whose origin is in `origins` and failing if the client takes
+/// longer than `timeout` to send it. Then relays the RFB stream between the
+/// client and `server` until either side closes.
+pub(crate) async fn relay(
+    mut client: PolledSocket<socket2::Socket>,
+    server: PolledSocket<socket2::Socket>,
+    origins: &[String],
+    mut timer: PolledTimer,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    let request = futures::select! { // race semantics
+        r = read_request(&mut client).fuse() => r?,
+        _ = timer.sleep(timeout).fuse() => anyhow::bail!("WebSocket handshake timed out"),
+    };
+    handshake(&mut client, &request, origins).await?;
+    let (mut client_read, mut client_write) = client.split();
+    let (mut server_read, mut server_write) = server.split();
+    // Replies to the client's pings, sent between messages.
+    let (control_send, mut control_recv) = mesh::channel();
+    let close = {
+        let mut incoming =
+            pin!(incoming(&mut client_read, &mut server_write, &control_send).fuse());
+        let mut outgoing =
+            pin!(outgoing(&mut server_read, &mut client_write, &mut control_recv).fuse());
+        futures::select! { // race semantics
+            r = incoming => r?,
+            r = outgoing => return r,
+        }
+    };
+    // The client closed the connection, so answer with a close frame of our
+    // own, echoing its status.
+    client_write
+        .write_all(&frame(OPCODE_CLOSE, &close[..close.len().min(2)]))
+        .await?;
+    Ok(())
+}
+
+/// Reads the client's opening handshake.
+async fn read_request(client: &mut PolledSocket<socket2::Socket>) -> anyhow::Result<String> {
+    // Read the request a byte at a time so that none of the client's first
+    // frame is consumed.
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") {
+        if request.len() == MAX_HTTP_REQUEST {
+            anyhow::bail!("WebSocket handshake too long");
+        }
+        let mut byte = 0;
+        client.read_exact(std::slice::from_mut(&mut byte)).await?;
+        request.push(byte);
+    }
+    Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+/// Answers the client's opening handshake `request`.
+async fn handshake(
+    client: &mut PolledSocket<socket2::Socket>,
+    request: &str,
+    origins: &[String],
+) -> anyhow::Result<()> {
+    let response = match accept(request, origins) {
+        Ok(response) => response,
+        Err(err) => {
+            let status = if err.is::<OriginNotAllowed>() {
+                "403 Forbidden"
+            } else {
+                "400 Bad Request"
+            };
+            let _ = client
+                .write_all(
+                    format!(
+                        "HTTP/1.1 {status}\r\n\
+                        Sec-WebSocket-Version: 13\r\n\
+                        Connection: close\r\n\
+                        Content-Length: 0\r\n\r\n"
+                    )
+                    .as_bytes(),
+                )
+                .await;
+            return Err(err);
+        }
+    };
+    client.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// The error for a handshake from a web page whose origin is not allowed.
+#[derive(Debug)]
+struct OriginNotAllowed(String);
+
+impl std::fmt::Display for OriginNotAllowed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WebSocket origin {:?} is not allowed", self.0)
+    }
+}
+
+impl std::error::Error for OriginNotAllowed {}
+
+/// Returns the response accepting the opening handshake `request`, which is
+/// refused if it comes from a web page whose origin is not in `origins`.
+fn accept(request: &str, origins: &[String]) -> anyhow::Result<String> {
+    let mut lines = request.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    if !request_line.starts_with("GET ") {
+        anyhow::bail!("not a WebSocket request: {request_line}");
+    }
+    let mut upgrade = false;
+    let mut connection_upgrade = false;
+    let mut version = None;
+    let mut key = None;
+    let mut protocols = None;
+    let mut origin = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        let tokens = || value.split(',').map(str::trim);
+        if name.eq_ignore_ascii_case("upgrade") {
+            upgrade = value.eq_ignore_ascii_case("websocket");
+        } else if name.eq_ignore_ascii_case("connection") {
+            connection_upgrade = tokens().any(|t| t.eq_ignore_ascii_case("upgrade"));
+        } else if name.eq_ignore_ascii_case("sec-websocket-version") {
+            version = Some(value);
+        } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+            key = Some(value);
+        } else if name.eq_ignore_ascii_case("sec-websocket-protocol") {
+            protocols = Some(tokens().any(|t| t == PROTOCOL_BINARY));
+        } else if name.eq_ignore_ascii_case("origin") {
+            origin = Some(value);
+        }
+    }
+    if !upgrade || !connection_upgrade {
+        anyhow::bail!("not a WebSocket upgrade request");
+    }
+    if version != Some("13") {
+        anyhow::bail!("unsupported WebSocket version {version:?}");
+    }
+    let key = key.context("missing WebSocket key")?;
+    // Only browsers send an origin, so other clients are always accepted.
+    if let Some(origin) = origin {
+        if !origins.iter().any(|o| o.eq_ignore_ascii_case(origin)) {
+            return Err(OriginNotAllowed(origin.into()).into());
+        }
+    }
+    // Clients that offer subprotocols (such as older versions of noVNC, which
+    // can also send base64 text) must be able to use binary messages.
+    let protocol = match protocols {
+        None => "",
+        Some(true) => "Sec-WebSocket-Protocol: binary\r\n",
+        Some(false) => anyhow::bail!("client does not support binary WebSocket messages"),
+    };
+    let accept = base64::engine::general_purpose::STANDARD
+        .encode(Sha1::digest(format!("{key}{ACCEPT_GUID}")));
+    Ok(format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Accept: {accept}\r\n\
+        {protocol}\r\n"
+    ))
+}
+
+/// Returns a frame with `opcode` and `payload`.
+fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![FLAG_FIN | opcode];
+    match payload.len() {
+        len @ ..126 => frame.push(len as u8),
+        len @ ..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Passes the payloads of the client's messages on to the server, until the
+/// client closes the connection. Returns the payload of the client's close
+/// frame.
+async fn incoming(
+    client: &mut ReadHalf<socket2::Socket>,
+    server: &mut WriteHalf<socket2::Socket>,
+    control: &mesh::Sender<Vec<u8>>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buf = vec![0; MESSAGE_SIZE];
+    loop {
+        let mut header = [0; 2];
+        client.read_exact(&mut header).await?;
+        let opcode = header[0] & 0xf;
+        if header[1] & FLAG_MASK == 0 {
+            anyhow::bail!("unmasked WebSocket frame from client");
+        }
+        let len = match header[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                client.read_exact(&mut len).await?;
+                u16::from_be_bytes(len).into()
+            }
+            127 => {
+                let mut len = [0; 8];
+                client.read_exact(&mut len).await?;
+                u64::from_be_bytes(len)
+            }
+            len => len.into(),
+        };
+        let mut mask = [0; 4];
+        client.read_exact(&mut mask).await?;
+        match opcode {
+            OPCODE_BINARY | OPCODE_CONTINUATION => {
+                // Stream the payload rather than buffering the whole message.
+                let mut offset = 0;
+                while offset < len {
+                    let n = (len - offset).min(buf.len() as u64) as usize;
+                    let data = &mut buf[..n];
+                    client.read_exact(data).await?;
+                    for (i, b) in data.iter_mut().enumerate() {
+                        *b ^= mask[(offset as usize + i) % 4];
+                    }
+                    server.write_all(data).await?;
+                    offset += n as u64;
+                }
+            }
+            OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => {
+                if len > MAX_CONTROL_PAYLOAD {
+                    anyhow::bail!("WebSocket control frame too long");
+                }
+                let mut payload = vec![0; len as usize];
+                client.read_exact(&mut payload).await?;
+                for (i, b) in payload.iter_mut().enumerate() {
+                    *b ^= mask[i % 4];
+                }
+                match opcode {
+                    OPCODE_CLOSE => break Ok(payload),
+                    OPCODE_PING => control.send(frame(OPCODE_PONG, &payload)),
+                    _ => {}
+                }
+            }
+            OPCODE_TEXT => anyhow::bail!("text WebSocket messages are not supported"),
+            _ => anyhow::bail!("unknown WebSocket opcode {opcode:#x}"),
+        }
+    }
+}
+
+/// Sends the server's output to the client as binary messages, along with any
+/// control frames, until the server closes the connection.
+async fn outgoing(
+    server: &mut ReadHalf<socket2::Socket>,
+    client: &mut WriteHalf<socket2::Socket>,
+    control: &mut mesh::Receiver<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0; MESSAGE_SIZE];
+    loop {
+        let msg = futures::select! { // merge semantics
+            r = server.read(&mut buf).fuse() => {
+                let n = r?;
+                if n == 0 {
+                    client
+                        .write_all(&frame(OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes()))
+                        .await?;
+                    break Ok(());
+                }
+                frame(OPCODE_BINARY, &buf[..n])
+            }
+            msg = control.select_next_some() => msg,
+        };
+        client.write_all(&msg).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OriginNotAllowed;
+    use super::accept;
+    use super::frame;
+    use super::relay;
+    use super::socket_pair;
+    use futures::AsyncReadExt;
+    use futures::AsyncWriteExt;
+    use pal_async::local::block_with_io;
+    use pal_async::timer::PolledTimer;
+    use std::time::Duration;
+
+    /// Returns an opening handshake with the key from RFC 6455, section 1.3,
+    /// and the additional header lines `headers`.
+    fn request(headers: &str) -> String {
+        format!(
+            "GET /chat HTTP/1.1\r\n\
+            Host: server.example.com\r\n\
+            Upgrade: websocket\r\n\
+            Connection: keep-alive, Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            {headers}\r\n"
+        )
+    }
+
+    #[test]
+    fn accept_key() {
+        assert_eq!(
+            accept(&request(""), &[]).unwrap(),
+            "HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"
+        );
+        assert_eq!(
+            accept(&request("Sec-WebSocket-Protocol: base64, binary\r\n"), &[]).unwrap(),
+            "HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+            Sec-WebSocket-Protocol: binary\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn accept_invalid() {
+        for request in [
+            request("").replace("GET", "POST"),
+            request("").replace("Upgrade: websocket", "Upgrade: h2c"),
+            request("").replace("keep-alive, Upgrade", "keep-alive"),
+            request("").replace("Version: 13", "Version: 8"),
+            request("").replace("Sec-WebSocket-Key", "X-Key"),
+            request("Sec-WebSocket-Protocol: base64\r\n"),
+        ] {
+            let err = accept(&request, &[]).unwrap_err();
+            assert!(!err.is::<OriginNotAllowed>(), "{request}");
+        }
+    }
+
+    #[test]
+    fn accept_origin() {
+        let origins = ["http://localhost:6080".to_owned()];
+        accept(&request("Origin: http://localhost:6080\r\n"), &origins).unwrap();
+        accept(&request("Origin: HTTP://LocalHost:6080\r\n"), &origins).unwrap();
+        for origin in ["http://example.com", "http://localhost:6081", "null"] {
+            let request = request(&format!("Origin: {origin}\r\n"));
+            assert!(
+                accept(&request, &origins)
+                    .unwrap_err()
+                    .is::<OriginNotAllowed>()
+            );
+            assert!(accept(&request, &[]).unwrap_err().is::<OriginNotAllowed>());
+        }
+    }
+
+    #[test]
+    fn frames() {
+        assert_eq!(frame(0x8, &[]), [0x88, 0]);
+        assert_eq!(frame(0x2, b"RFB"), [0x82, 3, b'R', b'F', b'B']);
+        for (len, header) in [
+            (125, &[0x82, 125][..]),
+            (126, &[0x82, 126, 0, 126]),
+            (0xffff, &[0x82, 126, 0xff, 0xff]),
+            (0x10000, &[0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]),
+        ] {
+            let frame = frame(0x2, &vec![0xa5; len]);
+            assert_eq!(&frame[..header.len()], header, "{len}");
+            assert_eq!(frame.len(), header.len() + len, "{len}");
+        }
+    }
+
+    /// Runs the relay with a client that sends `request`, returning the
+    /// relay's result and the client's response.
+    fn run_relay(request: &[u8]) -> (anyhow::Result<()>, String) {
+        block_with_io(async |driver| {
+            let (mut client, relay_end) = socket_pair(&driver).unwrap();
+            let (_server, server_end) = socket_pair(&driver).unwrap();
+            client.write_all(request).await.unwrap();
+            let result = relay(
+                relay_end,
+                server_end,
+                &[],
+                PolledTimer::new(&driver),
+                Duration::from_millis(50),
+            )
+            .await;
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            (result, String::from_utf8(response).unwrap())
+        })
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let (result, response) = run_relay(b"GET / HTTP/1.1\r\n");
+        assert!(result.is_err());
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn forbidden_origin() {
+        let (result, response) = run_relay(request("Origin: http://example.com\r\n").as_bytes());
+        assert!(result.is_err());
+        assert!(
+            response.starts_with("HTTP/1.1 403 Forbidden\r\n"),
+            "{response}"
+        );
+    }
+}
@@ -33,7 +33,7 @@ fn read_line(&mut self, line: u16, data: &mut [u8]) {
 
 impl vnc::Input for IgnoreInput {
     fn key(&mut self, _scancode: u16, _is_down: bool) {}
-    fn mouse(&mut self, _button_mask: u8, _x: u16, _y: u16) {}
+    fn mouse(&mut self, _button_mask: u8, _x: u16, _y: u16, _width: u16, _height: u16) {}
 }
 
 fn main() -> Result<(), Error> {
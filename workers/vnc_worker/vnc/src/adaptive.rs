@@ -0,0 +1,221 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Selection of lossless or lossy encoding, based on the client's requested
+//! quality and compression levels and the measured connection throughput.
+
+use crate::rfb;
+use std::time::Duration;
+
+/// A rectangle that can be sent in less than this time is sent losslessly,
+/// even if the client allows lossy compression (at the lowest compression
+/// level; higher levels shrink it).
+const LOSSLESS_BUDGET: Duration = Duration::from_millis(100);
+
+/// The compression level assumed if the client does not request one. This
+/// matches TigerVNC's default.
+const DEFAULT_COMPRESS_LEVEL: u8 = 2;
+
+/// Writes smaller than this mostly land in the socket's send buffer, so they
+/// say little about the connection's throughput.
+const MIN_MEASURED_WRITE: usize = 64 * 1024;
+
+/// The encoding preferences sent by the client.
+#[derive(Debug, Default, Copy, Clone)]
+pub(crate) struct EncodingSettings {
+    /// Whether the client supports the Tight encoding.
+    pub tight: bool,
+    /// The requested JPEG quality level (0-9). Lossy compression is only used
+    /// if the client requests a quality level.
+    pub quality_level: Option<u8>,
+    /// The requested compression level (0-9).
+    pub compress_level: Option<u8>,
+}
+
+impl EncodingSettings {
+    /// Parses the settings from the client's SetEncodings message.
+    pub fn from_encodings(encodings: &[u32]) -> Self {
+        // The first of each pseudo-encoding wins, since clients list them in
+        // order of preference.
+        let level = |first: u32, last: u32| {
+            encodings
+                .iter()
+                .find(|&&e| (first..=last).contains(&e))
+                .map(|&e| (e - first) as u8)
+        };
+        Self {
+            tight: encodings.contains(&rfb::ENCODING_TYPE_TIGHT),
+            quality_level: level(
+                rfb::ENCODING_TYPE_QUALITY_LEVEL_0,
+                rfb::ENCODING_TYPE_QUALITY_LEVEL_9,
+            ),
+            compress_level: level(
+                rfb::ENCODING_TYPE_COMPRESS_LEVEL_0,
+                rfb::ENCODING_TYPE_COMPRESS_LEVEL_9,
+            ),
+        }
+    }
+}
+
+/// An estimate of the connection's throughput, from the time taken to write
+/// recent updates.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Throughput {
+    bytes_per_sec: Option<f64>,
+}
+
+impl Throughput {
+    /// Records that writing `bytes` bytes took `elapsed`.
+    pub fn record(&mut self, bytes: usize, elapsed: Duration) {
+        if bytes < MIN_MEASURED_WRITE {
+            return;
+        }
+        let sample = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        self.bytes_per_sec = Some(match self.bytes_per_sec {
+            Some(estimate) => estimate.mul_add(0.75, sample * 0.25),
+            None => sample,
+        });
+    }
+
+    /// Returns the estimated time to send `bytes` bytes, or `None` if nothing
+    /// has been measured yet.
+    fn estimate(&self, bytes: usize) -> Option<Duration> {
+        self.bytes_per_sec
+            .map(|rate| Duration::from_secs_f64(bytes as f64 / rate))
+    }
+}
+
+/// Returns the JPEG quality level to use for a rectangle whose lossless
+/// encoding is `raw_bytes` long, or `None` if it should be sent losslessly.
+///
+/// Rectangles are sent losslessly while the connection keeps up. Once it does
+/// not, the client's requested quality is used, lowered further the more the
+/// connection falls behind.
+pub(crate) fn lossy_quality_level(
+    settings: &EncodingSettings,
+    throughput: &Throughput,
+    raw_bytes: usize,
+) -> Option<u8> {
+    let quality_level = settings.quality_level?;
+    let estimate = throughput.estimate(raw_bytes)?;
+    let compress_level = settings.compress_level.unwrap_or(DEFAULT_COMPRESS_LEVEL);
+    // Higher compression levels ask to save bandwidth at the expense of
+    // fidelity, so switch to lossy encoding sooner.
+    let budget = LOSSLESS_BUDGET * (10 - u32::from(compress_level.min(9))) / 10;
+    if estimate <= budget {
+        return None;
+    }
+    let cap = match estimate.as_secs_f64() / budget.as_secs_f64() {
+        x if x > 16.0 => 3,
+        x if x > 4.0 => 5,
+        _ => 9,
+    };
+    Some(quality_level.min(cap))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EncodingSettings;
+    use super::Throughput;
+    use super::lossy_quality_level;
+    use crate::rfb;
+    use std::time::Duration;
+
+    /// Returns a throughput estimate of one million bytes per second.
+    fn megabyte_per_sec() -> Throughput {
+        let mut throughput = Throughput::default();
+        throughput.record(1_000_000, Duration::from_secs(1));
+        throughput
+    }
+
+    #[test]
+    fn settings_from_encodings() {
+        let settings = EncodingSettings::from_encodings(&[
+            rfb::ENCODING_TYPE_RAW,
+            rfb::ENCODING_TYPE_QUALITY_LEVEL_0 + 6,
+            rfb::ENCODING_TYPE_TIGHT,
+            rfb::ENCODING_TYPE_COMPRESS_LEVEL_0 + 1,
+            rfb::ENCODING_TYPE_QUALITY_LEVEL_0 + 2,
+            rfb::ENCODING_TYPE_COMPRESS_LEVEL_9,
+        ]);
+        assert!(settings.tight);
+        assert_eq!(settings.quality_level, Some(6));
+        assert_eq!(settings.compress_level, Some(1));
+
+        let settings = EncodingSettings::from_encodings(&[rfb::ENCODING_TYPE_RAW]);
+        assert!(!settings.tight);
+        assert_eq!(settings.quality_level, None);
+        assert_eq!(settings.compress_level, None);
+    }
+
+    #[test]
+    fn throughput() {
+        let mut throughput = Throughput::default();
+        assert_eq!(throughput.estimate(1000), None);
+
+        // Small writes are not measured.
+        throughput.record(1000, Duration::from_secs(1));
+        assert_eq!(throughput.estimate(1000), None);
+
+        throughput.record(1_000_000, Duration::from_secs(1));
+        assert_eq!(throughput.estimate(1_000_000), Some(Duration::from_secs(1)));
+
+        // New samples are averaged in with a weight of a quarter.
+        throughput.record(1_000_000, Duration::from_millis(500));
+        assert_eq!(throughput.estimate(1_250_000), Some(Duration::from_secs(1)));
+
+        // Instant writes do not divide by zero.
+        let mut throughput = Throughput::default();
+        throughput.record(100_000, Duration::ZERO);
+        assert_eq!(throughput.estimate(100_000), Some(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn quality_level() {
+        let settings = EncodingSettings {
+            tight: true,
+            quality_level: Some(6),
+            compress_level: None,
+        };
+        let throughput = megabyte_per_sec();
+
+        // Lossless while the rectangle can be sent within the budget (80ms at
+        // the default compression level).
+        assert_eq!(lossy_quality_level(&settings, &throughput, 50_000), None);
+        // The client's quality once it cannot...
+        assert_eq!(
+            lossy_quality_level(&settings, &throughput, 100_000),
+            Some(6)
+        );
+        // ...lowered the further the connection falls behind.
+        assert_eq!(
+            lossy_quality_level(&settings, &throughput, 400_000),
+            Some(5)
+        );
+        assert_eq!(
+            lossy_quality_level(&settings, &throughput, 2_000_000),
+            Some(3)
+        );
+
+        // A higher compression level shrinks the budget.
+        let compressed = EncodingSettings {
+            compress_level: Some(9),
+            ..settings
+        };
+        assert_eq!(
+            lossy_quality_level(&compressed, &throughput, 50_000),
+            Some(5)
+        );
+
+        // Never lossy without a requested quality level or a measurement.
+        let lossless = EncodingSettings {
+            quality_level: None,
+            ..settings
+        };
+        assert_eq!(lossy_quality_level(&lossless, &throughput, 2_000_000), None);
+        assert_eq!(
+            lossy_quality_level(&settings, &Throughput::default(), 2_000_000),
+            None
+        );
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Clipboard text exchanged with the client.
+//!
+//! The text of the original cut text messages is Latin-1. Clients that
+//! support the extended clipboard pseudo-encoding send UTF-8 text instead, in
+//! messages with a negative length.
+
+use crate::Error;
+use crate::rfb;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use std::io::Read;
+use std::io::Write;
+use zerocopy::IntoBytes;
+
+/// The extended clipboard capabilities a client announced.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct ClientCaps {
+    /// The extended clipboard flags.
+    pub flags: u32,
+    /// The largest text, in bytes, that the client accepts without asking for
+    /// it.
+    pub max_text_len: u32,
+}
+
+impl ClientCaps {
+    fn supports(&self, action: u32) -> bool {
+        self.flags & action != 0 && self.flags & rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT != 0
+    }
+}
+
+/// A message from the client's extended clipboard.
+pub(crate) enum ExtendedMessage {
+    /// The client's capabilities.
+    Caps(ClientCaps),
+    /// The client's clipboard has changed and contains text, which must be
+    /// requested.
+    TextAvailable,
+    /// The client asks for the server's clipboard text.
+    TextRequested,
+    /// The client's clipboard text.
+    Text(String),
+    /// A message the server has nothing to do for.
+    Ignored,
+}
+
+/// Decodes the text of a ClientCutText message.
+pub(crate) fn decode_latin1(text: &[u8]) -> String {
+    // Latin-1 maps directly to the first 256 Unicode code points.
+    text.iter().copied().map(char::from).collect()
+}
+
+/// Parses the payload of an extended ClientCutText message.
+pub(crate) fn parse_extended(
+    payload: &[u8],
+    max_text_len: usize,
+) -> Result<ExtendedMessage, Error> {
+    let (flags, data) = payload
+        .split_first_chunk()
+        .ok_or(Error::InvalidExtendedClipboard)?;
+    let flags = u32::from_be_bytes(*flags);
+    let has_text = flags & rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT != 0;
+    let message = if flags & rfb::EXTENDED_CLIPBOARD_ACTION_CAPS != 0 {
+        // The maximum sizes follow, one for each format in order of format
+        // bit. Text has the lowest bit, so its size comes first. A client
+        // that leaves it out gets text only when it asks.
+        let max_text_len = if has_text {
+            data.first_chunk().map_or(0, |len| u32::from_be_bytes(*len))
+        } else {
+            0
+        };
+        ExtendedMessage::Caps(ClientCaps {
+            flags,
+            max_text_len,
+        })
+    } else if flags & rfb::EXTENDED_CLIPBOARD_ACTION_PROVIDE != 0 && has_text {
+        // The data for each format is in a single zlib stream, in order of
+        // format bit. Text has the lowest bit, so it comes first.
+        let mut stream = ZlibDecoder::new(data);
+        let mut len = [0; 4];
+        stream
+            .read_exact(&mut len)
+            .map_err(|_| Error::InvalidExtendedClipboard)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > max_text_len {
+            return Err(Error::CutTextTooLarge(len));
+        }
+        let mut text = vec![0; len];
+        stream
+            .read_exact(&mut text)
+            .map_err(|_| Error::InvalidExtendedClipboard)?;
+        ExtendedMessage::Text(decode_utf8(&text))
+    } else if flags & rfb::EXTENDED_CLIPBOARD_ACTION_NOTIFY != 0 && has_text {
+        ExtendedMessage::TextAvailable
+    } else if flags & rfb::EXTENDED_CLIPBOARD_ACTION_REQUEST != 0 && has_text {
+        ExtendedMessage::TextRequested
+    } else {
+        ExtendedMessage::Ignored
+    };
+    Ok(message)
+}
+
+/// Decodes extended clipboard text, which is null terminated and uses CRLF
+/// line endings.
+fn decode_utf8(text: &[u8]) -> String {
+    let text = text.strip_suffix(&[0]).unwrap_or(text);
+    String::from_utf8_lossy(text).replace("\r\n", "\n")
+}
+
+/// Returns the ServerCutText message announcing the server's extended
+/// clipboard capabilities: it accepts text of up to `max_text_len` bytes,
+/// either directly or after announcing it, and provides its own text when
+/// asked.
+pub(crate) fn caps_message(max_text_len: u32) -> Vec<u8> {
+    extended_message(
+        rfb::EXTENDED_CLIPBOARD_ACTION_CAPS
+            | rfb::EXTENDED_CLIPBOARD_ACTION_REQUEST
+            | rfb::EXTENDED_CLIPBOARD_ACTION_NOTIFY
+            | rfb::EXTENDED_CLIPBOARD_ACTION_PROVIDE
+            | rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT,
+        &max_text_len.to_be_bytes(),
+    )
+}
+
+/// Returns the ServerCutText message requesting the client's clipboard text.
+pub(crate) fn request_text_message() -> Vec<u8> {
+    extended_message(
+        rfb::EXTENDED_CLIPBOARD_ACTION_REQUEST | rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT,
+        &[],
+    )
+}
+
+/// Returns the ServerCutText message offering the server's clipboard `text`
+/// to a client with the extended clipboard capabilities `caps`, or `None` if
+/// the client cannot receive it.
+///
+/// Clients without the extended clipboard receive the text as Latin-1, with
+/// any other characters replaced. Others receive it as UTF-8 if it is within
+/// the size they accept, and are otherwise told that it is available so that
+/// they can ask for it.
+pub(crate) fn text_message(text: &str, caps: Option<&ClientCaps>) -> Option<Vec<u8>> {
+    let Some(caps) = caps else {
+        let text = text
+            .chars()
+            .map(|c| u8::try_from(c).unwrap_or(b'?'))
+            .collect::<Vec<_>>();
+        let mut msg = rfb::ServerCutText {
+            message_type: rfb::SC_MESSAGE_TYPE_SERVER_CUT_TEXT,
+            padding: [0; 3],
+            length: (text.len() as u32).into(),
+        }
+        .as_bytes()
+        .to_vec();
+        msg.extend_from_slice(&text);
+        return Some(msg);
+    };
+    let text = encode_utf8(text);
+    if caps.supports(rfb::EXTENDED_CLIPBOARD_ACTION_PROVIDE)
+        && text.len() <= caps.max_text_len as usize
+    {
+        Some(provide_message(&text))
+    } else if caps.supports(rfb::EXTENDED_CLIPBOARD_ACTION_NOTIFY) {
+        Some(extended_message(
+            rfb::EXTENDED_CLIPBOARD_ACTION_NOTIFY | rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT,
+            &[],
+        ))
+    } else {
+        None
+    }
+}
+
+/// Returns the ServerCutText message providing the server's clipboard `text`
+/// to a client that asked for it.
+pub(crate) fn requested_text_message(text: &str) -> Vec<u8> {
+    provide_message(&encode_utf8(text))
+}
+
+/// Encodes extended clipboard text, which is null terminated and uses CRLF
+/// line endings.
+fn encode_utf8(text: &str) -> Vec<u8> {
+    let mut text = text
+        .replace("\r\n", "\n")
+        .replace('\n', "\r\n")
+        .into_bytes();
+    text.push(0);
+    text
+}
+
+fn provide_message(text: &[u8]) -> Vec<u8> {
+    // The text is preceded by its length in the zlib stream.
+    let mut stream = ZlibEncoder::new(Vec::new(), Compression::default());
+    stream
+        .write_all(&(text.len() as u32).to_be_bytes())
+        .and_then(|()| stream.write_all(text))
+        .expect("writing to a vector cannot fail");
+    let data = stream.finish().expect("writing to a vector cannot fail");
+    extended_message(
+        rfb::EXTENDED_CLIPBOARD_ACTION_PROVIDE | rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT,
+        &data,
+    )
+}
+
+fn extended_message(flags: u32, data: &[u8]) -> Vec<u8> {
+    let len = 4 + data.len() as i32;
+    let mut msg = rfb::ServerCutText {
+        message_type: rfb::SC_MESSAGE_TYPE_SERVER_CUT_TEXT,
+        padding: [0; 3],
+        length: (-len as u32).into(),
+    }
+    .as_bytes()
+    .to_vec();
+    msg.extend_from_slice(&flags.to_be_bytes());
+    msg.extend_from_slice(data);
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientCaps;
+    use super::ExtendedMessage;
+    use crate::Error;
+    use crate::rfb;
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+    use std::io::Write;
+
+    /// Returns the payload of an extended cut text message, after its header.
+    fn payload(msg: &[u8]) -> &[u8] {
+        &msg[8..]
+    }
+
+    #[test]
+    fn decode_latin1() {
+        assert_eq!(super::decode_latin1(b"abc"), "abc");
+        assert_eq!(super::decode_latin1(b"caf\xe9 \xa3"), "café £");
+    }
+
+    #[test]
+    fn caps_message() {
+        let flags = rfb::EXTENDED_CLIPBOARD_ACTION_CAPS
+            | rfb::EXTENDED_CLIPBOARD_ACTION_REQUEST
+            | rfb::EXTENDED_CLIPBOARD_ACTION_NOTIFY
+            | rfb::EXTENDED_CLIPBOARD_ACTION_PROVIDE
+            | rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT;
+        let mut expected = vec![rfb::SC_MESSAGE_TYPE_SERVER_CUT_TEXT, 0, 0, 0];
+        expected.extend_from_slice(&(-8i32).to_be_bytes());
+        expected.extend_from_slice(&flags.to_be_bytes());
+        expected.extend_from_slice(&0x1234u32.to_be_bytes());
+        assert_eq!(super::caps_message(0x1234), expected);
+    }
+
+    #[test]
+    fn parse_extended() {
+        let caps = super::caps_message(100);
+        let notify = (rfb::EXTENDED_CLIPBOARD_ACTION_NOTIFY | rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT)
+            .to_be_bytes();
+        let request = super::request_text_message();
+        let peek = (rfb::EXTENDED_CLIPBOARD_ACTION_PEEK | rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT)
+            .to_be_bytes();
+        assert!(matches!(
+            super::parse_extended(payload(&caps), 100),
+            Ok(ExtendedMessage::Caps(ClientCaps { flags, max_text_len: 100 }))
+                if flags & rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT != 0
+        ));
+        // A client that leaves out the text size gets text only on request.
+        let caps_without_size = (rfb::EXTENDED_CLIPBOARD_ACTION_CAPS
+            | rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT)
+            .to_be_bytes();
+        assert!(matches!(
+            super::parse_extended(&caps_without_size, 100),
+            Ok(ExtendedMessage::Caps(ClientCaps {
+                max_text_len: 0,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            super::parse_extended(&notify, 100),
+            Ok(ExtendedMessage::TextAvailable)
+        ));
+        assert!(matches!(
+            super::parse_extended(payload(&request), 100),
+            Ok(ExtendedMessage::TextRequested)
+        ));
+        assert!(matches!(
+            super::parse_extended(&peek, 100),
+            Ok(ExtendedMessage::Ignored)
+        ));
+        assert!(matches!(
+            super::parse_extended(&[0; 3], 100),
+            Err(Error::InvalidExtendedClipboard)
+        ));
+    }
+
+    #[test]
+    fn parse_extended_text() {
+        let msg = super::requested_text_message("a\nb €");
+        match super::parse_extended(payload(&msg), 100) {
+            Ok(ExtendedMessage::Text(text)) => assert_eq!(text, "a\nb €"),
+            _ => panic!("expected text"),
+        }
+        // "a\r\nb €\0" is 9 bytes.
+        assert!(matches!(
+            super::parse_extended(payload(&msg), 8),
+            Err(Error::CutTextTooLarge(9))
+        ));
+
+        // The text must be as long as its length says.
+        let mut stream = ZlibEncoder::new(Vec::new(), Compression::default());
+        stream.write_all(&10u32.to_be_bytes()).unwrap();
+        stream.write_all(b"short").unwrap();
+        let mut truncated = (rfb::EXTENDED_CLIPBOARD_ACTION_PROVIDE
+            | rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT)
+            .to_be_bytes()
+            .to_vec();
+        truncated.extend_from_slice(&stream.finish().unwrap());
+        assert!(matches!(
+            super::parse_extended(&truncated, 100),
+            Err(Error::InvalidExtendedClipboard)
+        ));
+    }
+
+    #[test]
+    fn text_message_latin1() {
+        // Characters outside Latin-1 are replaced.
+        let mut expected = vec![rfb::SC_MESSAGE_TYPE_SERVER_CUT_TEXT, 0, 0, 0];
+        expected.extend_from_slice(&7u32.to_be_bytes());
+        expected.extend_from_slice(b"caf\xe9 ?\n");
+        assert_eq!(super::text_message("café €\n", None), Some(expected));
+    }
+
+    #[test]
+    fn text_message_extended() {
+        let caps = |flags, max_text_len| ClientCaps {
+            flags: flags | rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT,
+            max_text_len,
+        };
+        let provide_notify =
+            rfb::EXTENDED_CLIPBOARD_ACTION_PROVIDE | rfb::EXTENDED_CLIPBOARD_ACTION_NOTIFY;
+        let notify = (rfb::EXTENDED_CLIPBOARD_ACTION_NOTIFY | rfb::EXTENDED_CLIPBOARD_FORMAT_TEXT)
+            .to_be_bytes();
+
+        // "a\r\nb €\0" is 9 bytes, which fits.
+        let msg = super::text_message("a\nb €", Some(&caps(provide_notify, 9))).unwrap();
+        assert_eq!(msg, super::requested_text_message("a\nb €"));
+        match super::parse_extended(payload(&msg), 100) {
+            Ok(ExtendedMessage::Text(text)) => assert_eq!(text, "a\nb €"),
+            _ => panic!("expected text"),
+        }
+
+        // Text larger than the client accepts is announced instead.
+        let msg = super::text_message("a\nb €", Some(&caps(provide_notify, 8))).unwrap();
+        assert_eq!(payload(&msg), notify);
+
+        // Without notifications, the client only gets text that fits.
+        let provide = rfb::EXTENDED_CLIPBOARD_ACTION_PROVIDE;
+        assert!(super::text_message("a\nb €", Some(&caps(provide, 9))).is_some());
+        assert_eq!(super::text_message("a\nb €", Some(&caps(provide, 8))), None);
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Drawing the guest's pointer on the client, with the Cursor and
+//! CursorWithAlpha pseudo-encodings.
+//!
+//! The client draws the cursor locally, so it follows the client's mouse
+//! without waiting for a round trip through the guest.
+
+use crate::Rect;
+use crate::push_pixels;
+use crate::push_rect_header;
+use crate::rfb;
+use zerocopy::IntoBytes;
+
+/// A cursor image.
+#[derive(Debug, Clone)]
+pub struct Cursor {
+    /// Width in pixels.
+    pub width: u16,
+    /// Height in pixels.
+    pub height: u16,
+    /// The pixel within the image that is at the pointer's position.
+    pub hotspot_x: u16,
+    /// The pixel within the image that is at the pointer's position.
+    pub hotspot_y: u16,
+    /// The pixels, in rows from the top, as `0xAARRGGBB` with straight
+    /// (not premultiplied) alpha.
+    pub pixels: Vec<u32>,
+}
+
+/// The alpha at or above which a pixel is drawn by clients that only support
+/// an opaque or transparent cursor.
+const OPAQUE_ALPHA: u32 = 0x80;
+
+/// Returns the cursor pseudo-encoding to use for the client, preferring
+/// whichever the client listed first.
+pub(crate) fn preferred_encoding(encodings: &[u32]) -> Option<u32> {
+    encodings
+        .iter()
+        .copied()
+        .find(|&e| e == rfb::ENCODING_TYPE_CURSOR_WITH_ALPHA || e == rfb::ENCODING_TYPE_CURSOR)
+}
+
+/// Returns the FramebufferUpdate message setting the client's cursor to
+/// `cursor` with `encoding`, or hiding it if `cursor` is `None`.
+pub(crate) fn cursor_message(
+    fmt: &rfb::PixelFormat,
+    encoding: u32,
+    cursor: Option<&Cursor>,
+) -> Vec<u8> {
+    // A hidden cursor is sent as a single transparent pixel, since not all
+    // clients accept an empty one.
+    let hidden = Cursor {
+        width: 1,
+        height: 1,
+        hotspot_x: 0,
+        hotspot_y: 0,
+        pixels: vec![0],
+    };
+    let cursor = cursor.unwrap_or(&hidden);
+    let mut msg = rfb::FramebufferUpdate {
+        message_type: rfb::SC_MESSAGE_TYPE_FRAMEBUFFER_UPDATE,
+        padding: 0,
+        rectangle_count: 1.into(),
+    }
+    .as_bytes()
+    .to_vec();
+    // The rectangle's position is the hotspot.
+    let rect = Rect {
+        x: cursor.hotspot_x,
+        y: cursor.hotspot_y,
+        width: cursor.width,
+        height: cursor.height,
+    };
+    push_rect_header(&mut msg, &rect, encoding);
+    if encoding == rfb::ENCODING_TYPE_CURSOR_WITH_ALPHA {
+        // The pixels are in an encoding of their own, always RGBA bytes with
+        // premultiplied alpha.
+        msg.extend_from_slice(&rfb::ENCODING_TYPE_RAW.to_be_bytes());
+        for &p in &cursor.pixels {
+            let [b, g, r, a] = p.to_le_bytes();
+            let premultiply = |c: u8| (c as u32 * a as u32 / 255) as u8;
+            msg.extend_from_slice(&[premultiply(r), premultiply(g), premultiply(b), a]);
+        }
+    } else {
+        // The pixels are in the client's format, followed by a mask of the
+        // pixels to draw.
+        push_pixels(&mut msg, fmt, &cursor.pixels);
+        let width = cursor.width as usize;
+        for row in cursor.pixels.chunks(width.max(1)) {
+            let mut mask = vec![0u8; width.div_ceil(8)];
+            for (x, &p) in row.iter().enumerate() {
+                if p >> 24 >= OPAQUE_ALPHA {
+                    mask[x / 8] |= 0x80 >> (x % 8);
+                }
+            }
+            msg.extend_from_slice(&mask);
+        }
+    }
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cursor;
+    use crate::rfb;
+
+    /// Returns the FramebufferUpdate header for a cursor rectangle.
+    fn header(x: u16, y: u16, width: u16, height: u16, encoding: u32) -> Vec<u8> {
+        let mut msg = vec![rfb::SC_MESSAGE_TYPE_FRAMEBUFFER_UPDATE, 0, 0, 1];
+        for v in [x, y, width, height] {
+            msg.extend_from_slice(&v.to_be_bytes());
+        }
+        msg.extend_from_slice(&encoding.to_be_bytes());
+        msg
+    }
+
+    #[test]
+    fn cursor_message() {
+        let fmt = rfb::PixelFormat {
+            bits_per_pixel: 32,
+            depth: 24,
+            big_endian_flag: 0,
+            true_color_flag: 1,
+            red_max: 255.into(),
+            green_max: 255.into(),
+            blue_max: 255.into(),
+            red_shift: 16,
+            green_shift: 8,
+            blue_shift: 0,
+            padding: [0; 3],
+        };
+        let cursor = Cursor {
+            width: 2,
+            height: 1,
+            hotspot_x: 1,
+            hotspot_y: 0,
+            pixels: vec![0xff112233, 0x40445566],
+        };
+        let alpha = rfb::ENCODING_TYPE_CURSOR_WITH_ALPHA;
+        let mask = rfb::ENCODING_TYPE_CURSOR;
+        let cases = [
+            // RGBA with premultiplied alpha, in a raw encoding of its own.
+            (alpha, Some(&cursor), {
+                let mut msg = header(1, 0, 2, 1, alpha);
+                msg.extend_from_slice(&rfb::ENCODING_TYPE_RAW.to_be_bytes());
+                msg.extend_from_slice(&[0x11, 0x22, 0x33, 0xff, 0x11, 0x15, 0x19, 0x40]);
+                msg
+            }),
+            // The client's pixel format, with only the mostly opaque pixel
+            // in the mask.
+            (mask, Some(&cursor), {
+                let mut msg = header(1, 0, 2, 1, mask);
+                msg.extend_from_slice(&[0x33, 0x22, 0x11, 0xff, 0x66, 0x55, 0x44, 0x40]);
+                msg.push(0x80);
+                msg
+            }),
+            // A hidden cursor is a single transparent pixel.
+            (alpha, None, {
+                let mut msg = header(0, 0, 1, 1, alpha);
+                msg.extend_from_slice(&rfb::ENCODING_TYPE_RAW.to_be_bytes());
+                msg.extend_from_slice(&[0; 4]);
+                msg
+            }),
+            (mask, None, {
+                let mut msg = header(0, 0, 1, 1, mask);
+                msg.extend_from_slice(&[0; 5]);
+                msg
+            }),
+        ];
+        for (encoding, cursor, expected) in cases {
+            assert_eq!(
+                super::cursor_message(&fmt, encoding, cursor),
+                expected,
+                "encoding {encoding:#x}, {cursor:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,83 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! A pool of threads for encoding framebuffer updates off of the connection
+//! tasks.
+
+use std::panic::AssertUnwindSafe;
+
+/// A unit of work for the pool.
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A bounded pool of encoder threads, which can be shared between connections.
+///
+/// Encoding a large frame can take long enough to be noticeable, so doing it
+/// on the connection's task would delay processing of client input messages
+/// (and, with a shared executor, other connections). Instead, the connection
+/// hands a snapshot of the frame to the pool and keeps servicing the socket
+/// until the encoded data is ready.
+#[derive(Clone)]
+pub struct EncoderPool {
+    send: async_channel::Sender<Job>,
+}
+
+impl EncoderPool {
+    /// Creates a new pool with `threads` threads, allowing up to `queue_depth`
+    /// jobs to be queued before callers have to wait.
+    ///
+    /// The threads exit once all clones of the pool have been dropped.
+    pub fn new(threads: usize, queue_depth: usize) -> std::io::Result<Self> {
+        let (send, recv) = async_channel::bounded::<Job>(queue_depth.max(1));
+        for i in 0..threads.max(1) {
+            let recv = recv.clone();
+            std::thread::Builder::new()
+                .name(format!("vnc-encoder-{i}"))
+                .spawn(move || {
+                    while let Ok(job) = recv.recv_blocking() {
+                        job();
+                    }
+                })?;
+        }
+        Ok(Self { send })
+    }
+
+    /// Runs `f` on one of the pool's threads and returns its result, or `None`
+    /// if it panicked.
+    ///
+    /// A panic only fails the job, leaving the thread to run further jobs.
+    pub(crate) async fn run<R: 'static + Send>(
+        &self,
+        f: impl 'static + Send + FnOnce() -> R,
+    ) -> Option<R> {
+        let (result_send, result_recv) = async_channel::bounded(1);
+        let job: Job = Box::new(move || {
+            if let Ok(result) = std::panic::catch_unwind(AssertUnwindSafe(f)) {
+                let _ = result_send.try_send(result);
+            }
+        });
+        if let Err(async_channel::SendError(job)) = self.send.send(job).await {
+            // The receivers only go away if every thread has exited. Run the
+            // job inline rather than failing the connection.
+            job();
+        }
+        // The sender is dropped without a result if the job panicked.
+        result_recv.recv().await.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EncoderPool;
+    use pal_async::DefaultPool;
+
+    #[test]
+    fn panicking_job_fails_alone() {
+        DefaultPool::run_with(async |_| {
+            let pool = EncoderPool::new(1, 1).unwrap();
+            assert_eq!(pool.run(|| 1).await, Some(1));
+            assert_eq!(pool.run(|| -> i32 { panic!("job panicked") }).await, None);
+            // The thread survives to run the next job.
+            assert_eq!(pool.run(|| 2).await, Some(2));
+        })
+    }
+}
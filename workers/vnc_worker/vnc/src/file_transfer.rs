@@ -0,0 +1,526 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Support for the UltraVNC file transfer extension, backed by a host-side
+//! staging directory.
+//!
+//! The staging directory is presented to the client as a single drive (`C:`).
+//! Paths received from the client are always resolved relative to the staging
+//! directory, and `..` components, additional drive prefixes, and symbolic
+//! links that lead outside of it are rejected, so the client cannot reach
+//! anything outside of it.
+//!
+//! File system operations block, so they are run on the blocking thread pool
+//! rather than on the connection's task.
+
+use crate::Error;
+use crate::rfb;
+use blocking::unblock;
+use futures::AsyncWriteExt;
+use pal_async::socket::PolledSocket;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use zerocopy::FromZeros;
+use zerocopy::IntoBytes;
+
+/// The maximum length of the data attached to a single client message.
+pub(crate) const MAX_DATA_LENGTH: usize = rfb::FT_BLOCK_SIZE * 2;
+
+/// The drive presented to the client.
+const DRIVE: &str = "C:";
+
+/// The drive list sent to the client: one local drive.
+const DRIVE_LIST: &[u8] = b"C:l\0";
+
+/// The number of 100ns intervals between 1601-01-01 and 1970-01-01.
+const FILETIME_UNIX_EPOCH: u64 = 116444736000000000;
+
+/// File transfer state for a single connection.
+pub(crate) struct FileTransfer {
+    root: Arc<Path>,
+    upload: Option<Upload>,
+}
+
+/// An in-progress upload from the client.
+///
+/// Data is written to a temporary file next to the destination, which is
+/// renamed into place once the client signals the end of the file.
+struct Upload {
+    file: Arc<File>,
+    partial_path: PathBuf,
+    path: PathBuf,
+    remaining: u64,
+}
+
+impl Drop for FileTransfer {
+    fn drop(&mut self) {
+        if let Some(upload) = self.upload.take() {
+            unblock(move || upload.remove()).detach();
+        }
+    }
+}
+
+impl Upload {
+    /// Closes and removes the partially written file.
+    fn remove(self) {
+        drop(self.file);
+        let _ = std::fs::remove_file(&self.partial_path);
+    }
+}
+
+impl FileTransfer {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root: root.into(),
+            upload: None,
+        }
+    }
+
+    /// Handles a file transfer message from the client.
+    pub async fn handle(
+        &mut self,
+        socket: &mut PolledSocket<socket2::Socket>,
+        msg: &rfb::FileTransfer,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        match msg.content_type {
+            rfb::FT_FILE_TRANSFER_ACCESS => {
+                send(socket, rfb::FT_FILE_TRANSFER_ACCESS, 1, 0, &[]).await?;
+            }
+            rfb::FT_DIR_CONTENT_REQUEST => match msg.content_param {
+                rfb::FT_REQUEST_DRIVES_LIST => {
+                    send(
+                        socket,
+                        rfb::FT_DIR_PACKET,
+                        rfb::FT_DIR_PACKET_DRIVES_LIST,
+                        DRIVE_LIST.len() as u32,
+                        DRIVE_LIST,
+                    )
+                    .await?;
+                }
+                rfb::FT_REQUEST_DIR_CONTENT => self.send_directory(socket, data).await?,
+                _ => {}
+            },
+            rfb::FT_FILE_TRANSFER_REQUEST => self.send_file(socket, data).await?,
+            rfb::FT_FILE_TRANSFER_OFFER => {
+                self.abort_upload().await;
+                // The offer is formatted as "<path>,<modification time>".
+                let name = match data.iter().rposition(|&c| c == b',') {
+                    Some(i) => &data[..i],
+                    None => data,
+                };
+                let accepted = self.start_upload(name, msg.size.get().into()).await;
+                send(
+                    socket,
+                    rfb::FT_FILE_ACCEPT_HEADER,
+                    0,
+                    if accepted { 0 } else { rfb::FT_SIZE_FAILED },
+                    name,
+                )
+                .await?;
+            }
+            rfb::FT_FILE_PACKET => {
+                if let Some(upload) = &mut self.upload {
+                    let ok = (data.len() as u64) <= upload.remaining && {
+                        let file = upload.file.clone();
+                        let data = data.to_vec();
+                        unblock(move || (&*file).write_all(&data)).await.is_ok()
+                    };
+                    if ok {
+                        upload.remaining -= data.len() as u64;
+                    } else {
+                        self.abort_upload().await;
+                        send(socket, rfb::FT_ABORT_FILE_TRANSFER, 0, 0, &[]).await?;
+                    }
+                }
+            }
+            rfb::FT_END_OF_FILE => {
+                if let Some(upload) = self.upload.take() {
+                    // Only a complete file replaces the destination.
+                    if upload.remaining != 0 {
+                        unblock(move || upload.remove()).await;
+                        send(socket, rfb::FT_ABORT_FILE_TRANSFER, 0, 0, &[]).await?;
+                        return Ok(());
+                    }
+                    let Upload {
+                        file,
+                        partial_path,
+                        path,
+                        remaining: _,
+                    } = upload;
+                    unblock(move || {
+                        drop(file);
+                        if std::fs::rename(&partial_path, &path).is_err() {
+                            let _ = std::fs::remove_file(&partial_path);
+                        }
+                    })
+                    .await;
+                }
+            }
+            rfb::FT_ABORT_FILE_TRANSFER => self.abort_upload().await,
+            rfb::FT_COMMAND => self.command(socket, msg.content_param, data).await?,
+            _ => {
+                // Ignore checksums and anything else unknown.
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_directory(
+        &mut self,
+        socket: &mut PolledSocket<socket2::Socket>,
+        name: &[u8],
+    ) -> Result<(), Error> {
+        let root = self.root.clone();
+        let path = name.to_vec();
+        let entries = unblock(move || {
+            let entries = std::fs::read_dir(resolve(&root, &path)?).ok()?;
+            Some(
+                entries
+                    .flatten()
+                    .filter_map(|entry| find_data(&entry))
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .await;
+        if let Some(entries) = entries {
+            send(
+                socket,
+                rfb::FT_DIR_PACKET,
+                rfb::FT_DIR_PACKET_DIRECTORY,
+                0,
+                name,
+            )
+            .await?;
+            for find_data in entries {
+                send(
+                    socket,
+                    rfb::FT_DIR_PACKET,
+                    rfb::FT_DIR_PACKET_DIRECTORY,
+                    0,
+                    find_data.as_bytes(),
+                )
+                .await?;
+            }
+        }
+        // An empty packet terminates the listing (or reports failure).
+        send(socket, rfb::FT_DIR_PACKET, 0, 0, &[]).await
+    }
+
+    async fn send_file(
+        &mut self,
+        socket: &mut PolledSocket<socket2::Socket>,
+        name: &[u8],
+    ) -> Result<(), Error> {
+        let root = self.root.clone();
+        let path = name.to_vec();
+        let opened = unblock(move || {
+            let file = File::open(resolve(&root, &path)?).ok()?;
+            let metadata = file.metadata().ok()?;
+            if !metadata.is_file() {
+                return None;
+            }
+            // The file header only carries a 32-bit size.
+            let len = u32::try_from(metadata.len())
+                .ok()
+                .filter(|&len| len != rfb::FT_SIZE_FAILED)?;
+            Some((Arc::new(file), len))
+        })
+        .await;
+        let Some((file, len)) = opened else {
+            return send(socket, rfb::FT_FILE_HEADER, 0, rfb::FT_SIZE_FAILED, name).await;
+        };
+        send(socket, rfb::FT_FILE_HEADER, 0, len, name).await?;
+        let mut buf = vec![0; rfb::FT_BLOCK_SIZE];
+        loop {
+            let file = file.clone();
+            let (n, b) = unblock(move || ((&*file).read(&mut buf), buf)).await;
+            buf = b;
+            let n = match n {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(_) => {
+                    return send(socket, rfb::FT_ABORT_FILE_TRANSFER, 0, 0, &[]).await;
+                }
+            };
+            send(socket, rfb::FT_FILE_PACKET, 0, 0, &buf[..n]).await?;
+        }
+        send(socket, rfb::FT_END_OF_FILE, 0, 0, &[]).await
+    }
+
+    async fn start_upload(&mut self, name: &[u8], size: u64) -> bool {
+        let root = self.root.clone();
+        let name = name.to_vec();
+        let upload = unblock(move || {
+            let path = resolve_entry(&root, &name)?;
+            if path.is_dir() {
+                return None;
+            }
+            // The partial file is resolved separately, since it may be a
+            // symbolic link of its own.
+            let len = name.iter().rposition(|&c| c != 0).map_or(0, |i| i + 1);
+            let partial_name = [&name[..len], b".vncpart"].concat();
+            let partial_path = resolve_entry(&root, &partial_name)?;
+            let file = File::create(&partial_path).ok()?;
+            Some(Upload {
+                file: Arc::new(file),
+                partial_path,
+                path,
+                remaining: size,
+            })
+        })
+        .await;
+        self.upload = upload;
+        self.upload.is_some()
+    }
+
+    async fn abort_upload(&mut self) {
+        if let Some(upload) = self.upload.take() {
+            unblock(move || upload.remove()).await;
+        }
+    }
+
+    async fn command(
+        &mut self,
+        socket: &mut PolledSocket<socket2::Socket>,
+        command: u8,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        if !matches!(
+            command,
+            rfb::FT_COMMAND_DIR_CREATE | rfb::FT_COMMAND_FILE_DELETE | rfb::FT_COMMAND_FILE_RENAME
+        ) {
+            return Ok(());
+        }
+        let root = self.root.clone();
+        let names = data.to_vec();
+        let ok = unblock(move || match command {
+            rfb::FT_COMMAND_DIR_CREATE => {
+                resolve_entry(&root, &names).is_some_and(|path| std::fs::create_dir(path).is_ok())
+            }
+            rfb::FT_COMMAND_FILE_DELETE => resolve_entry(&root, &names).is_some_and(|path| {
+                if path.is_dir() {
+                    std::fs::remove_dir(path).is_ok()
+                } else {
+                    std::fs::remove_file(path).is_ok()
+                }
+            }),
+            rfb::FT_COMMAND_FILE_RENAME => {
+                // The names are separated by '*', which is not valid in a
+                // Windows file name.
+                let mut names = names.splitn(2, |&c| c == b'*');
+                let from = names.next().and_then(|name| resolve_entry(&root, name));
+                let to = names.next().and_then(|name| resolve_entry(&root, name));
+                match (from, to) {
+                    (Some(from), Some(to)) => std::fs::rename(from, to).is_ok(),
+                    _ => false,
+                }
+            }
+            _ => unreachable!(),
+        })
+        .await;
+        send(
+            socket,
+            rfb::FT_COMMAND_RETURN,
+            command,
+            if ok { 0 } else { rfb::FT_SIZE_FAILED },
+            data,
+        )
+        .await
+    }
+}
+
+/// Resolves a client-provided path to a path within the staging directory
+/// `root`.
+///
+/// Symbolic links are followed, so the resolved path is checked to still be
+/// within the staging directory after following them. A path that does not
+/// exist yet (such as an upload's destination) must be in a directory that
+/// does.
+#[expect(
+    clippy::disallowed_methods,
+    reason = "symbolic links must be followed to check where they lead"
+)]
+fn resolve(root: &Path, name: &[u8]) -> Option<PathBuf> {
+    let name = std::str::from_utf8(name).ok()?.trim_end_matches('\0');
+    let rest = match name.get(..DRIVE.len()) {
+        Some(drive) if drive.eq_ignore_ascii_case(DRIVE) => &name[DRIVE.len()..],
+        _ => name,
+    };
+    let root = root.canonicalize().ok()?;
+    let mut path = root.clone();
+    for component in rest.split(['\\', '/']) {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            c if c.contains(':') => return None,
+            c => path.push(c),
+        }
+    }
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        // A dangling symbolic link could be followed out of the staging
+        // directory when the file is created.
+        Err(_) if path.symlink_metadata().is_ok() => return None,
+        Err(_) => path.parent()?.canonicalize().ok()?.join(path.file_name()?),
+    };
+    path.starts_with(&root).then_some(path)
+}
+
+/// Resolves a client-provided path like [`resolve`], but refuses the staging
+/// directory itself, which the client may list but not replace, delete, or
+/// rename.
+#[expect(
+    clippy::disallowed_methods,
+    reason = "the root must be compared in the same form as resolved paths"
+)]
+fn resolve_entry(root: &Path, name: &[u8]) -> Option<PathBuf> {
+    let path = resolve(root, name)?;
+    (path != root.canonicalize().ok()?).then_some(path)
+}
+
+/// Replies to a file transfer message when file transfer is not enabled.
+pub(crate) async fn deny(
+    socket: &mut PolledSocket<socket2::Socket>,
+    msg: &rfb::FileTransfer,
+) -> Result<(), Error> {
+    if msg.content_type == rfb::FT_FILE_TRANSFER_ACCESS {
+        send(socket, rfb::FT_FILE_TRANSFER_ACCESS, !0, 0, &[]).await?;
+    }
+    Ok(())
+}
+
+async fn send(
+    socket: &mut PolledSocket<socket2::Socket>,
+    content_type: u8,
+    content_param: u8,
+    size: u32,
+    data: &[u8],
+) -> Result<(), Error> {
+    let mut msg = rfb::FileTransfer {
+        message_type: rfb::SC_MESSAGE_TYPE_FILE_TRANSFER,
+        content_type,
+        content_param,
+        padding: 0,
+        size: size.into(),
+        length: (data.len() as u32).into(),
+    }
+    .as_bytes()
+    .to_vec();
+    msg.extend_from_slice(data);
+    socket.write_all(&msg).await?;
+    Ok(())
+}
+
+/// Builds the directory listing entry for `entry`, or `None` if it cannot be
+/// represented.
+fn find_data(entry: &std::fs::DirEntry) -> Option<rfb::Win32FindData> {
+    let metadata = entry.metadata().ok()?;
+    let name = entry.file_name();
+    let name = name.to_str()?.as_bytes();
+    let mut find_data = rfb::Win32FindData::new_zeroed();
+    // Leave room for the null terminator.
+    if name.len() >= find_data.file_name.len() {
+        return None;
+    }
+    find_data.file_name[..name.len()].copy_from_slice(name);
+    let (attributes, len) = if metadata.is_dir() {
+        (rfb::FILE_ATTRIBUTE_DIRECTORY, 0)
+    } else {
+        (rfb::FILE_ATTRIBUTE_NORMAL, metadata.len())
+    };
+    find_data.file_attributes = attributes.into();
+    find_data.file_size_high = ((len >> 32) as u32).into();
+    find_data.file_size_low = (len as u32).into();
+    let filetime = |time: std::io::Result<SystemTime>| {
+        time.ok()
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |d| FILETIME_UNIX_EPOCH + (d.as_nanos() / 100) as u64)
+    };
+    find_data.creation_time = filetime(metadata.created()).into();
+    find_data.last_access_time = filetime(metadata.accessed()).into();
+    find_data.last_write_time = filetime(metadata.modified()).into();
+    Some(find_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve;
+    use super::resolve_entry;
+    use std::path::Path;
+
+    #[test]
+    fn resolve_within_root() {
+        let dir = tempfile::tempdir().unwrap();
+        // The root itself resolves to its canonical path.
+        let root = resolve(dir.path(), b"").unwrap();
+        std::fs::create_dir(root.join("sub")).unwrap();
+
+        assert_eq!(resolve(&root, b"C:\\").unwrap(), root);
+        assert_eq!(resolve(&root, b"C:\\sub").unwrap(), root.join("sub"));
+        assert_eq!(
+            resolve(&root, b"c:\\sub\\new\0").unwrap(),
+            root.join("sub/new")
+        );
+        assert_eq!(resolve(&root, b"sub/./new").unwrap(), root.join("sub/new"));
+        // Absolute paths are relative to the root.
+        assert_eq!(resolve(&root, b"/sub").unwrap(), root.join("sub"));
+        assert_eq!(resolve(&root, b"\\\\sub").unwrap(), root.join("sub"));
+        // The parent of a new file must exist.
+        assert!(resolve(&root, b"C:\\missing\\new").is_none());
+    }
+
+    #[test]
+    fn resolve_entry_rejects_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = resolve(dir.path(), b"").unwrap();
+
+        assert!(resolve_entry(&root, b"").is_none());
+        assert!(resolve_entry(&root, b"C:\\").is_none());
+        assert!(resolve_entry(&root, b"C:\\.\0").is_none());
+        assert_eq!(resolve_entry(&root, b"C:\\new").unwrap(), root.join("new"));
+    }
+
+    #[test]
+    fn resolve_rejects_escapes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(dir.path().join("secret"), b"").unwrap();
+
+        assert!(resolve(&root, b"C:\\..\\secret").is_none());
+        assert!(resolve(&root, b"C:\\sub\\..\\..\\secret").is_none());
+        assert!(resolve(&root, b"D:\\secret").is_none());
+        assert!(resolve(&root, b"C:\\C:\\secret").is_none());
+        assert!(resolve(&root, b"C:\\\xff").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_rejects_symlink_escapes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::create_dir(dir.path().join("outside")).unwrap();
+        std::fs::write(dir.path().join("secret"), b"").unwrap();
+        let link =
+            |target: &Path, name| std::os::unix::fs::symlink(target, root.join(name)).unwrap();
+        link(&dir.path().join("secret"), "file");
+        link(&dir.path().join("outside"), "dir");
+        link(&dir.path().join("missing"), "dangling");
+        link(Path::new("."), "self");
+
+        assert!(resolve(&root, b"C:\\file").is_none());
+        assert!(resolve(&root, b"C:\\dir").is_none());
+        assert!(resolve(&root, b"C:\\dir\\new").is_none());
+        assert!(resolve(&root, b"C:\\dangling").is_none());
+        // Links that stay within the root are followed.
+        let root = resolve(&root, b"").unwrap();
+        assert_eq!(resolve(&root, b"C:\\self\\new").unwrap(), root.join("new"));
+    }
+}
@@ -5,23 +5,47 @@
 
 #![expect(missing_docs)]
 
+mod adaptive;
+mod clipboard;
+mod cursor;
+mod encoder;
+mod file_transfer;
 mod rfb;
 mod scancode;
+mod tight;
+
 use futures::AsyncReadExt;
 use futures::AsyncWriteExt;
 use futures::FutureExt;
 use futures::StreamExt;
 use futures::channel::mpsc;
+use futures::future::BoxFuture;
 use futures::future::OptionFuture;
+use futures::stream::BoxStream;
+use input_core::TabletData;
+use input_core::rate_limit::InputRateLimit;
+use input_core::rate_limit::InputRateLimiter;
 use pal_async::socket::PolledSocket;
+use pal_async::timer::PolledTimer;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
 use thiserror::Error;
 use zerocopy::FromZeros;
 use zerocopy::IntoBytes;
 
+pub use cursor::Cursor;
+pub use encoder::EncoderPool;
+pub use input_core::keymap::KeyboardLayout;
+
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("unsupported protocol version")]
+    #[error("unsupported protocol version, only RFB 3.3, 3.7, and 3.8 are supported")]
     UnsupportedVersion(rfb::ProtocolVersion),
+    #[error("unsupported security type {0}, only None is supported")]
+    UnsupportedSecurityType(u8),
     #[error("unsupported message type: {0:#x}")]
     UnknownMessage(u8),
     #[error("unsupported qemu message type: {0:#x}")]
@@ -30,15 +54,157 @@ pub enum Error {
     Io(#[from] std::io::Error),
     #[error("client does not support desktop resize extension")]
     DesktopResizeNotSupported,
+    #[error("file transfer message too large: {0} bytes")]
+    FileTransferMessageTooLarge(usize),
+    #[error("clipboard text too large: {0} bytes")]
+    CutTextTooLarge(usize),
+    #[error("invalid extended clipboard message")]
+    InvalidExtendedClipboard,
+    #[error("unsupported pixel format")]
+    InvalidPixelFormat,
+    #[error("text chat message too large: {0} bytes")]
+    TextChatTooLarge(usize),
+    #[error("too many encodings: {0}")]
+    TooManyEncodings(usize),
+    #[error("fence payload too large: {0} bytes")]
+    FenceTooLarge(usize),
+    #[error("client timed out during the handshake, waiting for {0}")]
+    HandshakeTimeout(&'static str),
+    #[error("client stopped accepting data for {0:?}")]
+    WriteTimeout(Duration),
+    #[error("encoding a framebuffer update panicked")]
+    EncoderPanicked,
+}
+
+/// The broad category of an [`Error`], for logging and statistics.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorKind {
+    /// The client failed the initial protocol handshake.
+    Handshake,
+    /// The client does not support an encoding the server requires.
+    UnsupportedEncoding,
+    /// The client sent a message that the server does not understand.
+    Protocol,
+    /// The client sent a message larger than the server accepts.
+    MessageTooLarge,
+    /// The connection failed.
+    Io,
+    /// Encoding a framebuffer update failed.
+    Encoder,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Handshake => "handshake",
+            ErrorKind::UnsupportedEncoding => "unsupported_encoding",
+            ErrorKind::Protocol => "protocol",
+            ErrorKind::MessageTooLarge => "message_too_large",
+            ErrorKind::Io => "io",
+            ErrorKind::Encoder => "encoder",
+        }
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::UnsupportedVersion(_)
+            | Error::UnsupportedSecurityType(_)
+            | Error::HandshakeTimeout(_) => ErrorKind::Handshake,
+            Error::DesktopResizeNotSupported => ErrorKind::UnsupportedEncoding,
+            Error::UnknownMessage(_)
+            | Error::UnknownQemuMessage(_)
+            | Error::InvalidExtendedClipboard
+            | Error::InvalidPixelFormat => ErrorKind::Protocol,
+            Error::FileTransferMessageTooLarge(_)
+            | Error::CutTextTooLarge(_)
+            | Error::TextChatTooLarge(_)
+            | Error::TooManyEncodings(_)
+            | Error::FenceTooLarge(_) => ErrorKind::MessageTooLarge,
+            Error::Io(_) | Error::WriteTimeout(_) => ErrorKind::Io,
+            Error::EncoderPanicked => ErrorKind::Encoder,
+        }
+    }
 }
 
 /// A trait used to retrieve data from a framebuffer.
 pub trait Framebuffer: Send + Sync {
     fn resolution(&mut self) -> (u16, u16);
     fn read_line(&mut self, line: u16, data: &mut [u8]);
+
+    /// Reads the pixels of `line` starting at column `x`.
+    ///
+    /// By default, this reads the line from its start and copies out the
+    /// requested part.
+    fn read_line_at(&mut self, line: u16, x: u16, data: &mut [u8]) {
+        let skip = x as usize * 4;
+        let mut buf = vec![0; skip + data.len()];
+        self.read_line(line, &mut buf);
+        data.copy_from_slice(&buf[skip..]);
+    }
+
+    /// Returns the regions updated since the last call, or `None` if they are
+    /// not known and the whole framebuffer should be treated as changed.
+    ///
+    /// Called after [`Self::resolution`], so the regions are relative to the
+    /// current resolution (but may still need clipping).
+    fn take_damage(&mut self) -> Option<Vec<Rect>> {
+        None
+    }
+
+    /// Returns whether the framebuffer's memory layout has changed since the
+    /// last call, even if its resolution has not, so that everything the
+    /// client has been sent is stale.
+    ///
+    /// Called after [`Self::resolution`].
+    fn take_format_change(&mut self) -> bool {
+        false
+    }
+
+    /// Returns what caused the framebuffer's latest resolution change.
+    ///
+    /// Called after [`Self::resolution`] returns a new resolution.
+    fn resize_reason(&mut self) -> ResizeReason {
+        ResizeReason::Server
+    }
+}
+
+/// What caused a change to the framebuffer's resolution.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResizeReason {
+    /// The guest or the server changed the resolution.
+    Server,
+    /// The client asked for the resolution.
+    Client,
+    /// Another client asked for the resolution.
+    OtherClient,
+}
+
+/// A region of the framebuffer, in pixels.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
 }
 
-pub const HID_MOUSE_MAX_ABS_VALUE: u32 = 0x7FFFu32;
+/// The maximum number of rectangles sent in a single update. More damaged
+/// regions than this are merged into their bounding box.
+const MAX_UPDATE_RECTS: usize = 32;
+
+/// The maximum clipboard text length accepted from a client, to bound the
+/// memory a single message can make the server allocate.
+const MAX_CUT_TEXT_LENGTH: usize = 1024 * 1024;
+
+/// The maximum number of encodings accepted in a SetEncodings message. Clients
+/// typically send a few dozen.
+const MAX_ENCODINGS: usize = 1024;
+
+/// The amount of serial output kept while the client's text chat is closed,
+/// to be sent when it is opened.
+const MAX_SERIAL_BACKLOG: usize = 16 * 1024;
 
 /// A VNC server handling a single connection.
 pub struct Server<F, I> {
@@ -48,11 +214,94 @@ pub struct Server<F, I> {
     update_recv: mpsc::Receiver<()>,
     update_send: mpsc::Sender<()>,
     name: String,
+    name_updates: Option<BoxStream<'static, String>>,
 
     // ctrl-alt-p paste intercept
     ctrl_left_pressed: bool,
     alt_left_pressed: bool,
     clipboard: String,
+
+    file_transfer: Option<file_transfer::FileTransfer>,
+    encoder: Option<EncoderPool>,
+    preferences: Option<ClientPreferences>,
+    serial: Option<SerialMirror>,
+    shared_clipboard: Option<SharedClipboard>,
+    cursor: Option<GuestCursor>,
+    desktop_resize: Option<Box<dyn FnMut(u16, u16) + Send>>,
+    sharing: Option<Box<dyn FnOnce(bool) + Send>>,
+    keyboard_layout: KeyboardLayout,
+    permissive: bool,
+    quirks: Quirks,
+    handshake_timeout: Option<(PolledTimer, Duration)>,
+    write_timeout: Option<(PolledTimer, Duration)>,
+    refusal_reason: Option<String>,
+    input_rate_limiter: Option<InputRateLimiter>,
+    /// The pointer buttons held down by the last delivered pointer event.
+    button_mask: u8,
+}
+
+/// A guest serial port mirrored to the client's text chat.
+struct SerialMirror {
+    output: BoxStream<'static, Vec<u8>>,
+    input: Option<Box<dyn FnMut(Vec<u8>) + Send>>,
+}
+
+/// A clipboard kept in sync between the client and the guest.
+struct SharedClipboard {
+    guest: BoxStream<'static, String>,
+    client: Box<dyn FnMut(String) + Send>,
+}
+
+/// The guest's pointer, drawn by the client.
+struct GuestCursor {
+    shapes: BoxStream<'static, Option<Cursor>>,
+    enable: Box<dyn FnMut(bool) + Send>,
+}
+
+/// A client's negotiated pixel format, along with the measured connection
+/// throughput, for reuse when the same client reconnects.
+///
+/// Encodings are not remembered: the server may only use the encodings that
+/// the current connection has asked for.
+#[derive(Debug, Clone)]
+pub struct ClientPreferences {
+    pixel_format: rfb::PixelFormat,
+    throughput: adaptive::Throughput,
+}
+
+impl ClientPreferences {
+    fn new(pixel_format: rfb::PixelFormat, throughput: &adaptive::Throughput) -> Self {
+        Self {
+            pixel_format,
+            throughput: throughput.clone(),
+        }
+    }
+}
+
+/// Counts of the workarounds applied for a client that does not follow the
+/// protocol, which are only applied in permissive mode.
+#[derive(Debug, Default, Clone)]
+pub struct Quirks {
+    /// The client sent SetEncodings in place of ClientInit.
+    pub client_init_skipped: u64,
+    /// Updates sent without the client requesting them.
+    pub unsolicited_updates: u64,
+    /// The client did not support the DesktopSize pseudo-encoding.
+    pub missing_desktop_size: u64,
+}
+
+impl Quirks {
+    /// Adds the counts in `other` to these.
+    pub fn add(&mut self, other: &Quirks) {
+        let Quirks {
+            client_init_skipped,
+            unsolicited_updates,
+            missing_desktop_size,
+        } = other;
+        self.client_init_skipped += client_init_skipped;
+        self.unsolicited_updates += unsolicited_updates;
+        self.missing_desktop_size += missing_desktop_size;
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -67,7 +316,10 @@ pub fn update(&self) {
 /// A trait used to handle VNC client input.
 pub trait Input {
     fn key(&mut self, scancode: u16, is_down: bool);
-    fn mouse(&mut self, button_mask: u8, x: u16, y: u16);
+    /// Reports a pointer event. `x` and `y` are scaled to
+    /// [`TabletData::MAX_COORDINATE`]; `width` and `height` are the
+    /// framebuffer size they were scaled from.
+    fn mouse(&mut self, button_mask: u8, x: u16, y: u16, width: u16, height: u16);
 }
 
 impl<F: Framebuffer, I: Input> Server<F, I> {
@@ -86,13 +338,182 @@ pub fn new(
             update_recv,
             update_send,
             name,
+            name_updates: None,
 
             ctrl_left_pressed: false,
             alt_left_pressed: false,
             clipboard: String::new(),
+
+            file_transfer: None,
+            encoder: None,
+            preferences: None,
+            serial: None,
+            shared_clipboard: None,
+            cursor: None,
+            desktop_resize: None,
+            sharing: None,
+            keyboard_layout: KeyboardLayout::default(),
+            permissive: false,
+            quirks: Quirks::default(),
+            handshake_timeout: None,
+            write_timeout: None,
+            refusal_reason: None,
+            input_rate_limiter: None,
+            button_mask: 0,
         }
     }
 
+    /// Disconnects the client if it takes longer than `timeout` to send its
+    /// part of each phase of the handshake, so that a peer that connects and
+    /// then stalls cannot hold the connection indefinitely.
+    pub fn set_handshake_timeout(&mut self, timer: PolledTimer, timeout: Duration) {
+        self.handshake_timeout = Some((timer, timeout));
+    }
+
+    /// Disconnects the client if it stops accepting data for longer than
+    /// `timeout`, so that a client that stops reading cannot leave the
+    /// connection (and its pending update) stuck indefinitely.
+    pub fn set_write_timeout(&mut self, timer: PolledTimer, timeout: Duration) {
+        self.write_timeout = Some((timer, timeout));
+    }
+
+    /// Drops key presses and pointer events from the client beyond `limit`.
+    ///
+    /// The limit applies to each message the client sends, before it is
+    /// translated to guest input, so that a typed character is either
+    /// delivered with its modifier keys or dropped entirely. Key and button
+    /// releases are always delivered, so that dropping input never leaves a
+    /// key or button held down in the guest.
+    pub fn set_input_rate_limit(&mut self, limit: InputRateLimit) {
+        self.input_rate_limiter = Some(InputRateLimiter::new(limit));
+    }
+
+    /// Returns the number of input events dropped by the rate limit.
+    pub fn dropped_input(&self) -> u64 {
+        self.input_rate_limiter
+            .as_ref()
+            .map_or(0, |limiter| limiter.dropped())
+    }
+
+    /// Enables workarounds for clients that do not follow the protocol:
+    /// clients that skip ClientInit and start with SetEncodings, clients that
+    /// expect updates without requesting them, and clients that do not
+    /// support the DesktopSize pseudo-encoding (which are disconnected if the
+    /// resolution changes). Such clients are otherwise disconnected or stop
+    /// receiving updates.
+    pub fn set_permissive(&mut self, permissive: bool) {
+        self.permissive = permissive;
+    }
+
+    /// Returns the workarounds applied for the client so far.
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    /// Returns the reason the client was given for refusing its connection
+    /// during the handshake, if it was refused and its protocol version allows
+    /// for one.
+    pub fn refusal_reason(&self) -> Option<&str> {
+        self.refusal_reason.as_deref()
+    }
+
+    /// Enables the UltraVNC file transfer extension, exposing the host
+    /// directory `root` to the client.
+    pub fn set_file_transfer_root(&mut self, root: PathBuf) {
+        self.file_transfer = Some(file_transfer::FileTransfer::new(root));
+    }
+
+    /// Encodes framebuffer updates on `pool` rather than on the connection's
+    /// task.
+    pub fn set_encoder_pool(&mut self, pool: EncoderPool) {
+        self.encoder = Some(pool);
+    }
+
+    /// Renames the desktop to each name yielded by `names`, for clients that
+    /// support the DesktopName pseudo-encoding.
+    pub fn set_name_updates(&mut self, names: BoxStream<'static, String>) {
+        self.name_updates = Some(names);
+    }
+
+    /// Mirrors a guest serial port to the client through the UltraVNC text
+    /// chat extension, once the client opens a chat.
+    ///
+    /// `output` yields the port's output. Each line the client sends is passed
+    /// to `input`, ending with a carriage return; if `input` is `None`, the
+    /// client can only watch.
+    pub fn set_serial(
+        &mut self,
+        output: BoxStream<'static, Vec<u8>>,
+        input: Option<Box<dyn FnMut(Vec<u8>) + Send>>,
+    ) {
+        self.serial = Some(SerialMirror { output, input });
+    }
+
+    /// Keeps the client's clipboard in sync with the guest's.
+    ///
+    /// Each text yielded by `guest` is sent to the client, as UTF-8 if the
+    /// client supports the extended clipboard. Text the client copies is
+    /// passed to `client`.
+    pub fn set_clipboard(
+        &mut self,
+        guest: BoxStream<'static, String>,
+        client: Box<dyn FnMut(String) + Send>,
+    ) {
+        self.shared_clipboard = Some(SharedClipboard { guest, client });
+    }
+
+    /// Has the client draw the guest's pointer, if it supports the Cursor or
+    /// CursorWithAlpha pseudo-encoding.
+    ///
+    /// `shapes` yields the pointer's image, or `None` while it is hidden.
+    /// `enable` is called when the client starts or stops supporting a cursor
+    /// pseudo-encoding, since the guest must draw the pointer into the
+    /// framebuffer itself while the client cannot.
+    pub fn set_cursor(
+        &mut self,
+        shapes: BoxStream<'static, Option<Cursor>>,
+        enable: Box<dyn FnMut(bool) + Send>,
+    ) {
+        self.cursor = Some(GuestCursor { shapes, enable });
+    }
+
+    /// Accepts requests from clients that support the ExtendedDesktopSize
+    /// pseudo-encoding to change the desktop size, passing each requested
+    /// width and height to `resize`.
+    ///
+    /// The request is only a hint: the client is told the new size once the
+    /// framebuffer changes, which may be to a different size or not at all.
+    pub fn set_desktop_resize(&mut self, resize: Box<dyn FnMut(u16, u16) + Send>) {
+        self.desktop_resize = Some(resize);
+    }
+
+    /// Passes whether the client agrees to share the desktop with other
+    /// clients to `sharing`, once the client has said so in ClientInit. A
+    /// client that does not agree asks for the others to be disconnected.
+    pub fn set_sharing(&mut self, sharing: Box<dyn FnOnce(bool) + Send>) {
+        self.sharing = Some(sharing);
+    }
+
+    /// Translates the characters the client types to scancodes for `layout`,
+    /// which should match the layout the guest is configured with, rather
+    /// than for a US layout.
+    pub fn set_keyboard_layout(&mut self, layout: KeyboardLayout) {
+        self.keyboard_layout = layout;
+    }
+
+    /// Starts the connection with the preferences of an earlier connection
+    /// from the same client, so that the first updates are sent in the format
+    /// and at the quality it is likely to settle on.
+    pub fn set_preferences(&mut self, preferences: ClientPreferences) {
+        self.preferences = Some(preferences);
+    }
+
+    /// Returns the client's current preferences, if it has negotiated any (or
+    /// they were set with [`Self::set_preferences`]).
+    pub fn preferences(&self) -> Option<&ClientPreferences> {
+        self.preferences.as_ref()
+    }
+
     pub fn updater(&mut self) -> Updater {
         Updater(self.update_send.clone())
     }
@@ -102,29 +523,67 @@ pub fn done(self) -> (F, I) {
     }
 
     /// Runs the VNC server.
+    ///
+    /// Returns `Ok(())` if the client disconnects, including abruptly (in the
+    /// middle of a message or by resetting the connection).
     pub async fn run(&mut self) -> Result<(), Error> {
         match self.run_internal().await {
             Ok(()) => Ok(()),
-            Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::ConnectionReset => Ok(()),
+            Err(Error::Io(err))
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::ConnectionReset
+                        | std::io::ErrorKind::ConnectionAborted
+                        | std::io::ErrorKind::BrokenPipe
+                        | std::io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                Ok(())
+            }
             err => err,
         }
     }
 
     async fn run_internal(&mut self) -> Result<(), Error> {
         let socket = &mut self.socket;
-        socket
-            .write_all(rfb::ProtocolVersion(rfb::PROTOCOL_VERSION_33).as_bytes())
-            .await?;
+        let write_timeout = &mut self.write_timeout;
+        write(
+            socket,
+            write_timeout,
+            rfb::ProtocolVersion(rfb::PROTOCOL_VERSION_38).as_bytes(),
+        )
+        .await?;
 
         let mut version = rfb::ProtocolVersion::new_zeroed();
-        socket.read_exact(version.as_mut_bytes()).await?;
+        read_handshake(
+            socket,
+            &mut self.handshake_timeout,
+            "protocol version",
+            version.as_mut_bytes(),
+        )
+        .await?;
 
-        if version.0 != rfb::PROTOCOL_VERSION_33 {
-            return Err(Error::UnsupportedVersion(version));
+        if ![
+            rfb::PROTOCOL_VERSION_33,
+            rfb::PROTOCOL_VERSION_37,
+            rfb::PROTOCOL_VERSION_38,
+        ]
+        .contains(&version.0)
+        {
+            let err = Error::UnsupportedVersion(version);
+            let reason = err.to_string();
+            // Report the handshake failure rather than any failure to tell the
+            // client about it.
+            let _ = refuse_connection(socket, write_timeout, &version, &reason).await;
+            self.refusal_reason = Some(reason);
+            return Err(err);
         }
 
-        socket
-            .write_all(
+        if version.0 == rfb::PROTOCOL_VERSION_33 {
+            // The server chooses the security type.
+            write(
+                socket,
+                write_timeout,
                 rfb::Security33 {
                     padding: [0; 3],
                     security_type: rfb::SECURITY_TYPE_NONE,
@@ -132,9 +591,66 @@ async fn run_internal(&mut self) -> Result<(), Error> {
                 .as_bytes(),
             )
             .await?;
+        } else {
+            // The client chooses from the offered security types.
+            let mut msg = rfb::Security37 { type_count: 1 }.as_bytes().to_vec();
+            msg.push(rfb::SECURITY_TYPE_NONE);
+            write(socket, write_timeout, &msg).await?;
+            let mut security_type = 0u8;
+            read_handshake(
+                socket,
+                &mut self.handshake_timeout,
+                "security type",
+                security_type.as_mut_bytes(),
+            )
+            .await?;
+            // Only RFB 3.8 sends the result of the None security type, and
+            // the reason for a failure.
+            let result_sent = version.0 == rfb::PROTOCOL_VERSION_38;
+            if security_type != rfb::SECURITY_TYPE_NONE {
+                let err = Error::UnsupportedSecurityType(security_type);
+                if result_sent {
+                    let reason = err.to_string();
+                    let _ = write(socket, write_timeout, &security_failure_message(&reason)).await;
+                    self.refusal_reason = Some(reason);
+                }
+                return Err(err);
+            }
+            if result_sent {
+                write(
+                    socket,
+                    write_timeout,
+                    rfb::SecurityResult {
+                        status: rfb::SECURITY_RESULT_STATUS_OK.into(),
+                    }
+                    .as_bytes(),
+                )
+                .await?;
+            }
+        }
 
         let mut init = rfb::ClientInit::new_zeroed();
-        socket.read_exact(init.as_mut_bytes()).await?;
+        read_handshake(
+            socket,
+            &mut self.handshake_timeout,
+            "ClientInit",
+            init.as_mut_bytes(),
+        )
+        .await?;
+
+        // Any value is a valid shared flag, so only treat the client as having
+        // skipped ClientInit when asked to.
+        let mut early_message = None;
+        let mut shared = init.shared_flag != 0;
+        if self.permissive && init.shared_flag == rfb::CS_MESSAGE_SET_ENCODINGS {
+            self.quirks.client_init_skipped += 1;
+            early_message = Some(init.shared_flag);
+            // The client has not said, so give it the desktop to itself.
+            shared = false;
+        }
+        if let Some(sharing) = self.sharing.take() {
+            sharing(shared);
+        }
 
         let mut fmt = rfb::PixelFormat {
             bits_per_pixel: 32,
@@ -150,180 +666,381 @@ async fn run_internal(&mut self) -> Result<(), Error> {
             padding: [0; 3],
         };
 
+        let mut encodings = Vec::new();
+        let mut throughput = adaptive::Throughput::default();
+        if let Some(preferences) = &self.preferences {
+            // Offer the remembered format as the server's own, so updates use
+            // it even before the client sends SetPixelFormat. Color map
+            // formats would need the color map to be sent as well.
+            if preferences.pixel_format.true_color_flag != 0 {
+                fmt = preferences.pixel_format;
+            }
+            throughput = preferences.throughput.clone();
+        }
+
         let name = self.name.as_bytes();
         let (mut width, mut height) = self.fb.resolution();
-        socket
-            .write_all(
-                rfb::ServerInit {
-                    framebuffer_width: width.into(),
-                    framebuffer_height: height.into(),
-                    server_pixel_format: fmt,
-                    name_length: (name.len() as u32).into(),
-                }
-                .as_bytes(),
-            )
-            .await?;
-        socket.write_all(name).await?;
+        write(
+            socket,
+            write_timeout,
+            rfb::ServerInit {
+                framebuffer_width: width.into(),
+                framebuffer_height: height.into(),
+                server_pixel_format: fmt,
+                name_length: (name.len() as u32).into(),
+            }
+            .as_bytes(),
+        )
+        .await?;
+        write(socket, write_timeout, name).await?;
 
         let mut ready_for_update = false;
-        let mut scancode_state = scancode::State::new();
+        let mut full_update = true;
+        let mut pending_update: Option<BoxFuture<'static, Result<Vec<u8>, Error>>> = None;
+        let mut scancode_state = scancode::State::new(self.keyboard_layout);
+        let mut settings = adaptive::EncodingSettings::from_encodings(&encodings);
+        let mut desktop_name_supported = false;
+        let mut name_changed = false;
+        let mut extended_clipboard = false;
+        let mut client_clipboard_caps = None;
+        // The last text sent from the guest, which clients often echo back.
+        let mut guest_clipboard = None;
+        let mut chat_open = false;
+        let mut serial_backlog = VecDeque::new();
+        let mut resize_unsupported = false;
+        let mut extended_desktop_size = false;
+        // The ExtendedDesktopSize reason and status to send the client.
+        let mut desktop_size_reply = None;
+        let mut cursor_encoding = None;
+        let mut guest_cursor = None;
+        let mut cursor_changed = false;
+        let mut continuous_updates_supported = false;
+        let mut fence_supported = false;
+        // Whether the client sent a fence with the SyncNext flag, so that no
+        // update may start until its next message has been handled.
+        let mut sync_next = false;
+        // The region to send updates for without waiting for requests, while
+        // continuous updates are enabled.
+        let mut continuous_updates = None;
         loop {
             let mut socket_ready = false;
             let mut update_ready = false;
             let mut message_type = 0u8;
-            let update_recv = &mut self.update_recv;
-            let mut update: OptionFuture<_> = ready_for_update
-                .then(|| update_recv.select_next_some())
-                .into();
-            futures::select! { // merge semantics
-                _ = update => update_ready = true,
-                r = socket.read(message_type.as_mut_bytes()).fuse() => {
-                    if r? == 0 {
-                        return Ok(())
+            let mut encoded_update = None;
+            let mut new_name = None;
+            let mut serial_output = None;
+            let mut guest_text = None;
+            let mut new_cursor = None;
+            if let Some(early) = early_message.take() {
+                socket_ready = true;
+                message_type = early;
+            } else if (ready_for_update || continuous_updates.is_some())
+                && full_update
+                && pending_update.is_none()
+                && !sync_next
+            {
+                // Send full updates as soon as they are requested rather than
+                // waiting for the next update tick, so that a newly connected
+                // (or just resized) client is not left showing a blank or
+                // stale screen.
+                update_ready = true;
+            } else {
+                let update_recv = &mut self.update_recv;
+                let mut update: OptionFuture<_> =
+                    ((ready_for_update || continuous_updates.is_some() || self.permissive)
+                        && pending_update.is_none()
+                        && !sync_next)
+                        .then(|| update_recv.select_next_some())
+                        .into();
+                let mut encoded: OptionFuture<_> = pending_update.as_mut().map(|f| f.fuse()).into();
+                let mut rename: OptionFuture<_> = self
+                    .name_updates
+                    .as_mut()
+                    .map(|names| names.next().fuse())
+                    .into();
+                let mut serial: OptionFuture<_> = self
+                    .serial
+                    .as_mut()
+                    .map(|serial| serial.output.next().fuse())
+                    .into();
+                let mut clipboard: OptionFuture<_> = self
+                    .shared_clipboard
+                    .as_mut()
+                    .map(|clipboard| clipboard.guest.next().fuse())
+                    .into();
+                let mut cursor: OptionFuture<_> = self
+                    .cursor
+                    .as_mut()
+                    .map(|cursor| cursor.shapes.next().fuse())
+                    .into();
+                futures::select! { // merge semantics
+                    _ = update => update_ready = true,
+                    data = encoded => encoded_update = data,
+                    name = rename => new_name = name,
+                    data = serial => serial_output = data,
+                    text = clipboard => guest_text = text,
+                    shape = cursor => new_cursor = shape,
+                    r = socket.read(message_type.as_mut_bytes()).fuse() => {
+                        if r? == 0 {
+                            return Ok(())
+                        }
+                        socket_ready = true;
                     }
-                    socket_ready = true;
                 }
             }
 
-            if ready_for_update && update_ready {
+            match new_name {
+                Some(Some(name)) => {
+                    self.name = name;
+                    // Clients without DesktopName support keep the name they
+                    // were given at connection time.
+                    name_changed = desktop_name_supported;
+                }
+                Some(None) => self.name_updates = None,
+                None => {}
+            }
+
+            match serial_output {
+                Some(Some(data)) => {
+                    if chat_open {
+                        write_text_chat(socket, write_timeout, &data).await?;
+                    } else {
+                        serial_backlog.extend(data);
+                        let excess = serial_backlog.len().saturating_sub(MAX_SERIAL_BACKLOG);
+                        serial_backlog.drain(..excess);
+                    }
+                }
+                Some(None) => self.serial = None,
+                None => {}
+            }
+
+            match guest_text {
+                Some(Some(text)) => {
+                    if let Some(msg) =
+                        clipboard::text_message(&text, client_clipboard_caps.as_ref())
+                    {
+                        write(socket, write_timeout, &msg).await?;
+                    }
+                    guest_clipboard = Some(text);
+                }
+                Some(None) => self.shared_clipboard = None,
+                None => {}
+            }
+
+            match new_cursor {
+                Some(Some(cursor)) => {
+                    guest_cursor = cursor;
+                    cursor_changed = cursor_encoding.is_some();
+                }
+                Some(None) => self.cursor = None,
+                None => {}
+            }
+
+            if let Some(data) = encoded_update {
+                pending_update = None;
+                let data = data?;
+                let start = Instant::now();
+                write(socket, write_timeout, &data).await?;
+                throughput.record(data.len(), start.elapsed());
+                self.preferences = Some(ClientPreferences::new(fmt, &throughput));
+            }
+
+            // Only with continuous updates or in permissive mode can an update
+            // be ready without the client having asked for one.
+            let requested = ready_for_update;
+            let unsolicited = update_ready && !requested && continuous_updates.is_none();
+            if update_ready {
                 ready_for_update = false;
 
                 // Ensure the desktop size has not changed.
                 let (new_width, new_height) = self.fb.resolution();
-                if new_width != width || new_height != height {
+                if self.fb.take_format_change() {
+                    // Nothing the client has can be updated incrementally.
+                    full_update = true;
+                }
+                if name_changed {
+                    // Send the new desktop name. Any framebuffer changes go
+                    // out with the client's next update request.
+                    name_changed = false;
+                    let name = self.name.as_bytes();
+                    let mut msg = rfb::FramebufferUpdate {
+                        message_type: rfb::SC_MESSAGE_TYPE_FRAMEBUFFER_UPDATE,
+                        padding: 0,
+                        rectangle_count: 1.into(),
+                    }
+                    .as_bytes()
+                    .to_vec();
+                    msg.extend_from_slice(
+                        rfb::Rectangle {
+                            x: 0.into(),
+                            y: 0.into(),
+                            width: 0.into(),
+                            height: 0.into(),
+                            encoding_type: rfb::ENCODING_TYPE_DESKTOP_NAME.into(),
+                        }
+                        .as_bytes(),
+                    );
+                    msg.extend_from_slice(
+                        zerocopy::U32::<zerocopy::BE>::new(name.len() as u32).as_bytes(),
+                    );
+                    msg.extend_from_slice(name);
+                    write(socket, write_timeout, &msg).await?;
+                } else if let Some(encoding) = cursor_encoding.filter(|_| cursor_changed) {
+                    // Send the new cursor, also on its own.
+                    cursor_changed = false;
+                    let msg = cursor::cursor_message(&fmt, encoding, guest_cursor.as_ref());
+                    write(socket, write_timeout, &msg).await?;
+                } else if new_width != width || new_height != height {
+                    if resize_unsupported {
+                        return Err(Error::DesktopResizeNotSupported);
+                    }
                     // Send the new desktop size.
                     width = new_width;
                     height = new_height;
-                    socket
-                        .write_all(
-                            rfb::FramebufferUpdate {
-                                message_type: rfb::SC_MESSAGE_TYPE_FRAMEBUFFER_UPDATE,
-                                padding: 0,
-                                rectangle_count: 1.into(),
-                            }
-                            .as_bytes(),
-                        )
-                        .await?;
-                    socket
-                        .write_all(
-                            rfb::Rectangle {
-                                x: 0.into(),
-                                y: 0.into(),
-                                width: width.into(),
-                                height: height.into(),
-                                encoding_type: rfb::ENCODING_TYPE_DESKTOP_SIZE.into(),
-                            }
-                            .as_bytes(),
-                        )
-                        .await?;
+                    let reason = match self.fb.resize_reason() {
+                        ResizeReason::Server => rfb::EXTENDED_DESKTOP_SIZE_REASON_SERVER,
+                        ResizeReason::Client => rfb::EXTENDED_DESKTOP_SIZE_REASON_CLIENT,
+                        ResizeReason::OtherClient => rfb::EXTENDED_DESKTOP_SIZE_REASON_OTHER_CLIENT,
+                    };
+                    let extended = extended_desktop_size
+                        .then_some((reason, rfb::EXTENDED_DESKTOP_SIZE_STATUS_OK));
+                    write(
+                        socket,
+                        write_timeout,
+                        &desktop_size_message(width, height, extended),
+                    )
+                    .await?;
+                    // The client needs the whole framebuffer at the new size.
+                    full_update = true;
+                } else if let Some(reply) = desktop_size_reply.take() {
+                    // Send the layout on its own.
+                    write(
+                        socket,
+                        write_timeout,
+                        &desktop_size_message(width, height, Some(reply)),
+                    )
+                    .await?;
                 } else {
-                    // Send the update. Just update the whole framebuffer for now.
-                    socket
-                        .write_all(
-                            rfb::FramebufferUpdate {
-                                message_type: rfb::SC_MESSAGE_TYPE_FRAMEBUFFER_UPDATE,
-                                padding: 0,
-                                rectangle_count: 1.into(),
-                            }
-                            .as_bytes(),
-                        )
-                        .await?;
-                    socket
-                        .write_all(
-                            rfb::Rectangle {
-                                x: 0.into(),
-                                y: 0.into(),
-                                width: width.into(),
-                                height: height.into(),
-                                encoding_type: rfb::ENCODING_TYPE_RAW.into(),
-                            }
-                            .as_bytes(),
-                        )
-                        .await?;
-                    let mut src_line = vec![0u32; width as usize];
-                    let dest_depth = fmt.bits_per_pixel as usize / 8;
-                    let shift_r = 24 - fmt.red_max.get().count_ones();
-                    let shift_g = 16 - fmt.green_max.get().count_ones();
-                    let shift_b = 8 - fmt.red_max.get().count_ones();
-                    match dest_depth {
-                        1 => {
-                            let mut line = vec![0u8; width as usize];
-                            for y in 0..height {
-                                self.fb.read_line(y, src_line.as_mut_bytes());
-                                for x in 0..width as usize {
-                                    let p = src_line[x];
-                                    let (r, g, b) = (p & 0xff0000, p & 0xff00, p & 0xff);
-                                    let p2 = r >> shift_r << fmt.red_shift
-                                        | g >> shift_g << fmt.green_shift
-                                        | b >> shift_b << fmt.blue_shift;
-                                    line[x] = p2 as u8;
-                                }
-                                socket.write_all(&line).await?;
-                            }
-                        }
-                        2 => {
-                            let mut line = vec![0u16; width as usize];
-                            for y in 0..height {
-                                self.fb.read_line(y, src_line.as_mut_bytes());
-                                for x in 0..width as usize {
-                                    let p = src_line[x];
-                                    let (r, g, b) = (p & 0xff0000, p & 0xff00, p & 0xff);
-                                    let p2 = r >> shift_r << fmt.red_shift
-                                        | g >> shift_g << fmt.green_shift
-                                        | b >> shift_b << fmt.blue_shift;
-                                    line[x] = p2 as u16;
-                                }
-                                socket.write_all(line.as_bytes()).await?;
-                            }
-                        }
-                        4 if shift_r == fmt.red_shift as u32
-                            && shift_g == fmt.green_shift as u32
-                            && shift_b == fmt.blue_shift as u32 =>
-                        {
-                            for y in 0..height {
-                                self.fb.read_line(y, src_line.as_mut_bytes());
-                                socket.write_all(src_line.as_bytes()).await?;
-                            }
-                        }
-                        4 => {
-                            let mut line = vec![0u32; width as usize];
-                            for y in 0..height {
-                                self.fb.read_line(y, src_line.as_mut_bytes());
-                                for x in (width as usize & !3)..width as usize {
-                                    let p = src_line[x];
-                                    let (r, g, b) = (p & 0xff0000, p & 0xff00, p & 0xff);
-                                    let p2 = r >> shift_r << fmt.red_shift
-                                        | g >> shift_g << fmt.green_shift
-                                        | b >> shift_b << fmt.blue_shift;
-                                    line[x] = p2;
-                                }
-                                socket.write_all(line.as_bytes()).await?;
+                    let damage = self.fb.take_damage();
+                    let bounds = Rect {
+                        x: 0,
+                        y: 0,
+                        width,
+                        height,
+                    };
+                    let rects = match damage {
+                        Some(damage) if !full_update => clip_damage(damage, &bounds),
+                        _ => vec![bounds],
+                    };
+                    // Updates the client did not ask for are limited to the
+                    // continuous updates region.
+                    let rects = match continuous_updates {
+                        Some(region) if !requested => clip_damage(rects, &region),
+                        _ => rects,
+                    };
+                    if rects.is_empty() {
+                        // Nothing has changed. Keep waiting.
+                        ready_for_update = requested;
+                        continue;
+                    }
+                    full_update = false;
+
+                    // Snapshot the updated regions, copying them straight out
+                    // of the framebuffer, and hand them off to be encoded,
+                    // continuing to service the socket in the meantime.
+                    let rects = rects
+                        .into_iter()
+                        .map(|rect| {
+                            let mut pixels = vec![0u32; rect.width as usize * rect.height as usize];
+                            for (y, row) in (rect.y..rect.y + rect.height)
+                                .zip(pixels.chunks_exact_mut(rect.width as usize))
+                            {
+                                self.fb.read_line_at(y, rect.x, row.as_mut_bytes());
                             }
+                            (rect, pixels)
+                        })
+                        .collect::<Vec<_>>();
+                    let tight = settings.tight && tight::supports_format(&fmt);
+                    let throughput = throughput.clone();
+                    let encode = move || encode_update(&fmt, tight, &settings, &throughput, &rects);
+                    pending_update = Some(match &self.encoder {
+                        Some(pool) => {
+                            let pool = pool.clone();
+                            async move { pool.run(encode).await.ok_or(Error::EncoderPanicked) }
+                                .boxed()
                         }
-                        _ => unreachable!(),
-                    }
+                        None => std::future::ready(Ok(encode())).boxed(),
+                    });
+                }
+                if unsolicited {
+                    self.quirks.unsolicited_updates += 1;
                 }
             }
 
             if socket_ready {
+                sync_next = false;
                 match message_type {
                     rfb::CS_MESSAGE_SET_PIXEL_FORMAT => {
                         let mut input = rfb::SetPixelFormat::new_zeroed();
                         socket.read_exact(&mut input.as_mut_bytes()[1..]).await?;
+                        if !pixel_format_supported(&input.pixel_format) {
+                            return Err(Error::InvalidPixelFormat);
+                        }
                         fmt = input.pixel_format;
+                        self.preferences = Some(ClientPreferences::new(fmt, &throughput));
                     }
                     rfb::CS_MESSAGE_SET_ENCODINGS => {
                         let mut input = rfb::SetEncodings::new_zeroed();
                         socket.read_exact(&mut input.as_mut_bytes()[1..]).await?;
-                        let mut encodings: Vec<zerocopy::U32<zerocopy::BE>> =
-                            vec![0.into(); input.encoding_count.get().into()];
-                        socket.read_exact(encodings.as_mut_bytes()).await?;
-                        if !encodings.contains(&rfb::ENCODING_TYPE_DESKTOP_SIZE.into()) {
+                        let count = input.encoding_count.get().into();
+                        if count > MAX_ENCODINGS {
+                            return Err(Error::TooManyEncodings(count));
+                        }
+                        let mut encodings_be: Vec<zerocopy::U32<zerocopy::BE>> =
+                            vec![0.into(); count];
+                        socket.read_exact(encodings_be.as_mut_bytes()).await?;
+                        encodings = encodings_be.iter().map(|e| e.get()).collect();
+                        desktop_name_supported =
+                            encodings.contains(&rfb::ENCODING_TYPE_DESKTOP_NAME);
+                        settings = adaptive::EncodingSettings::from_encodings(&encodings);
+                        self.preferences = Some(ClientPreferences::new(fmt, &throughput));
+                        let extended =
+                            encodings.contains(&rfb::ENCODING_TYPE_EXTENDED_DESKTOP_SIZE);
+                        if extended && !extended_desktop_size {
+                            // Tell the client the current layout, which also
+                            // tells it that it can request a new size.
+                            desktop_size_reply = Some((
+                                rfb::EXTENDED_DESKTOP_SIZE_REASON_SERVER,
+                                rfb::EXTENDED_DESKTOP_SIZE_STATUS_OK,
+                            ));
+                        }
+                        extended_desktop_size = extended;
+                        let desktop_size =
+                            extended || encodings.contains(&rfb::ENCODING_TYPE_DESKTOP_SIZE);
+                        if !desktop_size {
                             // Can't really operate without being able to change the desktop size dynamically.
-                            return Err(Error::DesktopResizeNotSupported);
+                            // In permissive mode, only disconnect the client if the size changes.
+                            if !self.permissive {
+                                return Err(Error::DesktopResizeNotSupported);
+                            }
+                            if !resize_unsupported {
+                                self.quirks.missing_desktop_size += 1;
+                            }
                         }
+                        resize_unsupported = !desktop_size;
 
-                        if encodings.contains(&rfb::ENCODING_TYPE_QEMU_EXTENDED_KEY_EVENT.into()) {
+                        let encoding = cursor::preferred_encoding(&encodings);
+                        if encoding != cursor_encoding {
+                            if let Some(cursor) = &mut self.cursor {
+                                if encoding.is_some() != cursor_encoding.is_some() {
+                                    (cursor.enable)(encoding.is_some());
+                                }
+                            }
+                            cursor_encoding = encoding;
+                            cursor_changed = encoding.is_some() && guest_cursor.is_some();
+                        }
+
+                        if encodings.contains(&rfb::ENCODING_TYPE_QEMU_EXTENDED_KEY_EVENT) {
                             // Request qemu extended key events.
                             let mut msg = rfb::FramebufferUpdate {
                                 message_type: rfb::SC_MESSAGE_TYPE_FRAMEBUFFER_UPDATE,
@@ -343,30 +1060,76 @@ async fn run_internal(&mut self) -> Result<(), Error> {
                                 }
                                 .as_bytes(),
                             );
-                            socket.write_all(&msg).await?;
+                            write(socket, write_timeout, &msg).await?;
+                        }
+
+                        if !continuous_updates_supported
+                            && encodings.contains(&rfb::ENCODING_TYPE_CONTINUOUS_UPDATES)
+                        {
+                            // Announce support for continuous updates. The
+                            // client may then enable them.
+                            continuous_updates_supported = true;
+                            write(
+                                socket,
+                                write_timeout,
+                                &[rfb::SC_MESSAGE_TYPE_END_OF_CONTINUOUS_UPDATES],
+                            )
+                            .await?;
+                        }
+
+                        if !fence_supported && encodings.contains(&rfb::ENCODING_TYPE_FENCE) {
+                            // Announce support for fences with one of our own,
+                            // which the client answers.
+                            fence_supported = true;
+                            write(
+                                socket,
+                                write_timeout,
+                                &fence_message(rfb::FENCE_FLAG_REQUEST, &[]),
+                            )
+                            .await?;
+                        }
+
+                        if !extended_clipboard
+                            && encodings.contains(&rfb::ENCODING_TYPE_EXTENDED_CLIPBOARD)
+                        {
+                            // Announce support for UTF-8 clipboard text. The
+                            // client responds with its own capabilities.
+                            extended_clipboard = true;
+                            write(
+                                socket,
+                                write_timeout,
+                                &clipboard::caps_message(MAX_CUT_TEXT_LENGTH as u32),
+                            )
+                            .await?;
                         }
                     }
                     rfb::CS_MESSAGE_FRAMEBUFFER_UPDATE_REQUEST => {
                         let mut input = rfb::FramebufferUpdateRequest::new_zeroed();
                         socket.read_exact(&mut input.as_mut_bytes()[1..]).await?;
                         ready_for_update = true;
+                        if input.incremental == 0 {
+                            full_update = true;
+                        }
                     }
                     rfb::CS_MESSAGE_KEY_EVENT => {
                         let mut input = rfb::KeyEvent::new_zeroed();
                         socket.read_exact(&mut input.as_mut_bytes()[1..]).await?;
+                        if !allow_input(&mut self.input_rate_limiter, input.down_flag == 0) {
+                            continue;
+                        }
 
                         // RFB key events are in xkeysym format. Convert them to
-                        // US keyboard scancodes and send them to the keyboard
-                        // device.
+                        // scancodes for the guest's keyboard layout and send
+                        // them to the keyboard device.
                         //
                         // Ideally the VNC client would support the qemu
                         // extensions that provide the scancodes directly.
 
                         // intercept ctrl-alt-p to paste clipboard contents
-                        const KEYSYM_CONTROL_LEFT: u16 = 0xffe3;
-                        const KEYSYM_ALT_LEFT: u16 = 0xffe9;
+                        const KEYSYM_CONTROL_LEFT: u32 = 0xffe3;
+                        const KEYSYM_ALT_LEFT: u32 = 0xffe9;
 
-                        match input.key.get() as u16 {
+                        match input.key.get() {
                             KEYSYM_CONTROL_LEFT => self.ctrl_left_pressed = input.down_flag == 1,
                             KEYSYM_ALT_LEFT => self.alt_left_pressed = input.down_flag == 1,
                             _ => {}
@@ -387,22 +1150,21 @@ async fn run_internal(&mut self) -> Result<(), Error> {
                                 });
                             }
 
-                            // make sure that the clipboard only contains printable ASCII chars
-                            if self.clipboard.chars().all(|c| (' '..='~').contains(&c)) {
-                                for c in self.clipboard.as_bytes() {
-                                    let i = &mut self.input;
-                                    scancode_state.emit_ascii_char(*c, true, |scancode, down| {
-                                        i.key(scancode, down);
-                                    });
-                                    scancode_state.emit_ascii_char(*c, false, |scancode, down| {
-                                        i.key(scancode, down);
-                                    });
-                                }
+                            // Type the clipboard out on the guest's layout,
+                            // skipping any characters it cannot type.
+                            for c in self.clipboard.chars() {
+                                let i = &mut self.input;
+                                scancode_state.emit_char(c, true, |scancode, down| {
+                                    i.key(scancode, down);
+                                });
+                                scancode_state.emit_char(c, false, |scancode, down| {
+                                    i.key(scancode, down);
+                                });
                             }
                         } else {
                             let i = &mut self.input;
                             scancode_state.emit(
-                                input.key.get() as u16,
+                                input.key.get(),
                                 input.down_flag != 0,
                                 |scancode, down| {
                                     i.key(scancode, down);
@@ -413,6 +1175,11 @@ async fn run_internal(&mut self) -> Result<(), Error> {
                     rfb::CS_MESSAGE_POINTER_EVENT => {
                         let mut input = rfb::PointerEvent::new_zeroed();
                         socket.read_exact(&mut input.as_mut_bytes()[1..]).await?;
+                        let release = self.button_mask & !input.button_mask != 0;
+                        if !allow_input(&mut self.input_rate_limiter, release) {
+                            continue;
+                        }
+                        self.button_mask = input.button_mask;
                         //scale the mouse coordinates in the VNC itself
                         let mut x = 0;
                         let mut y = 0;
@@ -426,18 +1193,205 @@ async fn run_internal(&mut self) -> Result<(), Error> {
                             if y_val > height as u32 - 1 {
                                 y_val = height as u32 - 1;
                             }
-                            x = ((x_val * HID_MOUSE_MAX_ABS_VALUE) / (width as u32 - 1)) as u16;
-                            y = ((y_val * HID_MOUSE_MAX_ABS_VALUE) / (height as u32 - 1)) as u16;
+                            let max = TabletData::MAX_COORDINATE as u32;
+                            x = ((x_val * max) / (width as u32 - 1)) as u16;
+                            y = ((y_val * max) / (height as u32 - 1)) as u16;
                         }
-                        self.input.mouse(input.button_mask, x, y);
+                        self.input.mouse(input.button_mask, x, y, width, height);
                     }
                     rfb::CS_MESSAGE_CLIENT_CUT_TEXT => {
                         let mut input = rfb::ClientCutText::new_zeroed();
                         socket.read_exact(&mut input.as_mut_bytes()[1..]).await?;
-                        let mut text_latin1 = vec![0; input.length.get() as usize];
-                        socket.read_exact(&mut text_latin1).await?;
-                        // Latin1 characters map to the first 256 characters of Unicode (roughly).
-                        self.clipboard = text_latin1.iter().copied().map(|c| c as char).collect();
+                        // A negative length indicates an extended clipboard
+                        // message, once the extension has been negotiated.
+                        // Otherwise the length is unsigned.
+                        let length = input.length.get();
+                        let extended = extended_clipboard && (length as i32) < 0;
+                        let length = if extended {
+                            (length as i32).unsigned_abs()
+                        } else {
+                            length
+                        } as usize;
+                        if length > MAX_CUT_TEXT_LENGTH {
+                            return Err(Error::CutTextTooLarge(length));
+                        }
+                        let mut data = vec![0; length];
+                        socket.read_exact(&mut data).await?;
+                        let text = if !extended {
+                            Some(clipboard::decode_latin1(&data))
+                        } else {
+                            match clipboard::parse_extended(&data, MAX_CUT_TEXT_LENGTH)? {
+                                clipboard::ExtendedMessage::Caps(caps) => {
+                                    client_clipboard_caps = Some(caps);
+                                    None
+                                }
+                                clipboard::ExtendedMessage::TextAvailable => {
+                                    if client_clipboard_caps.is_some_and(|caps| {
+                                        caps.flags & rfb::EXTENDED_CLIPBOARD_ACTION_REQUEST != 0
+                                    }) {
+                                        write(
+                                            socket,
+                                            write_timeout,
+                                            &clipboard::request_text_message(),
+                                        )
+                                        .await?;
+                                    }
+                                    None
+                                }
+                                clipboard::ExtendedMessage::TextRequested => {
+                                    if let Some(text) = &guest_clipboard {
+                                        write(
+                                            socket,
+                                            write_timeout,
+                                            &clipboard::requested_text_message(text),
+                                        )
+                                        .await?;
+                                    }
+                                    None
+                                }
+                                clipboard::ExtendedMessage::Text(text) => Some(text),
+                                clipboard::ExtendedMessage::Ignored => None,
+                            }
+                        };
+                        if let Some(text) = text {
+                            if let Some(shared) = &mut self.shared_clipboard {
+                                if guest_clipboard.as_ref() != Some(&text) {
+                                    (shared.client)(text.clone());
+                                }
+                            }
+                            self.clipboard = text;
+                        }
+                    }
+                    rfb::CS_MESSAGE_FILE_TRANSFER => {
+                        let mut input = rfb::FileTransfer::new_zeroed();
+                        socket.read_exact(&mut input.as_mut_bytes()[1..]).await?;
+                        let length = input.length.get() as usize;
+                        if length > file_transfer::MAX_DATA_LENGTH {
+                            return Err(Error::FileTransferMessageTooLarge(length));
+                        }
+                        let mut data = vec![0; length];
+                        socket.read_exact(&mut data).await?;
+                        match &mut self.file_transfer {
+                            Some(file_transfer) => {
+                                file_transfer.handle(socket, &input, &data).await?
+                            }
+                            None => file_transfer::deny(socket, &input).await?,
+                        }
+                    }
+                    rfb::CS_MESSAGE_ENABLE_CONTINUOUS_UPDATES => {
+                        let mut input = rfb::EnableContinuousUpdates::new_zeroed();
+                        socket.read_exact(&mut input.as_mut_bytes()[1..]).await?;
+                        if input.enable_flag != 0 {
+                            continuous_updates = Some(Rect {
+                                x: input.x.get(),
+                                y: input.y.get(),
+                                width: input.width.get(),
+                                height: input.height.get(),
+                            });
+                        } else {
+                            // Tell the client that no more updates will be sent
+                            // unrequested, after any that is in flight.
+                            continuous_updates = None;
+                            flush_update(socket, write_timeout, &mut pending_update).await?;
+                            write(
+                                socket,
+                                write_timeout,
+                                &[rfb::SC_MESSAGE_TYPE_END_OF_CONTINUOUS_UPDATES],
+                            )
+                            .await?;
+                        }
+                    }
+                    rfb::CS_MESSAGE_FENCE => {
+                        let mut input = rfb::Fence::new_zeroed();
+                        socket.read_exact(&mut input.as_mut_bytes()[1..]).await?;
+                        let length = input.length.into();
+                        if length > rfb::FENCE_MAX_PAYLOAD {
+                            return Err(Error::FenceTooLarge(length));
+                        }
+                        let mut payload = vec![0; length];
+                        socket.read_exact(&mut payload).await?;
+                        // A fence without the request flag answers our own.
+                        let flags = input.flags.get();
+                        if flags & rfb::FENCE_FLAG_REQUEST != 0 {
+                            // Messages are handled in order, so BlockBefore and
+                            // BlockAfter are satisfied by answering once the
+                            // update in flight (which may be in a format the
+                            // client has since replaced) has been sent. For
+                            // SyncNext, no update starts until the client's
+                            // next message has been handled, so everything
+                            // sent after the answer reflects that message.
+                            flush_update(socket, write_timeout, &mut pending_update).await?;
+                            sync_next = flags & rfb::FENCE_FLAG_SYNC_NEXT != 0;
+                            let flags = flags
+                                & (rfb::FENCE_FLAG_BLOCK_BEFORE
+                                    | rfb::FENCE_FLAG_BLOCK_AFTER
+                                    | rfb::FENCE_FLAG_SYNC_NEXT);
+                            write(socket, write_timeout, &fence_message(flags, &payload)).await?;
+                        }
+                    }
+                    rfb::CS_MESSAGE_SET_DESKTOP_SIZE => {
+                        let mut input = rfb::SetDesktopSize::new_zeroed();
+                        socket.read_exact(&mut input.as_mut_bytes()[1..]).await?;
+                        let mut screens =
+                            vec![rfb::Screen::new_zeroed(); input.number_of_screens.into()];
+                        socket.read_exact(screens.as_mut_bytes()).await?;
+                        let (new_width, new_height) = (input.width.get(), input.height.get());
+                        // The guest has a single display, so only a layout
+                        // with a single screen can be shown.
+                        let status = match &mut self.desktop_resize {
+                            None => rfb::EXTENDED_DESKTOP_SIZE_STATUS_PROHIBITED,
+                            Some(_) if new_width == 0 || new_height == 0 || screens.len() != 1 => {
+                                rfb::EXTENDED_DESKTOP_SIZE_STATUS_INVALID_LAYOUT
+                            }
+                            Some(resize) => {
+                                resize(new_width, new_height);
+                                rfb::EXTENDED_DESKTOP_SIZE_STATUS_OK
+                            }
+                        };
+                        desktop_size_reply =
+                            Some((rfb::EXTENDED_DESKTOP_SIZE_REASON_CLIENT, status));
+                    }
+                    rfb::CS_MESSAGE_TEXT_CHAT => {
+                        let mut input = rfb::TextChat::new_zeroed();
+                        socket.read_exact(&mut input.as_mut_bytes()[1..]).await?;
+                        match input.length.get() {
+                            rfb::TEXT_CHAT_OPEN => {
+                                if self.serial.is_some() {
+                                    chat_open = true;
+                                    let backlog = Vec::from(std::mem::take(&mut serial_backlog));
+                                    write_text_chat(socket, write_timeout, &backlog).await?;
+                                } else {
+                                    // There is nothing to chat with.
+                                    write(
+                                        socket,
+                                        write_timeout,
+                                        &text_chat_message(rfb::TEXT_CHAT_CLOSE, &[]),
+                                    )
+                                    .await?;
+                                }
+                            }
+                            rfb::TEXT_CHAT_CLOSE | rfb::TEXT_CHAT_FINISHED => chat_open = false,
+                            length => {
+                                let length = length as usize;
+                                if length > rfb::TEXT_CHAT_MAX_SIZE {
+                                    return Err(Error::TextChatTooLarge(length));
+                                }
+                                let mut text = vec![0; length];
+                                socket.read_exact(&mut text).await?;
+                                if let Some(input) = self
+                                    .serial
+                                    .as_mut()
+                                    .and_then(|serial| serial.input.as_mut())
+                                {
+                                    // Send the line as if typed at a terminal.
+                                    while text.last().is_some_and(|c| b"\0\r\n".contains(c)) {
+                                        text.pop();
+                                    }
+                                    text.push(b'\r');
+                                    input(text);
+                                }
+                            }
+                        }
                     }
                     rfb::CS_MESSAGE_QEMU => {
                         let mut input = rfb::QemuMessageHeader::new_zeroed();
@@ -446,6 +1400,10 @@ async fn run_internal(&mut self) -> Result<(), Error> {
                             rfb::QEMU_MESSAGE_EXTENDED_KEY_EVENT => {
                                 let mut input = rfb::QemuExtendedKeyEvent::new_zeroed();
                                 socket.read_exact(&mut input.as_mut_bytes()[2..]).await?;
+                                let release = input.down_flag.get() == 0;
+                                if !allow_input(&mut self.input_rate_limiter, release) {
+                                    continue;
+                                }
                                 let mut scancode = input.keycode.get() as u16;
                                 // An E0 prefix is sometimes encoded via the
                                 // high bit on a single byte.
@@ -463,3 +1421,512 @@ async fn run_internal(&mut self) -> Result<(), Error> {
         }
     }
 }
+
+/// Returns whether the rate limit allows an input event, which is always the
+/// case for events that release a key or button.
+fn allow_input(limiter: &mut Option<InputRateLimiter>, release: bool) -> bool {
+    release || limiter.as_mut().is_none_or(|limiter| limiter.try_acquire())
+}
+
+/// Builds a text chat message. `length` is the length of `text`, or one of the
+/// special values sent without text.
+fn text_chat_message(length: u32, text: &[u8]) -> Vec<u8> {
+    let mut msg = rfb::TextChat {
+        message_type: rfb::SC_MESSAGE_TYPE_TEXT_CHAT,
+        padding: [0; 3],
+        length: length.into(),
+    }
+    .as_bytes()
+    .to_vec();
+    msg.extend_from_slice(text);
+    msg
+}
+
+/// Builds a fence message with `flags` and `payload`.
+fn fence_message(flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut msg = rfb::Fence {
+        message_type: rfb::SC_MESSAGE_TYPE_FENCE,
+        padding: [0; 3],
+        flags: flags.into(),
+        length: payload.len() as u8,
+    }
+    .as_bytes()
+    .to_vec();
+    msg.extend_from_slice(payload);
+    msg
+}
+
+/// Waits for the update being encoded, if any, and sends it, so that the
+/// client receives it before anything sent afterward.
+async fn flush_update(
+    socket: &mut PolledSocket<socket2::Socket>,
+    write_timeout: &mut Option<(PolledTimer, Duration)>,
+    pending_update: &mut Option<BoxFuture<'static, Result<Vec<u8>, Error>>>,
+) -> Result<(), Error> {
+    if let Some(update) = pending_update.take() {
+        write(socket, write_timeout, &update.await?).await?;
+    }
+    Ok(())
+}
+
+/// Reads `buf` from the client during the `phase` of the handshake, failing if
+/// it takes longer than `timeout`.
+async fn read_handshake(
+    socket: &mut PolledSocket<socket2::Socket>,
+    timeout: &mut Option<(PolledTimer, Duration)>,
+    phase: &'static str,
+    buf: &mut [u8],
+) -> Result<(), Error> {
+    let read = socket.read_exact(buf);
+    match timeout {
+        Some((timer, timeout)) => futures::select! { // race semantics
+            r = read.fuse() => Ok(r?),
+            _ = timer.sleep(*timeout).fuse() => Err(Error::HandshakeTimeout(phase)),
+        },
+        None => Ok(read.await?),
+    }
+}
+
+/// Writes `buf` to the client, failing if the client stops accepting data for
+/// longer than `timeout`.
+async fn write(
+    socket: &mut PolledSocket<socket2::Socket>,
+    timeout: &mut Option<(PolledTimer, Duration)>,
+    buf: &[u8],
+) -> Result<(), Error> {
+    let write = socket.write_all(buf);
+    match timeout {
+        Some((timer, timeout)) => futures::select! { // race semantics
+            r = write.fuse() => Ok(r?),
+            _ = timer.sleep(*timeout).fuse() => Err(Error::WriteTimeout(*timeout)),
+        },
+        None => Ok(write.await?),
+    }
+}
+
+/// Tells the client why the connection is being refused, in the form
+/// expected for the protocol `version` the client asked for.
+async fn refuse_connection(
+    socket: &mut PolledSocket<socket2::Socket>,
+    write_timeout: &mut Option<(PolledTimer, Duration)>,
+    version: &rfb::ProtocolVersion,
+    reason: &str,
+) -> Result<(), Error> {
+    let mut msg = if version.0 == rfb::PROTOCOL_VERSION_37 || version.0 == rfb::PROTOCOL_VERSION_38
+    {
+        // An empty list of security types.
+        rfb::Security37 { type_count: 0 }.as_bytes().to_vec()
+    } else {
+        rfb::Security33 {
+            padding: [0; 3],
+            security_type: rfb::SECURITY_TYPE_INVALID,
+        }
+        .as_bytes()
+        .to_vec()
+    };
+    msg.extend_from_slice(&(reason.len() as u32).to_be_bytes());
+    msg.extend_from_slice(reason.as_bytes());
+    write(socket, write_timeout, &msg).await?;
+    Ok(())
+}
+
+/// Returns the RFB 3.8 SecurityResult message telling the client that the
+/// handshake failed because of `reason`.
+fn security_failure_message(reason: &str) -> Vec<u8> {
+    let mut msg = rfb::SecurityResult {
+        status: rfb::SECURITY_RESULT_STATUS_FAILED.into(),
+    }
+    .as_bytes()
+    .to_vec();
+    msg.extend_from_slice(&(reason.len() as u32).to_be_bytes());
+    msg.extend_from_slice(reason.as_bytes());
+    msg
+}
+
+/// Sends `text` to the client's text chat, split into as many messages as
+/// needed.
+async fn write_text_chat(
+    socket: &mut PolledSocket<socket2::Socket>,
+    write_timeout: &mut Option<(PolledTimer, Duration)>,
+    text: &[u8],
+) -> Result<(), Error> {
+    for chunk in text.chunks(rfb::TEXT_CHAT_MAX_SIZE) {
+        write(
+            socket,
+            write_timeout,
+            &text_chat_message(chunk.len() as u32, chunk),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Clips `damage` to `bounds`, dropping empty regions and merging them into a
+/// single bounding box if there are too many.
+fn clip_damage(damage: Vec<Rect>, bounds: &Rect) -> Vec<Rect> {
+    let bounds_right = bounds.x.saturating_add(bounds.width);
+    let bounds_bottom = bounds.y.saturating_add(bounds.height);
+    let mut rects = damage
+        .into_iter()
+        .filter_map(|rect| {
+            let x = rect.x.max(bounds.x);
+            let y = rect.y.max(bounds.y);
+            let right = rect.x.saturating_add(rect.width).min(bounds_right);
+            let bottom = rect.y.saturating_add(rect.height).min(bounds_bottom);
+            (right > x && bottom > y).then(|| Rect {
+                x,
+                y,
+                width: right - x,
+                height: bottom - y,
+            })
+        })
+        .collect::<Vec<_>>();
+    if rects.len() > MAX_UPDATE_RECTS {
+        let x = rects.iter().map(|r| r.x).min().unwrap();
+        let y = rects.iter().map(|r| r.y).min().unwrap();
+        let right = rects.iter().map(|r| r.x + r.width).max().unwrap();
+        let bottom = rects.iter().map(|r| r.y + r.height).max().unwrap();
+        rects = vec![Rect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }];
+    }
+    rects
+}
+
+/// Builds a framebuffer update message containing `rects`, each with its
+/// pixels, for a client using pixel format `fmt`.
+///
+/// If `tight` is set, solid rectangles are sent as Tight fills, and
+/// photographic rectangles are compressed with JPEG if the client's `settings`
+/// allow it and the connection's `throughput` cannot keep up with sending that
+/// rectangle losslessly. Everything else is sent with the raw encoding.
+fn encode_update(
+    fmt: &rfb::PixelFormat,
+    tight: bool,
+    settings: &adaptive::EncodingSettings,
+    throughput: &adaptive::Throughput,
+    rects: &[(Rect, Vec<u32>)],
+) -> Vec<u8> {
+    let mut data = rfb::FramebufferUpdate {
+        message_type: rfb::SC_MESSAGE_TYPE_FRAMEBUFFER_UPDATE,
+        padding: 0,
+        rectangle_count: 0.into(),
+    }
+    .as_bytes()
+    .to_vec();
+    let mut count = 0u16;
+    for (rect, pixels) in rects {
+        if !tight {
+            encode_raw_rect(&mut data, fmt, rect, pixels);
+            count += 1;
+            continue;
+        }
+        // Tight rectangles are limited in width.
+        for x in (0..rect.width).step_by(rfb::TIGHT_MAX_WIDTH.into()) {
+            let width = (rect.width - x).min(rfb::TIGHT_MAX_WIDTH);
+            let sub = Rect {
+                x: rect.x + x,
+                y: rect.y,
+                width,
+                height: rect.height,
+            };
+            let sub_pixels: Cow<'_, [u32]> = if width == rect.width {
+                pixels.into()
+            } else {
+                pixels
+                    .chunks_exact(rect.width.into())
+                    .flat_map(|line| &line[x.into()..(x + width).into()])
+                    .copied()
+                    .collect()
+            };
+            // The quality is chosen for each rectangle, so that a large
+            // photographic region can be sent lossily without degrading
+            // the rest of the update.
+            let raw_bytes = sub_pixels.len() * (fmt.bits_per_pixel / 8) as usize;
+            let quality_level = adaptive::lossy_quality_level(settings, throughput, raw_bytes);
+            encode_tight_rect(&mut data, fmt, &sub, &sub_pixels, quality_level);
+            count += 1;
+        }
+    }
+    data[2..4].copy_from_slice(&count.to_be_bytes());
+    data
+}
+
+/// Returns the FramebufferUpdate message telling the client the desktop size.
+///
+/// If `extended` is set, the size is sent with the ExtendedDesktopSize
+/// pseudo-encoding, with its reason and status, as a single screen. Otherwise
+/// it is sent with DesktopSize.
+fn desktop_size_message(width: u16, height: u16, extended: Option<(u16, u16)>) -> Vec<u8> {
+    let mut msg = rfb::FramebufferUpdate {
+        message_type: rfb::SC_MESSAGE_TYPE_FRAMEBUFFER_UPDATE,
+        padding: 0,
+        rectangle_count: 1.into(),
+    }
+    .as_bytes()
+    .to_vec();
+    let Some((reason, status)) = extended else {
+        push_rect_header(
+            &mut msg,
+            &Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+            rfb::ENCODING_TYPE_DESKTOP_SIZE,
+        );
+        return msg;
+    };
+    push_rect_header(
+        &mut msg,
+        &Rect {
+            x: reason,
+            y: status,
+            width,
+            height,
+        },
+        rfb::ENCODING_TYPE_EXTENDED_DESKTOP_SIZE,
+    );
+    msg.extend_from_slice(
+        rfb::ExtendedDesktopSize {
+            number_of_screens: 1,
+            padding: [0; 3],
+        }
+        .as_bytes(),
+    );
+    msg.extend_from_slice(
+        rfb::Screen {
+            id: 0.into(),
+            x: 0.into(),
+            y: 0.into(),
+            width: width.into(),
+            height: height.into(),
+            flags: 0.into(),
+        }
+        .as_bytes(),
+    );
+    msg
+}
+
+/// Appends a rectangle header for `rect` with `encoding_type`.
+fn push_rect_header(data: &mut Vec<u8>, rect: &Rect, encoding_type: u32) {
+    data.extend_from_slice(
+        rfb::Rectangle {
+            x: rect.x.into(),
+            y: rect.y.into(),
+            width: rect.width.into(),
+            height: rect.height.into(),
+            encoding_type: encoding_type.into(),
+        }
+        .as_bytes(),
+    );
+}
+
+/// Appends `rect` using the Tight encoding if that is worthwhile, falling back
+/// to the raw encoding.
+fn encode_tight_rect(
+    data: &mut Vec<u8>,
+    fmt: &rfb::PixelFormat,
+    rect: &Rect,
+    pixels: &[u32],
+    quality_level: Option<u8>,
+) {
+    if let Some(color) = tight::solid_color(pixels) {
+        push_rect_header(data, rect, rfb::ENCODING_TYPE_TIGHT);
+        tight::encode_fill(data, color);
+        return;
+    }
+    if let Some(quality_level) = quality_level {
+        if tight::should_use_jpeg(pixels) {
+            let header_len = data.len();
+            push_rect_header(data, rect, rfb::ENCODING_TYPE_TIGHT);
+            if tight::encode_jpeg(data, rect.width, rect.height, pixels, quality_level) {
+                return;
+            }
+            data.truncate(header_len);
+        }
+    }
+    encode_raw_rect(data, fmt, rect, pixels);
+}
+
+/// Appends `rect` using the raw encoding.
+fn encode_raw_rect(data: &mut Vec<u8>, fmt: &rfb::PixelFormat, rect: &Rect, pixels: &[u32]) {
+    push_rect_header(data, rect, rfb::ENCODING_TYPE_RAW);
+    push_pixels(data, fmt, pixels);
+}
+
+/// Appends `pixels` in the client's pixel format.
+/// Returns whether pixels can be converted to `fmt` by [`push_pixels`]: 8, 16,
+/// or 32 bits per pixel, with each color's maximum nonzero and at most 8 bits
+/// wide (as in the framebuffer), and shifted within the pixel.
+fn pixel_format_supported(fmt: &rfb::PixelFormat) -> bool {
+    matches!(fmt.bits_per_pixel, 8 | 16 | 32)
+        && [
+            (fmt.red_max.get(), fmt.red_shift),
+            (fmt.green_max.get(), fmt.green_shift),
+            (fmt.blue_max.get(), fmt.blue_shift),
+        ]
+        .into_iter()
+        .all(|(max, shift)| (1..=0xff).contains(&max) && shift < fmt.bits_per_pixel)
+}
+
+fn push_pixels(data: &mut Vec<u8>, fmt: &rfb::PixelFormat, pixels: &[u32]) {
+    let shift_r = 24 - fmt.red_max.get().count_ones();
+    let shift_g = 16 - fmt.green_max.get().count_ones();
+    let shift_b = 8 - fmt.blue_max.get().count_ones();
+    let convert = |p: u32| {
+        let (r, g, b) = (p & 0xff0000, p & 0xff00, p & 0xff);
+        r >> shift_r << fmt.red_shift
+            | g >> shift_g << fmt.green_shift
+            | b >> shift_b << fmt.blue_shift
+    };
+    match fmt.bits_per_pixel / 8 {
+        1 => data.extend(pixels.iter().map(|&p| convert(p) as u8)),
+        2 => {
+            for &p in pixels {
+                data.extend_from_slice((convert(p) as u16).as_bytes());
+            }
+        }
+        4 if shift_r == fmt.red_shift as u32
+            && shift_g == fmt.green_shift as u32
+            && shift_b == fmt.blue_shift as u32 =>
+        {
+            data.extend_from_slice(pixels.as_bytes());
+        }
+        4 => {
+            for &p in pixels {
+                data.extend_from_slice(convert(p).as_bytes());
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MAX_UPDATE_RECTS;
+    use super::Rect;
+    use crate::rfb;
+
+    fn rect(x: u16, y: u16, width: u16, height: u16) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn clip_damage() {
+        let cases = [
+            // Inside the bounds.
+            (
+                vec![rect(1, 2, 3, 4)],
+                rect(0, 0, 100, 50),
+                vec![rect(1, 2, 3, 4)],
+            ),
+            // Partly outside, including past the origin of offset bounds.
+            (
+                vec![rect(90, 40, 20, 20), rect(0, 0, 15, 15)],
+                rect(10, 10, 90, 40),
+                vec![rect(90, 40, 10, 10), rect(10, 10, 5, 5)],
+            ),
+            // Outside, empty, or at the edge of the coordinate space.
+            (
+                vec![
+                    rect(100, 0, 10, 10),
+                    rect(10, 10, 0, 5),
+                    rect(65530, 0, 10, 10),
+                ],
+                rect(0, 0, 100, 50),
+                vec![],
+            ),
+            (
+                vec![rect(65530, 65530, 10, 10)],
+                rect(0, 0, u16::MAX, u16::MAX),
+                vec![rect(65530, 65530, 5, 5)],
+            ),
+        ];
+        for (damage, bounds, expected) in cases {
+            assert_eq!(
+                super::clip_damage(damage.clone(), &bounds),
+                expected,
+                "{damage:?} in {bounds:?}"
+            );
+        }
+
+        // Too many regions are merged into their bounding box, after
+        // clipping.
+        let damage = (0..=MAX_UPDATE_RECTS as u16)
+            .map(|i| rect(i, i, 1, 1))
+            .chain([rect(200, 200, 1, 1)])
+            .collect();
+        assert_eq!(
+            super::clip_damage(damage, &rect(0, 0, 100, 50)),
+            [rect(
+                0,
+                0,
+                MAX_UPDATE_RECTS as u16 + 1,
+                MAX_UPDATE_RECTS as u16 + 1
+            )]
+        );
+    }
+
+    #[test]
+    fn pixel_format_supported() {
+        let rgb888 = rfb::PixelFormat {
+            bits_per_pixel: 32,
+            depth: 24,
+            big_endian_flag: 0,
+            true_color_flag: 1,
+            red_max: 0xff.into(),
+            green_max: 0xff.into(),
+            blue_max: 0xff.into(),
+            red_shift: 16,
+            green_shift: 8,
+            blue_shift: 0,
+            padding: [0; 3],
+        };
+        let rgb565 = rfb::PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            red_max: 0x1f.into(),
+            green_max: 0x3f.into(),
+            blue_max: 0x1f.into(),
+            red_shift: 11,
+            green_shift: 5,
+            ..rgb888
+        };
+        assert!(super::pixel_format_supported(&rgb888));
+        assert!(super::pixel_format_supported(&rgb565));
+        for fmt in [
+            rfb::PixelFormat {
+                bits_per_pixel: 24,
+                ..rgb888
+            },
+            rfb::PixelFormat {
+                bits_per_pixel: 0,
+                ..rgb888
+            },
+            rfb::PixelFormat {
+                green_max: 0.into(),
+                ..rgb888
+            },
+            rfb::PixelFormat {
+                blue_max: 0x3ff.into(),
+                ..rgb888
+            },
+            rfb::PixelFormat {
+                red_shift: 16,
+                ..rgb565
+            },
+        ] {
+            assert!(!super::pixel_format_supported(&fmt), "{fmt:?}");
+        }
+    }
+}
@@ -13,6 +13,8 @@
 mod packed_nums {
     pub type u16_be = zerocopy::U16<zerocopy::BigEndian>;
     pub type u32_be = zerocopy::U32<zerocopy::BigEndian>;
+    pub type u32_le = zerocopy::U32<zerocopy::LittleEndian>;
+    pub type u64_le = zerocopy::U64<zerocopy::LittleEndian>;
 }
 
 // As defined in https://github.com/rfbproto/rfbproto/blob/master/rfbproto.rst#handshaking-messages
@@ -95,6 +97,11 @@ pub struct PixelFormat {
 pub const CS_MESSAGE_KEY_EVENT: u8 = 4;
 pub const CS_MESSAGE_POINTER_EVENT: u8 = 5;
 pub const CS_MESSAGE_CLIENT_CUT_TEXT: u8 = 6;
+pub const CS_MESSAGE_FILE_TRANSFER: u8 = 7;
+pub const CS_MESSAGE_TEXT_CHAT: u8 = 11;
+pub const CS_MESSAGE_ENABLE_CONTINUOUS_UPDATES: u8 = 150;
+pub const CS_MESSAGE_FENCE: u8 = 248;
+pub const CS_MESSAGE_SET_DESKTOP_SIZE: u8 = 251;
 pub const CS_MESSAGE_QEMU: u8 = 255;
 
 #[repr(C)]
@@ -126,7 +133,32 @@ pub struct SetEncodings {
 pub const ENCODING_TYPE_TIGHT_PNG: u32 = -260i32 as u32;
 
 pub const ENCODING_TYPE_DESKTOP_SIZE: u32 = -223i32 as u32;
+pub const ENCODING_TYPE_CURSOR: u32 = -239i32 as u32;
 pub const ENCODING_TYPE_QEMU_EXTENDED_KEY_EVENT: u32 = -258i32 as u32;
+pub const ENCODING_TYPE_DESKTOP_NAME: u32 = -307i32 as u32;
+pub const ENCODING_TYPE_EXTENDED_DESKTOP_SIZE: u32 = -308i32 as u32;
+pub const ENCODING_TYPE_FENCE: u32 = -312i32 as u32;
+pub const ENCODING_TYPE_CONTINUOUS_UPDATES: u32 = -313i32 as u32;
+pub const ENCODING_TYPE_CURSOR_WITH_ALPHA: u32 = -314i32 as u32;
+pub const ENCODING_TYPE_QUALITY_LEVEL_0: u32 = -32i32 as u32;
+pub const ENCODING_TYPE_QUALITY_LEVEL_9: u32 = -23i32 as u32;
+pub const ENCODING_TYPE_COMPRESS_LEVEL_0: u32 = -256i32 as u32;
+pub const ENCODING_TYPE_COMPRESS_LEVEL_9: u32 = -247i32 as u32;
+pub const ENCODING_TYPE_EXTENDED_CLIPBOARD: u32 = 0xc0a1e5ce;
+
+pub const TIGHT_CONTROL_FILL: u8 = 0x80;
+pub const TIGHT_CONTROL_JPEG: u8 = 0x90;
+pub const TIGHT_MAX_WIDTH: u16 = 2048;
+
+// Extended clipboard flags, sent in place of the text of a cut text message
+// whose length is negative.
+pub const EXTENDED_CLIPBOARD_FORMAT_TEXT: u32 = 1 << 0;
+pub const EXTENDED_CLIPBOARD_FORMAT_MASK: u32 = 0xffff;
+pub const EXTENDED_CLIPBOARD_ACTION_CAPS: u32 = 1 << 24;
+pub const EXTENDED_CLIPBOARD_ACTION_REQUEST: u32 = 1 << 25;
+pub const EXTENDED_CLIPBOARD_ACTION_PEEK: u32 = 1 << 26;
+pub const EXTENDED_CLIPBOARD_ACTION_NOTIFY: u32 = 1 << 27;
+pub const EXTENDED_CLIPBOARD_ACTION_PROVIDE: u32 = 1 << 28;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
@@ -166,12 +198,51 @@ pub struct ClientCutText {
     // text: [u8; N],
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct EnableContinuousUpdates {
+    pub message_type: u8,
+    pub enable_flag: u8,
+    pub x: u16_be,
+    pub y: u16_be,
+    pub width: u16_be,
+    pub height: u16_be,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct SetDesktopSize {
+    pub message_type: u8,
+    pub padding: u8,
+    pub width: u16_be,
+    pub height: u16_be,
+    pub number_of_screens: u8,
+    pub padding2: u8,
+    // screens: [Screen; N],
+}
+
+/// A screen in the desktop layout of SetDesktopSize and ExtendedDesktopSize.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct Screen {
+    pub id: u32_be,
+    pub x: u16_be,
+    pub y: u16_be,
+    pub width: u16_be,
+    pub height: u16_be,
+    pub flags: u32_be,
+}
+
 // Server to client messages
 
 pub const SC_MESSAGE_TYPE_FRAMEBUFFER_UPDATE: u8 = 0;
 pub const SC_MESSAGE_TYPE_SET_COLOR_MAP_ENTRIES: u8 = 1;
 pub const SC_MESSAGE_TYPE_BELL: u8 = 2;
 pub const SC_MESSAGE_TYPE_SERVER_CUT_TEXT: u8 = 3;
+pub const SC_MESSAGE_TYPE_FILE_TRANSFER: u8 = 7;
+pub const SC_MESSAGE_TYPE_TEXT_CHAT: u8 = 11;
+pub const SC_MESSAGE_TYPE_END_OF_CONTINUOUS_UPDATES: u8 = 150;
+pub const SC_MESSAGE_TYPE_FENCE: u8 = 248;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
@@ -193,6 +264,25 @@ pub struct Rectangle {
     // data: ...
 }
 
+/// The data of an ExtendedDesktopSize rectangle, whose x and y are the reason
+/// for the change and its status.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct ExtendedDesktopSize {
+    pub number_of_screens: u8,
+    pub padding: [u8; 3],
+    // screens: [Screen; N],
+}
+
+pub const EXTENDED_DESKTOP_SIZE_REASON_SERVER: u16 = 0;
+pub const EXTENDED_DESKTOP_SIZE_REASON_CLIENT: u16 = 1;
+pub const EXTENDED_DESKTOP_SIZE_REASON_OTHER_CLIENT: u16 = 2;
+
+pub const EXTENDED_DESKTOP_SIZE_STATUS_OK: u16 = 0;
+pub const EXTENDED_DESKTOP_SIZE_STATUS_PROHIBITED: u16 = 1;
+pub const EXTENDED_DESKTOP_SIZE_STATUS_OUT_OF_RESOURCES: u16 = 2;
+pub const EXTENDED_DESKTOP_SIZE_STATUS_INVALID_LAYOUT: u16 = 3;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
 pub struct SetColorMapEntries {
@@ -226,6 +316,26 @@ pub struct ServerCutText {
     // text: [u8; N],
 }
 
+// Fence extension, shared by both directions.
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct Fence {
+    pub message_type: u8,
+    pub padding: [u8; 3],
+    pub flags: u32_be,
+    pub length: u8,
+    // payload: [u8; length]
+}
+
+pub const FENCE_FLAG_BLOCK_BEFORE: u32 = 1 << 0;
+pub const FENCE_FLAG_BLOCK_AFTER: u32 = 1 << 1;
+pub const FENCE_FLAG_SYNC_NEXT: u32 = 1 << 2;
+pub const FENCE_FLAG_REQUEST: u32 = 1 << 31;
+
+/// The largest payload allowed in a fence.
+pub const FENCE_MAX_PAYLOAD: usize = 64;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
 pub struct QemuMessageHeader {
@@ -244,3 +354,96 @@ pub struct QemuExtendedKeyEvent {
     pub keysym: u32_be,
     pub keycode: u32_be,
 }
+
+// UltraVNC file transfer extension, shared by both directions.
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct FileTransfer {
+    pub message_type: u8,
+    pub content_type: u8,
+    pub content_param: u8,
+    pub padding: u8,
+    pub size: u32_be,
+    pub length: u32_be,
+    // data: [u8; length]
+}
+
+pub const FT_DIR_CONTENT_REQUEST: u8 = 1;
+pub const FT_DIR_PACKET: u8 = 2;
+pub const FT_FILE_TRANSFER_REQUEST: u8 = 3;
+pub const FT_FILE_HEADER: u8 = 4;
+pub const FT_FILE_PACKET: u8 = 5;
+pub const FT_END_OF_FILE: u8 = 6;
+pub const FT_ABORT_FILE_TRANSFER: u8 = 7;
+pub const FT_FILE_TRANSFER_OFFER: u8 = 8;
+pub const FT_FILE_ACCEPT_HEADER: u8 = 9;
+pub const FT_COMMAND: u8 = 10;
+pub const FT_COMMAND_RETURN: u8 = 11;
+pub const FT_FILE_CHECKSUMS: u8 = 12;
+pub const FT_FILE_TRANSFER_ACCESS: u8 = 14;
+
+// Content parameters for FT_DIR_CONTENT_REQUEST.
+pub const FT_REQUEST_DIR_CONTENT: u8 = 1;
+pub const FT_REQUEST_DRIVES_LIST: u8 = 2;
+
+// Content parameters for FT_DIR_PACKET.
+pub const FT_DIR_PACKET_DIRECTORY: u8 = 1;
+pub const FT_DIR_PACKET_FILE: u8 = 2;
+pub const FT_DIR_PACKET_DRIVES_LIST: u8 = 3;
+
+// Content parameters for FT_COMMAND and FT_COMMAND_RETURN.
+pub const FT_COMMAND_DIR_CREATE: u8 = 1;
+pub const FT_COMMAND_FILE_DELETE: u8 = 2;
+pub const FT_COMMAND_FILE_RENAME: u8 = 3;
+
+/// The `size` value used to report failure.
+pub const FT_SIZE_FAILED: u32 = !0;
+
+/// The largest data payload sent in a single file packet.
+pub const FT_BLOCK_SIZE: usize = 8192;
+
+/// The Win32 `WIN32_FIND_DATAA` structure used by UltraVNC for directory
+/// listings. Unlike the rest of the protocol, this is little endian.
+///
+/// The Win32 structure is padded to a multiple of 4 bytes, which the unaligned
+/// fields here do not do, so the padding is explicit.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct Win32FindData {
+    pub file_attributes: u32_le,
+    pub creation_time: u64_le,
+    pub last_access_time: u64_le,
+    pub last_write_time: u64_le,
+    pub file_size_high: u32_le,
+    pub file_size_low: u32_le,
+    pub reserved0: u32_le,
+    pub reserved1: u32_le,
+    pub file_name: [u8; 260],
+    pub alternate_file_name: [u8; 14],
+    pub padding: [u8; 2],
+}
+
+const _: () = assert!(size_of::<Win32FindData>() == 320);
+
+pub const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+pub const FILE_ATTRIBUTE_NORMAL: u32 = 0x80;
+
+// UltraVNC text chat extension, shared by both directions.
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, IntoBytes, Immutable, KnownLayout, FromBytes)]
+pub struct TextChat {
+    pub message_type: u8,
+    pub padding: [u8; 3],
+    pub length: u32_be,
+    // text: [u8; length]
+}
+
+// Special `length` values, sent without any text.
+pub const TEXT_CHAT_OPEN: u32 = 0xffffffff;
+pub const TEXT_CHAT_CLOSE: u32 = 0xfffffffe;
+pub const TEXT_CHAT_FINISHED: u32 = 0xfffffffd;
+
+/// The longest text sent in a single message.
+pub const TEXT_CHAT_MAX_SIZE: usize = 4096;
@@ -2,7 +2,14 @@
 // Licensed under the MIT License.
 
 //! This module provides machinery to convert from the xkeysym keyboard input
-//! format used by RFB to US keyboard scancodes used by VMs.
+//! format used by RFB to the keyboard scancodes used by VMs, for the keyboard
+//! layout the guest is configured with.
+
+use input_core::keymap::Dead;
+use input_core::keymap::KeyboardLayout;
+use input_core::keymap::Keystroke;
+use input_core::keymap::Level;
+use input_core::keymap::Position;
 
 /// If set on a scancode value, a shift key must be held to emit the desired
 /// character.
@@ -10,147 +17,94 @@
 /// If set on a scancode value, there must be no shift key held in order to emit
 /// the desired character.
 const UNSHIFT: u32 = 0x20000;
+/// If set on a scancode value, AltGr (and no shift key) must be held to emit
+/// the desired character.
+const ALTGR: u32 = 0x40000;
 
-const ASCII_PRINT_START: u16 = 32;
+/// X keysyms (other than the ones for characters, which depend on the layout).
+const KEYSYM_SPACE: u32 = 0x20;
+const KEYSYM_BACK_SPACE: u32 = 0xff08;
+const KEYSYM_TAB: u32 = 0xff09;
+const KEYSYM_RETURN_OR_ENTER: u32 = 0xff0d;
+const KEYSYM_ESCAPE: u32 = 0xff1b;
+const KEYSYM_INSERT: u32 = 0xff63;
+const KEYSYM_DELETE: u32 = 0xffff;
+const KEYSYM_HOME: u32 = 0xff50;
+const KEYSYM_END: u32 = 0xff57;
+const KEYSYM_PAGE_UP: u32 = 0xff55;
+const KEYSYM_PAGE_DOWN: u32 = 0xff56;
+const KEYSYM_LEFT: u32 = 0xff51;
+const KEYSYM_UP: u32 = 0xff52;
+const KEYSYM_RIGHT: u32 = 0xff53;
+const KEYSYM_DOWN: u32 = 0xff54;
+const KEYSYM_F1: u32 = 0xffbe;
+const KEYSYM_F2: u32 = 0xffbf;
+const KEYSYM_F3: u32 = 0xffc0;
+const KEYSYM_F4: u32 = 0xffc1;
+const KEYSYM_F5: u32 = 0xffc2;
+const KEYSYM_F6: u32 = 0xffc3;
+const KEYSYM_F7: u32 = 0xffc4;
+const KEYSYM_F8: u32 = 0xffc5;
+const KEYSYM_F9: u32 = 0xffc6;
+const KEYSYM_F10: u32 = 0xffc7;
+const KEYSYM_F11: u32 = 0xffc8;
+const KEYSYM_F12: u32 = 0xffc9;
+const KEYSYM_SHIFT_LEFT: u32 = 0xffe1;
+const KEYSYM_SHIFT_RIGHT: u32 = 0xffe2;
+const KEYSYM_CONTROL_LEFT: u32 = 0xffe3;
+const KEYSYM_CONTROL_RIGHT: u32 = 0xffe4;
+const KEYSYM_META_LEFT: u32 = 0xffe7;
+const KEYSYM_META_RIGHT: u32 = 0xffe8;
+const KEYSYM_ALT_LEFT: u32 = 0xffe9;
+const KEYSYM_ALT_RIGHT: u32 = 0xffea;
+const KEYSYM_SUPER_LEFT: u32 = 0xffeb;
+const KEYSYM_SUPER_RIGHT: u32 = 0xffec;
+const KEYSYM_MENU: u32 = 0xff67;
+const KEYSYM_ISO_LEVEL3_SHIFT: u32 = 0xfe03;
+const KEYSYM_EURO_SIGN: u32 = 0x20ac;
+const KEYSYM_UNICODE: u32 = 0x0100_0000;
 
-/// This table maps ASCII values to US keyboard scancodes, starting at
-/// ASCII_PRINT_START.
-const ASCII_TO_US: &[u32] = &[
-    0x39,           // 32 ' '
-    0x02 | SHIFT,   // 33 !
-    0x28 | SHIFT,   // 34 "
-    0x04 | SHIFT,   // 35 #
-    0x05 | SHIFT,   // 36 $
-    0x06 | SHIFT,   // 37 %
-    0x08 | SHIFT,   // 38 &
-    0x28 | UNSHIFT, // 39 '
-    0x0a | SHIFT,   // 40 (
-    0x0b | SHIFT,   // 41 )
-    0x09 | SHIFT,   // 42 *
-    0x0d | SHIFT,   // 43 +
-    0x33 | UNSHIFT, // 44 ,
-    0x0c | UNSHIFT, // 45 -
-    0x34 | UNSHIFT, // 46 .
-    0x35 | UNSHIFT, // 47 /
-    0x0b | UNSHIFT, // 48 0
-    0x02 | UNSHIFT, // 49 1
-    0x03 | UNSHIFT, // 50 2
-    0x04 | UNSHIFT, // 51 3
-    0x05 | UNSHIFT, // 52 4
-    0x06 | UNSHIFT, // 53 5
-    0x07 | UNSHIFT, // 54 6
-    0x08 | UNSHIFT, // 55 7
-    0x09 | UNSHIFT, // 56 8
-    0x0a | UNSHIFT, // 57 9
-    0x27 | SHIFT,   // 58 :
-    0x27 | UNSHIFT, // 59 ;
-    0x33 | SHIFT,   // 60 <
-    0x0d | UNSHIFT, // 61 =
-    0x34 | SHIFT,   // 62 >
-    0x35 | SHIFT,   // 63 ?
-    0x03 | SHIFT,   // 64 @
-    0x1e | SHIFT,   // 65 A
-    0x30 | SHIFT,   // 66 B
-    0x2e | SHIFT,   // 67 C
-    0x20 | SHIFT,   // 68 D
-    0x12 | SHIFT,   // 69 E
-    0x21 | SHIFT,   // 70 F
-    0x22 | SHIFT,   // 71 G
-    0x23 | SHIFT,   // 72 H
-    0x17 | SHIFT,   // 73 I
-    0x24 | SHIFT,   // 74 J
-    0x25 | SHIFT,   // 75 K
-    0x26 | SHIFT,   // 76 L
-    0x32 | SHIFT,   // 77 M
-    0x31 | SHIFT,   // 78 N
-    0x18 | SHIFT,   // 79 O
-    0x19 | SHIFT,   // 80 P
-    0x10 | SHIFT,   // 81 Q
-    0x13 | SHIFT,   // 82 R
-    0x1f | SHIFT,   // 83 S
-    0x14 | SHIFT,   // 84 T
-    0x16 | SHIFT,   // 85 U
-    0x2f | SHIFT,   // 86 V
-    0x11 | SHIFT,   // 87 W
-    0x2d | SHIFT,   // 88 X
-    0x15 | SHIFT,   // 89 Y
-    0x2c | SHIFT,   // 90 Z
-    0x1a | UNSHIFT, // 91 [
-    0x2b | UNSHIFT, // 92 '\'
-    0x1b | UNSHIFT, // 93 ]
-    0x07 | SHIFT,   // 94 ^
-    0x0c | SHIFT,   // 95 _
-    0x29 | UNSHIFT, // 96 `
-    0x1e | UNSHIFT, // 97 a
-    0x30 | UNSHIFT, // 98 b
-    0x2e | UNSHIFT, // 99 c
-    0x20 | UNSHIFT, // 100 d
-    0x12 | UNSHIFT, // 101 e
-    0x21 | UNSHIFT, // 102 f
-    0x22 | UNSHIFT, // 103 g
-    0x23 | UNSHIFT, // 104 h
-    0x17 | UNSHIFT, // 105 i
-    0x24 | UNSHIFT, // 106 j
-    0x25 | UNSHIFT, // 107 k
-    0x26 | UNSHIFT, // 108 l
-    0x32 | UNSHIFT, // 109 m
-    0x31 | UNSHIFT, // 110 n
-    0x18 | UNSHIFT, // 111 o
-    0x19 | UNSHIFT, // 112 p
-    0x10 | UNSHIFT, // 113 q
-    0x13 | UNSHIFT, // 114 r
-    0x1f | UNSHIFT, // 115 s
-    0x14 | UNSHIFT, // 116 t
-    0x16 | UNSHIFT, // 117 u
-    0x2f | UNSHIFT, // 118 v
-    0x11 | UNSHIFT, // 119 w
-    0x2d | UNSHIFT, // 120 x
-    0x15 | UNSHIFT, // 121 y
-    0x2c | UNSHIFT, // 122 z
-    0x1a | SHIFT,   // 123 {
-    0x2b | SHIFT,   // 124 |
-    0x1b | SHIFT,   // 125 }
-    0x29 | SHIFT,   // 126 ~
-];
+// Dead keys.
+const KEYSYM_DEAD_GRAVE: u32 = 0xfe50;
+const KEYSYM_DEAD_ACUTE: u32 = 0xfe51;
+const KEYSYM_DEAD_CIRCUMFLEX: u32 = 0xfe52;
+const KEYSYM_DEAD_TILDE: u32 = 0xfe53;
+const KEYSYM_DEAD_DIAERESIS: u32 = 0xfe57;
 
-/// X keysyms (other than the ones that match ASCII values).
-const KEYSYM_BACK_SPACE: u16 = 0xff08;
-const KEYSYM_TAB: u16 = 0xff09;
-const KEYSYM_RETURN_OR_ENTER: u16 = 0xff0d;
-const KEYSYM_ESCAPE: u16 = 0xff1b;
-const KEYSYM_INSERT: u16 = 0xff63;
-const KEYSYM_DELETE: u16 = 0xffff;
-const KEYSYM_HOME: u16 = 0xff50;
-const KEYSYM_END: u16 = 0xff57;
-const KEYSYM_PAGE_UP: u16 = 0xff55;
-const KEYSYM_PAGE_DOWN: u16 = 0xff56;
-const KEYSYM_LEFT: u16 = 0xff51;
-const KEYSYM_UP: u16 = 0xff52;
-const KEYSYM_RIGHT: u16 = 0xff53;
-const KEYSYM_DOWN: u16 = 0xff54;
-const KEYSYM_F1: u16 = 0xffbe;
-const KEYSYM_F2: u16 = 0xffbf;
-const KEYSYM_F3: u16 = 0xffc0;
-const KEYSYM_F4: u16 = 0xffc1;
-const KEYSYM_F5: u16 = 0xffc2;
-const KEYSYM_F6: u16 = 0xffc3;
-const KEYSYM_F7: u16 = 0xffc4;
-const KEYSYM_F8: u16 = 0xffc5;
-const KEYSYM_F9: u16 = 0xffc6;
-const KEYSYM_F10: u16 = 0xffc7;
-const KEYSYM_F11: u16 = 0xffc8;
-const KEYSYM_F12: u16 = 0xffc9;
-const KEYSYM_SHIFT_LEFT: u16 = 0xffe1;
-const KEYSYM_SHIFT_RIGHT: u16 = 0xffe2;
-const KEYSYM_CONTROL_LEFT: u16 = 0xffe3;
-const KEYSYM_CONTROL_RIGHT: u16 = 0xffe4;
-const KEYSYM_META_LEFT: u16 = 0xffe7;
-const KEYSYM_META_RIGHT: u16 = 0xffe8;
-const KEYSYM_ALT_LEFT: u16 = 0xffe9;
-const KEYSYM_ALT_RIGHT: u16 = 0xffea;
+// Keys of Japanese keyboards.
+const KEYSYM_MUHENKAN: u32 = 0xff22;
+const KEYSYM_HENKAN: u32 = 0xff23;
+const KEYSYM_HIRAGANA_KATAKANA: u32 = 0xff27;
+const KEYSYM_ZENKAKU_HANKAKU: u32 = 0xff2a;
 
-/// Table mapping non-ASCII xkeysyms to US keyboard scancodes.
-const KEYSYM_TO_US: &[(u16, u32)] = &[
+// XFree86 vendor keysyms, sent for multimedia and browser keys.
+const KEYSYM_XF86_AUDIO_LOWER_VOLUME: u32 = 0x1008ff11;
+const KEYSYM_XF86_AUDIO_MUTE: u32 = 0x1008ff12;
+const KEYSYM_XF86_AUDIO_RAISE_VOLUME: u32 = 0x1008ff13;
+const KEYSYM_XF86_AUDIO_PLAY: u32 = 0x1008ff14;
+const KEYSYM_XF86_AUDIO_STOP: u32 = 0x1008ff15;
+const KEYSYM_XF86_AUDIO_PREV: u32 = 0x1008ff16;
+const KEYSYM_XF86_AUDIO_NEXT: u32 = 0x1008ff17;
+const KEYSYM_XF86_HOME_PAGE: u32 = 0x1008ff18;
+const KEYSYM_XF86_MAIL: u32 = 0x1008ff19;
+const KEYSYM_XF86_SEARCH: u32 = 0x1008ff1b;
+const KEYSYM_XF86_CALCULATOR: u32 = 0x1008ff1d;
+const KEYSYM_XF86_BACK: u32 = 0x1008ff26;
+const KEYSYM_XF86_FORWARD: u32 = 0x1008ff27;
+const KEYSYM_XF86_STOP: u32 = 0x1008ff28;
+const KEYSYM_XF86_REFRESH: u32 = 0x1008ff29;
+const KEYSYM_XF86_POWER_OFF: u32 = 0x1008ff2a;
+const KEYSYM_XF86_WAKE_UP: u32 = 0x1008ff2b;
+const KEYSYM_XF86_SLEEP: u32 = 0x1008ff2f;
+const KEYSYM_XF86_FAVORITES: u32 = 0x1008ff30;
+const KEYSYM_XF86_AUDIO_PAUSE: u32 = 0x1008ff31;
+const KEYSYM_XF86_AUDIO_MEDIA: u32 = 0x1008ff32;
+const KEYSYM_XF86_MY_COMPUTER: u32 = 0x1008ff33;
+
+/// Table mapping xkeysyms for keys that do not type characters (and so are in
+/// the same place on every layout) to scancodes.
+const KEYSYM_TO_SCANCODE: &[(u32, u32)] = &[
+    (KEYSYM_SPACE, 0x39),
     (KEYSYM_BACK_SPACE, 0x0e),
     (KEYSYM_TAB, 0x0f),
     (KEYSYM_RETURN_OR_ENTER, 0x1c),
@@ -185,67 +139,169 @@
     (KEYSYM_META_RIGHT, 0xe05c),
     (KEYSYM_ALT_LEFT, 0x38),
     (KEYSYM_ALT_RIGHT, 0xe038),
+    (KEYSYM_SUPER_LEFT, 0xe05b),
+    (KEYSYM_SUPER_RIGHT, 0xe05c),
+    (KEYSYM_MENU, 0xe05d),
+    // AltGr is the right Alt key.
+    (KEYSYM_ISO_LEVEL3_SHIFT, 0xe038),
+    (KEYSYM_MUHENKAN, 0x7b),
+    (KEYSYM_HENKAN, 0x79),
+    (KEYSYM_HIRAGANA_KATAKANA, 0x70),
+    (KEYSYM_ZENKAKU_HANKAKU, 0x29),
+    (KEYSYM_XF86_AUDIO_LOWER_VOLUME, 0xe02e),
+    (KEYSYM_XF86_AUDIO_MUTE, 0xe020),
+    (KEYSYM_XF86_AUDIO_RAISE_VOLUME, 0xe030),
+    (KEYSYM_XF86_AUDIO_PLAY, 0xe022),
+    (KEYSYM_XF86_AUDIO_STOP, 0xe024),
+    (KEYSYM_XF86_AUDIO_PREV, 0xe010),
+    (KEYSYM_XF86_AUDIO_NEXT, 0xe019),
+    (KEYSYM_XF86_HOME_PAGE, 0xe032),
+    (KEYSYM_XF86_MAIL, 0xe06c),
+    (KEYSYM_XF86_SEARCH, 0xe065),
+    (KEYSYM_XF86_CALCULATOR, 0xe021),
+    (KEYSYM_XF86_BACK, 0xe06a),
+    (KEYSYM_XF86_FORWARD, 0xe069),
+    (KEYSYM_XF86_STOP, 0xe068),
+    (KEYSYM_XF86_REFRESH, 0xe067),
+    (KEYSYM_XF86_POWER_OFF, 0xe05e),
+    (KEYSYM_XF86_WAKE_UP, 0xe063),
+    (KEYSYM_XF86_SLEEP, 0xe05f),
+    (KEYSYM_XF86_FAVORITES, 0xe066),
+    // There is a single play/pause key.
+    (KEYSYM_XF86_AUDIO_PAUSE, 0xe022),
+    (KEYSYM_XF86_AUDIO_MEDIA, 0xe06d),
+    (KEYSYM_XF86_MY_COMPUTER, 0xe06b),
 ];
 
-/// Converts an xkeysym to a US keyboard scancode (possibly with SHIFT or
-/// UNSHIFT set). Returns None if there is no such mapping.
-fn keysym_to_scancode(keysym: u16) -> Option<u32> {
-    if keysym >= ASCII_PRINT_START && ((keysym - ASCII_PRINT_START) as usize) < ASCII_TO_US.len() {
-        Some(ASCII_TO_US[(keysym - ASCII_PRINT_START) as usize])
-    } else {
-        KEYSYM_TO_US
-            .iter()
-            .find_map(|(ks, code)| if keysym == *ks { Some(*code) } else { None })
+/// Converts an xkeysym for a key that does not type a character to a
+/// scancode. Returns None if there is no such mapping.
+fn keysym_to_scancode(keysym: u32) -> Option<u32> {
+    KEYSYM_TO_SCANCODE
+        .iter()
+        .find_map(|(ks, code)| if keysym == *ks { Some(*code) } else { None })
+}
+
+/// Returns the character typed for `keysym`, if it is one.
+fn keysym_to_char(keysym: u32) -> Option<char> {
+    match keysym {
+        // Latin-1 keysyms match their code points.
+        0x20..=0x7e | 0xa0..=0xff => char::from_u32(keysym),
+        KEYSYM_EURO_SIGN => Some('€'),
+        KEYSYM_UNICODE..=0x0110_ffff => char::from_u32(keysym - KEYSYM_UNICODE),
+        _ => None,
     }
 }
 
+/// Returns the keysym that types `c`.
+fn char_to_keysym(c: char) -> u32 {
+    match c {
+        '\n' => KEYSYM_RETURN_OR_ENTER,
+        '\t' => KEYSYM_TAB,
+        // Latin-1 keysyms match their code points.
+        ' '..='~' | '\u{a0}'..='\u{ff}' => c.into(),
+        _ => KEYSYM_UNICODE + u32::from(c),
+    }
+}
+
+/// Returns the accent for a dead key's `keysym`, if it is one.
+fn keysym_to_dead(keysym: u32) -> Option<Dead> {
+    let dead = match keysym {
+        KEYSYM_DEAD_GRAVE => Dead::Grave,
+        KEYSYM_DEAD_ACUTE => Dead::Acute,
+        KEYSYM_DEAD_CIRCUMFLEX => Dead::Circumflex,
+        KEYSYM_DEAD_TILDE => Dead::Tilde,
+        KEYSYM_DEAD_DIAERESIS => Dead::Diaeresis,
+        _ => return None,
+    };
+    Some(dead)
+}
+
+/// Returns the scancode for a key on the layout, with the flags for the
+/// modifiers it must be typed with.
+fn position_to_scancode(position: Position) -> u32 {
+    position.scancode as u32
+        | match position.level {
+            Level::Base => UNSHIFT,
+            Level::Shift => SHIFT,
+            Level::AltGr => ALTGR,
+        }
+}
+
 /// Scancode tracking state.
 pub struct State {
+    layout: KeyboardLayout,
     lshift: bool,
     rshift: bool,
+    altgr: bool,
 }
 
 impl State {
-    /// Constructs a new State.
-    pub fn new() -> Self {
+    /// Constructs a new State, for a guest configured with `layout`.
+    pub fn new(layout: KeyboardLayout) -> Self {
         Self {
+            layout,
             lshift: false,
             rshift: false,
+            altgr: false,
         }
     }
 
-    /// Emits scancodes (by calling `f`) corresponding to the provided ASCII char.
-    /// Panics if `c` is outside of the ASCII printable range (' ' to '~').
-    pub fn emit_ascii_char<F: FnMut(u16, bool)>(&mut self, c: u8, down: bool, f: F) {
-        self.emit_us_scancode(ASCII_TO_US[(c - ASCII_PRINT_START as u8) as usize], down, f)
+    /// Emits scancodes (by calling `f`) that type `c` on the guest's layout.
+    /// Nothing is emitted if `c` cannot be typed on the layout.
+    pub fn emit_char<F: FnMut(u16, bool)>(&mut self, c: char, down: bool, f: F) {
+        self.emit(char_to_keysym(c), down, f)
     }
 
-    /// Emits scancodes (by calling `f`) corresponding to the provided US keyboard scancode.
-    pub fn emit_us_scancode<F: FnMut(u16, bool)>(&mut self, scancode: u32, down: bool, mut f: F) {
+    /// Emits scancodes (by calling `f`) corresponding to the provided
+    /// scancode, pressing or releasing modifiers around it as its flags
+    /// require.
+    fn emit_scancode<F: FnMut(u16, bool)>(&mut self, scancode: u32, down: bool, f: &mut F) {
         if down {
-            if scancode & SHIFT != 0 && !self.lshift && !self.rshift {
-                let lshift = keysym_to_scancode(KEYSYM_SHIFT_LEFT).unwrap() as u16;
-                f(lshift, true);
-                f(scancode as u16, true);
-                f(lshift, false);
-            } else if scancode & UNSHIFT != 0 && (self.lshift || self.rshift) {
-                let lshift = keysym_to_scancode(KEYSYM_SHIFT_LEFT).unwrap() as u16;
-                let rshift = keysym_to_scancode(KEYSYM_SHIFT_RIGHT).unwrap() as u16;
+            let lshift = keysym_to_scancode(KEYSYM_SHIFT_LEFT).unwrap() as u16;
+            let rshift = keysym_to_scancode(KEYSYM_SHIFT_RIGHT).unwrap() as u16;
+            let altgr = keysym_to_scancode(KEYSYM_ISO_LEVEL3_SHIFT).unwrap() as u16;
+            let shifted = self.lshift || self.rshift;
+            let add_shift = scancode & SHIFT != 0 && !shifted;
+            let remove_shift = scancode & (UNSHIFT | ALTGR) != 0 && shifted;
+            let add_altgr = scancode & ALTGR != 0 && !self.altgr;
+            // On layouts without AltGr, the right Alt key is an ordinary Alt
+            // key and is left alone.
+            let remove_altgr =
+                scancode & (SHIFT | UNSHIFT) != 0 && self.altgr && self.layout.has_altgr();
+            if remove_shift {
                 if self.lshift {
                     f(lshift, false);
                 }
                 if self.rshift {
                     f(rshift, false);
                 }
-                f(scancode as u16, true);
+            }
+            if remove_altgr {
+                f(altgr, false);
+            }
+            if add_shift {
+                f(lshift, true);
+            }
+            if add_altgr {
+                f(altgr, true);
+            }
+            f(scancode as u16, true);
+            if add_altgr {
+                f(altgr, false);
+            }
+            if add_shift {
+                f(lshift, false);
+            }
+            if remove_altgr {
+                f(altgr, true);
+            }
+            if remove_shift {
                 if self.lshift {
                     f(lshift, true);
                 }
                 if self.rshift {
                     f(rshift, true);
                 }
-            } else {
-                f(scancode as u16, true);
             }
         } else {
             f(scancode as u16, false);
@@ -253,16 +309,145 @@ pub fn emit_us_scancode<F: FnMut(u16, bool)>(&mut self, scancode: u32, down: boo
     }
 
     /// Emits scancodes (by calling `f`) corresponding to the provided xkeysym.
-    pub fn emit<F: FnMut(u16, bool)>(&mut self, keysym: u16, down: bool, f: F) {
+    pub fn emit<F: FnMut(u16, bool)>(&mut self, keysym: u32, down: bool, mut f: F) {
         if let Some(scancode) = keysym_to_scancode(keysym) {
-            self.emit_us_scancode(scancode, down, f);
+            self.emit_scancode(scancode, down, &mut f);
 
-            if keysym == KEYSYM_SHIFT_LEFT {
-                self.lshift = down;
+            match keysym {
+                KEYSYM_SHIFT_LEFT => self.lshift = down,
+                KEYSYM_SHIFT_RIGHT => self.rshift = down,
+                KEYSYM_ALT_RIGHT | KEYSYM_ISO_LEVEL3_SHIFT => self.altgr = down,
+                _ => {}
+            }
+            return;
+        }
+        let keystroke = match keysym_to_char(keysym) {
+            Some(c) => self.layout.keystroke(c),
+            None => keysym_to_dead(keysym)
+                .and_then(|dead| self.layout.dead_key(dead))
+                .map(Keystroke::Key),
+        };
+        match keystroke {
+            Some(Keystroke::Key(position)) => {
+                self.emit_scancode(position_to_scancode(position), down, &mut f);
             }
-            if keysym == KEYSYM_SHIFT_RIGHT {
-                self.rshift = down;
+            // The guest combines the dead key with the next key, so type both
+            // when the client's key is pressed.
+            Some(Keystroke::Composed { dead, key }) if down => {
+                for position in [dead, key] {
+                    let scancode = position_to_scancode(position);
+                    self.emit_scancode(scancode, true, &mut f);
+                    self.emit_scancode(scancode, false, &mut f);
+                }
             }
+            Some(Keystroke::Composed { .. }) | None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KEYSYM_ISO_LEVEL3_SHIFT;
+    use super::KEYSYM_SHIFT_LEFT;
+    use super::KEYSYM_SHIFT_RIGHT;
+    use super::State;
+    use input_core::keymap::KeyboardLayout;
+
+    const SHIFT: u16 = 0x2a;
+    const ALTGR: u16 = 0xe038;
+
+    fn type_char(state: &mut State, c: char) -> Vec<(u16, bool)> {
+        let mut keys = Vec::new();
+        for down in [true, false] {
+            state.emit_char(c, down, |scancode, down| keys.push((scancode, down)));
         }
+        keys
+    }
+
+    fn press(state: &mut State, keysym: u32) -> Vec<(u16, bool)> {
+        let mut keys = Vec::new();
+        state.emit(keysym, true, |scancode, down| keys.push((scancode, down)));
+        keys
+    }
+
+    #[test]
+    fn emit_char() {
+        let mut state = State::new(KeyboardLayout::EnUs);
+        assert_eq!(type_char(&mut state, 'a'), [(0x1e, true), (0x1e, false)]);
+        assert_eq!(
+            type_char(&mut state, 'A'),
+            [(0x2a, true), (0x1e, true), (0x2a, false), (0x1e, false)]
+        );
+        assert_eq!(type_char(&mut state, '\n'), [(0x1c, true), (0x1c, false)]);
+        assert_eq!(type_char(&mut state, '\t'), [(0x0f, true), (0x0f, false)]);
+        // Characters that cannot be typed are skipped.
+        assert_eq!(type_char(&mut state, '\r'), []);
+        assert_eq!(type_char(&mut state, '€'), []);
+    }
+
+    #[test]
+    fn layouts() {
+        let mut state = State::new(KeyboardLayout::De);
+        assert_eq!(
+            type_char(&mut state, '@'),
+            [(ALTGR, true), (0x10, true), (ALTGR, false), (0x10, false)]
+        );
+        assert_eq!(type_char(&mut state, 'z'), [(0x15, true), (0x15, false)]);
+
+        // Characters that are not on the layout are typed with a dead key.
+        let mut state = State::new(KeyboardLayout::Fr);
+        assert_eq!(
+            type_char(&mut state, 'ê'),
+            [(0x1a, true), (0x1a, false), (0x12, true), (0x12, false)]
+        );
+
+        let mut state = State::new(KeyboardLayout::Ja);
+        assert_eq!(type_char(&mut state, '\\'), [(0x7d, true), (0x7d, false)]);
+    }
+
+    #[test]
+    fn modifiers_restored() {
+        // A shift key the client holds is released for an unshifted key.
+        let mut state = State::new(KeyboardLayout::EnUs);
+        assert_eq!(press(&mut state, KEYSYM_SHIFT_LEFT), [(SHIFT, true)]);
+        assert_eq!(
+            type_char(&mut state, 'a'),
+            [(SHIFT, false), (0x1e, true), (SHIFT, true), (0x1e, false)]
+        );
+        assert_eq!(type_char(&mut state, 'A'), [(0x1e, true), (0x1e, false)]);
+
+        // Both shift keys are released and pressed again for an AltGr key.
+        let mut state = State::new(KeyboardLayout::De);
+        press(&mut state, KEYSYM_SHIFT_LEFT);
+        press(&mut state, KEYSYM_SHIFT_RIGHT);
+        assert_eq!(
+            type_char(&mut state, '@'),
+            [
+                (SHIFT, false),
+                (0x36, false),
+                (ALTGR, true),
+                (0x10, true),
+                (ALTGR, false),
+                (SHIFT, true),
+                (0x36, true),
+                (0x10, false),
+            ]
+        );
+
+        // AltGr the client holds is released for a key typed without it.
+        let mut state = State::new(KeyboardLayout::De);
+        assert_eq!(press(&mut state, KEYSYM_ISO_LEVEL3_SHIFT), [(ALTGR, true)]);
+        assert_eq!(
+            type_char(&mut state, 'Z'),
+            [
+                (ALTGR, false),
+                (SHIFT, true),
+                (0x15, true),
+                (SHIFT, false),
+                (ALTGR, true),
+                (0x15, false),
+            ]
+        );
+        assert_eq!(type_char(&mut state, '€'), [(0x12, true), (0x12, false)]);
     }
 }
@@ -0,0 +1,175 @@
+// Copyright (c) Microsoft Corporation.
+// Licensed under the MIT License.
+
+//! Support for the fill and JPEG compression methods of the Tight encoding.
+//!
+//! The zlib-based methods are not implemented, so rectangles that should be
+//! sent losslessly and are not a single color use the raw encoding instead.
+
+use crate::rfb;
+use std::collections::HashSet;
+
+/// The JPEG quality for each client quality level, matching TigerVNC.
+const JPEG_QUALITY: [u8; 10] = [15, 29, 41, 42, 62, 77, 79, 86, 92, 100];
+
+/// Rectangles smaller than this are sent losslessly, since JPEG's overhead
+/// outweighs its savings.
+const MIN_JPEG_PIXELS: usize = 64 * 64;
+
+/// Rectangles with at least this many colors are treated as photographic
+/// content, which is sent lossily. Anything with fewer (text, window
+/// decorations, and so on) would show visible artifacts.
+const PHOTOGRAPHIC_COLORS: usize = 64;
+
+/// Returns whether the Tight encoding can be used with the client's pixel
+/// format.
+///
+/// Fill colors are sent as 3-byte "TPIXEL"s, which are only defined for
+/// 24-bit true color formats.
+pub(crate) fn supports_format(fmt: &rfb::PixelFormat) -> bool {
+    fmt.true_color_flag != 0
+        && fmt.bits_per_pixel == 32
+        && fmt.depth == 24
+        && fmt.red_max.get() == 255
+        && fmt.green_max.get() == 255
+        && fmt.blue_max.get() == 255
+}
+
+/// Returns the color of `pixels` if they are all the same.
+pub(crate) fn solid_color(pixels: &[u32]) -> Option<u32> {
+    let (&first, rest) = pixels.split_first()?;
+    let first = first & 0xffffff;
+    rest.iter().all(|&p| p & 0xffffff == first).then_some(first)
+}
+
+/// Returns whether a rectangle should be sent lossily.
+pub(crate) fn should_use_jpeg(pixels: &[u32]) -> bool {
+    if pixels.len() < MIN_JPEG_PIXELS {
+        return false;
+    }
+    let mut colors = HashSet::with_capacity(PHOTOGRAPHIC_COLORS);
+    for &p in pixels {
+        colors.insert(p & 0xffffff);
+        if colors.len() >= PHOTOGRAPHIC_COLORS {
+            return true;
+        }
+    }
+    false
+}
+
+/// Appends the Tight data for a solid rectangle of `color`.
+pub(crate) fn encode_fill(data: &mut Vec<u8>, color: u32) {
+    let [b, g, r, _] = color.to_le_bytes();
+    data.extend_from_slice(&[rfb::TIGHT_CONTROL_FILL, r, g, b]);
+}
+
+/// Appends the Tight data for a JPEG-compressed rectangle, or returns `false`
+/// if the rectangle could not be compressed.
+pub(crate) fn encode_jpeg(
+    data: &mut Vec<u8>,
+    width: u16,
+    height: u16,
+    pixels: &[u32],
+    quality_level: u8,
+) -> bool {
+    let rgb = pixels
+        .iter()
+        .flat_map(|&p| {
+            let [b, g, r, _] = p.to_le_bytes();
+            [r, g, b]
+        })
+        .collect::<Vec<_>>();
+    let quality = JPEG_QUALITY[quality_level.min(9) as usize];
+    let mut jpeg = Vec::new();
+    let result = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, quality).encode(
+        &rgb,
+        width.into(),
+        height.into(),
+        image::ExtendedColorType::Rgb8,
+    );
+    // The compact length is limited to 22 bits.
+    if result.is_err() || jpeg.len() >= 1 << 22 {
+        return false;
+    }
+    data.push(rfb::TIGHT_CONTROL_JPEG);
+    push_compact_length(data, jpeg.len());
+    data.extend_from_slice(&jpeg);
+    true
+}
+
+/// Appends `len` in Tight's 1-3 byte compact representation.
+fn push_compact_length(data: &mut Vec<u8>, len: usize) {
+    let mut len = len;
+    for _ in 0..2 {
+        if len < 0x80 {
+            break;
+        }
+        data.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    data.push(len as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MIN_JPEG_PIXELS;
+    use super::PHOTOGRAPHIC_COLORS;
+    use super::push_compact_length;
+    use super::should_use_jpeg;
+    use super::solid_color;
+
+    #[test]
+    fn solid() {
+        assert_eq!(solid_color(&[]), None);
+        // The unused high byte is ignored.
+        assert_eq!(
+            solid_color(&[0xff12_3456, 0x0012_3456, 0x8012_3456]),
+            Some(0x12_3456)
+        );
+        assert_eq!(solid_color(&[0x12_3456, 0x12_3457]), None);
+    }
+
+    #[test]
+    fn jpeg() {
+        let colors = |len: usize, colors: usize| {
+            (0..len)
+                .map(|i| (i % colors) as u32 * 0x01_0101)
+                .collect::<Vec<_>>()
+        };
+        assert!(should_use_jpeg(&colors(
+            MIN_JPEG_PIXELS,
+            PHOTOGRAPHIC_COLORS
+        )));
+        // Too few colors.
+        assert!(!should_use_jpeg(&colors(
+            MIN_JPEG_PIXELS,
+            PHOTOGRAPHIC_COLORS - 1
+        )));
+        // Too small.
+        assert!(!should_use_jpeg(&colors(
+            MIN_JPEG_PIXELS - 1,
+            PHOTOGRAPHIC_COLORS
+        )));
+        // Colors differing only in the unused high byte are the same.
+        let pixels = (0..MIN_JPEG_PIXELS)
+            .map(|i| ((i % PHOTOGRAPHIC_COLORS) as u32) << 24)
+            .collect::<Vec<_>>();
+        assert!(!should_use_jpeg(&pixels));
+    }
+
+    #[test]
+    fn compact_length() {
+        for (len, expected) in [
+            (0, &[0x00][..]),
+            (0x7f, &[0x7f]),
+            (0x80, &[0x80, 0x01]),
+            (0x3fff, &[0xff, 0x7f]),
+            (0x4000, &[0x80, 0x80, 0x01]),
+            (0x3f_ffff, &[0xff, 0xff, 0xff]),
+        ] {
+            let mut data = Vec::new();
+            push_compact_length(&mut data, len);
+            assert_eq!(data, expected, "{len:#x}");
+        }
+    }
+}
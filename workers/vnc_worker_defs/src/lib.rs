@@ -3,23 +3,198 @@
 
 #![expect(missing_docs)]
 
+use input_core::keymap::KeyboardLayout;
+use input_core::rate_limit::InputRateLimit;
 use mesh::MeshPayload;
+use mesh::rpc::Rpc;
 use mesh_worker::WorkerId;
 use std::net::TcpListener;
+use std::path::PathBuf;
+use std::time::Duration;
+use unix_socket::UnixListener;
+use vm_resource::Resource;
+use vm_resource::kind::ConsoleHandleKind;
 
 /// The VNC server's input parameters.
 #[derive(MeshPayload)]
 pub struct VncParameters<T> {
     /// The socket the VNC server will listen on
     pub listener: T,
-    /// The framebuffer memory.
-    pub framebuffer: framebuffer::FramebufferAccess,
-    /// A channel to send input to.
-    pub input_send: mesh::Sender<input_core::InputData>,
+    /// The console to display and send input to.
+    pub console: Resource<ConsoleHandleKind>,
+    /// The desktop name shown by clients, typically the VM's name.
+    pub name: String,
+    /// New desktop names, sent when the VM is renamed. Connected clients that
+    /// support the DesktopName pseudo-encoding are updated immediately.
+    pub name_updates: mesh::Receiver<String>,
+    /// A host directory to expose to clients via the file transfer extension.
+    /// File transfer is disabled if this is `None`.
+    pub file_transfer_dir: Option<PathBuf>,
+    /// Log a summary of each connection's input (event counts, timing, and
+    /// use of special key combinations), without recording the keys typed.
+    pub input_audit: bool,
+    /// The maximum number of framebuffer updates sent to a client per second.
+    /// Changes made between updates are coalesced into the next one.
+    pub max_frame_rate: u32,
+    /// The limit on the rate of keyboard and pointer events accepted from
+    /// each client. Excess events are dropped. Input is not limited if this is
+    /// `None`.
+    pub input_rate_limit: Option<InputRateLimit>,
+    /// A listening viewer to connect to, in addition to accepting connections
+    /// on `listener`.
+    pub reverse_connection: Option<ReverseConnection>,
+    /// Accept WebSocket connections on `listener`, as made by browser clients
+    /// such as noVNC, rather than plain RFB ones. Reverse connections always
+    /// use plain RFB.
+    pub websocket: bool,
+    /// The origins of the web pages allowed to connect over WebSocket, such
+    /// as `http://localhost:6080`. Browsers send the origin of the page making
+    /// the connection, and connections from any other page are refused.
+    pub websocket_origins: Vec<String>,
+    /// A serial port to mirror to clients that open a text chat (an UltraVNC
+    /// extension).
+    pub serial: Option<VncSerial>,
+    /// A clipboard to keep in sync with connected clients, for a guest
+    /// integration device to share the guest's clipboard through.
+    pub clipboard: Option<VncClipboard>,
+    /// The keyboard layout the guest is configured with, which determines the
+    /// scancodes sent for the characters typed on clients.
+    pub keyboard_layout: KeyboardLayout,
+    /// Work around clients that do not follow the protocol, rather than
+    /// disconnecting them.
+    pub permissive: bool,
+    /// The time allowed for a client to send its part of each phase of the
+    /// connection handshake.
+    pub handshake_timeout: Duration,
+    /// Where to register the address `listener` is bound to, along with the
+    /// desktop name, so that the consoles of VMs with automatically allocated
+    /// ports can be found. The server registers again when it is renamed.
+    pub registry: Option<mesh::Sender<VncRegistryRequest>>,
 }
 
+impl<T> VncParameters<T> {
+    /// Returns the same parameters with a different listener, for choosing the
+    /// kind of socket (and so the worker) after the rest are built.
+    pub fn with_listener<U>(self, listener: U) -> VncParameters<U> {
+        let Self {
+            listener: _,
+            console,
+            name,
+            name_updates,
+            file_transfer_dir,
+            input_audit,
+            max_frame_rate,
+            input_rate_limit,
+            reverse_connection,
+            websocket,
+            websocket_origins,
+            serial,
+            clipboard,
+            keyboard_layout,
+            permissive,
+            handshake_timeout,
+            registry,
+        } = self;
+        VncParameters {
+            listener,
+            console,
+            name,
+            name_updates,
+            file_transfer_dir,
+            input_audit,
+            max_frame_rate,
+            input_rate_limit,
+            reverse_connection,
+            websocket,
+            websocket_origins,
+            serial,
+            clipboard,
+            keyboard_layout,
+            permissive,
+            handshake_timeout,
+            registry,
+        }
+    }
+}
+
+/// A VNC server's entry in a registry of consoles.
+#[derive(Debug, Clone, MeshPayload)]
+pub struct VncRegistration {
+    /// The desktop name shown by clients, typically the VM's name.
+    pub name: String,
+    /// The address the server is listening on.
+    pub address: String,
+}
+
+/// A request to a registry of consoles, which is shared by the VNC servers
+/// registering in it and anything looking up their addresses.
+#[derive(MeshPayload)]
+pub enum VncRegistryRequest {
+    /// Adds a server's entry, replacing any entry with the same address.
+    Register(VncRegistration),
+    /// Lists the registered servers.
+    List(Rpc<(), Vec<VncRegistration>>),
+}
+
+/// A guest serial port exposed through the VNC server.
+#[derive(MeshPayload)]
+pub struct VncSerial {
+    /// The port's name, e.g. `com1`.
+    pub name: String,
+    /// Data transmitted by the guest.
+    pub output: mesh::Receiver<Vec<u8>>,
+    /// Data to be received by the guest, or `None` if clients can only watch
+    /// the port.
+    pub input: Option<mesh::Sender<Vec<u8>>>,
+}
+
+/// A clipboard shared between VNC clients and the guest.
+#[derive(MeshPayload)]
+pub struct VncClipboard {
+    /// Text copied on the connected client.
+    pub client_text: mesh::Sender<String>,
+    /// Text copied in the guest, sent to the connected client and to each
+    /// new client.
+    pub guest_text: mesh::Receiver<String>,
+}
+
+/// An outbound ("reverse") connection from the VNC server to a viewer that is
+/// listening for one.
+///
+/// The server connects whenever no client is connected, retrying with a
+/// backoff while the viewer is unreachable.
+#[derive(Debug, Clone, MeshPayload)]
+pub struct ReverseConnection {
+    /// The viewer's address, as `host:port`.
+    pub viewer: String,
+    /// The proxy to connect through, if any.
+    pub proxy: Option<VncProxy>,
+}
+
+/// A proxy for outbound VNC connections.
+#[derive(Debug, Clone, MeshPayload)]
+pub enum VncProxy {
+    /// An HTTP proxy supporting the CONNECT method, at `host:port`.
+    HttpConnect(String),
+    /// A SOCKS5 proxy not requiring authentication, at `host:port`.
+    Socks5(String),
+}
+
+/// The default value for [`VncParameters::name`].
+pub const DEFAULT_NAME: &str = "HvLite VM";
+
+/// The default value for [`VncParameters::max_frame_rate`].
+pub const DEFAULT_MAX_FRAME_RATE: u32 = 30;
+
+/// The default value for [`VncParameters::handshake_timeout`].
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub const VNC_WORKER_TCP: WorkerId<VncParameters<TcpListener>> = WorkerId::new("VncWorkerTcp");
 
+/// A VNC server listening on a Unix domain socket, for management stacks that
+/// proxy console traffic without opening a TCP port on the host.
+pub const VNC_WORKER_UNIX: WorkerId<VncParameters<UnixListener>> = WorkerId::new("VncWorkerUnix");
+
 #[cfg(any(windows, target_os = "linux"))]
 pub const VNC_WORKER_VMSOCKET: WorkerId<VncParameters<vmsocket::VmListener>> =
     WorkerId::new("VncWorkerVmSocket");